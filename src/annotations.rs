@@ -0,0 +1,536 @@
+/*! Hand-written annotations on top of the score
+ *
+ * Annotations are made directly in the viewer. They are stored per song, in the same
+ * coordinates as [`Staff`](collection::Staff) (relative to a reference paper width of 1),
+ * so that they stick to the music regardless of layout and zoom.
+ *
+ * The viewer keeps them in the user's data directory, together with the files of external
+ * annotation editors (see [`EXTERNAL_FILES`]). Song files may carry a copy of all of them
+ * too, for sharing them along with the song (see [`AnnotationLayer`]).
+ *
+ * When pages are added to a song or removed from it, the annotations need to move along.
+ * Thus they remember the pages they were made for (see [`PageIdentity`]). Annotations on
+ * pages that were removed are put into quarantine: they are moved into a separate file.
+ */
+use super::*;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use typed_index_collections::TiVec;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+	Pen,
+	/// Wide and translucent
+	Highlighter,
+}
+
+impl Tool {
+	/// The line width at full pressure, relative to the page width
+	pub fn width(&self) -> f64 {
+		match self {
+			Self::Pen => 0.0025,
+			Self::Highlighter => 0.012,
+		}
+	}
+
+	/// RGBA
+	pub fn color(&self) -> (f64, f64, f64, f64) {
+		match self {
+			Self::Pen => (0.1, 0.1, 0.6, 1.0),
+			Self::Highlighter => (1.0, 0.9, 0.0, 0.4),
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct StrokePoint {
+	pub x: f64,
+	pub y: f64,
+	/// Between 0 and 1. Input devices without pressure information use 1
+	pub pressure: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Stroke {
+	pub page: collection::PageIndex,
+	pub tool: Tool,
+	pub points: Vec<StrokePoint>,
+}
+
+impl Stroke {
+	/// The line width between two points. Light pressure still gives a visible line
+	pub fn width_at(&self, from: &StrokePoint, to: &StrokePoint) -> f64 {
+		let pressure = (from.pressure + to.pressure) / 2.0;
+		self.tool.width() * (0.3 + 0.7 * pressure.clamp(0.0, 1.0))
+	}
+
+	fn distance_to(&self, x: f64, y: f64) -> f64 {
+		let point = |p: &StrokePoint| (p.x, p.y);
+		match self.points.as_slice() {
+			[] => f64::INFINITY,
+			[single] => distance_to_segment((x, y), point(single), point(single)),
+			points => points
+				.windows(2)
+				.map(|segment| distance_to_segment((x, y), point(&segment[0]), point(&segment[1])))
+				.fold(f64::INFINITY, f64::min),
+		}
+	}
+}
+
+/// Common marks, as (text, description)
+pub const STAMPS: &[(&str, &str)] = &[
+	("1", "Finger 1"),
+	("2", "Finger 2"),
+	("3", "Finger 3"),
+	("4", "Finger 4"),
+	("5", "Finger 5"),
+	("’", "Breath"),
+	("⊓", "Down bow"),
+	("V", "Up bow"),
+];
+
+/// A short text, like a fingering or a breath mark
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Stamp {
+	pub page: collection::PageIndex,
+	/// The center of the text
+	pub x: f64,
+	pub y: f64,
+	pub text: String,
+}
+
+impl Stamp {
+	/// The font size, relative to the page width
+	pub const SIZE: f64 = 0.02;
+}
+
+/// The pages of a song at some point in time
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PageIdentity {
+	/// The [`version_uuid`](collection::SongMeta::version_uuid) of the song
+	pub version: Option<Uuid>,
+	/// The [content hash](PageImage::content_hash) of each page
+	pub pages: TiVec<collection::PageIndex, u64>,
+}
+
+impl PageIdentity {
+	pub fn new(
+		song: &collection::SongMeta,
+		pages: &TiVec<collection::PageIndex, PageImage>,
+	) -> Self {
+		Self {
+			version: Some(song.version_uuid),
+			pages: pages.iter().map(PageImage::content_hash).collect(),
+		}
+	}
+
+	/// Unknown, for annotations made before their pages were tracked
+	pub fn is_unknown(&self) -> bool {
+		self.version.is_none() && self.pages.is_empty()
+	}
+
+	/// Are those the same pages, in the same order?
+	pub fn matches(&self, other: &Self) -> bool {
+		(self.version.is_some() && self.version == other.version) || self.pages == other.pages
+	}
+
+	/// Where each of our pages is in `other`, if it still exists. If the same page occurs
+	/// multiple times (like blank pages), the occurrences are matched in order.
+	pub fn find_pages_in(
+		&self,
+		other: &Self,
+	) -> HashMap<collection::PageIndex, collection::PageIndex> {
+		let mut occurrences = HashMap::<u64, std::collections::VecDeque<_>>::new();
+		for (index, hash) in other.pages.iter_enumerated() {
+			occurrences.entry(*hash).or_default().push_back(index);
+		}
+		self.pages
+			.iter_enumerated()
+			.filter_map(|(index, hash)| {
+				let found = occurrences.get_mut(hash)?.pop_front()?;
+				Some((index, found))
+			})
+			.collect()
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Annotations {
+	pub strokes: Vec<Stroke>,
+	pub stamps: Vec<Stamp>,
+	/// The pages these annotations were made for
+	#[serde(default)]
+	pub made_for: PageIdentity,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum AnnotationsFile<'a> {
+	#[serde(rename = "0")]
+	V0 {
+		annotations: maybe_owned::MaybeOwned<'a, Annotations>,
+	},
+}
+
+impl Annotations {
+	pub fn is_empty(&self) -> bool {
+		self.strokes.is_empty() && self.stamps.is_empty()
+	}
+
+	pub fn strokes_on(&self, page: collection::PageIndex) -> impl Iterator<Item = &Stroke> {
+		self.strokes
+			.iter()
+			.filter(move |stroke| stroke.page == page)
+	}
+
+	pub fn stamps_on(&self, page: collection::PageIndex) -> impl Iterator<Item = &Stamp> {
+		self.stamps.iter().filter(move |stamp| stamp.page == page)
+	}
+
+	/// Remove all strokes and stamps that come closer than `radius` to that point.
+	/// Returns whether anything was removed.
+	pub fn erase(&mut self, page: collection::PageIndex, x: f64, y: f64, radius: f64) -> bool {
+		let count = self.strokes.len() + self.stamps.len();
+		self.strokes
+			.retain(|stroke| stroke.page != page || stroke.distance_to(x, y) > radius);
+		self.stamps.retain(|stamp| {
+			stamp.page != page
+				|| distance_to_segment((x, y), (stamp.x, stamp.y), (stamp.x, stamp.y))
+					> radius + Stamp::SIZE / 2.0
+		});
+		count != self.strokes.len() + self.stamps.len()
+	}
+
+	/// Move the annotations to where their pages are now. The annotations on pages that don't
+	/// exist anymore are removed and returned, if there are any.
+	pub fn migrate(&mut self, current: &PageIdentity) -> Option<Annotations> {
+		if self.made_for.matches(current) {
+			return None;
+		}
+		let mapping: HashMap<_, _> = if self.made_for.is_unknown() {
+			/* Assume they were made for these pages, as far as they exist */
+			current.pages.keys().map(|page| (page, page)).collect()
+		} else {
+			self.made_for.find_pages_in(current)
+		};
+
+		let mut orphaned = Annotations {
+			made_for: std::mem::replace(&mut self.made_for, current.clone()),
+			..Default::default()
+		};
+		for stroke in std::mem::take(&mut self.strokes) {
+			match mapping.get(&stroke.page) {
+				Some(page) => self.strokes.push(Stroke {
+					page: *page,
+					..stroke
+				}),
+				None => orphaned.strokes.push(stroke),
+			}
+		}
+		for stamp in std::mem::take(&mut self.stamps) {
+			match mapping.get(&stamp.page) {
+				Some(page) => self.stamps.push(Stamp {
+					page: *page,
+					..stamp
+				}),
+				None => orphaned.stamps.push(stamp),
+			}
+		}
+		(!orphaned.is_empty()).then(|| orphaned)
+	}
+
+	/// Keep annotations that don't fit the song anymore in a separate file, so that they are not lost
+	pub fn quarantine(&self, song: &Uuid) -> anyhow::Result<std::path::PathBuf> {
+		let path = quarantine_path(song, "json")?;
+		log::warn!("Moving annotations into quarantine ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(&path, atomicwrites::DisallowOverwrite);
+		file.write(|file| self.to_writer(file))
+			.map_err(|err| match err {
+				atomicwrites::Error::Internal(err) => anyhow::Error::new(err),
+				atomicwrites::Error::User(err) => err,
+			})
+			.with_context(|| format!("Failed to save annotations to '{}'", path.display()))?;
+		Ok(path)
+	}
+
+	fn path(song: &Uuid) -> anyhow::Result<std::path::PathBuf> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(xdg.place_data_file(format!("annotations/{}.json", song))?)
+	}
+
+	/// Load the annotations of a song. Songs without any have an empty set.
+	pub fn load(song: &Uuid) -> anyhow::Result<Self> {
+		let path = Self::path(song)?;
+		if !path.exists() {
+			return Ok(Self::default());
+		}
+		catch!({ Self::from_reader(std::io::BufReader::new(std::fs::File::open(&path)?)) })
+			.with_context(|| format!("Failed to load annotations from '{}'", path.display()))
+	}
+
+	pub fn save(&self, song: &Uuid) -> anyhow::Result<()> {
+		let path = Self::path(song)?;
+		log::debug!("Saving annotations ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(&path, atomicwrites::AllowOverwrite);
+		file.write(|file| self.to_writer(file))
+			.map_err(|err| match err {
+				atomicwrites::Error::Internal(err) => anyhow::Error::new(err),
+				atomicwrites::Error::User(err) => err,
+			})
+			.with_context(|| format!("Failed to save annotations to '{}'", path.display()))
+	}
+
+	/// Read the versioned file format
+	pub fn from_reader(reader: impl std::io::Read) -> anyhow::Result<Self> {
+		let file: AnnotationsFile = serde_json::from_reader(reader)?;
+		Ok(match file {
+			AnnotationsFile::V0 { annotations } => annotations.into_owned(),
+		})
+	}
+
+	/// Write the versioned file format
+	pub fn to_writer(&self, writer: impl std::io::Write) -> anyhow::Result<()> {
+		serde_json::to_writer(
+			writer,
+			&AnnotationsFile::V0 {
+				annotations: self.into(),
+			},
+		)?;
+		Ok(())
+	}
+}
+
+/// The files of external annotation editors, by extension: the documents of the editors, the
+/// overlay exported from them and the pages it was made for
+pub const EXTERNAL_FILES: &[&str] = &["xopp", "annotated.pdf", "pdf", "pages.json"];
+
+/// Where a file of an external annotation editor is kept, see [`EXTERNAL_FILES`]
+pub fn external_file(song: &Uuid, extension: &str) -> anyhow::Result<std::path::PathBuf> {
+	// TODO don't hardcode here
+	let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+	Ok(xdg.place_data_file(format!("annotations/{}.{}", song, extension))?)
+}
+
+/**
+ * All annotations of a song, as they are shared along with it
+ *
+ * In song files, they are part of the song format and thus versioned with it, see
+ * [`SongFile::save`](collection::SongFile::save).
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnnotationLayer {
+	/// The ones made in the viewer
+	pub annotations: Annotations,
+	/// The files of external annotation editors, by extension (see [`EXTERNAL_FILES`])
+	pub external: BTreeMap<String, Vec<u8>>,
+}
+
+impl AnnotationLayer {
+	pub fn is_empty(&self) -> bool {
+		self.annotations.is_empty() && self.external.is_empty()
+	}
+
+	/// The pages got a new identity without changing otherwise, like when their images were
+	/// re-encoded. What was made for the old pages is taken over to the new ones.
+	pub fn renew_pages(&mut self, old: &PageIdentity, new: &PageIdentity) -> anyhow::Result<()> {
+		if self.annotations.made_for.matches(old) {
+			self.annotations.made_for = new.clone();
+		}
+		if let Some(pages) = self.external.get_mut("pages.json") {
+			let made_for: PageIdentity =
+				serde_json::from_slice(pages).context("Failed to read the pages of the overlay")?;
+			if made_for.matches(old) {
+				*pages = serde_json::to_vec(new)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Collect everything the user's data directory has for a song
+	pub fn load(song: &Uuid) -> anyhow::Result<Self> {
+		let mut external = BTreeMap::new();
+		for extension in EXTERNAL_FILES {
+			let path = external_file(song, extension)?;
+			if path.exists() {
+				let data = std::fs::read(&path)
+					.with_context(|| format!("Failed to read '{}'", path.display()))?;
+				external.insert(extension.to_string(), data);
+			}
+		}
+		Ok(AnnotationLayer {
+			annotations: Annotations::load(song)?,
+			external,
+		})
+	}
+
+	/// Put into the user's data directory, replacing all annotations of the song that are there.
+	/// Each file is replaced atomically, and those that are not part of the layer are only
+	/// removed once all others were written.
+	pub fn save(&self, song: &Uuid) -> anyhow::Result<()> {
+		use std::io::Write;
+
+		if !self.annotations.is_empty() {
+			self.annotations.save(song)?;
+		}
+		let mut stale = Vec::new();
+		for extension in EXTERNAL_FILES {
+			let path = external_file(song, extension)?;
+			match self.external.get(*extension) {
+				Some(data) => atomicwrites::AtomicFile::new(&path, atomicwrites::AllowOverwrite)
+					.write(|file| file.write_all(data))
+					.with_context(|| format!("Failed to write '{}'", path.display()))?,
+				None => stale.push(path),
+			}
+		}
+		if self.annotations.is_empty() {
+			stale.push(Annotations::path(song)?);
+		}
+		for path in stale.into_iter().filter(|path| path.exists()) {
+			std::fs::remove_file(&path)
+				.with_context(|| format!("Failed to remove '{}'", path.display()))?;
+		}
+		Ok(())
+	}
+}
+
+/// A new file name for putting annotations of a song into quarantine, with that extension
+pub fn quarantine_path(song: &Uuid, extension: &str) -> anyhow::Result<std::path::PathBuf> {
+	// TODO don't hardcode here
+	let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+	let timestamp = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)?
+		.as_secs();
+	Ok(xdg.place_data_file(format!(
+		"annotations/quarantine/{}-{}.{}",
+		song, timestamp, extension
+	))?)
+}
+
+fn distance_to_segment(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+	let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+	let length_squared = dx * dx + dy * dy;
+	/* Project the point onto the segment */
+	let t = if length_squared > 0.0 {
+		(((point.0 - start.0) * dx + (point.1 - start.1) * dy) / length_squared).clamp(0.0, 1.0)
+	} else {
+		0.0
+	};
+	let (x, y) = (start.0 + t * dx, start.1 + t * dy);
+	((point.0 - x).powi(2) + (point.1 - y).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_erase() {
+		let point = |x, y| StrokePoint {
+			x,
+			y,
+			pressure: 1.0,
+		};
+		let mut annotations = Annotations {
+			strokes: vec![
+				Stroke {
+					page: collection::PageIndex(0),
+					tool: Tool::Pen,
+					points: vec![point(0.1, 0.1), point(0.5, 0.1)],
+				},
+				Stroke {
+					page: collection::PageIndex(1),
+					tool: Tool::Highlighter,
+					points: vec![point(0.1, 0.1), point(0.5, 0.1)],
+				},
+			],
+			stamps: vec![Stamp {
+				page: collection::PageIndex(0),
+				x: 0.8,
+				y: 0.8,
+				text: "V".into(),
+			}],
+			..Default::default()
+		};
+
+		/* Near the middle of the line, but not near its points */
+		assert!(!annotations.erase(collection::PageIndex(0), 0.3, 0.2, 0.05));
+		assert!(annotations.erase(collection::PageIndex(0), 0.3, 0.12, 0.05));
+		assert_eq!(annotations.strokes.len(), 1);
+		assert_eq!(annotations.strokes[0].page, collection::PageIndex(1));
+
+		assert!(annotations.erase(collection::PageIndex(0), 0.81, 0.79, 0.01));
+		assert!(annotations.stamps.is_empty());
+		assert!(!annotations.is_empty());
+
+		let mut json = Vec::new();
+		annotations.to_writer(&mut json).unwrap();
+		assert_eq!(
+			Annotations::from_reader(json.as_slice()).unwrap(),
+			annotations
+		);
+	}
+
+	#[test]
+	fn test_migrate() {
+		let stroke = |page| Stroke {
+			page: collection::PageIndex(page),
+			tool: Tool::Pen,
+			points: vec![],
+		};
+		let stamp = |page| Stamp {
+			page: collection::PageIndex(page),
+			x: 0.5,
+			y: 0.5,
+			text: "p".into(),
+		};
+		let identity = |version: u128, pages: &[u64]| PageIdentity {
+			version: Some(Uuid::from_u128(version)),
+			pages: pages.to_vec().into(),
+		};
+		let mut annotations = Annotations {
+			strokes: vec![stroke(0), stroke(1), stroke(2), stroke(3)],
+			stamps: vec![stamp(1), stamp(2)],
+			made_for: identity(1, &[10, 11, 12, 11]),
+		};
+
+		/* Same version, or same pages */
+		assert!(annotations.migrate(&identity(1, &[])).is_none());
+		assert!(annotations
+			.migrate(&identity(2, &[10, 11, 12, 11]))
+			.is_none());
+		assert_eq!(annotations.strokes.len(), 4);
+
+		/* Page 12 removed, a page inserted in front, the duplicate pages are kept in order */
+		let current = identity(3, &[9, 10, 11, 11]);
+		let orphaned = annotations.migrate(&current).unwrap();
+		let pages = |annotations: &Annotations| {
+			(
+				annotations
+					.strokes
+					.iter()
+					.map(|s| s.page.0)
+					.collect::<Vec<_>>(),
+				annotations
+					.stamps
+					.iter()
+					.map(|s| s.page.0)
+					.collect::<Vec<_>>(),
+			)
+		};
+		assert_eq!(pages(&annotations), (vec![1, 2, 3], vec![2]));
+		assert_eq!(pages(&orphaned), (vec![2], vec![2]));
+		assert_eq!(orphaned.made_for, identity(1, &[10, 11, 12, 11]));
+		assert_eq!(annotations.made_for, current);
+		assert!(annotations.migrate(&current).is_none());
+
+		/* Unknown pages: keep what fits */
+		let mut legacy = Annotations {
+			strokes: vec![stroke(0), stroke(5)],
+			..Default::default()
+		};
+		let orphaned = legacy.migrate(&identity(4, &[1, 2])).unwrap();
+		assert_eq!(pages(&legacy), (vec![0], vec![]));
+		assert_eq!(pages(&orphaned), (vec![5], vec![]));
+	}
+}
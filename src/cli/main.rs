@@ -73,6 +73,42 @@ enum CliCommand {
 		#[clap(min_values = 1)]
 		input_files: Vec<PathBuf>,
 	},
+	/// Put the annotations made in the viewer into the song files, to share them along
+	#[clap(arg_required_else_help = true)]
+	ExportAnnotations {
+		/// Output directory
+		#[clap(short = 'o', long = "out-dir")]
+		output: PathBuf,
+		/// Overwrite existing files in the output directory
+		#[clap(short = 'f', long)]
+		overwrite: bool,
+		/// Files to export the annotations into
+		#[clap(min_values = 1)]
+		input_files: Vec<PathBuf>,
+	},
+	/// Take over the annotations contained in the song files into the viewer
+	#[clap(arg_required_else_help = true)]
+	ImportAnnotations {
+		/// Replace annotations that were already made in the viewer
+		#[clap(short = 'f', long)]
+		overwrite: bool,
+		/// Files to import the annotations from
+		#[clap(min_values = 1)]
+		input_files: Vec<PathBuf>,
+	},
+	/// Remove the annotations from the song files, to share them without
+	#[clap(arg_required_else_help = true)]
+	StripAnnotations {
+		/// Output directory
+		#[clap(short = 'o', long = "out-dir")]
+		output: PathBuf,
+		/// Overwrite existing files in the output directory
+		#[clap(short = 'f', long)]
+		overwrite: bool,
+		/// Files to strip
+		#[clap(min_values = 1)]
+		input_files: Vec<PathBuf>,
+	},
 }
 
 #[derive(Debug, Parser)]
@@ -124,6 +160,7 @@ fn main() -> anyhow::Result<()> {
 				let output_path = output.join(input.file_name().unwrap());
 				let song = collection::SongFile::new(input, &mut Default::default())
 					.context("Corrupt song file")?;
+				let annotations = song.load_annotation_layer()?;
 				let sheets: TiVec<_, PageImage> =
 					song.load_sheets()().context("Failed to load sheets")?;
 				let thumbnail = song.thumbnail().cloned();
@@ -133,7 +170,14 @@ fn main() -> anyhow::Result<()> {
 					collection::SongFile::generate_thumbnail(&meta, &sheets)
 						.expect("Failed to generate thumbnail")
 				});
-				collection::SongFile::save(output_path, meta, &sheets, thumbnail, overwrite)?;
+				collection::SongFile::save(
+					output_path,
+					meta,
+					&sheets,
+					thumbnail,
+					annotations.as_ref(),
+					overwrite,
+				)?;
 			}
 		},
 		CliCommand::V4ExtractImages {
@@ -150,8 +194,10 @@ fn main() -> anyhow::Result<()> {
 				let output_path = output.join(input.file_name().unwrap());
 				let song = collection::SongFile::new(input, &mut Default::default())
 					.context("Corrupt song file")?;
+				let mut annotations = song.load_annotation_layer()?;
 				let mut sheets: TiVec<_, PageImage> =
 					song.load_sheets()().context("Failed to load sheets")?;
+				let old_pages = annotations::PageIdentity::new(&song.index, &sheets);
 				for (i, sheet) in sheets.iter_mut_enumerated() {
 					if !sheet.is_pdf() {
 						log::info!("Page {i} is not a PDF; skipping");
@@ -173,7 +219,19 @@ fn main() -> anyhow::Result<()> {
 				let thumbnail = song.thumbnail().cloned();
 				let mut meta = song.index;
 				meta.version_uuid = uuid::Uuid::new_v4();
-				collection::SongFile::save(output_path, meta, &sheets, thumbnail, overwrite)?;
+				/* The pages are still the same, only their hashes changed */
+				if let Some(annotations) = &mut annotations {
+					annotations
+						.renew_pages(&old_pages, &annotations::PageIdentity::new(&meta, &sheets))?;
+				}
+				collection::SongFile::save(
+					output_path,
+					meta,
+					&sheets,
+					thumbnail,
+					annotations.as_ref(),
+					overwrite,
+				)?;
 			}
 		},
 		CliCommand::ReRecognize {
@@ -191,6 +249,7 @@ fn main() -> anyhow::Result<()> {
 				let output_path = output.join(input.file_name().unwrap());
 				let song = collection::SongFile::new(&input, &mut Default::default())
 					.context("Corrupt song file")?;
+				let annotations = song.load_annotation_layer()?;
 				let mut sheets: TiVec<_, PageImage> =
 					song.load_sheets()().context("Failed to load sheets")?;
 				let thumbnail = song.thumbnail().cloned();
@@ -199,7 +258,7 @@ fn main() -> anyhow::Result<()> {
 				for (page, sheet) in sheets.iter_mut_enumerated() {
 					log::info!("Detecting page {page}");
 					let image = sheet.render_scaled(400);
-					let detected_staves: Vec<collection::Staff> =
+					let (mut detected_staves, systems) =
 						recognition::recognize_staves(&image, page);
 
 					let staves = meta
@@ -214,6 +273,16 @@ fn main() -> anyhow::Result<()> {
 					}
 
 					let result = catch!({
+						/* Older files have one box per system, so merge the proposed systems for them */
+						if detected_staves.len() != staves.len() {
+							for system in systems.iter().rev() {
+								let merged = detected_staves
+									.drain(system.clone())
+									.reduce(|a, b| a.merge(&b))
+									.unwrap();
+								detected_staves.insert(*system.start(), merged);
+							}
+						}
 						anyhow::ensure!(detected_staves.len() == staves.len(),
 							"Detection did not give the expected number of staves: Expected {} but detected {}", staves.len(), detected_staves.len());
 
@@ -237,7 +306,14 @@ fn main() -> anyhow::Result<()> {
 					}
 				}
 				meta.version_uuid = uuid::Uuid::new_v4();
-				collection::SongFile::save(output_path, meta, &sheets, thumbnail, overwrite)?;
+				collection::SongFile::save(
+					output_path,
+					meta,
+					&sheets,
+					thumbnail,
+					annotations.as_ref(),
+					overwrite,
+				)?;
 			}
 
 			log::info!("Done.");
@@ -261,13 +337,99 @@ fn main() -> anyhow::Result<()> {
 				let output_path = output.join(input.file_name().unwrap());
 				let song = collection::SongFile::new(input, &mut Default::default())
 					.context("Corrupt song file")?;
+				let annotations = song.load_annotation_layer()?;
 				let sheets: TiVec<_, PageImage> =
 					song.load_sheets()().context("Failed to load sheets")?;
 				let mut meta = song.index;
 				meta.version_uuid = uuid::Uuid::new_v4();
 				let thumbnail = collection::SongFile::generate_thumbnail(&meta, &sheets)
 					.expect("Failed to generate thumbnail");
-				collection::SongFile::save(output_path, meta, &sheets, thumbnail, overwrite)?;
+				collection::SongFile::save(
+					output_path,
+					meta,
+					&sheets,
+					thumbnail,
+					annotations.as_ref(),
+					overwrite,
+				)?;
+			}
+		},
+		CliCommand::ExportAnnotations {
+			output,
+			overwrite,
+			input_files,
+		} => {
+			std::fs::create_dir_all(&output)?;
+
+			for input in input_files {
+				log::info!("Exporting annotations into '{}'", input.display());
+
+				let output_path = output.join(input.file_name().unwrap());
+				let song = collection::SongFile::new(&input, &mut Default::default())
+					.context("Corrupt song file")?;
+				let shared = song.load_annotation_layer()?;
+				let annotations = annotations::AnnotationLayer::load(song.uuid())?;
+				if annotations.is_empty() {
+					log::warn!("There are no annotations for '{}'", input.display());
+				}
+				let sheets: TiVec<_, PageImage> =
+					song.load_sheets()().context("Failed to load sheets")?;
+				let thumbnail = song.thumbnail().cloned();
+				/* The staves stay the same, so does the version */
+				let meta = song.index;
+				collection::SongFile::save(
+					output_path,
+					meta,
+					&sheets,
+					thumbnail,
+					Some(&annotations)
+						.filter(|annotations| !annotations.is_empty())
+						.or(shared.as_ref()),
+					overwrite,
+				)?;
+			}
+		},
+		CliCommand::ImportAnnotations {
+			overwrite,
+			input_files,
+		} => {
+			for input in input_files {
+				let song = collection::SongFile::new(&input, &mut Default::default())
+					.context("Corrupt song file")?;
+				let annotations = match song.load_annotation_layer()? {
+					Some(annotations) => annotations,
+					None => {
+						log::warn!("'{}' contains no annotations", input.display());
+						continue;
+					},
+				};
+				anyhow::ensure!(
+					overwrite || annotations::AnnotationLayer::load(song.uuid())?.is_empty(),
+					"'{}' already has annotations in the viewer. Use --overwrite to replace them",
+					input.display()
+				);
+				annotations.save(song.uuid())?;
+				log::info!("Imported the annotations of '{}'", input.display());
+			}
+		},
+		CliCommand::StripAnnotations {
+			output,
+			overwrite,
+			input_files,
+		} => {
+			std::fs::create_dir_all(&output)?;
+
+			for input in input_files {
+				log::info!("Stripping annotations from '{}'", input.display());
+
+				let output_path = output.join(input.file_name().unwrap());
+				let song = collection::SongFile::new(&input, &mut Default::default())
+					.context("Corrupt song file")?;
+				let sheets: TiVec<_, PageImage> =
+					song.load_sheets()().context("Failed to load sheets")?;
+				let thumbnail = song.thumbnail().cloned();
+				let meta = song.index;
+				collection::SongFile::save(output_path, meta, &sheets, thumbnail, None, overwrite)?;
 			}
 		},
 	}
@@ -46,11 +46,17 @@ pub fn load() -> anyhow::Result<(HashMap<Uuid, SongFile>, HashSet<String>)> {
 		.map(|songs| (songs, outdated_format))
 }
 
+/// The files of external annotation editors are named this in song files, plus their extension
+const EXTERNAL_ANNOTATIONS_PREFIX: &str = "annotations/external.";
+
 #[derive(Debug)]
 pub struct SongFile {
 	file: Arc<Mutex<zip::read::ZipArchive<std::fs::File>>>,
 	pub index: SongMeta,
 	thumbnail: Option<gdk_pixbuf::Pixbuf>,
+	/// Annotations that were shared along with the song, if any. The files of external
+	/// editors are only loaded on demand, see [`load_annotation_layer`](Self::load_annotation_layer)
+	pub annotations: Option<annotations::Annotations>,
 }
 
 impl SongFile {
@@ -66,6 +72,7 @@ impl SongFile {
 		log::debug!("Loading: {}", path.display());
 		let mut song = zip::read::ZipArchive::new(std::fs::File::open(path)?)?;
 
+		let current_format;
 		let (mut index, mut song): (SongMeta, _) = {
 			let index: SongMetaVersioned = pipeline::pipe!(
 				song.by_name("staves.json")?
@@ -73,13 +80,15 @@ impl SongFile {
 				=> serde_json::from_reader(_)?
 			);
 			/* Backwards compatibility handling */
+			current_format = matches!(index, SongMetaVersioned::V(_));
+			if !current_format {
+				outdated_format.insert(path.file_name().unwrap().to_string_lossy().to_string());
+			}
 			use std::cell::RefCell;
 			let song = RefCell::new(song);
 			let index: SongMeta = index.update(|n_pages| {
 				/* Warning: n_pages might be zero in the case of guaranteed legacy path! */
 
-				outdated_format.insert(path.file_name().unwrap().to_string_lossy().to_string());
-
 				let mut song = song.borrow_mut();
 				Ok(
 					Self::load_pages_inner(&mut song, n_pages, |index, file, data| {
@@ -134,10 +143,24 @@ impl SongFile {
 			.transpose() /* Result<Option<_>> */
 			.context("Could not load thumbnail")?;
 
+		/* Annotations are part of the format since version 5. They are versioned along with it,
+		 * so when their format changes, the upgrade of older songs must convert them as well.
+		 */
+		let annotations = match song.by_name("annotations.json") {
+			Ok(stream) if current_format => {
+				Some(serde_json::from_reader(std::io::BufReader::new(stream)).map_err(Into::into))
+			},
+			Ok(_) | Err(zip::result::ZipError::FileNotFound) => None,
+			Err(e) => Some(Err(anyhow::Error::from(e))),
+		}
+		.transpose()
+		.context("Could not load annotations")?;
+
 		Ok(SongFile {
 			file: Arc::new(Mutex::new(song)),
 			index,
 			thumbnail,
+			annotations,
 		})
 	}
 
@@ -231,6 +254,33 @@ impl SongFile {
 		}
 	}
 
+	/// All annotations that were shared along with the song, including the files of external
+	/// editors, which are read from the song file
+	pub fn load_annotation_layer(&self) -> anyhow::Result<Option<annotations::AnnotationLayer>> {
+		let mut file = self.file.lock().unwrap();
+		let names = file
+			.file_names()
+			.filter_map(|name| name.strip_prefix(EXTERNAL_ANNOTATIONS_PREFIX))
+			.filter(|extension| annotations::EXTERNAL_FILES.contains(extension))
+			.map(str::to_owned)
+			.collect::<Vec<_>>();
+		let mut external = BTreeMap::new();
+		for extension in names {
+			let mut data = Vec::new();
+			std::io::copy(
+				&mut file.by_name(&format!("{}{}", EXTERNAL_ANNOTATIONS_PREFIX, extension))?,
+				&mut data,
+			)
+			.context("Failed to read annotations")?;
+			external.insert(extension, data);
+		}
+		let layer = annotations::AnnotationLayer {
+			annotations: self.annotations.clone().unwrap_or_default(),
+			external,
+		};
+		Ok((!layer.is_empty()).then(|| layer))
+	}
+
 	pub fn title(&self) -> Option<&str> {
 		self.index.title.as_deref()
 	}
@@ -244,6 +294,7 @@ impl SongFile {
 		metadata: SongMeta,
 		pages: impl IntoIterator<Item = &'a PageImage>,
 		thumbnail: Option<gdk_pixbuf::Pixbuf>,
+		annotations: Option<&annotations::AnnotationLayer>,
 		overwrite: bool,
 	) -> anyhow::Result<()> {
 		let pages = pages.into_iter();
@@ -282,6 +333,22 @@ impl SongFile {
 				writer.write_all(&buffer)?;
 			}
 
+			if let Some(layer) = annotations.filter(|layer| !layer.is_empty()) {
+				log::info!("Saving annotations");
+				if !layer.annotations.is_empty() {
+					writer.start_file("annotations.json", zip::write::FileOptions::default())?;
+					serde_json::to_writer(&mut writer, &layer.annotations)?;
+				}
+				for (extension, data) in &layer.external {
+					writer.start_file(
+						format!("{}{}", EXTERNAL_ANNOTATIONS_PREFIX, extension),
+						zip::write::FileOptions::default(),
+					)?;
+					use std::io::Write;
+					writer.write_all(data)?;
+				}
+			}
+
 			writer.finish()?;
 
 			anyhow::Ok(())
@@ -343,6 +410,102 @@ pub struct SectionMeta {
 	pub section_end: bool,
 }
 
+/// Jump instructions that send the player back to an earlier position
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+	/// D.C., go back to the start of the piece
+	DaCapo,
+	/// D.S., go back to the segno
+	DalSegno,
+}
+
+/// Repetition and navigation markup of a staff, used to calculate the performance order
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct JumpMarks {
+	/// A repeated passage starts in this staff
+	pub repeat_start: bool,
+	/// A repeated passage ends in this staff. The value is how often it is played in total, usually 2
+	pub repeat_end: Option<u32>,
+	/// This staff is part of an ending bracket (volta). It is only played on the listed passes
+	pub volta: Vec<u32>,
+	pub segno: bool,
+	/// The target of a "To Coda" jump
+	pub coda: bool,
+	/// After a D.C. or D.S., continue at the coda from here
+	pub to_coda: bool,
+	/// After a D.C. or D.S., the piece ends here
+	pub fine: bool,
+	pub jump: Option<Jump>,
+}
+
+impl JumpMarks {
+	pub fn is_empty(&self) -> bool {
+		self == &Self::default()
+	}
+}
+
+/// Bar numbers and rehearsal marks of a staff, to navigate the way musicians talk in rehearsals
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MeasureMarks {
+	/// The number of the first measure in this staff, as printed in the score
+	pub first_measure: Option<u32>,
+	/// A rehearsal mark in this staff, usually a letter or a boxed number
+	pub rehearsal_mark: Option<String>,
+}
+
+impl MeasureMarks {
+	pub fn is_empty(&self) -> bool {
+		self == &Self::default()
+	}
+}
+
+/// Where to go, see [`SongMeta::find_position`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PositionQuery {
+	Measure(u32),
+	RehearsalMark(String),
+}
+
+impl PositionQuery {
+	/// Parse user input like "23", "bar 23", "C" or "letter C"
+	pub fn parse(query: &str) -> Option<Self> {
+		let query = query.trim();
+		/* The first word, which may be followed by a number without a space like in "m.23" */
+		let (keyword, rest) = query.split_at(
+			query
+				.find(|c: char| c.is_whitespace() || c.is_ascii_digit())
+				.unwrap_or(query.len()),
+		);
+		let rest = rest.trim();
+		match keyword.to_lowercase().as_str() {
+			"bar" | "measure" | "m." => return rest.parse().ok().map(Self::Measure),
+			"letter" | "mark" => {
+				return (!rest.is_empty()).then(|| Self::RehearsalMark(rest.to_owned()))
+			},
+			_ => {},
+		}
+		if query.is_empty() {
+			None
+		} else if let Ok(number) = query.parse() {
+			Some(Self::Measure(number))
+		} else {
+			Some(Self::RehearsalMark(query.to_owned()))
+		}
+	}
+}
+
+/// One step when playing through the song in performance order, see [`SongMeta::performance_order`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerformedStaff {
+	pub staff: StaffIndex,
+	/// How often the current repetition has been played through so far, starting at 1
+	pub pass: u32,
+	/// Set once a D.C. or D.S. has been taken
+	pub after_jump: Option<Jump>,
+}
+
 #[derive(
 	Debug,
 	Display,
@@ -390,7 +553,7 @@ pub struct StaffIndex(pub usize);
 )]
 pub struct PageIndex(pub usize);
 
-pub type SongMeta = SongMetaV4;
+pub type SongMeta = SongMetaV5;
 
 impl SongMeta {
 	pub fn sections(&self) -> Vec<(RangeInclusive<StaffIndex>, bool)> {
@@ -427,6 +590,248 @@ impl SongMeta {
 
 		(page, page_staff.into())
 	}
+
+	/// The staves of the piece that contains this staff
+	pub fn piece_range(&self, staff: StaffIndex) -> RangeInclusive<StaffIndex> {
+		let start = *self.piece_starts.range(..=staff).next_back().unwrap().0;
+		let end = self
+			.piece_starts
+			.range(staff + StaffIndex(1)..)
+			.next()
+			.map(|(next, _)| *next - StaffIndex(1))
+			.unwrap_or_else(|| StaffIndex(self.staves.len() - 1));
+		start..=end
+	}
+
+	/// The staves of the system that contains this staff. Staves that aren't grouped with others
+	/// form a system of their own.
+	pub fn system_of(&self, staff: StaffIndex) -> RangeInclusive<StaffIndex> {
+		self.systems
+			.range(..=staff)
+			.next_back()
+			.map(|(&start, &length)| start..=start + StaffIndex(length - 1))
+			.filter(|system| system.contains(&staff))
+			.unwrap_or(staff..=staff)
+	}
+
+	/// Find the staff with that measure or rehearsal mark. Since numbering usually restarts with
+	/// each piece, the piece around `near` is searched first.
+	pub fn find_position(&self, query: &PositionQuery, near: StaffIndex) -> Option<StaffIndex> {
+		let current_piece = self.piece_range(near);
+		match query {
+			PositionQuery::Measure(measure) => {
+				/* The last numbered staff starting at or before that measure */
+				let find_in = |piece: RangeInclusive<StaffIndex>| {
+					let mut numbered = self
+						.measures
+						.range(piece)
+						.filter_map(|(staff, marks)| {
+							marks.first_measure.map(|first| (*staff, first))
+						})
+						.peekable();
+					numbered.peek()?;
+					Some(
+						numbered
+							.filter(|(_, first)| first <= measure)
+							.max_by_key(|(staff, first)| (*first, std::cmp::Reverse(*staff)))
+							.map(|(staff, _)| staff),
+					)
+				};
+				match find_in(current_piece) {
+					Some(found) => found,
+					/* Fall back to the first piece that has measure numbers */
+					None => self
+						.piece_starts
+						.keys()
+						.find_map(|&start| find_in(self.piece_range(start)))
+						.flatten(),
+				}
+			},
+			PositionQuery::RehearsalMark(mark) => {
+				let mark = mark.to_lowercase();
+				let mut found = self
+					.measures
+					.iter()
+					.filter(|(_, marks)| {
+						marks
+							.rehearsal_mark
+							.as_ref()
+							.map_or(false, |other| other.trim().to_lowercase() == mark)
+					})
+					.map(|(staff, _)| *staff)
+					.peekable();
+				let first = *found.peek()?;
+				Some(
+					found
+						.find(|staff| current_piece.contains(staff))
+						.unwrap_or(first),
+				)
+			},
+		}
+	}
+
+	/// The jump markup of the song. Songs without any use their repeated sections instead.
+	pub fn effective_jumps(&self) -> std::borrow::Cow<'_, BTreeMap<StaffIndex, JumpMarks>> {
+		use std::borrow::Cow;
+
+		if !self.jumps.is_empty() {
+			return Cow::Borrowed(&self.jumps);
+		}
+		let mut jumps = BTreeMap::<StaffIndex, JumpMarks>::new();
+		for (range, is_repetition) in self.sections() {
+			if is_repetition {
+				jumps.entry(*range.start()).or_default().repeat_start = true;
+				jumps.entry(*range.end()).or_default().repeat_end = Some(2);
+			}
+		}
+		Cow::Owned(jumps)
+	}
+
+	/// Unroll all repetitions and jumps into the sequence of staves as they are actually played.
+	///
+	/// Repetitions never go beyond the start of the current piece. After a D.C. or D.S., repetitions
+	/// are not played again and only the last ending of each volta group is taken.
+	pub fn performance_order(&self) -> Vec<PerformedStaff> {
+		let jumps = self.effective_jumps();
+		let no_marks = JumpMarks::default();
+		let marks = |staff: usize| jumps.get(&StaffIndex(staff)).unwrap_or(&no_marks);
+
+		/* The pass that is played last in the group of consecutive volta staves around `staff` */
+		let last_ending = |staff: usize| {
+			let first = (0..=staff)
+				.rev()
+				.take_while(|&staff| !marks(staff).volta.is_empty())
+				.last()
+				.unwrap_or(staff);
+			(first..self.staves.len())
+				.take_while(|&staff| !marks(staff).volta.is_empty())
+				.flat_map(|staff| marks(staff).volta.iter().copied())
+				.max()
+				.unwrap_or(1)
+		};
+
+		let mut order = Vec::new();
+		let piece_bounds = self
+			.piece_starts
+			.keys()
+			.map(|start| **start)
+			.chain(std::iter::once(self.staves.len()))
+			.collect::<Vec<_>>();
+		for bounds in piece_bounds.windows(2) {
+			let (piece_start, piece_end) = (bounds[0], bounds[1]);
+
+			let mut position = piece_start;
+			/* The staff we came from, if we got here without jumping */
+			let mut previous: Option<usize> = None;
+			let mut repeat_from = piece_start;
+			let mut pass = 1;
+			let mut finished_repeat = false;
+			let mut after_jump = None;
+
+			/* Failsafe against weird markup, which should never happen in practice */
+			let mut budget = (piece_end - piece_start) * 100;
+
+			while position < piece_end {
+				budget -= 1;
+				if budget == 0 {
+					log::warn!("Jump markup of piece at staff {piece_start} does not terminate");
+					break;
+				}
+
+				let current = marks(position);
+				let in_volta = !current.volta.is_empty();
+				let sequential = previous.map_or(false, |previous| previous + 1 == position);
+
+				/* Leaving a repetition (or its endings) resets the pass counter */
+				if !in_volta && sequential {
+					let left_volta = !marks(position - 1).volta.is_empty();
+					if left_volta {
+						repeat_from = position;
+					}
+					if left_volta || finished_repeat {
+						pass = 1;
+						finished_repeat = false;
+					}
+				}
+				if current.repeat_start && repeat_from != position {
+					repeat_from = position;
+					pass = 1;
+					finished_repeat = false;
+				}
+
+				if in_volta {
+					let play = if after_jump.is_some() {
+						current.volta.contains(&last_ending(position))
+					} else {
+						current.volta.contains(&pass)
+					};
+					if !play {
+						previous = Some(position);
+						position += 1;
+						continue;
+					}
+				}
+
+				order.push(PerformedStaff {
+					staff: StaffIndex(position),
+					pass,
+					after_jump,
+				});
+
+				if after_jump.is_some() {
+					if current.fine {
+						break;
+					}
+					if current.to_coda {
+						if let Some(coda) =
+							(position + 1..piece_end).find(|&staff| marks(staff).coda)
+						{
+							previous = None;
+							position = coda;
+							continue;
+						}
+						log::warn!("Staff {position} has a \"To Coda\", but there is no coda");
+					}
+				} else {
+					if let Some(times) = current.repeat_end {
+						if pass < times {
+							pass += 1;
+							previous = None;
+							position = repeat_from;
+							continue;
+						}
+						finished_repeat = true;
+						repeat_from = position + 1;
+					}
+					if let Some(jump) = current.jump {
+						let target = match jump {
+							Jump::DaCapo => piece_start,
+							Jump::DalSegno => (piece_start..=position)
+								.rev()
+								.find(|&staff| marks(staff).segno)
+								.unwrap_or_else(|| {
+									log::warn!(
+										"Staff {position} has a D.S., but there is no segno"
+									);
+									piece_start
+								}),
+						};
+						after_jump = Some(jump);
+						pass = 1;
+						finished_repeat = false;
+						repeat_from = target;
+						previous = None;
+						position = target;
+						continue;
+					}
+				}
+
+				previous = Some(position);
+				position += 1;
+			}
+		}
+		order
+	}
 }
 
 /* Check invariants after deserialization */
@@ -478,6 +883,60 @@ impl<'de> Deserialize<'de> for SongMeta {
 				"Invalid data: Section start out of bounds",
 			));
 		}
+		if unchecked
+			.jumps
+			.keys()
+			.next_back()
+			.map(|staff| **staff >= unchecked.staves.len())
+			.unwrap_or(false)
+		{
+			return Err(de::Error::custom("Invalid data: Jump marks out of bounds"));
+		}
+		if unchecked
+			.measures
+			.keys()
+			.next_back()
+			.map(|staff| **staff >= unchecked.staves.len())
+			.unwrap_or(false)
+		{
+			return Err(de::Error::custom(
+				"Invalid data: Measure marks out of bounds",
+			));
+		}
+		let mut previous_end = None;
+		for (&start, &length) in &unchecked.systems {
+			if length < 2 {
+				return Err(de::Error::custom(
+					"Invalid data: A system must have at least two staves",
+				));
+			}
+			let end = start + StaffIndex(length - 1);
+			if *end >= unchecked.staves.len() {
+				return Err(de::Error::custom("Invalid data: System out of bounds"));
+			}
+			if previous_end
+				.map(|previous_end| previous_end >= start)
+				.unwrap_or(false)
+			{
+				return Err(de::Error::custom("Invalid data: Systems overlap"));
+			}
+			if unchecked.staves[start].page != unchecked.staves[end].page {
+				return Err(de::Error::custom(format!(
+					"Invalid data: System at staff {start} spans multiple pages"
+				)));
+			}
+			if unchecked
+				.piece_starts
+				.range(start + StaffIndex(1)..=end)
+				.next()
+				.is_some()
+			{
+				return Err(de::Error::custom(format!(
+					"Invalid data: A piece starts inside the system at staff {start}"
+				)));
+			}
+			previous_end = Some(end);
+		}
 		Ok(unchecked)
 	}
 }
@@ -495,6 +954,35 @@ impl Serialize for SongMeta {
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(remote = "Self")] /* Call custom ser/de for invariants checking. ONLY FOR LATEST VERSION! */
+pub struct SongMetaV5 {
+	pub n_pages: usize,
+	pub staves: TiVec<StaffIndex, StaffV3>,
+	#[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+	pub piece_starts: BTreeMap<StaffIndex, String>,
+	/// The bool tells if it is a repetition or not
+	#[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+	pub section_starts: BTreeMap<StaffIndex, SectionMeta>,
+	/// Repetitions and jumps. Staves without any markup are omitted
+	#[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+	pub jumps: BTreeMap<StaffIndex, JumpMarks>,
+	/// Bar numbers and rehearsal marks. Staves without any are omitted
+	#[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+	pub measures: BTreeMap<StaffIndex, MeasureMarks>,
+	/// Staves that belong together, like the two staves of a piano part or all staves of an
+	/// orchestral score. Maps the first staff of each system to its number of staves
+	#[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+	pub systems: BTreeMap<StaffIndex, usize>,
+	/// A unique identifier for this song that is stable across file modifications
+	pub song_uuid: Uuid,
+	/// Effectively a random string generated on each save. Useful for caching
+	pub version_uuid: Uuid,
+	pub title: Option<String>,
+	pub composer: Option<String>,
+}
+
+// Remove once https://github.com/serde-rs/serde/issues/1183 is closed
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SongMetaV4 {
 	pub n_pages: usize,
 	pub staves: TiVec<StaffIndex, StaffV3>,
@@ -571,6 +1059,26 @@ struct SongMetaV0 {
 	pub section_starts: BTreeMap<StaffIndex, bool>,
 }
 
+impl SongMetaV4 {
+	fn update(self) -> SongMeta {
+		log::debug!("Updating file: v4 -> v5");
+		/* What changed: added jumps, measures and systems. Old files have none of them */
+		SongMetaV5 {
+			n_pages: self.n_pages,
+			staves: self.staves,
+			piece_starts: self.piece_starts,
+			section_starts: self.section_starts,
+			jumps: Default::default(),
+			measures: Default::default(),
+			systems: Default::default(),
+			song_uuid: self.song_uuid,
+			version_uuid: self.version_uuid,
+			composer: self.composer,
+			title: self.title,
+		}
+	}
+}
+
 impl SongMetaV3 {
 	fn update(self, page_sizes: &[(f64, f64)]) -> SongMeta {
 		log::debug!("Updating file: v3 -> v4");
@@ -600,6 +1108,7 @@ impl SongMetaV3 {
 			composer: self.composer,
 			title: self.title,
 		}
+		.update()
 	}
 }
 
@@ -688,8 +1197,10 @@ impl SongMetaV0 {
 #[serde(tag = "version")]
 enum SongMetaVersioned {
 	// The newest variant is always called "V" to reduce renamings
+	#[serde(rename = "5")]
+	V(SongMetaV5),
 	#[serde(rename = "4")]
-	V(SongMetaV4),
+	V4(SongMetaV4),
 	#[serde(rename = "3")]
 	V3(SongMetaV3),
 	#[serde(rename = "2")]
@@ -707,6 +1218,7 @@ impl SongMetaVersioned {
 	) -> anyhow::Result<SongMeta> {
 		match self {
 			SongMetaVersioned::V(meta) => Ok(meta),
+			SongMetaVersioned::V4(meta) => Ok(meta.update()),
 			SongMetaVersioned::V3(meta @ SongMetaV3 { n_pages, .. }) => {
 				Ok(meta.update(&load_page_sizes(n_pages)?))
 			},
@@ -819,6 +1331,85 @@ impl StaffV1 {
 	}
 }
 
+/// Build songs for tests, with defaults for everything that a test doesn't care about
+#[cfg(test)]
+pub struct SongBuilder {
+	song: SongMeta,
+}
+
+#[cfg(test)]
+impl SongBuilder {
+	/// Evenly spaced staves, with the given number of them on each page
+	pub fn pages(staves_per_page: &[usize]) -> Self {
+		Self::staves(
+			staves_per_page
+				.iter()
+				.enumerate()
+				.flat_map(|(page, &count)| {
+					let height = 0.9 / count as f64;
+					(0..count).map(move |i| Staff {
+						page: PageIndex(page),
+						start: (0.1, 0.05 + i as f64 * height),
+						end: (0.9, 0.05 + (i as f64 + 0.8) * height),
+					})
+				}),
+		)
+	}
+
+	/// One untitled piece with one section, no further markup
+	pub fn staves(staves: impl IntoIterator<Item = Staff>) -> Self {
+		let staves: TiVec<StaffIndex, Staff> = staves.into_iter().collect();
+		SongBuilder {
+			song: SongMetaV5 {
+				n_pages: staves.last().map_or(0, |staff| *staff.page + 1),
+				staves,
+				piece_starts: [(StaffIndex(0), String::new())].into(),
+				section_starts: [(StaffIndex(0), SectionMeta::default())].into(),
+				jumps: Default::default(),
+				measures: Default::default(),
+				systems: Default::default(),
+				song_uuid: Uuid::new_v4(),
+				version_uuid: Uuid::new_v4(),
+				title: None,
+				composer: None,
+			},
+		}
+	}
+
+	pub fn piece(mut self, staff: usize, name: &str) -> Self {
+		self.song
+			.piece_starts
+			.insert(StaffIndex(staff), name.into());
+		self
+	}
+
+	pub fn section(mut self, staff: usize) -> Self {
+		self.song
+			.section_starts
+			.insert(StaffIndex(staff), SectionMeta::default());
+		self
+	}
+
+	pub fn jump(mut self, staff: usize, marks: JumpMarks) -> Self {
+		self.song.jumps.insert(StaffIndex(staff), marks);
+		self
+	}
+
+	pub fn system(mut self, staff: usize, length: usize) -> Self {
+		self.song.systems.insert(StaffIndex(staff), length);
+		self
+	}
+
+	pub fn title(mut self, title: &str) -> Self {
+		self.song.title = Some(title.into());
+		self
+	}
+
+	pub fn build(self) -> SongMeta {
+		self.song
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -840,4 +1431,334 @@ mod test {
 		let song = SongFile::new(&"./test/format_v4.zip", &mut Default::default()).unwrap();
 		song.load_sheets()().unwrap();
 	}
+
+	/// A song with one staff per page and the given jump marks
+	fn song_with_jumps(
+		n_staves: usize,
+		pieces: &[usize],
+		jumps: impl IntoIterator<Item = (usize, JumpMarks)>,
+	) -> SongMeta {
+		let mut song = SongBuilder::pages(&vec![1; n_staves]);
+		for &staff in pieces {
+			song = song.piece(staff, "");
+		}
+		for (staff, marks) in jumps {
+			song = song.jump(staff, marks);
+		}
+		song.build()
+	}
+
+	/// The staves and passes of the performance order
+	fn played(song: &SongMeta) -> (Vec<usize>, Vec<u32>) {
+		song.performance_order()
+			.iter()
+			.map(|step| (*step.staff, step.pass))
+			.unzip()
+	}
+
+	#[test]
+	fn test_performance_order_plain() {
+		let song = song_with_jumps(4, &[0], []);
+		assert_eq!(played(&song), (vec![0, 1, 2, 3], vec![1, 1, 1, 1]));
+	}
+
+	#[test]
+	fn test_performance_order_repeat() {
+		let song = song_with_jumps(
+			5,
+			&[0],
+			[
+				(
+					1,
+					JumpMarks {
+						repeat_start: true,
+						..Default::default()
+					},
+				),
+				(
+					2,
+					JumpMarks {
+						repeat_end: Some(3),
+						..Default::default()
+					},
+				),
+			],
+		);
+		assert_eq!(
+			played(&song),
+			(
+				vec![0, 1, 2, 1, 2, 1, 2, 3, 4],
+				vec![1, 1, 1, 2, 2, 3, 3, 1, 1]
+			)
+		);
+	}
+
+	#[test]
+	fn test_performance_order_volta() {
+		/* 0 |: 1 [1. 2 :|] [2. 3] 4 |: 5 :| */
+		let song = song_with_jumps(
+			6,
+			&[0],
+			[
+				(
+					1,
+					JumpMarks {
+						repeat_start: true,
+						..Default::default()
+					},
+				),
+				(
+					2,
+					JumpMarks {
+						repeat_end: Some(2),
+						volta: vec![1],
+						..Default::default()
+					},
+				),
+				(
+					3,
+					JumpMarks {
+						volta: vec![2],
+						..Default::default()
+					},
+				),
+				(
+					5,
+					JumpMarks {
+						repeat_start: true,
+						repeat_end: Some(2),
+						..Default::default()
+					},
+				),
+			],
+		);
+		assert_eq!(
+			played(&song),
+			(vec![0, 1, 2, 1, 3, 4, 5, 5], vec![1, 1, 1, 2, 2, 1, 1, 2])
+		);
+	}
+
+	#[test]
+	fn test_performance_order_dal_segno_al_coda() {
+		/* 0 𝄋1 |: 2 :| (To Coda) 3 (D.S. al Coda) 4 𝄌5 */
+		let song = song_with_jumps(
+			6,
+			&[0],
+			[
+				(
+					1,
+					JumpMarks {
+						segno: true,
+						..Default::default()
+					},
+				),
+				(
+					2,
+					JumpMarks {
+						repeat_start: true,
+						repeat_end: Some(2),
+						..Default::default()
+					},
+				),
+				(
+					3,
+					JumpMarks {
+						to_coda: true,
+						..Default::default()
+					},
+				),
+				(
+					4,
+					JumpMarks {
+						jump: Some(Jump::DalSegno),
+						..Default::default()
+					},
+				),
+				(
+					5,
+					JumpMarks {
+						coda: true,
+						..Default::default()
+					},
+				),
+			],
+		);
+		assert_eq!(
+			played(&song),
+			(
+				vec![0, 1, 2, 2, 3, 4, 1, 2, 3, 5],
+				vec![1, 1, 1, 2, 1, 1, 1, 1, 1, 1]
+			)
+		);
+		let order = song.performance_order();
+		assert_eq!(order[5].after_jump, None);
+		assert_eq!(order[6].after_jump, Some(Jump::DalSegno));
+	}
+
+	#[test]
+	fn test_performance_order_da_capo_al_fine() {
+		/* Piece 1: 0 1 (Fine) 2 (D.C.), piece 2: 3 4 (D.C.) */
+		let song = song_with_jumps(
+			5,
+			&[0, 3],
+			[
+				(
+					1,
+					JumpMarks {
+						fine: true,
+						..Default::default()
+					},
+				),
+				(
+					2,
+					JumpMarks {
+						jump: Some(Jump::DaCapo),
+						..Default::default()
+					},
+				),
+				(
+					4,
+					JumpMarks {
+						jump: Some(Jump::DaCapo),
+						..Default::default()
+					},
+				),
+			],
+		);
+		assert_eq!(
+			played(&song),
+			(
+				vec![0, 1, 2, 0, 1, 3, 4, 3, 4],
+				vec![1, 1, 1, 1, 1, 1, 1, 1, 1]
+			)
+		);
+	}
+
+	#[test]
+	fn test_performance_order_legacy_sections() {
+		/* Without jump markup, repeated sections are played twice */
+		let mut song = song_with_jumps(4, &[0], []);
+		song.section_starts = [
+			(StaffIndex(0), SectionMeta::default()),
+			(
+				StaffIndex(1),
+				SectionMeta {
+					is_repetition: true,
+					section_end: false,
+				},
+			),
+			(StaffIndex(3), SectionMeta::default()),
+		]
+		.into();
+		assert_eq!(
+			played(&song),
+			(vec![0, 1, 2, 1, 2, 3], vec![1, 1, 1, 2, 2, 1])
+		);
+	}
+
+	#[test]
+	fn test_parse_position_query() {
+		use PositionQuery::*;
+		assert_eq!(PositionQuery::parse("23"), Some(Measure(23)));
+		assert_eq!(PositionQuery::parse(" Bar 23 "), Some(Measure(23)));
+		assert_eq!(PositionQuery::parse("m. 7"), Some(Measure(7)));
+		assert_eq!(PositionQuery::parse("m.2"), Some(Measure(2)));
+		assert_eq!(PositionQuery::parse("Bar12"), Some(Measure(12)));
+		assert_eq!(PositionQuery::parse("bar C"), None);
+		/* Only whole words are keywords */
+		assert_eq!(
+			PositionQuery::parse("Baroque"),
+			Some(RehearsalMark("Baroque".into()))
+		);
+		assert_eq!(
+			PositionQuery::parse("Markus"),
+			Some(RehearsalMark("Markus".into()))
+		);
+		/* With a Kelvin sign, which becomes an ASCII "k" in lower case */
+		assert_eq!(
+			PositionQuery::parse("mar\u{212A} D"),
+			Some(RehearsalMark("D".into()))
+		);
+		assert_eq!(PositionQuery::parse("mar\u{212A}"), None);
+		assert_eq!(PositionQuery::parse("C"), Some(RehearsalMark("C".into())));
+		assert_eq!(
+			PositionQuery::parse("Letter B"),
+			Some(RehearsalMark("B".into()))
+		);
+		assert_eq!(
+			PositionQuery::parse("mark 12"),
+			Some(RehearsalMark("12".into()))
+		);
+		assert_eq!(PositionQuery::parse("  "), None);
+	}
+
+	#[test]
+	fn test_find_position() {
+		/* Two pieces, with bar numbers restarting and rehearsal marks */
+		let mut song = song_with_jumps(6, &[0, 3], []);
+		let marks = |first_measure: u32, rehearsal_mark: Option<&str>| MeasureMarks {
+			first_measure: Some(first_measure),
+			rehearsal_mark: rehearsal_mark.map(Into::into),
+		};
+		song.measures = [
+			(StaffIndex(0), marks(1, None)),
+			(StaffIndex(1), marks(9, Some("A"))),
+			(StaffIndex(2), marks(17, Some("B"))),
+			(StaffIndex(3), marks(1, None)),
+			(StaffIndex(4), marks(12, Some("A"))),
+		]
+		.into();
+		let find = |song: &SongMeta, query: &str, near: usize| {
+			song.find_position(&PositionQuery::parse(query).unwrap(), StaffIndex(near))
+				.map(|staff| *staff)
+		};
+
+		assert_eq!(find(&song, "1", 0), Some(0));
+		assert_eq!(find(&song, "8", 0), Some(0));
+		assert_eq!(find(&song, "9", 0), Some(1));
+		assert_eq!(find(&song, "30", 1), Some(2));
+		/* Same numbers, other piece */
+		assert_eq!(find(&song, "9", 5), Some(3));
+		assert_eq!(find(&song, "12", 5), Some(4));
+		assert_eq!(find(&song, "a", 0), Some(1));
+		assert_eq!(find(&song, "A", 5), Some(4));
+		/* Only in the first piece */
+		assert_eq!(find(&song, "letter B", 4), Some(2));
+		assert_eq!(find(&song, "Z", 0), None);
+
+		/* Without numbers in the current piece, use the first one that has some */
+		song.measures.remove(&StaffIndex(0));
+		song.measures.remove(&StaffIndex(1));
+		song.measures.remove(&StaffIndex(2));
+		assert_eq!(find(&song, "12", 0), Some(4));
+	}
+
+	#[test]
+	fn test_systems() {
+		let mut song = song_with_jumps(6, &[0, 4], []);
+		/* Put the first five staves onto one page */
+		for staff in song.staves[..StaffIndex(5)].iter_mut() {
+			staff.page = PageIndex(0);
+		}
+		song.systems = [(StaffIndex(1), 3)].into();
+		assert_eq!(song.system_of(StaffIndex(0)), StaffIndex(0)..=StaffIndex(0));
+		assert_eq!(song.system_of(StaffIndex(2)), StaffIndex(1)..=StaffIndex(3));
+		assert_eq!(song.system_of(StaffIndex(3)), StaffIndex(1)..=StaffIndex(3));
+		assert_eq!(song.system_of(StaffIndex(4)), StaffIndex(4)..=StaffIndex(4));
+
+		let roundtrip = |song: &SongMeta| {
+			serde_json::from_value::<SongMeta>(serde_json::to_value(song).unwrap())
+		};
+		assert!(roundtrip(&song).is_ok());
+		/* A piece may not start within a system */
+		song.systems = [(StaffIndex(3), 2)].into();
+		assert!(roundtrip(&song).is_err());
+		/* Systems can't span pages */
+		song.systems = [(StaffIndex(4), 2)].into();
+		assert!(roundtrip(&song).is_err());
+		song.systems = [(StaffIndex(0), 2), (StaffIndex(1), 2)].into();
+		assert!(roundtrip(&song).is_err());
+		song.systems = [(StaffIndex(0), 1)].into();
+		assert!(roundtrip(&song).is_err());
+	}
 }
@@ -1,4 +1,5 @@
 use dinoscore::{collection::*, prelude::*, *};
+use std::ops::RangeInclusive;
 use uuid::Uuid;
 
 /// Business logic for the editor
@@ -7,6 +8,10 @@ struct FullStaff {
 	staff: Staff,
 	piece_start: Option<String>,
 	section_start: Option<SectionMeta>,
+	jumps: JumpMarks,
+	measures: MeasureMarks,
+	/// This staff belongs to the same system as the one before
+	joined: bool,
 }
 
 /**
@@ -23,6 +28,8 @@ pub struct EditorSongFile {
 	 * version_uuid: Uuid, */
 	pub song_name: String,
 	pub song_composer: String,
+	/// Annotations shared along with the song. They are kept as they are
+	annotations: Option<annotations::AnnotationLayer>,
 }
 
 impl Default for EditorSongFile {
@@ -39,6 +46,7 @@ impl EditorSongFile {
 			song_uuid: Uuid::new_v4(),
 			song_name: "".into(),
 			song_composer: "".into(),
+			annotations: None,
 		}
 	}
 
@@ -81,6 +89,14 @@ impl EditorSongFile {
 			.unwrap()
 	}
 
+	pub fn jumps(&self, staff: StaffIndex) -> &JumpMarks {
+		&self.staves[staff].jumps
+	}
+
+	pub fn measures(&self, staff: StaffIndex) -> &MeasureMarks {
+		&self.staves[staff].measures
+	}
+
 	pub fn piece_start_mut(&mut self, staff: StaffIndex) -> &mut Option<String> {
 		&mut self.staves[staff].piece_start
 	}
@@ -89,6 +105,60 @@ impl EditorSongFile {
 		&mut self.staves[staff].section_start
 	}
 
+	pub fn jumps_mut(&mut self, staff: StaffIndex) -> &mut JumpMarks {
+		&mut self.staves[staff].jumps
+	}
+
+	pub fn measures_mut(&mut self, staff: StaffIndex) -> &mut MeasureMarks {
+		&mut self.staves[staff].measures
+	}
+
+	/// Whether that staff may belong to the same system as the one before. Systems
+	/// cannot span pages or pieces.
+	fn can_join(&self, staff: StaffIndex) -> bool {
+		*staff > 0
+			&& self.staves[staff].piece_start.is_none()
+			&& self.staves[staff].staff.page == self.staves[staff - StaffIndex(1)].staff.page
+	}
+
+	/// The staves of the system that contains this staff
+	pub fn system_of(&self, staff: StaffIndex) -> RangeInclusive<StaffIndex> {
+		let mut start = staff;
+		while self.staves[start].joined && self.can_join(start) {
+			start = start - StaffIndex(1);
+		}
+		let mut end = staff;
+		while *end + 1 < self.staves.len()
+			&& self.staves[end + StaffIndex(1)].joined
+			&& self.can_join(end + StaffIndex(1))
+		{
+			end = end + StaffIndex(1);
+		}
+		start..=end
+	}
+
+	/// Whether the staves may be grouped into one system
+	pub fn can_group(&self, staves: RangeInclusive<StaffIndex>) -> bool {
+		staves.start() < staves.end()
+			&& (**staves.start() + 1..=**staves.end()).all(|staff| self.can_join(StaffIndex(staff)))
+	}
+
+	/// Group the staves into one system, or add them to an existing one
+	pub fn group_staves(&mut self, staves: RangeInclusive<StaffIndex>) {
+		assert!(self.can_group(staves.clone()), "Cannot group those staves");
+		for staff in **staves.start() + 1..=**staves.end() {
+			self.staves[StaffIndex(staff)].joined = true;
+		}
+	}
+
+	/// Split the system that contains this staff into single staves again
+	pub fn ungroup_staves(&mut self, staff: StaffIndex) {
+		let system = self.system_of(staff);
+		for staff in **system.start()..=**system.end() {
+			self.staves[StaffIndex(staff)].joined = false;
+		}
+	}
+
 	pub fn count_staves_before(&self, page: PageIndex) -> usize {
 		self.staves
 			.iter()
@@ -123,8 +193,21 @@ impl EditorSongFile {
 		}
 	}
 
-	/// Add staves to the end of a page. Staves must already be y-ordered
-	pub fn add_staves(&mut self, page_index: PageIndex, staves: Vec<Staff>) {
+	/// Add staves to the end of a page. Staves must already be y-ordered. The systems are
+	/// ranges of indices into `staves`
+	pub fn add_staves(
+		&mut self,
+		page_index: PageIndex,
+		staves: Vec<Staff>,
+		systems: Vec<RangeInclusive<usize>>,
+	) {
+		let joined = (0..staves.len())
+			.map(|staff| {
+				systems
+					.iter()
+					.any(|system| system.contains(&staff) && *system.start() != staff)
+			})
+			.collect::<Vec<_>>();
 		let index = self
 			.staves
 			.iter_enumerated()
@@ -135,11 +218,15 @@ impl EditorSongFile {
 			index..index,
 			staves
 				.into_iter()
-				.inspect(|staff| assert_eq!(staff.page, page_index))
-				.map(|staff| FullStaff {
+				.zip(joined)
+				.inspect(|(staff, _)| assert_eq!(staff.page, page_index))
+				.map(|(staff, joined)| FullStaff {
 					staff,
 					piece_start: None,
 					section_start: None,
+					jumps: Default::default(),
+					measures: Default::default(),
+					joined,
 				}),
 		);
 
@@ -180,6 +267,9 @@ impl EditorSongFile {
 				staff,
 				piece_start: None,
 				section_start: None,
+				jumps: Default::default(),
+				measures: Default::default(),
+				joined: false,
 			},
 		);
 
@@ -269,7 +359,7 @@ impl EditorSongFile {
 		index_rel_page
 	}
 
-	pub fn load(&mut self, song: SongMeta) {
+	pub fn load(&mut self, song: SongMeta, annotations: Option<annotations::AnnotationLayer>) {
 		self.staves = song
 			.staves
 			.into_iter_enumerated()
@@ -277,11 +367,15 @@ impl EditorSongFile {
 				staff,
 				piece_start: song.piece_starts.get(&index).cloned(),
 				section_start: song.section_starts.get(&index).cloned(),
+				jumps: song.jumps.get(&index).cloned().unwrap_or_default(),
+				measures: song.measures.get(&index).cloned().unwrap_or_default(),
+				joined: *song.system_of(index).start() != index,
 			})
 			.collect();
 		self.song_name = song.title.unwrap_or_default();
 		self.song_composer = song.composer.unwrap_or_default();
 		self.song_uuid = song.song_uuid;
+		self.annotations = annotations;
 	}
 
 	pub fn save(&self, file: std::path::PathBuf) -> anyhow::Result<()> {
@@ -299,6 +393,25 @@ impl EditorSongFile {
 				.iter_enumerated()
 				.filter_map(|(i, staff)| staff.section_start.clone().map(|p| (i, p)))
 				.collect(),
+			jumps: self
+				.staves
+				.iter_enumerated()
+				.filter(|(_, staff)| !staff.jumps.is_empty())
+				.map(|(i, staff)| (i, staff.jumps.clone()))
+				.collect(),
+			measures: self
+				.staves
+				.iter_enumerated()
+				.filter(|(_, staff)| !staff.measures.is_empty())
+				.map(|(i, staff)| (i, staff.measures.clone()))
+				.collect(),
+			systems: self
+				.staves
+				.iter_enumerated()
+				.map(|(i, _)| self.system_of(i))
+				.filter(|system| system.start() < system.end())
+				.map(|system| (*system.start(), **system.end() - **system.start() + 1))
+				.collect(),
 			song_uuid: self.song_uuid,
 			version_uuid: uuid::Uuid::new_v4(),
 			title: Some(&self.song_name)
@@ -316,6 +429,7 @@ impl EditorSongFile {
 			song,
 			self.pages.iter().map(Deref::deref),
 			thumbnail,
+			self.annotations.as_ref(),
 			true, // TODO overwrite?!
 		)?;
 		Ok(())
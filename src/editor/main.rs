@@ -200,9 +200,10 @@ mod imp {
 									yield_now().await;
 
 									let song = SongFile::new(path, &mut Default::default()).unwrap();
+									let annotations = song.load_annotation_layer().unwrap();
 									let load_sheets = song.load_sheets();
 									let sheets = blocking::unblock(move || load_sheets()).await.unwrap();
-									obj.imp().load(sheets, song.index);
+									obj.imp().load(sheets, song.index, annotations);
 
 									yield_now().await;
 									progress_dialog.emit_close();
@@ -214,7 +215,12 @@ mod imp {
 			);
 		}
 
-		pub fn load(&self, pages: TiVec<PageIndex, PageImage>, song: SongMeta) {
+		pub fn load(
+			&self,
+			pages: TiVec<PageIndex, PageImage>,
+			song: SongMeta,
+			annotations: Option<annotations::AnnotationLayer>,
+		) {
 			self.unload_and_clear();
 			for page in pages {
 				self.add_page(page);
@@ -224,7 +230,7 @@ mod imp {
 			self.song_composer
 				.set_text(song.composer.as_deref().unwrap_or(""));
 
-			self.file.borrow_mut().load(song);
+			self.file.borrow_mut().load(song, annotations);
 
 			self.editor.update_page();
 		}
@@ -506,8 +512,15 @@ mod imp {
 			self.editor.load_page(selected_page);
 		}
 
-		fn add_staves(&self, page_index: PageIndex, staves: Vec<Staff>) {
-			self.file.borrow_mut().add_staves(page_index, staves);
+		fn add_staves(
+			&self,
+			page_index: PageIndex,
+			staves: Vec<Staff>,
+			systems: Vec<std::ops::RangeInclusive<usize>>,
+		) {
+			self.file
+				.borrow_mut()
+				.add_staves(page_index, staves, systems);
 			self.editor.update_page();
 		}
 
@@ -546,18 +559,26 @@ mod imp {
 
 						// TODO already convert pixbuf to bytes here, then remove the unsafe
 						let data = unsafe { unsafe_force::Send::new(data) };
-						let (page, bars_inner) = blocking::unblock(move || {
+						let (page, bars_inner, systems) = blocking::unblock(move || {
 							log::info!("Autodetecting {} ({}/{})", page, i, total_work);
 							let page = PageIndex(page);
-							let bars_inner: Vec<Staff> =
+							let (bars_inner, systems) =
 								recognition::recognize_staves(&unsafe { data.unwrap() }, page);
-							log::debug!("Found {} staves", bars_inner.len());
-							(page, bars_inner)
+							log::debug!(
+								"Found {} staves in {} systems",
+								bars_inner.len(),
+								bars_inner.len()
+									- systems
+										.iter()
+										.map(|system| system.end() - system.start())
+										.sum::<usize>()
+							);
+							(page, bars_inner, systems)
 						})
 						.await;
 						progress.set_fraction((i + 1) as f64 / total_work as f64);
 
-						obj.imp().add_staves(page, bars_inner);
+						obj.imp().add_staves(page, bars_inner, systems);
 					}
 
 					// tokio::time::sleep(std::time::Duration::from_millis(350)).await;
@@ -1,7 +1,7 @@
 use crate::EditorSongFile;
 use dinoscore::{collection::*, prelude::*, *};
 
-use std::sync::mpsc::*;
+use std::{ops::RangeInclusive, sync::mpsc::*};
 
 glib::wrapper! {
 	pub struct EditorPage(ObjectSubclass<imp::EditorPage>)
@@ -59,11 +59,39 @@ mod imp {
 		section_repetition: TemplateChild<gtk::CheckButton>,
 		#[template_child]
 		section_end: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		repeat_start: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		repeat_end: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		repeat_times: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		volta: TemplateChild<gtk::Entry>,
+		#[template_child]
+		segno: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		coda: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		to_coda: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		fine: TemplateChild<gtk::CheckButton>,
+		#[template_child]
+		jump: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		first_measure: TemplateChild<gtk::Entry>,
+		#[template_child]
+		rehearsal_mark: TemplateChild<gtk::Entry>,
+		#[template_child]
+		group_staves: TemplateChild<gtk::Button>,
+		#[template_child]
+		ungroup_staves: TemplateChild<gtk::Button>,
 
 		pub(super) current_page: RefCell<Option<PageState>>,
 
 		pub file: OnceCell<Rc<RefCell<EditorSongFile>>>,
 
+		#[template_child]
+		click_gesture: TemplateChild<gtk::GestureClick>,
 		#[template_child]
 		drag_gesture: TemplateChild<gtk::GestureDrag>,
 		drag_state: Cell<Option<DragState>>,
@@ -141,6 +169,7 @@ mod imp {
 					bars,
 					staves_before: file.count_staves_before(page_index),
 					selected_staff: None,
+					selection_end: None,
 					renderer,
 				}
 			});
@@ -177,6 +206,23 @@ mod imp {
 				.unwrap_or(false);
 			let has_section_start = section_start.is_some();
 			let has_section_end = section_start.map(|meta| meta.section_end).unwrap_or(false);
+			let jumps: JumpMarks = index
+				.map(|i| file.jumps(StaffIndex(i)).clone())
+				.unwrap_or_default();
+			let measures: MeasureMarks = index
+				.map(|i| file.measures(StaffIndex(i)).clone())
+				.unwrap_or_default();
+			let can_group = self
+				.current_page
+				.borrow()
+				.as_ref()
+				.and_then(PageState::selected_range)
+				.map(|range| file.can_group(range))
+				.unwrap_or(false);
+			let is_grouped = index
+				.map(|i| file.system_of(StaffIndex(i)))
+				.map(|system| system.start() < system.end())
+				.unwrap_or(false);
 
 			/* Set the selected_staff to None to implicitly inhibit events */
 			let selected_staff_backup = self
@@ -205,6 +251,50 @@ mod imp {
 			self.section_end.set_sensitive(has_section_start);
 			self.section_end.set_active(has_section_end);
 
+			let has_staff = index.is_some();
+			self.repeat_start.set_sensitive(has_staff);
+			self.repeat_start.set_active(jumps.repeat_start);
+			self.repeat_end.set_sensitive(has_staff);
+			self.repeat_end.set_active(jumps.repeat_end.is_some());
+			self.repeat_times.set_sensitive(jumps.repeat_end.is_some());
+			self.repeat_times
+				.set_value(jumps.repeat_end.unwrap_or(2) as f64);
+			self.volta.set_sensitive(has_staff);
+			self.volta.set_text(
+				&jumps
+					.volta
+					.iter()
+					.map(ToString::to_string)
+					.collect::<Vec<_>>()
+					.join(", "),
+			);
+			self.segno.set_sensitive(has_staff);
+			self.segno.set_active(jumps.segno);
+			self.coda.set_sensitive(has_staff);
+			self.coda.set_active(jumps.coda);
+			self.to_coda.set_sensitive(has_staff);
+			self.to_coda.set_active(jumps.to_coda);
+			self.fine.set_sensitive(has_staff);
+			self.fine.set_active(jumps.fine);
+			self.jump.set_sensitive(has_staff);
+			self.jump.set_active_id(Some(match jumps.jump {
+				None => "none",
+				Some(Jump::DaCapo) => "da-capo",
+				Some(Jump::DalSegno) => "dal-segno",
+			}));
+			self.first_measure.set_sensitive(has_staff);
+			self.first_measure.set_text(
+				&measures
+					.first_measure
+					.map(|measure| measure.to_string())
+					.unwrap_or_default(),
+			);
+			self.rehearsal_mark.set_sensitive(has_staff);
+			self.rehearsal_mark
+				.set_text(measures.rehearsal_mark.as_deref().unwrap_or_default());
+			self.group_staves.set_sensitive(can_group);
+			self.ungroup_staves.set_sensitive(is_grouped);
+
 			if selected_staff_backup.is_some() {
 				self.current_page
 					.borrow_mut()
@@ -247,6 +337,20 @@ mod imp {
 				.cast_ray(x, y, scale)
 				.map(|(i, _)| i)
 				.collect::<Vec<_>>();
+			let extend_selection = self
+				.click_gesture
+				.current_event_state()
+				.contains(gdk::ModifierType::SHIFT_MASK);
+
+			if extend_selection && page.selected_staff.is_some() && !cast_result.is_empty() {
+				/* Select all staves in between, for grouping them */
+				page.selection_end = cast_result.first().copied();
+				self.editor.queue_draw();
+				std::mem::drop(page_);
+				self.update_page_state();
+				return;
+			}
+			page.selection_end = None;
 
 			if cast_result
 				.iter()
@@ -319,6 +423,10 @@ mod imp {
 			let scale = self.editor.get().height() as f64 / image.height() as f64;
 			let (mut x, mut y) = self.drag_gesture.start_point().unwrap();
 			let (mut w, mut h) = self.drag_gesture.offset().unwrap();
+			if self.drag_state.get().is_some() {
+				/* Staves might get reordered */
+				page.selection_end = None;
+			}
 			x /= scale;
 			y /= scale;
 			w /= scale;
@@ -469,6 +577,7 @@ mod imp {
 				Some(selected_staff) => selected_staff,
 				None => return,
 			};
+			page.selection_end = None;
 
 			let mut file = self.file.get().unwrap().borrow_mut();
 			file.delete_staff(page.page, selected_staff);
@@ -596,6 +705,114 @@ mod imp {
 			self.update_page_state();
 		}
 
+		/// Any of the repetition or jump widgets changed
+		#[template_callback]
+		fn update_jumps(&self) {
+			let mut page_ = self.current_page.borrow_mut();
+			let page = match page_.as_mut() {
+				Some(page) => page,
+				None => return,
+			};
+			let selected_staff = match page.selected_staff {
+				Some(selected_staff) => selected_staff,
+				None => return,
+			};
+			let mut file = self.file.get().unwrap().borrow_mut();
+			let index = StaffIndex(file.count_staves_before(page.page) + selected_staff);
+			*file.jumps_mut(index) = JumpMarks {
+				repeat_start: self.repeat_start.is_active(),
+				repeat_end: self
+					.repeat_end
+					.is_active()
+					.then(|| self.repeat_times.value_as_int() as u32),
+				/* Silently drop everything that isn't a number */
+				volta: self
+					.volta
+					.text()
+					.split(|c: char| c == ',' || c.is_whitespace())
+					.filter_map(|pass| pass.parse().ok())
+					.collect(),
+				segno: self.segno.is_active(),
+				coda: self.coda.is_active(),
+				to_coda: self.to_coda.is_active(),
+				fine: self.fine.is_active(),
+				jump: match self.jump.active_id().as_deref() {
+					Some("da-capo") => Some(Jump::DaCapo),
+					Some("dal-segno") => Some(Jump::DalSegno),
+					_ => None,
+				},
+			};
+
+			std::mem::drop((page_, file));
+			self.repeat_times.set_sensitive(self.repeat_end.is_active());
+			self.editor.queue_draw();
+		}
+
+		/// The bar number or rehearsal mark changed
+		#[template_callback]
+		fn update_measures(&self) {
+			let mut page_ = self.current_page.borrow_mut();
+			let page = match page_.as_mut() {
+				Some(page) => page,
+				None => return,
+			};
+			let selected_staff = match page.selected_staff {
+				Some(selected_staff) => selected_staff,
+				None => return,
+			};
+			let mut file = self.file.get().unwrap().borrow_mut();
+			let index = StaffIndex(file.count_staves_before(page.page) + selected_staff);
+			let rehearsal_mark = self.rehearsal_mark.text().trim().to_owned();
+			*file.measures_mut(index) = MeasureMarks {
+				/* Silently drop everything that isn't a number */
+				first_measure: self.first_measure.text().trim().parse().ok(),
+				rehearsal_mark: Some(rehearsal_mark).filter(|mark| !mark.is_empty()),
+			};
+		}
+
+		/// Group the selected staves into one system
+		#[template_callback]
+		fn group_staves(&self) {
+			let mut page_ = self.current_page.borrow_mut();
+			let page = match page_.as_mut() {
+				Some(page) => page,
+				None => return,
+			};
+			let range = match page.selected_range() {
+				Some(range) => range,
+				None => return,
+			};
+			let mut file = self.file.get().unwrap().borrow_mut();
+			if !file.can_group(range.clone()) {
+				return;
+			}
+			file.group_staves(range);
+			page.selection_end = None;
+
+			std::mem::drop((page_, file));
+			self.editor.queue_draw();
+			self.update_page_state();
+		}
+
+		/// Split the system of the selected staff
+		#[template_callback]
+		fn ungroup_staves(&self) {
+			let mut page_ = self.current_page.borrow_mut();
+			let page = match page_.as_mut() {
+				Some(page) => page,
+				None => return,
+			};
+			let index = match page.current_index() {
+				Some(index) => StaffIndex(index),
+				None => return,
+			};
+			self.file.get().unwrap().borrow_mut().ungroup_staves(index);
+
+			std::mem::drop(page_);
+			self.editor.queue_draw();
+			self.update_page_state();
+		}
+
 		/// Draw signal
 		fn editor_draw(
 			&self,
@@ -769,6 +986,13 @@ mod imp {
 
 						context.restore()?;
 					} else {
+						if page
+							.selected_range()
+							.map(|range| range.contains(&StaffIndex(absolute_index)))
+							.unwrap_or(false)
+						{
+							context.set_source_rgba(0.15, 0.3, 0.5, 0.3);
+						}
 						context.rectangle(staff_left, staff_top, staff_width, staff_height);
 						context.fill_preserve()?;
 						context.stroke()?;
@@ -904,6 +1128,28 @@ mod imp {
 
 					context.restore()?;
 				}
+
+				/* Draw a bracket left of each system */
+				context.set_source_rgba(0.15, 0.3, 0.5, 0.8);
+				let mut i = 0;
+				while i < page.bars.len() {
+					let system = file.system_of(StaffIndex(page.staves_before + i));
+					let end = *system.end() - StaffIndex(page.staves_before);
+					if system.start() < system.end() {
+						let staves = &page.bars[i..=*end];
+						let left = staves.iter().map(Staff::left).fold(f64::INFINITY, f64::min);
+						let top = staves[0].top();
+						let bottom = staves[staves.len() - 1].bottom();
+						context.rectangle(
+							left * effective_image_width - 16.0,
+							top * effective_image_width,
+							6.0,
+							(bottom - top) * effective_image_width,
+						);
+						context.fill()?;
+					}
+					i = *end + 1;
+				}
 				context.restore()?;
 				cairo::Result::Ok(())
 			})
@@ -941,6 +1187,8 @@ struct PageState {
 	// selected_page: PageIndex,
 	/* Relative to the currently selected page */
 	selected_staff: Option<usize>,
+	/// Shift+click selects all staves between `selected_staff` and this one
+	selection_end: Option<usize>,
 	/// Send new canvas size to background thread
 	renderer: Sender<i32>,
 }
@@ -951,6 +1199,16 @@ impl PageState {
 		self.selected_staff.map(|s| self.staves_before + s)
 	}
 
+	/// Absolute staff indices of all selected staves
+	fn selected_range(&self) -> Option<RangeInclusive<StaffIndex>> {
+		let start = self.selected_staff?;
+		let end = self.selection_end.unwrap_or(start);
+		Some(
+			StaffIndex(self.staves_before + start.min(end))
+				..=StaffIndex(self.staves_before + start.max(end)),
+		)
+	}
+
 	/// If we hit, return staff index of page and hit kind
 	fn cast_ray(
 		&self,
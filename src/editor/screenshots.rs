@@ -93,9 +93,10 @@ fn create_screenshots() -> anyhow::Result<()> {
 			&mut Default::default(),
 		)
 		.unwrap();
+		let annotations = song.load_annotation_layer().unwrap();
 		let load_sheets = song.load_sheets();
 		let sheets = blocking::unblock(move || load_sheets()).await.unwrap();
-		window.imp().load(sheets, song.index);
+		window.imp().load(sheets, song.index, annotations);
 		yield_now().await;
 
 		/* Select the first staff */
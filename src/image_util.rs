@@ -68,6 +68,14 @@ impl PageImage {
 		&self.raw
 	}
 
+	/// Identifies the page by its content (64 bit FNV-1a of the raw bytes).
+	/// Unlike [`std::hash::Hash`], this is stable across builds, so it may be stored.
+	pub fn content_hash(&self) -> u64 {
+		self.raw.iter().fold(0xcbf29ce484222325, |hash, byte| {
+			(hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+		})
+	}
+
 	/// The width of the coordinate system for this image
 	pub fn reference_width(&self) -> f64 {
 		self.width
@@ -296,6 +304,86 @@ buf = buf.getvalue()
 	Ok(locals.get_item("buf").unwrap().extract().unwrap())
 }
 
+/// A page of [`assemble_pdf`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PdfPage {
+	/// The page of one of the input files, by the index of the file and of the page
+	Of(usize, usize),
+	/// An empty page of that size
+	Blank(f64, f64),
+}
+
+/// Put together a PDF file from the pages of others. Annotations move along with their pages.
+pub fn assemble_pdf(pdfs: Vec<Vec<u8>>, pages: &[PdfPage]) -> anyhow::Result<Vec<u8>> {
+	use pyo3::{conversion::IntoPy, types::IntoPyDict};
+	let gil = pyo3::Python::acquire_gil();
+	let py = gil.python();
+
+	/* As (file, page) or None for blank pages, plus the size */
+	let pages: Vec<(Option<(usize, usize)>, (f64, f64))> = pages
+		.iter()
+		.map(|page| match *page {
+			PdfPage::Of(file, page) => (Some((file, page)), (0.0, 0.0)),
+			PdfPage::Blank(width, height) => (None, (width, height)),
+		})
+		.collect();
+	let locals = [("pdfs", pdfs.into_py(py)), ("pages", pages.into_py(py))].into_py_dict(py);
+	py.run(
+		r#"
+from pikepdf import Pdf
+from io import BytesIO
+
+sources = [Pdf.open(BytesIO(bytes(pdf))) for pdf in pdfs]
+out = Pdf.new()
+
+for (source, size) in pages:
+	if source is None:
+		out.add_blank_page(page_size=size)
+	else:
+		(file, page) = source
+		out.pages.append(sources[file].pages[page])
+
+buf = BytesIO(bytearray())
+out.save(buf)
+del out
+buf = buf.getvalue()
+"#,
+		None,
+		Some(locals),
+	)?;
+
+	Ok(locals.get_item("buf").unwrap().extract().unwrap())
+}
+
+/// Empty the contents of all pages, so that only their annotations remain
+pub fn strip_page_contents(pdf: &[u8]) -> anyhow::Result<Vec<u8>> {
+	use pyo3::{conversion::IntoPy, types::IntoPyDict};
+	let gil = pyo3::Python::acquire_gil();
+	let py = gil.python();
+
+	let locals = [("pdf", pdf.into_py(py))].into_py_dict(py);
+	py.run(
+		r#"
+from pikepdf import Pdf, Stream
+from io import BytesIO
+
+pdf = Pdf.open(BytesIO(bytes(pdf)))
+
+for page in pdf.pages:
+	page.Contents = Stream(pdf, b"")
+
+buf = BytesIO(bytearray())
+pdf.save(buf)
+del pdf
+buf = buf.getvalue()
+"#,
+		None,
+		Some(locals),
+	)?;
+
+	Ok(locals.get_item("buf").unwrap().extract().unwrap())
+}
+
 pub fn concat_files(pdfs: Vec<(Vec<u8>, bool)>) -> anyhow::Result<Vec<u8>> {
 	concat_pdfs(
 		pdfs.into_iter()
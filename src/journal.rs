@@ -0,0 +1,341 @@
+/*! A log of all practice sessions
+ *
+ * Each time a song is closed, a [`PracticeSession`] is appended to `journal.jsonl` in the
+ * user's data directory, one JSON object per line. Entries are never changed afterwards.
+ * Only active time counts, idle periods are left out (the viewer decides what "active" means).
+ *
+ * Days are counted in local time.
+ */
+use super::*;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	io::{BufRead, Write},
+	time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+/// Time spent within a section of a song
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SectionVisit {
+	/// The first staff of the piece
+	pub piece: collection::StaffIndex,
+	/// The staves of the section, as they were at the time
+	pub start: collection::StaffIndex,
+	pub end: collection::StaffIndex,
+	pub active_seconds: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PracticeSession {
+	pub song: Uuid,
+	pub start: SystemTime,
+	pub end: SystemTime,
+	pub active_seconds: u64,
+	/// In order of the first visit
+	pub visits: Vec<SectionVisit>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum JournalEntry {
+	#[serde(rename = "0")]
+	V0(PracticeSession),
+}
+
+impl PracticeSession {
+	pub fn new(song: Uuid, start: SystemTime) -> Self {
+		PracticeSession {
+			song,
+			start,
+			end: start,
+			active_seconds: 0,
+			visits: Vec::new(),
+		}
+	}
+
+	/// Account active time to the section around `staff`
+	pub fn record(
+		&mut self,
+		song: &collection::SongMeta,
+		staff: collection::StaffIndex,
+		seconds: u64,
+		now: SystemTime,
+	) {
+		self.end = now;
+		self.active_seconds += seconds;
+		let piece = *song.piece_range(staff).start();
+		let section = song
+			.sections()
+			.into_iter()
+			.map(|(section, _is_repetition)| section)
+			.find(|section| section.contains(&staff))
+			.unwrap_or(staff..=staff);
+		match self
+			.visits
+			.iter_mut()
+			.find(|visit| visit.start == *section.start())
+		{
+			Some(visit) => visit.active_seconds += seconds,
+			None => self.visits.push(SectionVisit {
+				piece,
+				start: *section.start(),
+				end: *section.end(),
+				active_seconds: seconds,
+			}),
+		}
+	}
+
+	/// The active time per piece, by the first staff of the piece
+	pub fn pieces(&self) -> BTreeMap<collection::StaffIndex, u64> {
+		let mut pieces = BTreeMap::new();
+		for visit in &self.visits {
+			*pieces.entry(visit.piece).or_default() += visit.active_seconds;
+		}
+		pieces
+	}
+}
+
+/// A session of an hour that ended at `end`, with the active seconds per piece
+#[cfg(test)]
+pub fn test_session(song: Uuid, end: SystemTime, pieces: &[(usize, u64)]) -> PracticeSession {
+	PracticeSession {
+		song,
+		start: end - Duration::from_secs(3600),
+		end,
+		active_seconds: pieces.iter().map(|(_, seconds)| seconds).sum(),
+		visits: pieces
+			.iter()
+			.map(|&(piece, active_seconds)| SectionVisit {
+				piece: collection::StaffIndex(piece),
+				start: collection::StaffIndex(piece),
+				end: collection::StaffIndex(piece),
+				active_seconds,
+			})
+			.collect(),
+	}
+}
+
+fn journal_file() -> anyhow::Result<std::path::PathBuf> {
+	// TODO don't hardcode here
+	let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+	Ok(xdg.place_data_file("journal.jsonl")?)
+}
+
+/// Read all sessions. Lines that can't be parsed (like one that was cut short by a crash) are skipped.
+pub fn load() -> anyhow::Result<Vec<PracticeSession>> {
+	let path = journal_file()?;
+	if !path.exists() {
+		return Ok(Vec::new());
+	}
+	let file = std::io::BufReader::new(
+		std::fs::File::open(&path)
+			.with_context(|| format!("Failed to open '{}'", path.display()))?,
+	);
+	let mut sessions = Vec::new();
+	for (number, line) in file.lines().enumerate() {
+		let line = line.with_context(|| format!("Failed to read '{}'", path.display()))?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		match serde_json::from_str(&line) {
+			Ok(JournalEntry::V0(session)) => sessions.push(session),
+			Err(err) => log::warn!(
+				"Skipping line {} of the practice journal: {}",
+				number + 1,
+				err
+			),
+		}
+	}
+	Ok(sessions)
+}
+
+pub fn append(session: &PracticeSession) -> anyhow::Result<()> {
+	let path = journal_file()?;
+	let mut line = serde_json::to_string(&JournalEntry::V0(session.clone()))?;
+	line.push('\n');
+	std::fs::OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&path)
+		.and_then(|mut file| file.write_all(line.as_bytes()))
+		.with_context(|| {
+			format!(
+				"Could not write to the practice journal ({})",
+				path.display()
+			)
+		})
+}
+
+fn local_day(time: SystemTime) -> chrono::NaiveDate {
+	chrono::DateTime::<chrono::Local>::from(time).date_naive()
+}
+
+/// The active time per song and piece since the start of the current week (Monday)
+pub fn time_per_piece_this_week(
+	sessions: &[PracticeSession],
+	now: SystemTime,
+) -> BTreeMap<(Uuid, collection::StaffIndex), Duration> {
+	use chrono::Datelike;
+
+	let today = local_day(now);
+	let monday = today - chrono::Duration::days(today.weekday().num_days_from_monday() as i64);
+	let mut pieces = BTreeMap::new();
+	for session in sessions
+		.iter()
+		.filter(|session| local_day(session.start) >= monday)
+	{
+		for (piece, seconds) in session.pieces() {
+			*pieces
+				.entry((session.song, piece))
+				.or_insert(Duration::ZERO) += Duration::from_secs(seconds);
+		}
+	}
+	pieces
+}
+
+/// The number of consecutive days with practice, up to today. If there was no practice today
+/// yet, the streak up to yesterday still counts.
+pub fn streak(sessions: &[PracticeSession], now: SystemTime) -> u32 {
+	let days: BTreeSet<chrono::NaiveDate> = sessions
+		.iter()
+		.filter(|session| session.active_seconds > 0)
+		.map(|session| local_day(session.start))
+		.collect();
+	let mut day = local_day(now);
+	if !days.contains(&day) {
+		day = day.pred_opt().unwrap();
+	}
+	let mut streak = 0;
+	while days.contains(&day) {
+		streak += 1;
+		day = day.pred_opt().unwrap();
+	}
+	streak
+}
+
+/// Songs that were played before, ordered by how long ago that was, the longest first.
+/// `songs` comes with [`LibrarySong::last_played`](library::LibrarySong::last_played),
+/// for songs that were played before the journal existed.
+pub fn most_neglected(
+	sessions: &[PracticeSession],
+	songs: impl IntoIterator<Item = (Uuid, Option<SystemTime>)>,
+) -> Vec<(Uuid, SystemTime)> {
+	let mut last_practiced: HashMap<Uuid, SystemTime> = HashMap::new();
+	for session in sessions {
+		let last = last_practiced.entry(session.song).or_insert(session.end);
+		*last = (*last).max(session.end);
+	}
+	let mut songs: Vec<(Uuid, SystemTime)> = songs
+		.into_iter()
+		.filter_map(|(song, last_played)| {
+			last_practiced
+				.get(&song)
+				.copied()
+				.max(last_played)
+				.map(|last| (song, last))
+		})
+		.collect();
+	songs.sort_by_key(|&(song, last)| (last, song));
+	songs
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn at(day: u32, hour: u32) -> SystemTime {
+		chrono::Local
+			.with_ymd_and_hms(2022, 3, day, hour, 0, 0)
+			.unwrap()
+			.into()
+	}
+
+	fn session(song: Uuid, day: u32, pieces: &[(usize, u64)]) -> PracticeSession {
+		test_session(song, at(day, 11), pieces)
+	}
+
+	#[test]
+	fn test_summary() {
+		let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+		/* 2022-03-14 is a Monday */
+		let sessions = [
+			session(a, 11, &[(0, 600)]),
+			session(b, 13, &[(0, 60)]),
+			session(a, 14, &[(0, 300), (4, 120)]),
+			session(a, 15, &[(0, 60)]),
+			session(b, 16, &[(4, 30)]),
+		];
+
+		let week = time_per_piece_this_week(&sessions, at(16, 20));
+		assert_eq!(week.len(), 3);
+		assert_eq!(
+			week[&(a, collection::StaffIndex(0))],
+			Duration::from_secs(360)
+		);
+		assert_eq!(
+			week[&(a, collection::StaffIndex(4))],
+			Duration::from_secs(120)
+		);
+		assert_eq!(
+			week[&(b, collection::StaffIndex(4))],
+			Duration::from_secs(30)
+		);
+
+		assert_eq!(streak(&sessions, at(16, 20)), 4);
+		/* Not practiced today yet */
+		assert_eq!(streak(&sessions, at(17, 8)), 4);
+		assert_eq!(streak(&sessions, at(18, 8)), 0);
+		assert_eq!(streak(&sessions[..2], at(13, 20)), 1);
+
+		let neglected = most_neglected(
+			&sessions,
+			[
+				(a, None),
+				(b, Some(at(1, 10))),
+				(c, Some(at(2, 10))),
+				(Uuid::new_v4(), None),
+			],
+		);
+		assert_eq!(
+			neglected,
+			[(c, at(2, 10)), (a, at(15, 11)), (b, at(16, 11))]
+		);
+	}
+
+	#[test]
+	fn test_record() {
+		/* Two pieces, the first one with a second section at staff 2 */
+		let song = collection::SongBuilder::pages(&[6])
+			.piece(0, "Allegro")
+			.piece(4, "Adagio")
+			.section(2)
+			.section(4)
+			.build();
+		let mut session = PracticeSession::new(song.song_uuid, at(14, 10));
+		session.record(&song, collection::StaffIndex(3), 60, at(14, 11));
+		session.record(&song, collection::StaffIndex(5), 30, at(14, 12));
+		session.record(&song, collection::StaffIndex(2), 20, at(14, 13));
+
+		assert_eq!(session.active_seconds, 110);
+		assert_eq!(session.end, at(14, 13));
+		assert_eq!(
+			session
+				.visits
+				.iter()
+				.map(|visit| (*visit.piece, *visit.start, *visit.end, visit.active_seconds))
+				.collect::<Vec<_>>(),
+			[(0, 2, 3, 80), (4, 4, 5, 30)]
+		);
+		assert_eq!(
+			session.pieces(),
+			[
+				(collection::StaffIndex(0), 80),
+				(collection::StaffIndex(4), 30)
+			]
+			.into()
+		);
+	}
+}
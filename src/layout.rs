@@ -4,6 +4,7 @@ use typed_index_collections::{TiSlice, TiVec};
 use derive_more::*;
 use noisy_float::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
 /// As opposed to [`collection::PageIndex`] which is about physical pages in the scanned material
 #[derive(
@@ -39,6 +40,17 @@ pub struct StaffLayout {
 	pub width: f64,
 }
 
+impl StaffLayout {
+	/// Convert a point on the laid out page into coordinates of the scanned page, like those of the staff
+	pub fn to_page_coordinates(&self, staff: &Staff, x: f64, y: f64) -> (f64, f64) {
+		let scale = self.width / staff.width();
+		(
+			staff.start.0 + (x - self.x) / scale,
+			staff.start.1 + (y - self.y) / scale,
+		)
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct PageLayout {
 	/* Pages[Staves] */
@@ -50,28 +62,99 @@ pub struct PageLayout {
 impl PageLayout {
 	/** Get the index of the staff at the center of the page. */
 	pub fn get_center_staff(&self, page: PageIndex) -> StaffIndex {
-		StaffIndex(
-			self.pages[0.into()..page]
-				.iter()
-				.map(Vec::len)
-				.sum::<usize>() + self.pages[page].len() / 2,
-		)
+		self.pages[page][self.pages[page].len() / 2].index
+	}
+
+	/// The staff at that point of the page, if any
+	pub fn get_staff_at(
+		&self,
+		song: &collection::SongMeta,
+		page: PageIndex,
+		x: f64,
+		y: f64,
+	) -> Option<&StaffLayout> {
+		self.pages[page].iter().find(|layout| {
+			let height = layout.width * song.staves[layout.index].aspect_ratio();
+			(layout.x..layout.x + layout.width).contains(&x)
+				&& (layout.y..layout.y + height).contains(&y)
+		})
 	}
 
 	pub fn get_staves_of_page(&self, page: PageIndex) -> impl Iterator<Item = StaffIndex> + '_ {
 		self.pages[page].iter().map(|page| page.index)
 	}
 
+	/// Staves that are not part of the layout (see [`layout_part`]) are on the page
+	/// of the closest staff before them.
 	pub fn get_page_of_staff(&self, staff: StaffIndex) -> PageIndex {
-		let mut sum = 0;
-		for (i, page) in self.pages.iter().enumerate() {
-			sum += page.len();
-			if sum > staff.into() {
-				return i.into();
-			}
+		self.pages
+			.iter()
+			.rposition(|page| page.first().map_or(false, |first| first.index <= staff))
+			.unwrap_or(0)
+			.into()
+	}
+}
+
+/// Lay out only the given staves, for example to show a single part of a score. The song is reduced
+/// to the visible staves and passed to `layout`, but the resulting layout refers to the original
+/// staff indices. With nothing visible, everything is shown.
+pub fn layout_part(
+	song: &collection::SongMeta,
+	visible: &BTreeSet<StaffIndex>,
+	layout: impl FnOnce(&collection::SongMeta) -> PageLayout,
+) -> PageLayout {
+	let kept: TiVec<StaffIndex, StaffIndex> = visible
+		.iter()
+		.copied()
+		.filter(|staff| **staff < song.staves.len())
+		.collect();
+	if kept.is_empty() {
+		return layout(song);
+	}
+	let new_index = |staff: StaffIndex| StaffIndex(kept.raw.partition_point(|kept| *kept < staff));
+
+	/* Pieces start at their first visible staff. Pieces without any are dropped */
+	let mut piece_starts = BTreeMap::new();
+	for (start, name) in &song.piece_starts {
+		if let Some(first) = visible.range(song.piece_range(*start)).next() {
+			piece_starts
+				.entry(new_index(*first))
+				.or_insert_with(|| name.clone());
 		}
-		unreachable!()
 	}
+	/* Systems keep only their visible staves, which are still next to each other */
+	let systems = song
+		.systems
+		.iter()
+		.filter_map(|(&start, &length)| {
+			let mut staves = visible.range(start..start + StaffIndex(length));
+			let first = *staves.next()?;
+			let length = staves.count() + 1;
+			(length > 1).then(|| (new_index(first), length))
+		})
+		.collect();
+
+	let part = collection::SongMetaV5 {
+		n_pages: song.n_pages,
+		staves: kept
+			.iter()
+			.map(|staff| song.staves[*staff].clone())
+			.collect(),
+		piece_starts,
+		section_starts: [(StaffIndex(0), Default::default())].into(),
+		jumps: Default::default(),
+		measures: Default::default(),
+		systems,
+		song_uuid: song.song_uuid,
+		version_uuid: song.version_uuid,
+		title: song.title.clone(),
+		composer: song.composer.clone(),
+	};
+	let mut layout = layout(&part);
+	for staff in layout.pages.iter_mut().flatten() {
+		staff.index = kept[staff.index];
+	}
+	layout
 }
 
 pub fn find_scale_for_fixed_staves(
@@ -125,8 +208,18 @@ pub fn layout_fixed_scale(
 
 			column_width = column_width.max(staff_width);
 
+			/* Never break within a system. Instead, check if all of it fits on its first staff */
+			let system = song.system_of(index);
+			let is_system_start = *system.start() == index;
+			let system_height = song.staves[system]
+				.iter()
+				.map(|staff| staff.height() * scale)
+				.sum::<f64>();
+
 			/* Start a new column for a new piece, or when the page is full */
-			if song.piece_starts.contains_key(&index) || (y + staff_height > height) {
+			if song.piece_starts.contains_key(&index)
+				|| (is_system_start && y + system_height > height)
+			{
 				y = 0.0;
 				column_starts.push((index, column_width));
 			}
@@ -224,7 +317,11 @@ pub fn layout_fixed_scale(
 						.rev()
 						.filter(|(_, (a, b))| {
 							a.bounds.page == b.bounds.page
-								&& a.bounds.bottom() - b.bounds.top() > 0.0
+								&& (a.bounds.bottom() - b.bounds.top() > 0.0
+									/* Keep systems together as they are printed */
+									|| song
+										.system_of(column_start + *b.staves.start())
+										.contains(&(column_start + *a.staves.end())))
 						})
 						.map(|(idx, _)| idx)
 						.collect();
@@ -351,7 +448,11 @@ pub fn layout_fixed_scale(
 	}
 }
 
-#[deprecated(note = "This has not been used in a while and also uses the old coordinate space")]
+/**
+ * Give each staff the full width of a column, with `column_count` columns per page. The
+ * height of a staff only depends on its aspect ratio then. Staves that are too high to fit
+ * on a page on their own get scaled down.
+ */
 pub fn layout_fixed_width(
 	song: &collection::SongMeta,
 	width: f64,
@@ -365,26 +466,26 @@ pub fn layout_fixed_width(
 	let column_starts = {
 		let mut column_starts = Vec::<StaffIndex>::new();
 		let mut y = 0.0;
-		// let mut y_with_spacing = 0.0;
 		for (index, staff) in song.staves.iter().enumerate() {
 			let index = StaffIndex(index);
 
 			let staff_height = column_width * staff.aspect_ratio();
 
-			/*
-			 * Start a new column for a new piece.
-			 * If staff doesn't fit anymore, first try to squeeze it in at the cost of spacing
-			 */
+			/* Never break within a system */
+			let system = song.system_of(index);
+			let system_height = song.staves[system.clone()]
+				.iter()
+				.map(|staff| column_width * staff.aspect_ratio())
+				.sum::<f64>();
+
+			/* Start a new column for a new piece, or when the column is full */
 			if song.piece_starts.contains_key(&index)
-				// || ((y_with_spacing + staff_height > height) && (y + staff_height <= height))
-				|| (y + staff_height > height)
+				|| (*system.start() == index && y + system_height > height)
 			{
 				y = 0.0;
-				// y_with_spacing = 0.0;
 				column_starts.push(index);
 			}
 			y += staff_height;
-			// y_with_spacing += staff_height + spacing;
 		}
 		/* Without this the last page will get swallowed */
 		column_starts.push(song.staves.len().into());
@@ -392,43 +493,47 @@ pub fn layout_fixed_width(
 	};
 
 	/* 2. Calculate the exact position of each staff */
-	let columns: Vec<Vec<StaffLayout>> = column_starts
+	let columns: Vec<(StaffIndex, Vec<StaffLayout>)> = column_starts
 		.windows(2)
 		.map(|v| (v[0], v[1]))
 		.map(|(chunk_start, chunk_end)| {
 			let mut column = Vec::new();
 			let staves: &TiSlice<_, Staff> = &song.staves[chunk_start..chunk_end];
-			if staves.len() == 1 {
-				let staff = &staves[StaffIndex(0)];
-				let staff_height = column_width * staff.aspect_ratio();
-				let x;
-				let y;
-				let staff_width;
-
-				if staff_height > height {
-					staff_width = height / staff.aspect_ratio();
-					x = (column_width - staff_width) / 2.0;
-					y = 0.0;
-				} else {
-					staff_width = column_width;
-					x = 0.0;
-					y = (height - staff_height) / 2.0;
+			let total_height = staves
+				.iter()
+				.map(|staff| column_width * staff.aspect_ratio())
+				.sum::<f64>();
+			if total_height > height {
+				/* Only a single staff or system may overflow a column. Scale it down to fit */
+				let staff_width = column_width * height / total_height;
+				let x = (column_width - staff_width) / 2.0;
+				let mut y = 0.0;
+				for (index, staff) in staves.iter().enumerate() {
+					column.push(StaffLayout {
+						index: chunk_start + StaffIndex(index),
+						x,
+						y,
+						width: staff_width,
+					});
+					y += staff_width * staff.aspect_ratio();
 				}
-
+			} else if staves.len() == 1 {
+				let staff_height = column_width * staves[StaffIndex(0)].aspect_ratio();
 				column.push(StaffLayout {
 					index: chunk_start,
-					x,
-					y,
-					width: staff_width,
+					x: 0.0,
+					y: (height - staff_height) / 2.0,
+					width: column_width,
 				});
 			} else {
+				/* Never negative, because only single staves or systems may overflow a column */
 				let excess_space = height
 					- staves
 						.iter()
 						.map(|staff| column_width * staff.aspect_ratio())
 						.sum::<f64>();
-				let spacing = f64::min(spacing, excess_space / staves.len() as f64);
-				let mut y = (excess_space - spacing * staves.len() as f64) / 2.0;
+				let spacing = f64::min(spacing, excess_space / (staves.len() - 1) as f64);
+				let mut y = (excess_space - spacing * (staves.len() - 1) as f64) / 2.0;
 				for (index, staff) in staves.iter().enumerate() {
 					column.push(StaffLayout {
 						index: chunk_start + StaffIndex(index),
@@ -439,29 +544,32 @@ pub fn layout_fixed_width(
 					y += column_width * staff.aspect_ratio() + spacing;
 				}
 			}
-			column
+			(chunk_start, column)
 		})
 		.collect();
 
-	/* 3. Merge the single columns to pages using iterator magic */
+	/* 3. Merge the single columns to pages. A new piece always starts on a new page */
 	let left_margin = (width - width * zoom) / 2.0;
-	let pages = columns
-		.chunks(column_count)
-		.map(|chunk| {
-			chunk
-				.iter()
-				.enumerate()
-				.flat_map(|(i, c)| {
-					c.iter().map(move |staff| StaffLayout {
-						index: staff.index,
-						x: staff.x + column_width * (i % column_count) as f64 + left_margin,
-						y: staff.y,
-						width: staff.width,
-					})
-				})
-				.collect()
-		})
-		.collect();
+	let mut pages = TiVec::<PageIndex, Vec<StaffLayout>>::new();
+	let mut column_in_page = 0;
+	for (column_start, column) in columns {
+		if pages.is_empty()
+			|| column_in_page >= column_count
+			|| song.piece_starts.contains_key(&column_start)
+		{
+			pages.push(Vec::new());
+			column_in_page = 0;
+		}
+		let offset = column_width * column_in_page as f64 + left_margin;
+		pages
+			.last_mut()
+			.unwrap()
+			.extend(column.into_iter().map(|staff| StaffLayout {
+				x: staff.x + offset,
+				..staff
+			}));
+		column_in_page += 1;
+	}
 
 	PageLayout {
 		pages,
@@ -469,22 +577,30 @@ pub fn layout_fixed_width(
 	}
 }
 
-#[deprecated(note = "This has not been used in a while and also uses the old coordinate space")]
+/**
+ * Split the page into `row_count` rows of equal height, with one staff per row. All staves
+ * on a page get the same width, so that their notes are roughly of the same size. Systems
+ * with more staves than rows get a page of their own, with smaller rows.
+ */
 pub fn layout_fixed_height(
 	song: &collection::SongMeta,
 	width: f64,
 	height: f64,
 	row_count: usize,
 ) -> PageLayout {
-	let row_height = height / row_count as f64;
-
 	let column_starts = {
 		let mut column_starts = Vec::<StaffIndex>::new();
 		let mut page_length = 0;
 		for index in 0..song.staves.len() {
 			let index = StaffIndex(index);
 
-			if song.piece_starts.contains_key(&index) || page_length >= row_count {
+			/* Never break within a system */
+			let system = song.system_of(index);
+			let system_length = **system.end() - **system.start() + 1;
+
+			if song.piece_starts.contains_key(&index)
+				|| (*system.start() == index && page_length + system_length > row_count)
+			{
 				column_starts.push(index);
 				page_length = 0;
 			}
@@ -499,6 +615,7 @@ pub fn layout_fixed_height(
 		.map(|v| (v[0], v[1]))
 		.map(|(chunk_start, chunk_end)| {
 			let staves: &TiSlice<_, Staff> = &song.staves[chunk_start..chunk_end];
+			let row_height = height / row_count.max(staves.len()) as f64;
 			let max_width: f64 = staves
 				.iter()
 				.map(|staff| r64(row_height / staff.aspect_ratio()))
@@ -511,13 +628,8 @@ pub fn layout_fixed_height(
 				.iter()
 				.enumerate()
 				.map(|(in_page_index, staff)| {
-					let mut staff_width = row_height / staff.aspect_ratio();
-					let mut staff_height = row_height;
-
-					if staff_width > max_width {
-						staff_width = max_width;
-						staff_height *= staff_width / max_width;
-					}
+					let staff_width = f64::min(row_height / staff.aspect_ratio(), max_width);
+					let staff_height = staff_width * staff.aspect_ratio();
 
 					StaffLayout {
 						index: StaffIndex(in_page_index) + chunk_start,
@@ -535,3 +647,159 @@ pub fn layout_fixed_height(
 		random_id: uuid::Uuid::new_v4(),
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Three scanned pages with staves of slightly varying height, and a second piece starting mid-page.
+	/// Some staves are grouped into systems
+	fn test_song() -> collection::SongMeta {
+		let staves = (0..3).flat_map(|page| {
+			(0..5).map(move |i| {
+				let top = 0.05 + i as f64 * 0.27;
+				Staff {
+					page: collection::PageIndex(page),
+					start: (0.1, top),
+					end: (0.9, top + 0.15 + 0.02 * i as f64),
+				}
+			})
+		});
+		collection::SongBuilder::staves(staves)
+			.piece(0, "I")
+			.piece(7, "II")
+			.system(1, 2)
+			.system(10, 3)
+			.build()
+	}
+
+	const SIZES: [(f64, f64); 4] = [
+		(800.0, 600.0),
+		(1920.0, 1080.0),
+		(600.0, 1000.0),
+		(200.0, 150.0),
+	];
+
+	fn check_layout(song: &collection::SongMeta, layout: &PageLayout) {
+		check_part_layout(
+			song,
+			&(0..song.staves.len()).map(StaffIndex).collect(),
+			layout,
+		);
+	}
+
+	fn check_part_layout(
+		song: &collection::SongMeta,
+		visible: &BTreeSet<StaffIndex>,
+		layout: &PageLayout,
+	) {
+		let staves = layout
+			.pages
+			.iter()
+			.flatten()
+			.map(|staff| staff.index)
+			.collect::<Vec<_>>();
+		assert_eq!(
+			staves,
+			visible.iter().copied().collect::<Vec<_>>(),
+			"Every staff must appear exactly once and in order"
+		);
+		assert!(layout.pages.iter().all(|page| !page.is_empty()));
+		for piece_start in song.piece_starts.keys() {
+			if let Some(first) = visible.range(song.piece_range(*piece_start)).next() {
+				let page = layout.get_page_of_staff(*first);
+				assert_eq!(layout.get_staves_of_page(page).next(), Some(*first));
+			}
+		}
+		for (&start, &length) in &song.systems {
+			let pages = visible
+				.range(start..start + StaffIndex(length))
+				.map(|staff| layout.get_page_of_staff(*staff))
+				.collect::<BTreeSet<_>>();
+			assert!(
+				pages.len() <= 1,
+				"The system at staff {start} must not be broken across pages"
+			);
+		}
+	}
+
+	#[test]
+	fn test_layout_fixed_scale() {
+		let song = test_song();
+		for (width, height) in SIZES {
+			for zoom in [0.5, 1.0, 2.0] {
+				check_layout(&song, &layout_fixed_scale(&song, width, height, zoom));
+			}
+		}
+	}
+
+	#[test]
+	fn test_layout_fixed_width() {
+		let song = test_song();
+		for (width, height) in SIZES {
+			for columns in 1..=3 {
+				check_layout(
+					&song,
+					&layout_fixed_width(&song, width, height, 1.0, columns, 10.0),
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn test_layout_fixed_height() {
+		let song = test_song();
+		for (width, height) in SIZES {
+			for rows in 1..=4 {
+				check_layout(&song, &layout_fixed_height(&song, width, height, rows));
+			}
+		}
+	}
+	#[test]
+	fn test_layout_part() {
+		let song = test_song();
+		/* One staff of each system, a single system, and nothing */
+		let parts: [BTreeSet<StaffIndex>; 3] = [
+			vec![0, 2, 3, 4, 5, 6, 7, 8, 9, 11, 13, 14],
+			vec![10, 11, 12],
+			vec![],
+		]
+		.map(|staves| staves.into_iter().map(StaffIndex).collect());
+		for (width, height) in SIZES {
+			for visible in &parts {
+				let layout = layout_part(&song, visible, |part| {
+					layout_fixed_height(part, width, height, 2)
+				});
+				if visible.is_empty() {
+					check_layout(&song, &layout);
+				} else {
+					check_part_layout(&song, visible, &layout);
+				}
+				/* Hidden staves belong to the page before them */
+				assert_eq!(layout.get_page_of_staff(StaffIndex(1)), PageIndex(0));
+			}
+		}
+	}
+	#[test]
+	fn test_staff_at() {
+		let song = test_song();
+		let layout = layout_fixed_height(&song, 800.0, 600.0, 3);
+		for staff_layout in layout.pages.iter().flatten() {
+			let staff = &song.staves[staff_layout.index];
+			let page = layout.get_page_of_staff(staff_layout.index);
+			let (x, y) = (staff_layout.x + 1.0, staff_layout.y + 1.0);
+			assert_eq!(
+				layout
+					.get_staff_at(&song, page, x, y)
+					.map(|found| found.index),
+				Some(staff_layout.index)
+			);
+			let (page_x, page_y) = staff_layout.to_page_coordinates(staff, x, y);
+			assert!(page_x > staff.left() && page_x < staff.left() + 0.01);
+			assert!(page_y > staff.top() && page_y < staff.top() + 0.01);
+		}
+		assert!(layout
+			.get_staff_at(&song, PageIndex(0), -1.0, -1.0)
+			.is_none());
+	}
+}
@@ -46,10 +46,13 @@ macro_rules! catch {
     }
 }
 
+pub mod annotations;
 pub mod collection;
 pub mod image_util;
+pub mod journal;
 pub mod layout;
 pub mod library;
+pub mod practice;
 #[cfg(feature = "editor")]
 pub mod recognition;
 pub mod unsafe_force;
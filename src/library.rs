@@ -8,7 +8,7 @@ use anyhow::Context;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
-	collections::HashMap,
+	collections::{BTreeSet, HashMap},
 	ops::{Add, Neg, Sub},
 	time::*,
 };
@@ -31,6 +31,113 @@ pub struct LibrarySong {
 	 */
 	usage_score: SystemTime,
 	pub scale_options: Option<ScaleMode>,
+	/// The first visible staff when the song was last left, unless it was played to the end
+	#[serde(default)]
+	pub last_position: Option<collection::StaffIndex>,
+	/// Ways to show only some of the staves, like those of a single instrument
+	#[serde(default)]
+	pub part_profiles: Vec<PartProfile>,
+	/// The name of the part profile in use, if any
+	#[serde(default)]
+	pub part_profile: Option<String>,
+	/// Typed notes, ordered by staff
+	#[serde(default)]
+	pub notes: Vec<StaffNote>,
+	/// How many seconds each staff was on screen, by staff index
+	#[serde(default)]
+	pub dwell_seconds: Vec<f64>,
+	/// How often the user wants to practice the song or some of its pieces
+	#[serde(default)]
+	pub practice_targets: Vec<PracticeTarget>,
+}
+
+/// "Practice this every few days"
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PracticeTarget {
+	/// The first staff of the piece, or `None` for the whole song
+	pub piece: Option<collection::StaffIndex>,
+	pub every_days: u32,
+}
+
+/// A typed note attached to a staff, like "take it slower here"
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StaffNote {
+	pub staff: collection::StaffIndex,
+	pub text: String,
+}
+
+/// A named selection of staves, for players who only need their own part of a score
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PartProfile {
+	pub name: String,
+	pub selection: PartSelection,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PartSelection {
+	/// Explicitly chosen staves. Indices that don't exist (anymore) are ignored
+	Staves(BTreeSet<collection::StaffIndex>),
+	/// The staves at these positions within each system, counted from the top starting at 0.
+	/// Staves that aren't grouped with others are always shown.
+	Position(BTreeSet<usize>),
+}
+
+impl PartSelection {
+	/// Parse a list of numbers and ranges like `1, 3-5`. Positions are counted from 1 here,
+	/// staves use the numbers shown in the editor.
+	pub fn parse(by_position: bool, list: &str) -> anyhow::Result<Self> {
+		let mut numbers = BTreeSet::new();
+		for item in list
+			.split(',')
+			.map(str::trim)
+			.filter(|item| !item.is_empty())
+		{
+			let (start, end): (usize, usize) = match item.split_once('-') {
+				Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+				None => (item.parse()?, item.parse()?),
+			};
+			anyhow::ensure!(start <= end, "Invalid range '{}'", item);
+			numbers.extend(start..=end);
+		}
+		anyhow::ensure!(!numbers.is_empty(), "Nothing selected");
+		if by_position {
+			anyhow::ensure!(!numbers.contains(&0), "Positions start at 1");
+			Ok(Self::Position(numbers.into_iter().map(|n| n - 1).collect()))
+		} else {
+			Ok(Self::Staves(
+				numbers.into_iter().map(collection::StaffIndex).collect(),
+			))
+		}
+	}
+
+	/// The inverse of [`parse`](Self::parse)
+	pub fn to_list(&self) -> String {
+		let numbers: Vec<String> = match self {
+			Self::Position(positions) => positions.iter().map(|n| (n + 1).to_string()).collect(),
+			Self::Staves(staves) => staves.iter().map(ToString::to_string).collect(),
+		};
+		numbers.join(", ")
+	}
+}
+
+impl PartProfile {
+	pub fn visible_staves(&self, song: &collection::SongMeta) -> BTreeSet<collection::StaffIndex> {
+		match &self.selection {
+			PartSelection::Staves(staves) => staves
+				.iter()
+				.copied()
+				.filter(|staff| **staff < song.staves.len())
+				.collect(),
+			PartSelection::Position(positions) => (0..song.staves.len())
+				.map(collection::StaffIndex)
+				.filter(|&staff| {
+					let system = song.system_of(staff);
+					system.start() == system.end()
+						|| positions.contains(&(*staff - **system.start()))
+				})
+				.collect(),
+		}
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -38,6 +145,10 @@ pub enum ScaleMode {
 	FitStaves(u32),
 	FitPages(u32),
 	Zoom(f32),
+	/// Staves take the full width of one of the given number of columns
+	FixedWidth(u32),
+	/// The page is split into the given number of rows, with one staff each
+	FixedHeight(u32),
 }
 
 impl Default for ScaleMode {
@@ -52,10 +163,66 @@ impl ScaleMode {
 			Self::FitStaves(_) => "fit-staves",
 			Self::FitPages(_) => "fit-columns",
 			Self::Zoom(_) => "manual",
+			Self::FixedWidth(_) => "fixed-width",
+			Self::FixedHeight(_) => "fixed-height",
 		}
 	}
 }
 
+/// Parse `<mode>[:<number>]`, where the mode is one of [`ScaleMode::action_string`],
+/// like `fit-staves:3` or `manual:1.5`
+impl std::str::FromStr for ScaleMode {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		let (mode, number) = match s.split_once(':') {
+			Some((mode, number)) => (mode, Some(number)),
+			None => (s, None),
+		};
+		let count = |default: u32| -> anyhow::Result<u32> {
+			let count = number.map_or(Ok(default), str::parse)?;
+			anyhow::ensure!(count > 0, "The number must be at least 1");
+			Ok(count)
+		};
+		Ok(match mode {
+			"fit-staves" => Self::FitStaves(count(3)?),
+			"fit-columns" => Self::FitPages(count(2)?),
+			"fixed-width" => Self::FixedWidth(count(1)?),
+			"fixed-height" => Self::FixedHeight(count(3)?),
+			"manual" => {
+				let zoom = number.map_or(Ok(1.0), str::parse)?;
+				anyhow::ensure!(zoom > 0.0, "The zoom must be positive");
+				Self::Zoom(zoom)
+			},
+			other => anyhow::bail!(
+				"Unknown scale mode '{}', expected one of fit-staves, fit-columns, fixed-width, fixed-height, manual",
+				other
+			),
+		})
+	}
+}
+
+/**
+ * One item of a [`Setlist`]: a piece of a song, as in [`collection::SongMeta::piece_starts`]
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct SetlistEntry {
+	pub song: Uuid,
+	/// The staff at which the piece starts
+	pub piece: collection::StaffIndex,
+	/// Overrides the song's [`LibrarySong::scale_options`] for this entry
+	pub scale_mode: Option<ScaleMode>,
+}
+
+/**
+ * An ordered program of pieces, for example for a concert. Played back one entry after another.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Setlist {
+	pub name: String,
+	pub entries: Vec<SetlistEntry>,
+}
+
 impl LibrarySong {
 	/**
 	 * The exponential decay factor for the usage score. This corresponding
@@ -64,19 +231,142 @@ impl LibrarySong {
 	const DECAY_FACTOR: f64 = std::f64::consts::LN_2 / (30.0 * 24.0 * 3600.0);
 
 	pub fn new(song: Uuid) -> Self {
+		Self::new_at(song, SystemTime::now())
+	}
+
+	pub fn new_at(song: Uuid, now: SystemTime) -> Self {
 		LibrarySong {
 			song,
 			times_played: 0,
 			seconds_played: 0,
 			last_played: None,
-			usage_score: SystemTime::now(),
+			usage_score: now,
 			scale_options: None,
+			last_position: None,
+			part_profiles: Vec::new(),
+			part_profile: None,
+			notes: Vec::new(),
+			dwell_seconds: Vec::new(),
+			practice_targets: Vec::new(),
+		}
+	}
+
+	/// How often the song or piece should be practiced, in days
+	pub fn practice_target(&self, piece: Option<collection::StaffIndex>) -> Option<u32> {
+		self.practice_targets
+			.iter()
+			.find(|target| target.piece == piece)
+			.map(|target| target.every_days)
+	}
+
+	/// Set or remove (with `None`) the practice target of the song or a piece
+	pub fn set_practice_target(
+		&mut self,
+		piece: Option<collection::StaffIndex>,
+		every_days: Option<u32>,
+	) {
+		self.practice_targets.retain(|target| target.piece != piece);
+		if let Some(every_days) = every_days {
+			self.practice_targets
+				.push(PracticeTarget { piece, every_days });
+			self.practice_targets.sort_by_key(|target| target.piece);
 		}
 	}
 
+	/// Add a note after those already on that staff
+	pub fn add_note(&mut self, staff: collection::StaffIndex, text: String) {
+		let index = self.notes.partition_point(|note| note.staff <= staff);
+		self.notes.insert(index, StaffNote { staff, text });
+	}
+
+	pub fn notes_on(&self, staff: collection::StaffIndex) -> impl Iterator<Item = &StaffNote> {
+		self.notes.iter().filter(move |note| note.staff == staff)
+	}
+
+	/// Does any note contain the (lowercase) query?
+	pub fn notes_contain(&self, query: &str) -> bool {
+		self.notes
+			.iter()
+			.any(|note| note.text.to_lowercase().contains(query))
+	}
+
+	/// All notes as a Markdown document, grouped by piece
+	pub fn notes_to_markdown(&self, song: &collection::SongMeta) -> String {
+		let mut markdown = format!("# {}\n", song.title.as_deref().unwrap_or("(no title)"));
+		let mut current_piece = None;
+		for note in self
+			.notes
+			.iter()
+			.filter(|note| *note.staff < song.staves.len())
+		{
+			let (piece_start, piece) = song.piece_starts.range(..=note.staff).next_back().unwrap();
+			if current_piece != Some(piece_start) {
+				current_piece = Some(piece_start);
+				markdown.push('\n');
+				if !piece.is_empty() {
+					markdown += &format!("## {}\n\n", piece);
+				}
+			}
+			let (page, staff) = song.page_of_piece(note.staff);
+			markdown += &format!(
+				"- Page {}, staff {}: {}\n",
+				*page + 1,
+				*staff,
+				note.text.replace('\n', " ")
+			);
+		}
+		markdown
+	}
+
+	/// Account the time some staves were on screen. It is split evenly among them, so that
+	/// the numbers are comparable no matter how many staves fit on a page.
+	pub fn record_dwell(&mut self, staves: &[collection::StaffIndex], seconds: f64) {
+		if staves.is_empty() {
+			return;
+		}
+		let share = seconds / staves.len() as f64;
+		for staff in staves {
+			if self.dwell_seconds.len() <= **staff {
+				self.dwell_seconds.resize(**staff + 1, 0.0);
+			}
+			self.dwell_seconds[**staff] += share;
+		}
+	}
+
+	/// The dwell time of each staff of the song, relative to the maximum
+	pub fn heatmap(&self, song: &collection::SongMeta) -> Vec<f64> {
+		let mut heat: Vec<f64> = (0..song.staves.len())
+			.map(|staff| self.dwell_seconds.get(staff).copied().unwrap_or(0.0))
+			.collect();
+		let max = heat.iter().copied().fold(0.0, f64::max);
+		if max > 0.0 {
+			heat.iter_mut().for_each(|heat| *heat /= max);
+		}
+		heat
+	}
+
+	/// The dwell time of each staff as CSV, with the same numbering as [`notes_to_markdown`](Self::notes_to_markdown)
+	pub fn dwell_to_csv(&self, song: &collection::SongMeta) -> String {
+		let mut csv = String::from("piece,page,staff,seconds\n");
+		for staff in (0..song.staves.len()).map(collection::StaffIndex) {
+			let piece = song.piece_starts.range(..=staff).next_back().unwrap().1;
+			let (page, page_staff) = song.page_of_piece(staff);
+			csv += &format!(
+				"\"{}\",{},{},{:.1}\n",
+				piece.replace('"', "\"\""),
+				*page + 1,
+				*page_staff,
+				self.dwell_seconds.get(*staff).copied().unwrap_or(0.0)
+			);
+		}
+		csv
+	}
+
 	pub fn on_load(&mut self) {
-		let now = SystemTime::now();
+		self.on_load_at(SystemTime::now());
+	}
 
+	pub fn on_load_at(&mut self, now: SystemTime) {
 		self.times_played += 1;
 		/* Add 5.0 to the score */
 		self.usage_score = Self::usage_score_to_timestamp(self.usage_score(&now) + 5.0, &now);
@@ -85,8 +375,7 @@ impl LibrarySong {
 
 	pub fn on_update(&mut self, add_seconds: u64) {
 		self.seconds_played += add_seconds;
-		// Don't forget to update usage_score when adding that line back in
-		//self.last_played = Some(SystemTime::now());
+		self.last_played = Some(SystemTime::now());
 	}
 
 	fn usage_score_to_timestamp(score: f64, now: &SystemTime) -> SystemTime {
@@ -99,6 +388,19 @@ impl LibrarySong {
 		}
 	}
 
+	/**
+	 * How often the song is usually played, judging from its usage score.
+	 *
+	 * When a song is played every `d` seconds, its score right after playing settles at
+	 * `S = 5 / (1 - exp(-DECAY_FACTOR * d))`. Solving for `d` gives the interval, using the
+	 * score at the last time it was played. Songs played only once have no such habit yet.
+	 */
+	pub fn usual_interval(&self) -> Option<Duration> {
+		let score = self.usage_score(&self.last_played?);
+		(score > 5.0)
+			.then(|| Duration::from_secs_f64(-f64::ln(1.0 - 5.0 / score) / Self::DECAY_FACTOR))
+	}
+
 	/*  */
 	pub fn usage_score(&self, now: &SystemTime) -> f64 {
 		let t = self
@@ -121,12 +423,24 @@ enum LibraryFile<'a> {
 	V0 {
 		songs: maybe_owned::MaybeOwned<'a, HashMap<Uuid, LibrarySong>>,
 	},
+	/// Added the setlists
+	#[serde(rename = "1")]
+	V1 {
+		songs: maybe_owned::MaybeOwned<'a, HashMap<Uuid, LibrarySong>>,
+		setlists: maybe_owned::MaybeOwned<'a, Vec<Setlist>>,
+	},
 }
 
 #[derive(Debug)]
 pub struct Library {
 	pub songs: HashMap<Uuid, collection::SongFile>,
 	pub stats: HashMap<Uuid, LibrarySong>,
+	pub setlists: Vec<Setlist>,
+	/// All practice sessions, oldest first
+	pub journal: Vec<journal::PracticeSession>,
+	/// Songs that were opened from outside the collection. They are read-only and not listed,
+	/// and their statistics are not saved.
+	pub external: HashSet<Uuid>,
 }
 
 impl Library {
@@ -135,7 +449,7 @@ impl Library {
 		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
 		let (songs, outdated_format) =
 			collection::load().context("Failed to load song collection")?;
-		let mut stats: HashMap<Uuid, LibrarySong> = catch!({
+		let (mut stats, setlists): (HashMap<Uuid, LibrarySong>, Vec<Setlist>) = catch!({
 			anyhow::Result::<_>::Ok(match xdg.find_data_file("library.json") {
 				Some(path) => {
 					let stats: LibraryFile = pipeline::pipe!(
@@ -145,20 +459,80 @@ impl Library {
 						=> serde_json::from_reader(_)?
 					);
 					match stats {
-						LibraryFile::V0 { songs } => songs.into_owned(),
+						LibraryFile::V0 { songs } => (songs.into_owned(), Vec::new()),
+						LibraryFile::V1 { songs, setlists } => {
+							(songs.into_owned(), setlists.into_owned())
+						},
 					}
 				},
-				None => HashMap::new(),
+				None => (HashMap::new(), Vec::new()),
 			})
 		})
 		.context("Failed to load statistics database")?;
+		let journal = journal::load().context("Failed to load the practice journal")?;
 		/* Create stats for all new songs */
 		for uuid in songs.keys() {
 			if !stats.contains_key(uuid) {
 				stats.insert(*uuid, LibrarySong::new(*uuid));
 			}
 		}
-		Ok((Library { songs, stats }, outdated_format))
+		Ok((
+			Library {
+				songs,
+				stats,
+				setlists,
+				journal,
+				external: HashSet::new(),
+			},
+			outdated_format,
+		))
+	}
+
+	/// Find a song by its UUID, or else by its title (ignoring case)
+	pub fn find_song(&self, query: &str) -> Option<Uuid> {
+		let query = query.trim();
+		if let Ok(uuid) = Uuid::parse_str(query) {
+			return self.songs.contains_key(&uuid).then(|| uuid);
+		}
+		let query = query.to_lowercase();
+		self.songs
+			.values()
+			.find(|song| {
+				song.title()
+					.map_or(false, |title| title.to_lowercase() == query)
+			})
+			.map(|song| *song.uuid())
+	}
+
+	/// Open a song file that may not be part of the collection, and return its UUID.
+	/// If the song is already in the collection, that one is used instead.
+	pub fn open_external(&mut self, path: &std::path::Path) -> anyhow::Result<Uuid> {
+		let song = collection::SongFile::new(path, &mut HashSet::new())
+			.with_context(|| format!("Failed to open '{}'", path.display()))?;
+		let uuid = *song.uuid();
+		if !self.songs.contains_key(&uuid) {
+			log::info!("Opening '{}' read-only", path.display());
+			self.songs.insert(uuid, song);
+			self.stats.insert(uuid, LibrarySong::new(uuid));
+			self.external.insert(uuid);
+		}
+		Ok(uuid)
+	}
+
+	pub fn is_read_only(&self, song: &Uuid) -> bool {
+		self.external.contains(song)
+	}
+
+	/// Add a session to the journal, unless the song is read-only
+	pub fn record_session(&mut self, session: &journal::PracticeSession) {
+		if self.is_read_only(&session.song) {
+			return;
+		}
+		/* Appending a line is quick, and doing it right away keeps the entries in order */
+		if let Err(err) = journal::append(session) {
+			log::error!("{:?}", err);
+		}
+		self.journal.push(session.clone());
 	}
 
 	/* Spawning a background thread is reasonably safe because our file operations are atomic.
@@ -168,7 +542,13 @@ impl Library {
 	 * TODO also this won't work on quit because who's going to wait for that thread to finish?
 	 */
 	pub fn save_in_background(&self) {
-		let stats = self.stats.clone();
+		let stats: HashMap<Uuid, LibrarySong> = self
+			.stats
+			.iter()
+			.filter(|(uuid, _)| !self.external.contains(uuid))
+			.map(|(uuid, stats)| (*uuid, stats.clone()))
+			.collect();
+		let setlists = self.setlists.clone();
 		std::thread::spawn(move || {
 			// TODO don't hardcode here
 			let xdg = xdg::BaseDirectories::with_prefix("dinoscore").unwrap();
@@ -178,8 +558,9 @@ impl Library {
 			file.write(|file| {
 				serde_json::to_writer_pretty(
 					file,
-					&LibraryFile::V0 {
+					&LibraryFile::V1 {
 						songs: stats.into(),
+						setlists: setlists.into(),
 					},
 				)
 			})
@@ -188,3 +569,112 @@ impl Library {
 		});
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_parse_scale_mode() {
+		let parse = |s: &str| s.parse::<ScaleMode>().map(|mode| format!("{:?}", mode));
+		assert_eq!(parse("fit-staves:3").unwrap(), "FitStaves(3)");
+		assert_eq!(parse("fit-columns").unwrap(), "FitPages(2)");
+		assert_eq!(parse("fixed-height:4").unwrap(), "FixedHeight(4)");
+		assert_eq!(parse("manual:1.5").unwrap(), "Zoom(1.5)");
+		assert!(parse("fit-staves:0").is_err());
+		assert!(parse("fit-staves:many").is_err());
+		assert!(parse("manual:-1").is_err());
+		assert!(parse("stretch").is_err());
+	}
+
+	#[test]
+	fn test_part_profile() {
+		/* A title staff, then two systems of three staves */
+		let song = collection::SongBuilder::pages(&[7])
+			.system(1, 3)
+			.system(4, 3)
+			.build();
+		let visible = |selection: PartSelection| {
+			PartProfile {
+				name: "Part".into(),
+				selection,
+			}
+			.visible_staves(&song)
+			.into_iter()
+			.map(|staff| *staff)
+			.collect::<Vec<_>>()
+		};
+		assert_eq!(visible(PartSelection::Position([1].into())), [0, 2, 5]);
+		assert_eq!(
+			visible(PartSelection::Position([0, 2].into())),
+			[0, 1, 3, 4, 6]
+		);
+		assert_eq!(
+			visible(PartSelection::Staves(
+				[3, 6, 9].map(collection::StaffIndex).into()
+			)),
+			[3, 6]
+		);
+
+		let parsed = PartSelection::parse(true, "2").unwrap();
+		assert_eq!(parsed, PartSelection::Position([1].into()));
+		assert_eq!(parsed.to_list(), "2");
+		let parsed = PartSelection::parse(false, " 0, 3-5,").unwrap();
+		assert_eq!(parsed.to_list(), "0, 3, 4, 5");
+		assert_eq!(
+			PartSelection::parse(false, &parsed.to_list()).unwrap(),
+			parsed
+		);
+		assert!(PartSelection::parse(true, "0").is_err());
+		assert!(PartSelection::parse(true, "").is_err());
+		assert!(PartSelection::parse(false, "5-3").is_err());
+		assert!(PartSelection::parse(false, "two").is_err());
+	}
+
+	#[test]
+	fn test_notes() {
+		/* Two pieces, the second one starting on the second page */
+		let song = collection::SongBuilder::pages(&[2, 2, 1])
+			.piece(0, "Allegro")
+			.piece(2, "Adagio")
+			.title("Sonata")
+			.build();
+		let mut stats = LibrarySong::new(song.song_uuid);
+		stats.add_note(collection::StaffIndex(4), "Watch the conductor".into());
+		stats.add_note(collection::StaffIndex(1), "Take it slower here".into());
+		stats.add_note(collection::StaffIndex(4), "Breathe".into());
+		/* Staves that don't exist (anymore) are left out */
+		stats.add_note(collection::StaffIndex(7), "Gone".into());
+
+		assert_eq!(stats.notes_on(collection::StaffIndex(4)).count(), 2);
+		assert!(stats.notes_contain("slower"));
+		assert!(!stats.notes_contain("faster"));
+		assert_eq!(
+			stats.notes_to_markdown(&song),
+			"# Sonata\n\n## Allegro\n\n- Page 1, staff 2: Take it slower here\n\n## Adagio\n\n- Page 2, staff 1: Watch the conductor\n- Page 2, staff 1: Breathe\n"
+		);
+	}
+
+	#[test]
+	fn test_dwell() {
+		let song = collection::SongBuilder::pages(&[2, 2])
+			.piece(0, "Allegro")
+			.piece(2, "Adagio \"cantabile\"")
+			.build();
+		let mut stats = LibrarySong::new(song.song_uuid);
+		/* Two staves per page, then one staff per page */
+		stats.record_dwell(
+			&[collection::StaffIndex(0), collection::StaffIndex(1)],
+			60.0,
+		);
+		stats.record_dwell(&[collection::StaffIndex(1)], 30.0);
+		stats.record_dwell(&[collection::StaffIndex(2)], 120.0);
+		stats.record_dwell(&[], 10.0);
+
+		assert_eq!(stats.heatmap(&song), [0.25, 0.5, 1.0, 0.0]);
+		assert_eq!(
+			stats.dwell_to_csv(&song),
+			"piece,page,staff,seconds\n\"Allegro\",1,1,30.0\n\"Allegro\",1,2,60.0\n\"Adagio \"\"cantabile\"\"\",1,1,120.0\n\"Adagio \"\"cantabile\"\"\",1,2,0.0\n"
+		);
+	}
+}
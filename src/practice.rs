@@ -0,0 +1,199 @@
+/*! Suggestions of what to practice next
+ *
+ * Songs and pieces with a [practice target](library::PracticeTarget) are due once that many
+ * days have passed since they were last practiced, according to the [journal]. Songs without
+ * any targets fall back to how often they are usually played (see
+ * [`LibrarySong::usual_interval`](library::LibrarySong::usual_interval)).
+ *
+ * The priority is the time since the last practice relative to that interval: at 1 a song is
+ * due, at 2 it is overdue by a whole interval. Everything takes the current time as argument,
+ * so that the ranking is deterministic.
+ */
+use super::*;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// How many items the daily list has at most
+pub const DAILY_COUNT: usize = 5;
+
+const DAY: u64 = 24 * 3600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+	pub song: Uuid,
+	/// The first staff of the piece, or `None` for the whole song
+	pub piece: Option<collection::StaffIndex>,
+	/// How often it should be practiced
+	pub interval: Duration,
+	pub last_practiced: Option<SystemTime>,
+	pub priority: f64,
+}
+
+impl Suggestion {
+	pub fn is_due(&self) -> bool {
+		self.priority >= 1.0
+	}
+}
+
+/// Rank everything that should be practiced, the most urgent first
+pub fn rank<'a>(
+	songs: impl IntoIterator<Item = &'a library::LibrarySong>,
+	sessions: &[journal::PracticeSession],
+	now: SystemTime,
+) -> Vec<Suggestion> {
+	let mut suggestions = Vec::new();
+	for stats in songs {
+		let find_last_practiced = |piece: Option<collection::StaffIndex>| -> Option<SystemTime> {
+			let last_session = sessions
+				.iter()
+				.filter(|session| session.song == stats.song)
+				.filter(|session| {
+					piece.map_or(true, |piece| {
+						session.visits.iter().any(|visit| visit.piece == piece)
+					})
+				})
+				.map(|session| session.end)
+				.max();
+			match piece {
+				Some(_) => last_session,
+				/* Songs may have been played before the journal existed */
+				None => last_session.max(stats.last_played),
+			}
+		};
+		let mut suggest = |piece: Option<collection::StaffIndex>, interval: Duration| {
+			let last_practiced = find_last_practiced(piece);
+			let priority = match last_practiced {
+				Some(last) => {
+					now.duration_since(last).unwrap_or_default().as_secs_f64()
+						/ interval.as_secs_f64()
+				},
+				/* Due right away */
+				None => 1.0,
+			};
+			suggestions.push(Suggestion {
+				song: stats.song,
+				piece,
+				interval,
+				last_practiced,
+				priority,
+			});
+		};
+
+		if stats.practice_targets.is_empty() {
+			if let Some(interval) = stats.usual_interval() {
+				suggest(None, interval);
+			}
+		} else {
+			for target in &stats.practice_targets {
+				suggest(
+					target.piece,
+					Duration::from_secs(target.every_days.max(1) as u64 * DAY),
+				);
+			}
+		}
+	}
+
+	suggestions.sort_by(|a, b| {
+		b.priority
+			.total_cmp(&a.priority)
+			.then_with(|| (a.song, a.piece).cmp(&(b.song, b.piece)))
+	});
+	suggestions
+}
+
+/// What to practice today: the most urgent items that are due
+pub fn daily_list(ranking: &[Suggestion]) -> impl Iterator<Item = &Suggestion> {
+	ranking
+		.iter()
+		.take_while(|suggestion| suggestion.is_due())
+		.take(DAILY_COUNT)
+}
+
+/// The highest priority of each song, for sorting the library
+pub fn song_priorities(ranking: &[Suggestion]) -> HashMap<Uuid, f64> {
+	let mut priorities = HashMap::new();
+	/* The ranking is sorted, so the first one of each song is the highest */
+	for suggestion in ranking {
+		priorities
+			.entry(suggestion.song)
+			.or_insert(suggestion.priority);
+	}
+	priorities
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn days(days: u64) -> Duration {
+		Duration::from_secs(days * DAY)
+	}
+
+	#[test]
+	fn test_rank() {
+		let start = SystemTime::UNIX_EPOCH + days(19000);
+		let now = start + days(7 * 25 + 14);
+		let [a, b, c, d, e] = [0, 1, 2, 3, 4].map(|_| Uuid::new_v4());
+
+		/* Every two days, last practiced three days ago */
+		let mut song_a = library::LibrarySong::new_at(a, start);
+		song_a.set_practice_target(None, Some(2));
+		/* The second piece weekly, last practiced eight days ago. Only the first piece was practiced since */
+		let mut song_b = library::LibrarySong::new_at(b, start);
+		song_b.set_practice_target(Some(collection::StaffIndex(4)), Some(7));
+		/* No target, but played weekly until two weeks ago */
+		let mut song_c = library::LibrarySong::new_at(c, start);
+		for week in 0..26 {
+			song_c.on_load_at(start + days(7 * week));
+		}
+		/* Neither played nor any target */
+		let song_d = library::LibrarySong::new_at(d, start);
+		/* Never practiced */
+		let mut song_e = library::LibrarySong::new_at(e, start);
+		song_e.set_practice_target(None, Some(30));
+
+		let sessions = [
+			journal::test_session(a, now - days(3), &[(0, 3600)]),
+			journal::test_session(b, now - days(8), &[(0, 1800), (4, 1800)]),
+			journal::test_session(b, now - days(1), &[(0, 3600)]),
+		];
+		let songs = [&song_a, &song_b, &song_c, &song_d, &song_e];
+		let ranking = rank(songs, &sessions, now);
+
+		let usual = song_c.usual_interval().unwrap().as_secs_f64() / DAY as f64;
+		assert!((usual - 7.0).abs() < 0.2, "{}", usual);
+		assert_eq!(
+			ranking
+				.iter()
+				.map(|suggestion| (suggestion.song, suggestion.piece))
+				.collect::<Vec<_>>(),
+			[
+				(c, None),
+				(a, None),
+				(b, Some(collection::StaffIndex(4))),
+				(e, None)
+			]
+		);
+		assert!((ranking[0].priority - 2.0).abs() < 0.05);
+		assert_eq!(ranking[1].priority, 1.5);
+		assert_eq!(ranking[2].priority, 8.0 / 7.0);
+		assert_eq!(ranking[3].priority, 1.0);
+		assert_eq!(daily_list(&ranking).count(), 4);
+		/* Deterministic */
+		assert_eq!(rank(songs, &sessions, now), ranking);
+
+		/* Two days earlier, neither a nor b were due yet */
+		let earlier = rank(songs, &sessions, now - days(2));
+		assert_eq!(
+			daily_list(&earlier)
+				.map(|suggestion| suggestion.song)
+				.collect::<Vec<_>>(),
+			[c, e]
+		);
+
+		let priorities = song_priorities(&ranking);
+		assert_eq!(priorities.len(), 4);
+		assert_eq!(priorities[&a], 1.5);
+		assert!(!priorities.contains_key(&d));
+	}
+}
@@ -1,6 +1,7 @@
 use super::*;
 use gtk::{cairo, gdk, gdk_pixbuf, gio, glib, prelude::*};
 use itertools::Itertools;
+use std::ops::RangeInclusive;
 use typed_index_collections::TiVec;
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -67,14 +68,20 @@ fn online_inference(image: &image::GrayImage) -> anyhow::Result<Vec<AbsoluteStaf
 	// )
 }
 
+/**
+ * Clean up the staves found by the inference, and propose which of them form a system.
+ *
+ * The systems are returned as ranges of indices into the staves, only for those that
+ * have more than one staff.
+ */
 #[cfg(feature = "editor")]
 fn post_process(
 	mut raw_staves: Vec<AbsoluteStaff>,
 	image: &image::GrayImage,
 	page: collection::PageIndex,
-) -> Vec<collection::Staff> {
+) -> (Vec<collection::Staff>, Vec<RangeInclusive<usize>>) {
 	if raw_staves.len() == 0 {
-		return vec![];
+		return (vec![], vec![]);
 	}
 
 	use image::GenericImageView;
@@ -85,7 +92,7 @@ fn post_process(
 	/* Debugging: return unprocessed staves */
 	if false {
 		// println!("{}", serde_json::to_string_pretty(&
-		return raw_staves
+		let staves = raw_staves
 			.iter()
 			.map(|staff| collection::Staff {
 				page,
@@ -100,6 +107,7 @@ fn post_process(
 			})
 			.collect::<Vec<_>>();
 		// ).unwrap());
+		return (staves, vec![]);
 	}
 
 	/* Sanitize input; clamp to image size */
@@ -175,7 +183,7 @@ fn post_process(
 		});
 
 	/* Double all staves in height (expand 50% up and down). This is important because it
-	 * has an impact on overlap clipping later on. We need to do it before merging though,
+	 * has an impact on overlap clipping later on. We need to do it before grouping though,
 	 * to be invariant across multi-staff systems.
 	 */
 	for staff in &mut raw_staves {
@@ -184,12 +192,12 @@ fn post_process(
 		staff.bottom = (staff.bottom + height).min(image_height - 1);
 	}
 
-	/* Merge staves from the same system */
+	/* Find staves from the same system. For each staff, this tells if it belongs to the previous one */
+	let mut joined = vec![false; raw_staves.len()];
 	{
-		let to_merge = (0..raw_staves.len())
+		let to_join = (0..raw_staves.len())
 			.collect::<Vec<_>>()
 			.windows(2)
-			.rev()
 			.map(|idx| (idx[0], idx[1]))
 			.filter(|(staff_a, staff_b)| {
 				let staff_a = &raw_staves[*staff_a];
@@ -237,13 +245,8 @@ fn post_process(
 			})
 			.collect::<Vec<_>>();
 
-		for (staff_a, staff_b) in to_merge {
-			let staff_b = raw_staves.remove(staff_b);
-			let staff_a = &mut raw_staves[staff_a];
-			staff_a.left = staff_a.left.min(staff_b.left);
-			staff_a.top = staff_a.top.min(staff_b.top);
-			staff_a.right = staff_a.right.max(staff_b.right);
-			staff_a.bottom = staff_a.bottom.max(staff_b.bottom);
+		for (_staff_a, staff_b) in to_join {
+			joined[staff_b] = true;
 		}
 	}
 
@@ -386,7 +389,7 @@ fn post_process(
 		}
 	}
 
-	/* Convert back to relative positions. Filter for too small artefacts */
+	/* Convert back to relative positions */
 	let staves = raw_staves
 		.iter()
 		.map(|staff| collection::Staff {
@@ -400,20 +403,49 @@ fn post_process(
 				staff.bottom as f64 / image_width as f64,
 			),
 		})
-		/* At least 20% width */
-		.filter(|staff| staff.width() >= 0.2)
-		/* At least 1% height */
-		.filter(|staff| staff.height() >= 0.01)
 		.collect::<Vec<_>>();
+	/* Filter for too small artefacts: at least 20% width and 1% height */
+	let kept = staves
+		.iter()
+		.map(|staff| staff.width() >= 0.2 && staff.height() >= 0.01)
+		.collect::<Vec<_>>();
+	let systems = group_systems(joined.into_iter().zip(kept.iter().copied()));
+	let staves = staves
+		.into_iter()
+		.zip(kept)
+		.filter(|(_staff, kept)| *kept)
+		.map(|(staff, _kept)| staff)
+		.collect();
+
+	(staves, systems)
+}
 
-	staves
+/// Group the joined staves into ranges, given for each staff whether it belongs to the previous
+/// one and whether it is kept. The ranges count only the kept staves. A staff whose predecessor
+/// is not kept starts a new system, so that it doesn't get joined with an unrelated one.
+fn group_systems(staves: impl IntoIterator<Item = (bool, bool)>) -> Vec<RangeInclusive<usize>> {
+	let mut systems: Vec<RangeInclusive<usize>> = Vec::new();
+	let mut index = 0;
+	let mut previous_kept = false;
+	for (joined, kept) in staves {
+		if kept {
+			match systems.last_mut() {
+				Some(system) if joined && previous_kept => *system = *system.start()..=index,
+				_ => systems.push(index..=index),
+			}
+			index += 1;
+		}
+		previous_kept = kept;
+	}
+	systems.retain(|system| system.end() > system.start());
+	systems
 }
 
 #[cfg(feature = "editor")]
 pub fn recognize_staves(
 	image: &gdk_pixbuf::Pixbuf,
 	page: collection::PageIndex,
-) -> Vec<collection::Staff> {
+) -> (Vec<collection::Staff>, Vec<RangeInclusive<usize>>) {
 	let png = image.save_to_bufferv("png", &[]).unwrap();
 	let image: image::GrayImage = image::load_from_memory(&png).unwrap().into_luma8();
 
@@ -489,22 +521,50 @@ mod test {
 					=> image::load_from_memory(&_).unwrap().into_luma8()
 				);
 
-				let processed = post_process(raw_staves.clone(), &image, index);
+				let (processed, systems) = post_process(raw_staves.clone(), &image, index);
+				/* The reference counts systems as one staff */
+				let n_systems = processed.len()
+					- systems
+						.iter()
+						.map(|system| system.end() - system.start())
+						.sum::<usize>();
 				println!(
 					"{name} {index} {} {} {}",
 					raw_staves.len(),
-					processed.len(),
+					n_systems,
 					reference_data.staves_per_page[index]
 				);
 				assert_eq!(
-					processed.len(),
-					reference_data.staves_per_page[index],
+					n_systems, reference_data.staves_per_page[index],
 					"Invalid number of staves found! File {name}, page {index}"
 				);
 			}
 		}
 	}
 
+	#[test]
+	fn test_group_systems() {
+		assert_eq!(
+			group_systems([(false, true), (true, true), (false, true), (true, true)]),
+			[0..=1, 2..=3]
+		);
+		/* The first staff of the second system got filtered out */
+		assert_eq!(
+			group_systems([
+				(false, true),
+				(true, true),
+				(false, false),
+				(true, true),
+				(true, true)
+			]),
+			[0..=1, 2..=3]
+		);
+		/* A single remaining staff is no system */
+		assert!(
+			group_systems([(false, true), (false, false), (true, true), (false, true)]).is_empty()
+		);
+	}
+
 	/// Post-processing tends to panic on bounds checks (:
 	#[test]
 	fn test_edges() {
@@ -0,0 +1,461 @@
+//! External programs for annotating the score
+//!
+//! Instead of our own ink, the score can be annotated in a full-fledged editor. The editor
+//! works on a document of its own, which we create from the pages of the song. Each time it
+//! saves, the annotations are exported as an overlay PDF (one page per page of the song), which
+//! we draw on top of the score.
+//!
+//! Next to the overlay we keep the [`PageIdentity`](annotations::PageIdentity) it was made for.
+//! When the pages of the song change, the pages of the overlay and of the documents move along
+//! with them. Those of pages that don't exist anymore are put into quarantine.
+
+use super::*;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+/// How often to look whether the editor saved or exited
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A program that can annotate the score
+pub trait AnnotationEditor: Send {
+	/// Shown to the user
+	fn name(&self) -> &str;
+
+	/// Fail if the program is not installed or otherwise unusable
+	fn check_available(&self) -> anyhow::Result<()>;
+
+	/// Where the document of a song is kept
+	fn document(&self, song: &uuid::Uuid) -> anyhow::Result<PathBuf>;
+
+	/// Create the document, unless it exists already. `background_pdf` contains all pages of
+	/// the song (see [`image_util::concat_files`]).
+	fn prepare(&self, document: &Path, background_pdf: Vec<u8>) -> anyhow::Result<()>;
+
+	/// Open the document at a page (starting at 1), without waiting for the program to exit
+	fn launch(&self, document: &Path, page: usize) -> anyhow::Result<std::process::Child>;
+
+	/// Write the annotations to `overlay`, without the score itself
+	fn export(&self, document: &Path, overlay: &Path) -> anyhow::Result<()>;
+
+	/// The content of `document` with its pages rearranged: each page is the one of `document`
+	/// with that index, or a new one for `None`. `background_pdf` contains all pages of the song
+	/// as they are now.
+	fn remap(
+		&self,
+		document: &Path,
+		pages: &[Option<usize>],
+		background_pdf: &[u8],
+	) -> anyhow::Result<Vec<u8>>;
+
+	/// Clean up after the program exited
+	fn finish(&self, _document: &Path) -> anyhow::Result<()> {
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EditorKind {
+	Xournal,
+	/// Any program that saves its annotations into the PDF
+	PdfAnnotator,
+}
+
+impl EditorKind {
+	pub const ALL: [EditorKind; 2] = [EditorKind::Xournal, EditorKind::PdfAnnotator];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditorConfig {
+	pub kind: EditorKind,
+	/// The command line of the [PDF annotator](EditorKind::PdfAnnotator). The file name is appended
+	pub pdf_annotator: String,
+}
+
+impl Default for EditorConfig {
+	fn default() -> Self {
+		EditorConfig {
+			kind: EditorKind::Xournal,
+			pdf_annotator: "okular".into(),
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum EditorConfigFile {
+	#[serde(rename = "0")]
+	V0(EditorConfig),
+}
+
+impl EditorConfig {
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("annotation-editor.json") {
+			Some(path) => {
+				let file: EditorConfigFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					EditorConfigFile::V0(config) => config,
+				}
+			},
+			None => EditorConfig::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("annotation-editor.json")?;
+		log::info!(
+			"Saving annotation editor configuration ({})",
+			path.display()
+		);
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| serde_json::to_writer_pretty(file, &EditorConfigFile::V0(self.clone())))
+			.context("Could not save annotation editor configuration (annotation-editor.json)")?;
+		Ok(())
+	}
+
+	pub fn editor(&self) -> Box<dyn AnnotationEditor> {
+		self.editor_of_kind(self.kind)
+	}
+
+	fn editor_of_kind(&self, kind: EditorKind) -> Box<dyn AnnotationEditor> {
+		match kind {
+			EditorKind::Xournal => Box::new(crate::xournal::Xournal),
+			EditorKind::PdfAnnotator => {
+				Box::new(crate::pdf_annotator::PdfAnnotator::new(&self.pdf_annotator))
+			},
+		}
+	}
+}
+
+fn overlay_file(song: &uuid::Uuid) -> anyhow::Result<PathBuf> {
+	annotations::external_file(song, "pdf")
+}
+
+fn pages_file(song: &uuid::Uuid) -> anyhow::Result<PathBuf> {
+	annotations::external_file(song, "pages.json")
+}
+
+/// Replace the file atomically, so that no annotations get lost if something goes wrong halfway
+fn write_file(
+	path: &Path,
+	data: &[u8],
+	overwrite: atomicwrites::OverwriteBehavior,
+) -> anyhow::Result<()> {
+	use std::io::Write;
+	atomicwrites::AtomicFile::new(path, overwrite)
+		.write(|file| file.write_all(data))
+		.with_context(|| format!("Failed to write '{}'", path.display()))
+}
+
+fn save_pages(song: &uuid::Uuid, pages: &annotations::PageIdentity) -> anyhow::Result<()> {
+	write_file(
+		&pages_file(song)?,
+		&serde_json::to_vec(pages)?,
+		atomicwrites::AllowOverwrite,
+	)
+}
+
+/// The exported annotations of a song, if there are any
+pub fn load_overlay(song: &uuid::Uuid) -> anyhow::Result<Option<poppler::Document>> {
+	let overlay = overlay_file(song)?;
+	if !overlay.exists() {
+		return Ok(None);
+	}
+	catch!({
+		let document = poppler::Document::from_bytes(
+			&glib::Bytes::from_owned(std::fs::read(&overlay)?),
+			None,
+		)?;
+		anyhow::Result::<_>::Ok(Some(document))
+	})
+	.with_context(|| format!("Failed to load '{}'", overlay.display()))
+}
+
+/// Make sure the external annotations of a song were made for its current pages. If not, they
+/// are moved to where their pages are now. Those on pages that don't exist anymore are moved
+/// into quarantine, and the location of that is returned.
+pub fn check_pages(
+	config: &EditorConfig,
+	song: &collection::SongFile,
+	current: &annotations::PageIdentity,
+) -> anyhow::Result<Option<PathBuf>> {
+	let uuid = song.uuid();
+	let overlay = overlay_file(uuid)?;
+	let pages_file = pages_file(uuid)?;
+	let documents = EditorKind::ALL
+		.iter()
+		.map(|kind| {
+			let editor = config.editor_of_kind(*kind);
+			let document = editor.document(uuid)?;
+			anyhow::Result::<_>::Ok((editor, document))
+		})
+		.filter(|result| {
+			result
+				.as_ref()
+				.map_or(true, |(_editor, document)| document.exists())
+		})
+		.collect::<anyhow::Result<Vec<_>>>()?;
+	if !overlay.exists() && documents.is_empty() {
+		return Ok(None);
+	}
+
+	let made_for: Option<annotations::PageIdentity> = if pages_file.exists() {
+		let made_for = catch!({
+			anyhow::Result::<_>::Ok(serde_json::from_slice(&std::fs::read(&pages_file)?)?)
+		})
+		.with_context(|| format!("Failed to read '{}'", pages_file.display()))?;
+		Some(made_for)
+	} else {
+		None
+	};
+	let old_overlay = load_overlay(uuid)?;
+	let mapping = match &made_for {
+		Some(made_for) if made_for.matches(current) => None,
+		Some(made_for) => Some((made_for.pages.len(), made_for.find_pages_in(current))),
+		/* From before we tracked the pages. Assume it fits if at least the number of pages does */
+		None => match &old_overlay {
+			Some(old_overlay) if old_overlay.n_pages() as usize != current.pages.len() => {
+				/* Otherwise, assume they were made for these pages, as far as they exist */
+				let n_pages = old_overlay.n_pages() as usize;
+				let mapping = (0..n_pages.min(current.pages.len()))
+					.map(|page| (collection::PageIndex(page), collection::PageIndex(page)))
+					.collect();
+				Some((n_pages, mapping))
+			},
+			_ => None,
+		},
+	};
+	let (n_pages, mapping) = match mapping {
+		Some(mapping) => mapping,
+		None => {
+			if !pages_file.exists() {
+				save_pages(uuid, current)?;
+			}
+			return Ok(None);
+		},
+	};
+
+	/* For each current page, where it was before */
+	let mut pages = vec![None; current.pages.len()];
+	for (old, new) in &mapping {
+		pages[new.0] = Some(old.0);
+	}
+	let gone = (0..n_pages)
+		.filter(|page| !mapping.contains_key(&collection::PageIndex(*page)))
+		.map(Some)
+		.collect::<Vec<_>>();
+	log::info!(
+		"Moving external annotations to their new pages ({} pages are gone)",
+		gone.len()
+	);
+	let background_pdf = pipeline::pipe!(
+		song.load_pages(|_index, file, data| Ok((data, file.ends_with(".pdf"))))()
+			.context("Failed to load pages")?
+		=> Into::into
+		=> image_util::concat_files
+	)
+	.context("Failed to create the background PDF")?;
+
+	/* Prepare everything first, so that nothing is touched if any of it fails */
+	let mut quarantined = Vec::new();
+	let mut remapped = Vec::new();
+	if let Some(old_overlay) = &old_overlay {
+		let old_pages = old_overlay.n_pages() as usize;
+		let background = poppler::Document::from_bytes(&glib::Bytes::from(&background_pdf), None)?;
+		let overlay_pages = pages
+			.iter()
+			.enumerate()
+			.map(|(index, page)| match page {
+				Some(page) if *page < old_pages => image_util::PdfPage::Of(0, *page),
+				_ => {
+					let (width, height) = background.page(index as i32).unwrap().size();
+					image_util::PdfPage::Blank(width, height)
+				},
+			})
+			.collect::<Vec<_>>();
+		let gone_pages = gone
+			.iter()
+			.flatten()
+			.filter(|page| **page < old_pages)
+			.map(|page| image_util::PdfPage::Of(0, *page))
+			.collect::<Vec<_>>();
+		let old_overlay = std::fs::read(&overlay)
+			.with_context(|| format!("Failed to read '{}'", overlay.display()))?;
+		if !gone_pages.is_empty() {
+			quarantined.push((
+				"pdf",
+				image_util::assemble_pdf(vec![old_overlay.clone()], &gone_pages)?,
+			));
+		}
+		remapped.push((
+			overlay.clone(),
+			image_util::assemble_pdf(vec![old_overlay], &overlay_pages)?,
+		));
+	}
+	for (editor, document) in &documents {
+		if !gone.is_empty() {
+			let extension = document
+				.file_name()
+				.and_then(|name| name.to_str())
+				.and_then(|name| name.split_once('.'))
+				.map(|(_uuid, extension)| extension)
+				.unwrap_or("document");
+			quarantined.push((extension, editor.remap(document, &gone, &background_pdf)?));
+		}
+		remapped.push((
+			document.clone(),
+			editor.remap(document, &pages, &background_pdf)?,
+		));
+	}
+
+	/* Only replace the annotations once those of the missing pages are safe */
+	let mut quarantine = None;
+	for (extension, data) in quarantined {
+		let path = annotations::quarantine_path(uuid, extension)?;
+		log::warn!(
+			"Moving external annotations into quarantine ({})",
+			path.display()
+		);
+		write_file(&path, &data, atomicwrites::DisallowOverwrite)?;
+		quarantine.get_or_insert(path);
+	}
+	for (path, data) in remapped {
+		write_file(&path, &data, atomicwrites::AllowOverwrite)?;
+	}
+	save_pages(uuid, current)?;
+	Ok(quarantine)
+}
+
+/// What happens while the editor is open
+pub enum SessionEvent {
+	/// The user saved, and the annotations were exported anew
+	Saved,
+	/// Something went wrong. The session continues unless the editor is closed
+	Failed(anyhow::Error),
+	/// The editor was closed
+	Closed,
+}
+
+/// Launch the editor on a background thread. Whenever the user saves, the annotations get
+/// exported. The returned channel reports what happens; it closes when the session is over.
+pub fn spawn_editor(
+	editor: Box<dyn AnnotationEditor>,
+	song: &collection::SongFile,
+	page: usize,
+	pages: annotations::PageIdentity,
+) -> glib::Receiver<SessionEvent> {
+	let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+	let uuid = *song.uuid();
+	let load_pages = song.load_pages(|_index, file, data| Ok((data, file.ends_with(".pdf"))));
+
+	std::thread::spawn(move || {
+		let result = catch!({
+			editor.check_available()?;
+			let background_pdf = catch!({
+				log::debug!("Creating the PDF background for the file");
+				pipeline::pipe!(
+					load_pages().context("Failed to load pages")?
+					=> Into::into
+					=> image_util::concat_files
+				)
+				.context("Internal error")
+			})
+			.with_context(|| {
+				format!(
+					"Failed to create the background PDF for the {} document",
+					editor.name()
+				)
+			})?;
+			run_editor(&*editor, &uuid, page, &pages, background_pdf, |event| {
+				tx.send(event).ok();
+			})
+		});
+		if let Err(err) = result {
+			tx.send(SessionEvent::Failed(err)).ok();
+		}
+		tx.send(SessionEvent::Closed).ok();
+	});
+	rx
+}
+
+/// Blocks until the editor is closed
+fn run_editor(
+	editor: &dyn AnnotationEditor,
+	song: &uuid::Uuid,
+	page: usize,
+	pages: &annotations::PageIdentity,
+	background_pdf: Vec<u8>,
+	events: impl Fn(SessionEvent),
+) -> anyhow::Result<()> {
+	let document = editor.document(song)?;
+	let overlay = overlay_file(song)?;
+
+	editor.prepare(&document, background_pdf)?;
+	anyhow::ensure!(
+		document.is_file(),
+		"'{}' must be a regular file. Please delete whatever is there",
+		document.display()
+	);
+
+	log::debug!("Launching {} (page {})", editor.name(), page);
+	let mut process = editor
+		.launch(&document, page)
+		.with_context(|| format!("Failed to launch {}", editor.name()))?;
+
+	/* Watch the file for saves. Only export once the modification time settled,
+	 * so that we don't read a file that is still being written
+	 */
+	let modified = || {
+		std::fs::metadata(&document)
+			.and_then(|metadata| metadata.modified())
+			.ok()
+	};
+	let export = || {
+		log::debug!("Integrating back the annotations into DiNoScore");
+		editor.export(&document, &overlay)?;
+		save_pages(song, pages)
+	};
+	let mut exported: Option<SystemTime> = modified();
+	let mut last_seen = exported;
+	let status = loop {
+		std::thread::sleep(POLL_INTERVAL);
+		let exited = process.try_wait()?;
+		let now = modified();
+		if now != exported && (now == last_seen || exited.is_some()) {
+			exported = now;
+			events(match export() {
+				Ok(()) => SessionEvent::Saved,
+				Err(err) => SessionEvent::Failed(err),
+			});
+		}
+		last_seen = now;
+		if let Some(status) = exited {
+			break status;
+		}
+	};
+	log::debug!("{} exited ({})", editor.name(), status);
+
+	editor
+		.finish(&document)
+		.context("Post-editor cleanup failed")?;
+	anyhow::ensure!(
+		status.success(),
+		"{} exited with an error ({})",
+		editor.name(),
+		status
+	);
+
+	Ok(())
+}
@@ -0,0 +1,153 @@
+//! Command line arguments
+//!
+//! `viewer --song <uuid|path|title> [--piece <name>|--staff N] [--fullscreen] [--scale fit-staves:3]`
+//! launches straight into a score. Song files may also be passed as plain arguments, which
+//! is how file managers open them. Files that are not in the collection are opened read-only.
+
+use anyhow::Context;
+use dinoscore::{library::ScaleMode, prelude::*};
+
+/// Where to start in the song
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StartAt {
+	#[default]
+	Beginning,
+	/// The first piece with that name (ignoring case)
+	Piece(String),
+	Staff(usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LaunchOptions {
+	/// UUID, title or path of the song to open
+	pub song: Option<String>,
+	pub start: StartAt,
+	pub fullscreen: bool,
+	pub scale: Option<ScaleMode>,
+}
+
+/// Tell GLib which options we understand, for parsing and for `--help`
+pub fn register(application: &gtk::Application) {
+	application.add_main_option(
+		"song",
+		glib::Char::from(b's'),
+		glib::OptionFlags::NONE,
+		glib::OptionArg::String,
+		"Open a song, given by its UUID, its title or the path to its file",
+		Some("SONG"),
+	);
+	application.add_main_option(
+		"piece",
+		glib::Char::from(b'p'),
+		glib::OptionFlags::NONE,
+		glib::OptionArg::String,
+		"Start at the piece with that name",
+		Some("NAME"),
+	);
+	application.add_main_option(
+		"staff",
+		glib::Char::from(b'\0'),
+		glib::OptionFlags::NONE,
+		glib::OptionArg::Int,
+		"Start at that staff, counting from zero",
+		Some("N"),
+	);
+	application.add_main_option(
+		"fullscreen",
+		glib::Char::from(b'f'),
+		glib::OptionFlags::NONE,
+		glib::OptionArg::None,
+		"Start in fullscreen mode",
+		None,
+	);
+	application.add_main_option(
+		"scale",
+		glib::Char::from(b'\0'),
+		glib::OptionFlags::NONE,
+		glib::OptionArg::String,
+		"How to lay out the song: fit-staves, fit-columns, fixed-width or fixed-height with an optional count, or manual with a zoom factor",
+		Some("MODE[:N]"),
+	);
+}
+
+impl LaunchOptions {
+	/// Read the options registered in [`register`]
+	pub fn from_options(options: &glib::VariantDict) -> anyhow::Result<Self> {
+		let piece = options.lookup::<String>("piece")?;
+		let staff = options.lookup::<i32>("staff")?;
+		let start = match (piece, staff) {
+			(Some(_), Some(_)) => anyhow::bail!("Only one of --piece and --staff may be given"),
+			(Some(piece), None) => StartAt::Piece(piece),
+			(None, Some(staff)) => {
+				StartAt::Staff(usize::try_from(staff).context("The staff must not be negative")?)
+			},
+			(None, None) => StartAt::Beginning,
+		};
+		let song = options.lookup::<String>("song")?;
+		anyhow::ensure!(
+			song.is_some() || start == StartAt::Beginning,
+			"--piece and --staff need a --song"
+		);
+		let scale = options
+			.lookup::<String>("scale")?
+			.map(|scale| scale.parse::<ScaleMode>())
+			.transpose()
+			.context("Invalid --scale")?;
+		Ok(LaunchOptions {
+			song,
+			start,
+			fullscreen: options.lookup::<bool>("fullscreen")?.unwrap_or(false),
+			scale,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_options() {
+		let parse = |options: &[(&str, glib::Variant)]| {
+			let dict = glib::VariantDict::new(None);
+			for (key, value) in options {
+				dict.insert_value(key, value);
+			}
+			LaunchOptions::from_options(&dict)
+		};
+
+		let options = parse(&[]).unwrap();
+		assert_eq!(options.song, None);
+		assert_eq!(options.start, StartAt::Beginning);
+		assert!(!options.fullscreen);
+
+		let options = parse(&[
+			("song", "Bolero".to_variant()),
+			("piece", "Finale".to_variant()),
+			("fullscreen", true.to_variant()),
+			("scale", "fit-staves:3".to_variant()),
+		])
+		.unwrap();
+		assert_eq!(options.song.as_deref(), Some("Bolero"));
+		assert_eq!(options.start, StartAt::Piece("Finale".into()));
+		assert!(options.fullscreen);
+		assert!(matches!(options.scale, Some(ScaleMode::FitStaves(3))));
+
+		let options = parse(&[("song", "Bolero".to_variant()), ("staff", 4.to_variant())]).unwrap();
+		assert_eq!(options.start, StartAt::Staff(4));
+
+		assert!(parse(&[
+			("song", "Bolero".to_variant()),
+			("piece", "Finale".to_variant()),
+			("staff", 4.to_variant()),
+		])
+		.is_err());
+		assert!(parse(&[
+			("song", "Bolero".to_variant()),
+			("staff", (-1).to_variant())
+		])
+		.is_err());
+		assert!(parse(&[("staff", 4.to_variant())]).is_err());
+		assert!(parse(&[("scale", "stretch".to_variant())]).is_err());
+	}
+}
@@ -7,6 +7,9 @@ use std::panic::PanicInfo;
 
 type PanicHook = Box<dyn Fn(&PanicInfo<'_>) + 'static + Sync + Send>;
 
+/** After a crash, we restart ourselves with this argument and the path of the crash log */
+pub const CRASH_REPORT_ARG: &str = "--crash-report";
+
 /** Initialize log and panic handling */
 pub fn init() -> anyhow::Result<()> {
 	let mut logger = fern::Dispatch::new()
@@ -152,11 +155,15 @@ fn panic_hook(panic_info: &PanicInfo, log_panics_hook: &PanicHook) {
 		#[cfg(unix)]
 		{
 			use std::os::unix::process::CommandExt;
-			let _ = Command::new(&exe).args(&[&crash]).exec();
+			let _ = Command::new(&exe).arg(CRASH_REPORT_ARG).arg(&crash).exec();
 		}
 		#[cfg(windows)]
 		{
-			if let Ok(status) = Command::new(&exe).args(&[&crash]).status() {
+			if let Ok(status) = Command::new(&exe)
+				.arg(CRASH_REPORT_ARG)
+				.arg(&crash)
+				.status()
+			{
 				std::process::exit(status.code().unwrap_or_default());
 			}
 		}
@@ -230,8 +237,7 @@ fn write_crash_message(info: &std::panic::PanicInfo) -> anyhow::Result<std::path
 /**
  * Show a crash dialog and exit
  *
- * The zeroth argument will be ignored, the first one will be displayed
- * as the path of the crash log.
+ * The arguments are expected to be `<arg0> --crash-report <path of the crash log>`.
  *
  * The application will exit with code 110 (Rust default for "panicked"),
  * but there is also the option for the user to directly re-start DiNoScore.
@@ -239,7 +245,7 @@ fn write_crash_message(info: &std::panic::PanicInfo) -> anyhow::Result<std::path
 pub fn show_crash_dialog(args: Vec<std::ffi::OsString>) -> ! {
 	use gtk::prelude::*;
 
-	let crash_file = &args[1];
+	let crash_file = &args[2];
 
 	gtk::init().expect("Failed to initialize GTK");
 	let dialog = gtk::MessageDialog::new(
@@ -0,0 +1,399 @@
+//! Scripting the viewer over D-Bus
+//!
+//! Exports the `de.piegames.dinoscore.Viewer` interface at `/de/piegames/dinoscore/viewer`
+//! on the session bus, under the name `de.piegames.dinoscore.viewer`. It has methods for
+//! the navigation and zoom actions of the song view and for opening a song, properties for
+//! what is currently shown and a `PageChanged` signal. For example:
+//!
+//! ```sh
+//! gdbus call --session --dest de.piegames.dinoscore.viewer \
+//! 	--object-path /de/piegames/dinoscore/viewer --method de.piegames.dinoscore.Viewer.NextPage
+//! ```
+//!
+//! The actual viewer is hidden behind the [`Viewer`] trait.
+
+use anyhow::Context;
+use dinoscore::{catch, collection::StaffIndex, prelude::*};
+use std::collections::HashMap;
+
+pub const BUS_NAME: &str = "de.piegames.dinoscore.viewer";
+pub const OBJECT_PATH: &str = "/de/piegames/dinoscore/viewer";
+pub const INTERFACE_NAME: &str = "de.piegames.dinoscore.Viewer";
+
+const INTERFACE: &str = r#"
+<node>
+	<interface name="de.piegames.dinoscore.Viewer">
+		<method name="NextPage"/>
+		<method name="PreviousPage"/>
+		<method name="NextPiece"/>
+		<method name="PreviousPiece"/>
+		<method name="ZoomIn"/>
+		<method name="ZoomOut"/>
+		<method name="ZoomReset"/>
+		<method name="ToggleAutoScroll"/>
+		<method name="OpenSong">
+			<arg name="song" type="s" direction="in"/>
+			<arg name="staff" type="u" direction="in"/>
+		</method>
+		<!-- Empty when no song is loaded -->
+		<property name="Song" type="s" access="read"/>
+		<property name="Title" type="s" access="read"/>
+		<property name="Piece" type="s" access="read"/>
+		<!-- Counting from zero, -1 when no song is loaded -->
+		<property name="Page" type="i" access="read"/>
+		<signal name="PageChanged">
+			<arg name="page" type="i"/>
+		</signal>
+	</interface>
+</node>
+"#;
+
+/// Which method calls which action of the song view
+const ACTIONS: &[(&str, &str)] = &[
+	("NextPage", "song.next-page"),
+	("PreviousPage", "song.previous-page"),
+	("NextPiece", "song.next-piece"),
+	("PreviousPiece", "song.previous-piece"),
+	("ZoomIn", "song.zoom-in"),
+	("ZoomOut", "song.zoom-out"),
+	("ZoomReset", "song.zoom-original"),
+	("ToggleAutoScroll", "song.auto-scroll"),
+];
+
+/// What is currently shown. Empty strings and -1 mean that no song is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewerState {
+	pub song: String,
+	pub title: String,
+	pub piece: String,
+	pub page: i32,
+}
+
+impl Default for ViewerState {
+	fn default() -> Self {
+		ViewerState {
+			song: String::new(),
+			title: String::new(),
+			piece: String::new(),
+			page: -1,
+		}
+	}
+}
+
+impl ViewerState {
+	fn property(&self, name: &str) -> Option<glib::Variant> {
+		Some(match name {
+			"Song" => self.song.to_variant(),
+			"Title" => self.title.to_variant(),
+			"Piece" => self.piece.to_variant(),
+			"Page" => self.page.to_variant(),
+			_ => return None,
+		})
+	}
+}
+
+/// What the D-Bus interface can see of and do with the viewer
+pub trait Viewer {
+	fn state(&self) -> ViewerState;
+	fn activate_action(&self, action: &str);
+	fn open_song(&self, song: uuid::Uuid, staff: StaffIndex) -> anyhow::Result<()>;
+}
+
+/// The exported object. Unexports itself when dropped.
+pub struct Exported {
+	connection: gio::DBusConnection,
+	registration: Option<gio::RegistrationId>,
+	owner: Option<gio::OwnerId>,
+	viewer: Rc<dyn Viewer>,
+	/// The state we last told the bus about
+	state: RefCell<ViewerState>,
+}
+
+impl Exported {
+	/// Export the viewer on the connection and request our bus name on it
+	pub fn export(
+		connection: &gio::DBusConnection,
+		viewer: Rc<dyn Viewer>,
+	) -> anyhow::Result<Self> {
+		let interface = gio::DBusNodeInfo::for_xml(INTERFACE)?
+			.lookup_interface(INTERFACE_NAME)
+			.context("Interface missing")?;
+
+		let method_viewer = Rc::downgrade(&viewer);
+		let property_viewer = Rc::downgrade(&viewer);
+		let registration = connection
+			.register_object(
+				OBJECT_PATH,
+				&interface,
+				move |_connection, _sender, _path, _interface, method, parameters, invocation| {
+					let viewer = match method_viewer.upgrade() {
+						Some(viewer) => viewer,
+						None => return,
+					};
+					call_method(&*viewer, method, parameters, invocation);
+				},
+				move |_connection, _sender, _path, _interface, property| {
+					property_viewer
+						.upgrade()
+						.map(|viewer| viewer.state())
+						.unwrap_or_default()
+						.property(property)
+						.unwrap()
+				},
+				|_connection, _sender, _path, _interface, _property, _value| false,
+			)
+			.context("Failed to register the D-Bus object")?;
+
+		/* Other instances may already have the name. That's fine, they can still be reached
+		 * through their unique name */
+		let owner = gio::bus_own_name_on_connection(
+			connection,
+			BUS_NAME,
+			gio::BusNameOwnerFlags::NONE,
+			|_connection, name| log::debug!("Acquired D-Bus name {}", name),
+			|_connection, name| log::info!("Could not acquire D-Bus name {}", name),
+		);
+
+		Ok(Exported {
+			connection: connection.clone(),
+			registration: Some(registration),
+			owner: Some(owner),
+			state: RefCell::new(viewer.state()),
+			viewer,
+		})
+	}
+
+	/// Look what changed since last time and emit the signals for it
+	pub fn update(&self) {
+		let state = self.viewer.state();
+		let old = self.state.replace(state.clone());
+
+		let changed = ["Song", "Title", "Piece", "Page"]
+			.into_iter()
+			.filter(|name| old.property(name) != state.property(name))
+			.map(|name| (name.to_owned(), state.property(name).unwrap()))
+			.collect::<HashMap<String, glib::Variant>>();
+		if changed.is_empty() {
+			return;
+		}
+		let result = self.connection.emit_signal(
+			None,
+			OBJECT_PATH,
+			"org.freedesktop.DBus.Properties",
+			"PropertiesChanged",
+			Some(&(INTERFACE_NAME, changed, Vec::<String>::new()).to_variant()),
+		);
+		if let Err(err) = result {
+			log::warn!("Failed to emit D-Bus signal: {}", err);
+		}
+
+		if old.page != state.page && state.page >= 0 {
+			let result = self.connection.emit_signal(
+				None,
+				OBJECT_PATH,
+				INTERFACE_NAME,
+				"PageChanged",
+				Some(&(state.page,).to_variant()),
+			);
+			if let Err(err) = result {
+				log::warn!("Failed to emit D-Bus signal: {}", err);
+			}
+		}
+	}
+}
+
+impl Drop for Exported {
+	fn drop(&mut self) {
+		if let Some(owner) = self.owner.take() {
+			gio::bus_unown_name(owner);
+		}
+		if let Some(registration) = self.registration.take() {
+			if let Err(err) = self.connection.unregister_object(registration) {
+				log::warn!("Failed to unregister the D-Bus object: {}", err);
+			}
+		}
+	}
+}
+
+fn call_method(
+	viewer: &dyn Viewer,
+	method: &str,
+	parameters: glib::Variant,
+	invocation: gio::DBusMethodInvocation,
+) {
+	if let Some((_, action)) = ACTIONS.iter().find(|(name, _)| *name == method) {
+		viewer.activate_action(action);
+		invocation.return_value(None);
+		return;
+	}
+	match method {
+		"OpenSong" => {
+			let result = catch!({
+				let (song, staff) = parameters
+					.get::<(String, u32)>()
+					.context("Invalid arguments")?;
+				let song = uuid::Uuid::parse_str(&song).context("Invalid UUID")?;
+				viewer.open_song(song, StaffIndex(staff as usize))
+			});
+			match result {
+				Ok(()) => invocation.return_value(None),
+				Err(err) => invocation.return_dbus_error(
+					"org.freedesktop.DBus.Error.InvalidArgs",
+					&format!("{:#}", err),
+				),
+			}
+		},
+		_ => invocation.return_dbus_error(
+			"org.freedesktop.DBus.Error.UnknownMethod",
+			&format!("Unknown method {}", method),
+		),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[derive(Default)]
+	struct FakeViewer {
+		state: RefCell<ViewerState>,
+		calls: RefCell<Vec<String>>,
+	}
+
+	impl Viewer for FakeViewer {
+		fn state(&self) -> ViewerState {
+			self.state.borrow().clone()
+		}
+
+		fn activate_action(&self, action: &str) {
+			self.calls.borrow_mut().push(action.to_owned());
+		}
+
+		fn open_song(&self, song: uuid::Uuid, staff: StaffIndex) -> anyhow::Result<()> {
+			anyhow::ensure!(*staff < 10, "No such staff");
+			self.calls
+				.borrow_mut()
+				.push(format!("open {} {}", song, staff));
+			Ok(())
+		}
+	}
+
+	/// Call a method and run the main loop until the reply arrives
+	fn call(
+		client: &gio::DBusConnection,
+		destination: &str,
+		interface: &str,
+		method: &str,
+		parameters: Option<&glib::Variant>,
+	) -> Result<glib::Variant, glib::Error> {
+		glib::MainContext::default().block_on(client.call_future(
+			Some(destination),
+			OBJECT_PATH,
+			interface,
+			method,
+			parameters,
+			None,
+			gio::DBusCallFlags::NONE,
+			1000,
+		))
+	}
+
+	#[test]
+	fn test_dbus_interface() {
+		/* A private session bus */
+		let bus = gio::TestDBus::new(gio::TestDBusFlags::NONE);
+		bus.up();
+		let address = bus.bus_address().unwrap();
+		let connect = || {
+			gio::DBusConnection::for_address_sync(
+				&address,
+				gio::DBusConnectionFlags::AUTHENTICATION_CLIENT
+					| gio::DBusConnectionFlags::MESSAGE_BUS_CONNECTION,
+				None,
+				gio::Cancellable::NONE,
+			)
+			.unwrap()
+		};
+		let server = connect();
+		let client = connect();
+		let server_name = server.unique_name().unwrap();
+
+		let viewer = Rc::new(FakeViewer::default());
+		let exported = Exported::export(&server, viewer.clone()).unwrap();
+		let method = |method: &str, parameters: Option<&glib::Variant>| {
+			call(&client, &server_name, INTERFACE_NAME, method, parameters)
+		};
+
+		/* Methods */
+		method("NextPage", None).unwrap();
+		method("ZoomReset", None).unwrap();
+		let song = uuid::Uuid::new_v4();
+		method("OpenSong", Some(&(song.to_string(), 3u32).to_variant())).unwrap();
+		assert!(method("OpenSong", Some(&(song.to_string(), 30u32).to_variant())).is_err());
+		assert!(method("OpenSong", Some(&("not a UUID", 0u32).to_variant())).is_err());
+		assert_eq!(
+			*viewer.calls.borrow(),
+			[
+				"song.next-page".to_owned(),
+				"song.zoom-original".to_owned(),
+				format!("open {} 3", song),
+			]
+		);
+
+		/* Signals */
+		let pages = Rc::new(RefCell::new(Vec::new()));
+		let subscription = client.signal_subscribe(
+			None,
+			Some(INTERFACE_NAME),
+			Some("PageChanged"),
+			Some(OBJECT_PATH),
+			None,
+			gio::DBusSignalFlags::NONE,
+			clone!(@strong pages => move |_connection, _sender, _path, _interface, _signal, parameters| {
+				pages.borrow_mut().push(parameters.get::<(i32,)>().unwrap().0);
+			}),
+		);
+		*viewer.state.borrow_mut() = ViewerState {
+			song: song.to_string(),
+			title: "Test song".into(),
+			piece: "Allegro".into(),
+			page: 4,
+		};
+		exported.update();
+		/* Nothing changed, so no second signal */
+		exported.update();
+		/* A round trip, so that the signal has arrived for sure */
+		method("ZoomIn", None).unwrap();
+		assert_eq!(*pages.borrow(), [4]);
+		client.signal_unsubscribe(subscription);
+
+		/* Properties */
+		let page = call(
+			&client,
+			&server_name,
+			"org.freedesktop.DBus.Properties",
+			"Get",
+			Some(&(INTERFACE_NAME, "Page").to_variant()),
+		)
+		.unwrap();
+		assert_eq!(
+			page.child_value(0).as_variant().unwrap().get::<i32>(),
+			Some(4)
+		);
+		let piece = call(
+			&client,
+			&server_name,
+			"org.freedesktop.DBus.Properties",
+			"Get",
+			Some(&(INTERFACE_NAME, "Piece").to_variant()),
+		)
+		.unwrap();
+		assert_eq!(
+			piece.child_value(0).as_variant().unwrap().get::<String>(),
+			Some("Allegro".into())
+		);
+
+		drop(exported);
+		bus.down();
+	}
+}
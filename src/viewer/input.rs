@@ -0,0 +1,224 @@
+//! Remote controlling the song view
+//!
+//! All the ways of turning pages without touching the screen (MIDI pedals, keyboards, OSC)
+//! trigger [`PageEvent`]s. Most commercial Bluetooth page turners pretend to be keyboards,
+//! so which keys do what is configured through [`KeyBindings`], with presets for common models.
+//! They are persisted in the user's configuration directory.
+
+use anyhow::Context;
+use dinoscore::collection;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PageEvent {
+	Next,
+	Previous,
+	NextPiece,
+	PreviousPiece,
+	ZoomIn,
+	ZoomOut,
+	/// Go to the first page of the song
+	JumpToStart,
+	/// Start or stop turning pages automatically
+	ToggleAutoScroll,
+}
+
+impl PageEvent {
+	pub const ALL: [PageEvent; 8] = [
+		PageEvent::Next,
+		PageEvent::Previous,
+		PageEvent::NextPiece,
+		PageEvent::PreviousPiece,
+		PageEvent::ZoomIn,
+		PageEvent::ZoomOut,
+		PageEvent::JumpToStart,
+		PageEvent::ToggleAutoScroll,
+	];
+
+	pub fn description(&self) -> &'static str {
+		match self {
+			PageEvent::Next => "Next page",
+			PageEvent::Previous => "Previous page",
+			PageEvent::NextPiece => "Next piece",
+			PageEvent::PreviousPiece => "Previous piece",
+			PageEvent::ZoomIn => "Zoom in",
+			PageEvent::ZoomOut => "Zoom out",
+			PageEvent::JumpToStart => "Go to start",
+			PageEvent::ToggleAutoScroll => "Toggle auto-scroll",
+		}
+	}
+}
+
+/// Everything a remote control can do. They all go through the same channel to the song view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteCommand {
+	Page(PageEvent),
+	/// Load a song, given by its UUID or title
+	LoadSong(String),
+	/// Jump to a staff of the current song
+	GoToStaff(collection::StaffIndex),
+	/// Go to where the leading viewer is, loading its song if necessary
+	Follow(crate::sync::SyncPosition),
+}
+
+impl From<PageEvent> for RemoteCommand {
+	fn from(event: PageEvent) -> Self {
+		RemoteCommand::Page(event)
+	}
+}
+
+/// Trigger an action on a key press
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyBinding {
+	/// The GDK name of the key (like `Page_Down` or `space`). Letters are in lower case.
+	pub key: String,
+	pub action: PageEvent,
+}
+
+/// A set of key bindings matching what some pedal models send
+pub struct Preset {
+	pub name: &'static str,
+	pub bindings: &'static [(&'static str, PageEvent)],
+}
+
+pub const PRESETS: &[Preset] = &[
+	Preset {
+		name: "Arrow keys",
+		bindings: &[
+			("Left", PageEvent::Previous),
+			("Right", PageEvent::Next),
+			("KP_Left", PageEvent::Previous),
+			("KP_Right", PageEvent::Next),
+		],
+	},
+	Preset {
+		name: "AirTurn (Up/Down arrows)",
+		bindings: &[("Up", PageEvent::Previous), ("Down", PageEvent::Next)],
+	},
+	Preset {
+		name: "PageFlip (Page Up/Page Down)",
+		bindings: &[
+			("Page_Up", PageEvent::Previous),
+			("Page_Down", PageEvent::Next),
+		],
+	},
+	Preset {
+		name: "Space and Backspace",
+		bindings: &[
+			("BackSpace", PageEvent::Previous),
+			("space", PageEvent::Next),
+		],
+	},
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyBindings {
+	pub bindings: Vec<KeyBinding>,
+}
+
+impl Default for KeyBindings {
+	/// The arrow keys, like it always was
+	fn default() -> Self {
+		KeyBindings::from_preset(&PRESETS[0])
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum KeyBindingsFile {
+	#[serde(rename = "0")]
+	V0 { bindings: Vec<KeyBinding> },
+}
+
+impl KeyBindings {
+	pub fn from_preset(preset: &Preset) -> Self {
+		KeyBindings {
+			bindings: preset
+				.bindings
+				.iter()
+				.map(|(key, action)| KeyBinding {
+					key: (*key).into(),
+					action: *action,
+				})
+				.collect(),
+		}
+	}
+
+	/// The action bound to a key, given by its GDK name
+	pub fn action_for(&self, key: &str) -> Option<PageEvent> {
+		self.bindings
+			.iter()
+			.find(|binding| binding.key == key)
+			.map(|binding| binding.action)
+	}
+
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("keys.json") {
+			Some(path) => {
+				let file: KeyBindingsFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					KeyBindingsFile::V0 { bindings } => KeyBindings { bindings },
+				}
+			},
+			None => KeyBindings::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("keys.json")?;
+		log::info!("Saving key bindings ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| {
+			serde_json::to_writer_pretty(
+				file,
+				&KeyBindingsFile::V0 {
+					bindings: self.bindings.clone(),
+				},
+			)
+		})
+		.context("Could not save key bindings (keys.json)")?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_presets() {
+		for preset in PRESETS {
+			let bindings = KeyBindings::from_preset(preset);
+			for (i, binding) in bindings.bindings.iter().enumerate() {
+				/* Every key only once */
+				assert_eq!(
+					bindings
+						.bindings
+						.iter()
+						.position(|other| other.key == binding.key),
+					Some(i),
+					"{}",
+					preset.name
+				);
+			}
+			assert!(bindings
+				.bindings
+				.iter()
+				.any(|binding| binding.action == PageEvent::Next));
+		}
+
+		let bindings = KeyBindings::default();
+		assert_eq!(bindings.action_for("Right"), Some(PageEvent::Next));
+		assert_eq!(bindings.action_for("KP_Left"), Some(PageEvent::Previous));
+		assert_eq!(bindings.action_for("space"), None);
+	}
+}
@@ -1,4 +1,4 @@
-use dinoscore::{prelude::*, *};
+use dinoscore::{library::ScaleMode, prelude::*, *};
 
 glib::wrapper! {
 	pub struct LibraryWidget(ObjectSubclass<imp::LibraryWidget>)
@@ -14,7 +14,34 @@ impl LibraryWidget {
 		song: crate::song_widget::SongWidget,
 	) {
 		self.imp().library.set(library.clone()).unwrap();
+		song.connect_local(
+			"end-of-entry",
+			false,
+			clone!(@weak self as obj => @default-return None, move |_| {
+				obj.imp().load_next_setlist_entry();
+				None
+			}),
+		);
+		song.connect_local(
+			"load-song",
+			false,
+			clone!(@weak self as obj => @default-return None, move |args| {
+				let query = args[1].get::<String>().unwrap();
+				let staff = collection::StaffIndex(args[2].get::<u64>().unwrap() as usize);
+				let uuid = obj.imp().library.get().unwrap().borrow().find_song(&query);
+				match uuid {
+					Some(uuid) => {
+						if let Err(err) = obj.load_song_checked(uuid, staff) {
+							log::warn!("Cannot load song '{}': {:?}", query, err);
+						}
+					},
+					None => log::warn!("No song '{}' found", query),
+				}
+				None
+			}),
+		);
 		self.imp().song.set(song).unwrap();
+		self.imp().reload_setlists();
 		self.imp().reload_songs_filtered();
 		self.imp().side_bar.get().init(library, self.clone());
 	}
@@ -28,6 +55,89 @@ impl LibraryWidget {
 		self.imp().load_song(song, start_at);
 	}
 
+	/// Load a song on behalf of a remote control, checking the arguments first
+	pub fn load_song_checked(
+		&self,
+		song: uuid::Uuid,
+		start_at: collection::StaffIndex,
+	) -> anyhow::Result<()> {
+		let n_staves = self
+			.imp()
+			.library
+			.get()
+			.unwrap()
+			.borrow()
+			.songs
+			.get(&song)
+			.map(|song| song.index.staves.len())
+			.ok_or_else(|| anyhow::format_err!("There is no song {}", song))?;
+		anyhow::ensure!(
+			*start_at < n_staves,
+			"The song only has {} staves",
+			n_staves
+		);
+		self.load_song(song, start_at);
+		Ok(())
+	}
+
+	/// Open a song given on the command line: by UUID, title or path to a song file
+	pub fn open_song(
+		&self,
+		song: &str,
+		start: &crate::args::StartAt,
+		scale_mode: Option<ScaleMode>,
+	) -> anyhow::Result<()> {
+		let mut library = self.imp().library.get().unwrap().borrow_mut();
+		let path = std::path::Path::new(song);
+		let uuid = if path.is_file() {
+			library.open_external(path)?
+		} else {
+			library
+				.find_song(song)
+				.ok_or_else(|| anyhow::format_err!("No song '{}' found", song))?
+		};
+
+		let index = &library.songs[&uuid].index;
+		let staff = match start {
+			crate::args::StartAt::Beginning => collection::StaffIndex(0),
+			crate::args::StartAt::Piece(name) => index
+				.piece_starts
+				.iter()
+				.find(|(_, piece)| piece.to_lowercase() == name.to_lowercase())
+				.map(|(staff, _)| *staff)
+				.ok_or_else(|| {
+					anyhow::format_err!(
+						"The song has no piece '{}'. Its pieces are: {}",
+						name,
+						index
+							.piece_starts
+							.values()
+							.filter(|piece| !piece.is_empty())
+							.map(String::as_str)
+							.collect::<Vec<_>>()
+							.join(", ")
+					)
+				})?,
+			crate::args::StartAt::Staff(staff) => {
+				anyhow::ensure!(
+					*staff < index.staves.len(),
+					"The song only has {} staves",
+					index.staves.len()
+				);
+				collection::StaffIndex(*staff)
+			},
+		};
+		std::mem::drop(library);
+
+		self.imp().load_song_with_scale(uuid, staff, scale_mode);
+		Ok(())
+	}
+
+	/* Called when the setlists in the library have been modified */
+	pub fn update_setlists(&self) {
+		self.imp().reload_setlists();
+	}
+
 	#[cfg(test)]
 	pub fn select_first_entry(&self) {
 		self.imp()
@@ -61,6 +171,27 @@ impl LibraryWidget {
 mod imp {
 	use super::*;
 
+	/// The scale modes a setlist entry can override, with their labels as in the song's menu
+	const SCALE_MODES: [(&str, &str); 5] = [
+		("fit-staves", "Three staves"),
+		("fit-columns", "Two columns"),
+		("fixed-width", "Full width"),
+		("fixed-height", "Three rows"),
+		("manual", "Manual zoom"),
+	];
+
+	type Sheets = TiVec<collection::PageIndex, PageImage>;
+
+	/// The setlist we are currently playing through
+	struct SetlistPlayback {
+		/// Index into [`library::Library::setlists`]
+		setlist: usize,
+		/// The entry that is currently loaded
+		entry: usize,
+		/// The next song, loaded in the background
+		preloaded: Option<(uuid::Uuid, std::thread::JoinHandle<anyhow::Result<Sheets>>)>,
+	}
+
 	#[derive(CompositeTemplate)]
 	#[template(resource = "/de/piegames/dinoscore/viewer/library.ui")]
 	pub struct LibraryWidget {
@@ -70,9 +201,33 @@ mod imp {
 		pub library_grid: TemplateChild<gtk::IconView>,
 		#[template_child]
 		search_entry: TemplateChild<gtk::SearchEntry>,
+		#[template_child]
+		setlist_selection: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		setlist_edit: TemplateChild<gtk::MenuButton>,
+		#[template_child]
+		setlist_popover: TemplateChild<gtk::Popover>,
+		#[template_child]
+		setlist_rename: TemplateChild<gtk::Entry>,
+		#[template_child]
+		setlist_entries: TemplateChild<gtk::ListBox>,
+		#[template_child]
+		sort_order: TemplateChild<gtk::ComboBoxText>,
 		/* Revealer (when clicked on song) */
 		#[template_child]
 		pub side_bar: TemplateChild<crate::song_preview::SongPreview>,
+		#[template_child]
+		practice_popover: TemplateChild<gtk::Popover>,
+		#[template_child]
+		practice_list: TemplateChild<gtk::ListBox>,
+		/// Where each row of the practice list starts
+		practice_targets: RefCell<Vec<(uuid::Uuid, collection::StaffIndex)>>,
+		#[template_child]
+		journal_streak: TemplateChild<gtk::Label>,
+		#[template_child]
+		journal_week: TemplateChild<gtk::ListBox>,
+		#[template_child]
+		journal_neglected: TemplateChild<gtk::ListBox>,
 
 		/**
 		 * Our scores decay over time, so we need to fix a point in time for the values to be comparable.
@@ -82,7 +237,10 @@ mod imp {
 		reference_time: std::time::SystemTime,
 		pub library: OnceCell<Rc<RefCell<library::Library>>>,
 		pub song: OnceCell<crate::song_widget::SongWidget>,
-		song_filter: RefCell<Box<dyn Fn(&collection::SongMeta) -> bool>>,
+		song_filter: RefCell<Box<dyn Fn(&collection::SongMeta, &library::LibrarySong) -> bool>>,
+		/// The setlist that is shown instead of all songs, if any
+		shown_setlist: Cell<Option<usize>>,
+		setlist_playback: RefCell<Option<SetlistPlayback>>,
 	}
 
 	impl Default for LibraryWidget {
@@ -91,11 +249,25 @@ mod imp {
 				store_songs: Default::default(),
 				library_grid: Default::default(),
 				search_entry: Default::default(),
+				setlist_selection: Default::default(),
+				setlist_edit: Default::default(),
+				setlist_popover: Default::default(),
+				setlist_rename: Default::default(),
+				setlist_entries: Default::default(),
+				sort_order: Default::default(),
 				side_bar: Default::default(),
+				practice_popover: Default::default(),
+				practice_list: Default::default(),
+				practice_targets: Default::default(),
+				journal_streak: Default::default(),
+				journal_week: Default::default(),
+				journal_neglected: Default::default(),
 				reference_time: std::time::SystemTime::now(),
 				library: Default::default(),
 				song: Default::default(),
-				song_filter: RefCell::new(Box::new(|_| true)),
+				song_filter: RefCell::new(Box::new(|_, _| true)),
+				shown_setlist: Default::default(),
+				setlist_playback: Default::default(),
 			}
 		}
 	}
@@ -121,10 +293,6 @@ mod imp {
 			self.parent_constructed();
 			let obj = self.obj();
 
-			let store_songs = &self.store_songs;
-			/* Sort by usage score */
-			store_songs.set_sort_column_id(gtk::SortColumn::Index(3), gtk::SortType::Descending);
-
 			/* Deferring is required for some reason */
 			glib::MainContext::default().spawn_local(
 				clone!(@weak obj => @default-panic, async move {
@@ -144,26 +312,347 @@ mod imp {
 		pub fn reload_songs_filtered(&self) {
 			let library = &self.library.get().unwrap().borrow();
 			self.store_songs.clear();
+
+			if let Some(setlist) = self.shown_setlist.get() {
+				/* Show the entries of the setlist in order */
+				self.store_songs
+					.set_sort_column_id(gtk::SortColumn::Index(4), gtk::SortType::Ascending);
+				for (index, entry) in library.setlists[setlist].entries.iter().enumerate() {
+					let song = match library.songs.get(&entry.song) {
+						Some(song) => song,
+						/* The song has been removed from the collection */
+						None => continue,
+					};
+					if (*self.song_filter.borrow())(&song.index, &library.stats[&entry.song]) {
+						let thumbnail = song.thumbnail().cloned();
+						let title = match song.index.piece_starts.get(&entry.piece) {
+							Some(piece) if !piece.is_empty() => format!(
+								"{}. {} – {}",
+								index + 1,
+								song.title().unwrap_or("<no title>"),
+								piece
+							),
+							_ => format!("{}. {}", index + 1, song.title().unwrap_or("<no title>")),
+						};
+						let score = library.stats[&entry.song].usage_score(&self.reference_time);
+						let uuid = entry.song.to_string();
+						let index = index as i32;
+
+						self.store_songs.set(
+							&self.store_songs.append(),
+							&[
+								(0, &thumbnail),
+								(1, &title),
+								(2, &uuid),
+								(3, &score),
+								(4, &index),
+							],
+						);
+					}
+				}
+				return;
+			}
+
+			/* Sort by usage score, or by what to practice next */
+			self.store_songs
+				.set_sort_column_id(gtk::SortColumn::Index(3), gtk::SortType::Descending);
+			let priorities =
+				(self.sort_order.active_id().as_deref() == Some("practice")).then(|| {
+					practice::song_priorities(&practice::rank(
+						library.stats.values(),
+						&library.journal,
+						std::time::SystemTime::now(),
+					))
+				});
 			for (uuid, song) in library.songs.iter() {
-				if (*self.song_filter.borrow())(&song.index) {
+				if library.is_read_only(uuid) {
+					continue;
+				}
+				if (*self.song_filter.borrow())(&song.index, &library.stats[uuid]) {
 					/* Add an item with the name and UUID */
 					// TODO cleanup once glib::Value implements ToValue
 					let thumbnail = song.thumbnail().cloned();
 					let title = song.title().unwrap_or("<no title>").to_owned();
-					let score = library.stats[uuid].usage_score(&self.reference_time);
+					let score = match &priorities {
+						Some(priorities) => priorities.get(uuid).copied().unwrap_or(0.0),
+						None => library.stats[uuid].usage_score(&self.reference_time),
+					};
 					let uuid = uuid.to_string();
 
 					self.store_songs.set(
 						&self.store_songs.append(),
-						/* The columns are: thumbnail, title, UUID, usage_score */
-						&[(0, &thumbnail), (1, &title), (2, &uuid), (3, &score)],
+						/* The columns are: thumbnail, title, UUID, usage_score, setlist entry */
+						&[
+							(0, &thumbnail),
+							(1, &title),
+							(2, &uuid),
+							(3, &score),
+							(4, &-1i32),
+						],
 					);
 				}
 			}
 		}
 
+		/// Update the setlist selection according to our library
+		pub fn reload_setlists(&self) {
+			let library = self.library.get().unwrap().borrow();
+			let active = self.setlist_selection.active_id();
+			self.setlist_selection.remove_all();
+			self.setlist_selection.append(Some("all"), "All songs");
+			for (index, setlist) in library.setlists.iter().enumerate() {
+				self.setlist_selection
+					.append(Some(&index.to_string()), &setlist.name);
+			}
+			self.setlist_selection
+				.set_visible(!library.setlists.is_empty());
+			std::mem::drop(library);
+
+			/* This will trigger a reload of the songs if required */
+			if active.is_none() || !self.setlist_selection.set_active_id(active.as_deref()) {
+				self.setlist_selection.set_active_id(Some("all"));
+			}
+		}
+
+		#[template_callback]
+		fn on_setlist_selected(&self) {
+			let setlist = self
+				.setlist_selection
+				.active_id()
+				.and_then(|id| id.parse::<usize>().ok());
+			self.setlist_edit.set_visible(setlist.is_some());
+			if setlist != self.shown_setlist.get() {
+				self.shown_setlist.set(setlist);
+				self.reload_songs_filtered();
+			}
+		}
+
+		#[template_callback]
+		fn on_setlist_edit_shown(&self) {
+			if let Some(setlist) = self.shown_setlist.get() {
+				let library = self.library.get().unwrap().borrow();
+				self.setlist_rename
+					.set_text(&library.setlists[setlist].name);
+				self.setlist_rename.remove_css_class("error");
+			}
+			self.reload_setlist_entries();
+		}
+
+		/// List the entries of the shown setlist for editing
+		fn reload_setlist_entries(&self) {
+			while let Some(row) = self.setlist_entries.first_child() {
+				self.setlist_entries.remove(&row);
+			}
+			let setlist = match self.shown_setlist.get() {
+				Some(setlist) => setlist,
+				None => return,
+			};
+			let library = self.library.get().unwrap().borrow();
+			let entries = &library.setlists[setlist].entries;
+			for (index, entry) in entries.iter().enumerate() {
+				let title = match library.songs.get(&entry.song) {
+					Some(song) => match song.index.piece_starts.get(&entry.piece) {
+						Some(piece) if !piece.is_empty() => {
+							format!("{} – {}", song.title().unwrap_or("<no title>"), piece)
+						},
+						_ => song.title().unwrap_or("<no title>").to_owned(),
+					},
+					/* The song has been removed from the collection */
+					None => "<missing song>".to_owned(),
+				};
+				let row = adw::ActionRow::builder()
+					.title(&format!("{}. {}", index + 1, title))
+					.build();
+
+				let scale_mode = gtk::ComboBoxText::builder()
+					.valign(gtk::Align::Center)
+					.tooltip_text("How to scale the score when playing this entry")
+					.build();
+				scale_mode.append(Some("default"), "As last viewed");
+				for (id, label) in SCALE_MODES {
+					scale_mode.append(Some(id), label);
+				}
+				scale_mode.set_active_id(Some(
+					entry
+						.scale_mode
+						.map_or("default", |mode| mode.action_string()),
+				));
+				scale_mode.connect_changed(clone_!(self, move |obj, scale_mode| {
+					let id = match scale_mode.active_id() {
+						Some(id) => id,
+						None => return,
+					};
+					obj.imp().update_setlist(|setlist| {
+						let entry = &mut setlist.entries[index];
+						/* Keep the number of staves or the zoom if the mode stays the same */
+						if entry.scale_mode.map(|mode| mode.action_string()) != Some(id.as_str()) {
+							entry.scale_mode =
+								(id.as_str() != "default").then(|| id.parse().unwrap());
+						}
+					});
+				}));
+				row.add_suffix(&scale_mode);
+
+				let move_up = gtk::Button::builder()
+					.icon_name("go-up-symbolic")
+					.tooltip_text("Move this entry up")
+					.valign(gtk::Align::Center)
+					.sensitive(index > 0)
+					.build();
+				move_up.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_setlist(|setlist| {
+						setlist.entries.swap(index - 1, index);
+					});
+					obj.imp().reload_setlist_entries();
+				}));
+				row.add_suffix(&move_up);
+
+				let move_down = gtk::Button::builder()
+					.icon_name("go-down-symbolic")
+					.tooltip_text("Move this entry down")
+					.valign(gtk::Align::Center)
+					.sensitive(index + 1 < entries.len())
+					.build();
+				move_down.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_setlist(|setlist| {
+						setlist.entries.swap(index, index + 1);
+					});
+					obj.imp().reload_setlist_entries();
+				}));
+				row.add_suffix(&move_down);
+
+				let delete = gtk::Button::builder()
+					.icon_name("user-trash-symbolic")
+					.tooltip_text("Remove this entry")
+					.valign(gtk::Align::Center)
+					.build();
+				delete.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_setlist(|setlist| {
+						setlist.entries.remove(index);
+					});
+					obj.imp().reload_setlist_entries();
+				}));
+				row.add_suffix(&delete);
+
+				self.setlist_entries.append(&row);
+			}
+			self.setlist_entries.set_placeholder(Some(
+				&gtk::Label::builder()
+					.label("The setlist is empty")
+					.margin_top(6)
+					.margin_bottom(6)
+					.css_classes(vec!["dim-label".into()])
+					.build(),
+			));
+		}
+
+		/// Modify the shown setlist, save it and update the songs list
+		fn update_setlist(&self, update: impl FnOnce(&mut library::Setlist)) {
+			let setlist = match self.shown_setlist.get() {
+				Some(setlist) => setlist,
+				None => return,
+			};
+			let order = |setlist: &library::Setlist| {
+				setlist
+					.entries
+					.iter()
+					.map(|entry| (entry.song, entry.piece))
+					.collect::<Vec<_>>()
+			};
+			let mut library = self.library.get().unwrap().borrow_mut();
+			let before = order(&library.setlists[setlist]);
+			update(&mut library.setlists[setlist]);
+			let reordered = order(&library.setlists[setlist]) != before;
+			library.save_in_background();
+			std::mem::drop(library);
+
+			/* The entry we are playing may have moved, so don't continue with a wrong one */
+			let playing = matches!(
+				self.setlist_playback.borrow().as_ref(),
+				Some(playback) if playback.setlist == setlist
+			);
+			if playing && reordered {
+				self.setlist_playback.take();
+			}
+			self.reload_songs_filtered();
+		}
+
+		#[template_callback]
+		fn on_setlist_renamed(&self) {
+			let setlist = match self.shown_setlist.get() {
+				Some(setlist) => setlist,
+				None => return,
+			};
+			let name = self.setlist_rename.text().trim().to_owned();
+			/* Songs are added to setlists by name, so keep the names unique */
+			let taken = self
+				.library
+				.get()
+				.unwrap()
+				.borrow()
+				.setlists
+				.iter()
+				.enumerate()
+				.any(|(index, other)| index != setlist && other.name == name);
+			if name.is_empty() || taken {
+				self.setlist_rename.add_css_class("error");
+				return;
+			}
+			self.setlist_rename.remove_css_class("error");
+			self.update_setlist(|shown| shown.name = name);
+			self.reload_setlists();
+			self.setlist_popover.popdown();
+		}
+
+		#[template_callback]
+		fn on_setlist_deleted(&self) {
+			let setlist = match self.shown_setlist.get() {
+				Some(setlist) => setlist,
+				None => return,
+			};
+			self.setlist_popover.popdown();
+			/* This shifts the indices of all following setlists */
+			self.setlist_playback.take();
+			self.setlist_selection.set_active_id(Some("all"));
+			let mut library = self.library.get().unwrap().borrow_mut();
+			library.setlists.remove(setlist);
+			library.save_in_background();
+			std::mem::drop(library);
+			self.reload_setlists();
+		}
+
+		#[template_callback]
+		fn on_sort_order_changed(&self) {
+			/* Not initialized yet */
+			if self.library.get().is_some() {
+				self.reload_songs_filtered();
+			}
+		}
+
 		/// Play a song
 		pub fn load_song(&self, uuid: uuid::Uuid, start_at: collection::StaffIndex) {
+			self.load_song_with_scale(uuid, start_at, None);
+		}
+
+		/// Play a song, overriding the scale mode it was last viewed with
+		pub fn load_song_with_scale(
+			&self,
+			uuid: uuid::Uuid,
+			start_at: collection::StaffIndex,
+			scale_mode: Option<ScaleMode>,
+		) {
+			/* Leave any setlist we might have been playing */
+			self.setlist_playback.take();
+			self.load_song_inner(uuid, start_at, scale_mode, None);
+		}
+
+		fn load_song_inner(
+			&self,
+			uuid: uuid::Uuid,
+			start_at: collection::StaffIndex,
+			scale_mode: Option<ScaleMode>,
+			sheets: Option<Sheets>,
+		) {
 			log::info!("Loading song: {}", uuid);
 
 			let mut library = self.library.get().unwrap().borrow_mut();
@@ -191,15 +680,20 @@ mod imp {
 
 			let index = song.index.clone();
 			// TODO load lazily
-			let sheets = song.load_sheets()().unwrap();
-			let scale_mode = library
-				.stats
-				.get_mut(&uuid)
-				.unwrap()
-				.scale_options
-				.as_ref()
-				.copied()
-				.unwrap_or_default();
+			let sheets = match sheets {
+				Some(sheets) => sheets,
+				None => song.load_sheets()().unwrap(),
+			};
+			let scale_mode = scale_mode.unwrap_or_else(|| {
+				library
+					.stats
+					.get_mut(&uuid)
+					.unwrap()
+					.scale_options
+					.as_ref()
+					.copied()
+					.unwrap_or_default()
+			});
 			std::mem::drop(library);
 			self.song
 				.get()
@@ -207,6 +701,71 @@ mod imp {
 				.load_song(index, sheets, scale_mode, start_at);
 		}
 
+		/// Start playing a setlist at the given entry
+		fn load_setlist_entry(&self, setlist: usize, entry: usize) {
+			let library = self.library.get().unwrap().borrow();
+			let entries = &library.setlists[setlist].entries;
+			let current = entries[entry];
+			/* Preload the next entry, unless it's the same song anyways */
+			let next = entries
+				.get(entry + 1)
+				.filter(|next| next.song != current.song)
+				.and_then(|next| library.songs.get(&next.song).map(|song| (next.song, song)))
+				.map(|(uuid, song)| (uuid, std::thread::spawn(song.load_sheets())));
+			std::mem::drop(library);
+
+			let preloaded = self
+				.setlist_playback
+				.take()
+				.and_then(|playback| playback.preloaded)
+				.filter(|(uuid, _)| *uuid == current.song)
+				/* Still loading: rather than blocking on it, load the song the normal way */
+				.filter(|(_, handle)| handle.is_finished())
+				.and_then(|(_, handle)| match handle.join() {
+					Ok(Ok(sheets)) => Some(sheets),
+					Ok(Err(err)) => {
+						log::warn!("Failed to preload song {}: {:?}", current.song, err);
+						None
+					},
+					Err(_) => {
+						log::warn!("Preloading song {} panicked", current.song);
+						None
+					},
+				});
+			self.load_song_inner(current.song, current.piece, current.scale_mode, preloaded);
+			self.song
+				.get()
+				.unwrap()
+				.set_setlist_piece(Some(current.piece));
+			*self.setlist_playback.borrow_mut() = Some(SetlistPlayback {
+				setlist,
+				entry,
+				preloaded: next,
+			});
+		}
+
+		/// The end of the current setlist entry has been reached
+		pub fn load_next_setlist_entry(&self) {
+			let (setlist, entry) = match self.setlist_playback.borrow().as_ref() {
+				Some(playback) => (playback.setlist, playback.entry),
+				None => return,
+			};
+			let library = self.library.get().unwrap().borrow();
+			/* Skip entries whose song is not in our collection (anymore) */
+			let next = library.setlists[setlist]
+				.entries
+				.iter()
+				.enumerate()
+				.skip(entry + 1)
+				.find(|(_, next)| library.songs.contains_key(&next.song))
+				.map(|(index, _)| index);
+			std::mem::drop(library);
+			match next {
+				Some(next) => self.load_setlist_entry(setlist, next),
+				None => log::info!("Reached the end of the setlist"),
+			}
+		}
+
 		#[template_callback]
 		pub fn on_item_selected(&self) {
 			let song: Option<uuid::Uuid> = {
@@ -237,7 +796,173 @@ mod imp {
 				.get()
 				.get::<glib::GString>(&self.store_songs.iter(item).unwrap(), 2);
 			let uuid = uuid::Uuid::parse_str(uuid.as_str()).unwrap();
-			self.load_song(uuid, 0.into());
+			let entry = self
+				.store_songs
+				.get()
+				.get::<i32>(&self.store_songs.iter(item).unwrap(), 4);
+			match self.shown_setlist.get() {
+				Some(setlist) if entry >= 0 => self.load_setlist_entry(setlist, entry as usize),
+				_ => self.load_song(uuid, 0.into()),
+			}
+		}
+
+		/// Suggest what to practice today
+		#[template_callback]
+		fn on_practice_shown(&self) {
+			while let Some(row) = self.practice_list.first_child() {
+				self.practice_list.remove(&row);
+			}
+			let mut targets = self.practice_targets.borrow_mut();
+			targets.clear();
+			let library = self.library.get().unwrap().borrow();
+			let now = std::time::SystemTime::now();
+			let ranking = practice::rank(
+				library
+					.stats
+					.values()
+					.filter(|stats| library.songs.contains_key(&stats.song)),
+				&library.journal,
+				now,
+			);
+			for suggestion in practice::daily_list(&ranking) {
+				let song = &library.songs[&suggestion.song];
+				let title = song.title().unwrap_or("<no title>");
+				let title = match suggestion
+					.piece
+					.and_then(|piece| song.index.piece_starts.get(&piece))
+				{
+					Some(piece) if !piece.is_empty() => format!("{} – {}", title, piece),
+					_ => title.to_owned(),
+				};
+				let days = |duration: std::time::Duration| {
+					(duration.as_secs_f64() / (24.0 * 3600.0)).round() as u64
+				};
+				let subtitle = match suggestion.last_practiced {
+					Some(last) => format!(
+						"Every {} days, last practiced {} days ago",
+						days(suggestion.interval),
+						days(now.duration_since(last).unwrap_or_default())
+					),
+					None => format!(
+						"Every {} days, not practiced yet",
+						days(suggestion.interval)
+					),
+				};
+				let row = adw::ActionRow::builder()
+					.title(&title)
+					.subtitle(&subtitle)
+					.activatable(true)
+					.build();
+				self.practice_list.append(&row);
+				/* Remember where to start */
+				targets.push((
+					suggestion.song,
+					suggestion.piece.unwrap_or(collection::StaffIndex(0)),
+				));
+			}
+			self.practice_list.set_placeholder(Some(
+				&gtk::Label::builder()
+					.label("Nothing is due today")
+					.margin_top(6)
+					.margin_bottom(6)
+					.css_classes(vec!["dim-label".into()])
+					.build(),
+			));
+		}
+
+		#[template_callback]
+		fn on_practice_activated(&self, row: &gtk::ListBoxRow) {
+			let target = self
+				.practice_targets
+				.borrow()
+				.get(row.index() as usize)
+				.copied();
+			if let Some((song, staff)) = target {
+				self.practice_popover.popdown();
+				self.load_song(song, staff);
+			}
+		}
+
+		/// Summarize the practice journal
+		#[template_callback]
+		fn on_journal_shown(&self) {
+			/// How many songs to list as neglected
+			const NEGLECTED_COUNT: usize = 5;
+
+			let library = self.library.get().unwrap().borrow();
+			let now = std::time::SystemTime::now();
+			let title = |song: &uuid::Uuid| {
+				library.songs[song]
+					.title()
+					.unwrap_or("<no title>")
+					.to_owned()
+			};
+			let row = |title: &str, value: &str| {
+				let row = adw::ActionRow::builder().title(title).build();
+				row.add_suffix(&gtk::Label::new(Some(value)));
+				row
+			};
+
+			let streak = journal::streak(&library.journal, now);
+			self.journal_streak.set_label(&match streak {
+				0 => "No practice streak".to_owned(),
+				1 => "1 day practice streak".to_owned(),
+				days => format!("{} days practice streak", days),
+			});
+
+			for list in [&self.journal_week, &self.journal_neglected] {
+				while let Some(row) = list.first_child() {
+					list.remove(&row);
+				}
+			}
+			/* Songs may have been removed from the collection since */
+			for ((song, piece), time) in journal::time_per_piece_this_week(&library.journal, now)
+				.into_iter()
+				.filter(|((song, _), _)| library.songs.contains_key(song))
+			{
+				let name = match library.songs[&song].index.piece_starts.get(&piece) {
+					Some(piece) if !piece.is_empty() => format!("{} – {}", title(&song), piece),
+					_ => title(&song),
+				};
+				let minutes = time.as_secs() / 60;
+				let time = if minutes < 60 {
+					format!("{} min", minutes)
+				} else {
+					format!("{} h {:02} min", minutes / 60, minutes % 60)
+				};
+				self.journal_week.append(&row(&name, &time));
+			}
+
+			let songs = library
+				.stats
+				.values()
+				.filter(|stats| {
+					library.songs.contains_key(&stats.song) && !library.is_read_only(&stats.song)
+				})
+				.map(|stats| (stats.song, stats.last_played));
+			for (song, last) in journal::most_neglected(&library.journal, songs)
+				.into_iter()
+				.take(NEGLECTED_COUNT)
+			{
+				let days = now.duration_since(last).unwrap_or_default().as_secs() / (24 * 3600);
+				let ago = match days {
+					0 => "Today".to_owned(),
+					1 => "Yesterday".to_owned(),
+					days => format!("{} days ago", days),
+				};
+				self.journal_neglected.append(&row(&title(&song), &ago));
+			}
+
+			for list in [&self.journal_week, &self.journal_neglected] {
+				list.set_placeholder(Some(
+					&gtk::Label::builder()
+						.label("Nothing yet")
+						.margin_top(6)
+						.margin_bottom(6)
+						.css_classes(vec!["dim-label".into()])
+						.build(),
+				));
+			}
 		}
 
 		#[template_callback]
@@ -245,9 +970,9 @@ mod imp {
 			/* TODO use unicase crate instead. And maybe also a fuzzy matcher */
 			let query = entry.text().to_string().trim().to_lowercase();
 			*self.song_filter.borrow_mut() = if query.is_empty() {
-				Box::new(|_| true)
+				Box::new(|_, _| true)
 			} else {
-				Box::new(move |song| {
+				Box::new(move |song, stats| {
 					song.title
 						.as_ref()
 						.map(|title| title.trim().to_lowercase().contains(&query))
@@ -255,7 +980,7 @@ mod imp {
 						.composer
 						.as_ref()
 						.map(|composer| composer.trim().to_lowercase().contains(&query))
-						.unwrap_or(false)
+						.unwrap_or(false) || stats.notes_contain(&query)
 				})
 			};
 			self.reload_songs_filtered();
@@ -295,7 +1020,7 @@ mod imp {
 
 		#[template_callback]
 		fn on_search_stopped(&self) {
-			*self.song_filter.borrow_mut() = Box::new(|_| true);
+			*self.song_filter.borrow_mut() = Box::new(|_, _| true);
 			self.reload_songs_filtered();
 		}
 	}
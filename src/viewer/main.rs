@@ -7,6 +7,8 @@
 //!     - [`song_preview`]: The song preview statistics on the right
 //!   - [`song_widget`]: The "play song" pane
 //!     - [`song_page`]: A single page on the song carousel
+//! - [`pedal_dialog`]: MIDI pedal configuration
+//! - [`preferences`]: Preferences, like key bindings for keyboard-emulating pedals
 
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::type_complexity)]
@@ -16,15 +18,25 @@
 use anyhow::Context;
 use dinoscore::{prelude::*, *};
 
+mod annotation_editor;
+mod args;
 mod crash_n_log;
+mod dbus;
+mod input;
 mod library_widget;
+mod osc;
+mod pdf_annotator;
 #[cfg(target_family = "unix")]
 mod pedal;
+#[cfg(target_family = "unix")]
+mod pedal_dialog;
+mod preferences;
 #[cfg(test)]
 mod screenshots;
 mod song_page;
 mod song_preview;
 mod song_widget;
+mod sync;
 mod window;
 mod xournal;
 
@@ -38,10 +50,10 @@ fn gtk_init(_application: &gtk::Application) {
 
 fn main() -> anyhow::Result<()> {
 	{
-		/* If we get called with an argument, show a crash dialog and exit */
+		/* If we get called after a crash, show a crash dialog and exit */
 		let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
 		/* As usual, ignore arg0 */
-		if args.len() > 1 {
+		if args.len() == 3 && args[1] == crash_n_log::CRASH_REPORT_ARG {
 			crash_n_log::show_crash_dialog(args);
 			/* Never returns */
 		}
@@ -78,12 +90,41 @@ fn main() -> anyhow::Result<()> {
 
 	let application = gtk::Application::builder()
 		.application_id("de.piegames.dinoscore.viewer")
-		.flags(gio::ApplicationFlags::NON_UNIQUE)
+		.flags(gio::ApplicationFlags::NON_UNIQUE | gio::ApplicationFlags::HANDLES_OPEN)
 		.resource_base_path("/de/piegames/dinoscore")
 		.build();
 
 	application.connect_startup(gtk_init);
 
+	args::register(&application);
+	let launch_options = Rc::new(RefCell::new(args::LaunchOptions::default()));
+	application.connect_handle_local_options(
+		clone!(@strong launch_options => move |_application, options| {
+			match args::LaunchOptions::from_options(options) {
+				Ok(options) => {
+					*launch_options.borrow_mut() = options;
+					/* Continue as usual */
+					-1
+				},
+				Err(err) => {
+					eprintln!("{:#}", err);
+					1
+				},
+			}
+		}),
+	);
+
+	/* Song files passed as arguments, for example by a file manager */
+	application.connect_open(
+		clone!(@strong launch_options => move |application, files, _hint| {
+			match files.first().and_then(|file| file.path()) {
+				Some(path) => launch_options.borrow_mut().song = Some(path.to_string_lossy().into_owned()),
+				None => log::warn!("Only local files can be opened"),
+			}
+			application.activate();
+		}),
+	);
+
 	application.connect_activate(move |application| {
 		let window = window::Window::new(application);
 
@@ -92,6 +133,7 @@ fn main() -> anyhow::Result<()> {
 
 		window.present();
 		log::info!("Application started");
+		window.open(&launch_options.borrow());
 
 		/* Check hardware acceleration */
 		if window.surface().create_gl_context().is_err() {
@@ -99,7 +141,7 @@ fn main() -> anyhow::Result<()> {
 		}
 	});
 
-	application.run_with_args(&[] as &[&str]);
+	application.run();
 
 	log::info!("Thank you for using DiNoScore.");
 	log::logger().flush();
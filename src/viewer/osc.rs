@@ -0,0 +1,490 @@
+//! Remote control through OSC over UDP
+//!
+//! Foot controllers and other devices on the local network can send
+//! [Open Sound Control](https://opensoundcontrol.stanford.edu/spec-1_0.html) messages:
+//!
+//! - `/dinoscore/next`, `/dinoscore/previous`: turn the page
+//! - `/dinoscore/next-piece`, `/dinoscore/previous-piece`: go to another piece
+//! - `/dinoscore/start`, `/dinoscore/zoom-in`, `/dinoscore/zoom-out`, `/dinoscore/auto-scroll`
+//! - `/dinoscore/song <string>`: load a song by UUID or title
+//! - `/dinoscore/staff <int>`: jump to a staff of the current song (counting from zero)
+//!
+//! Many controllers send buttons as `1` on press and `0` on release, so the argument-less
+//! messages are ignored when their first argument is zero.
+//!
+//! The listener is disabled by default. When enabled, it only accepts messages from the
+//! same machine unless configured otherwise.
+
+use crate::input::{PageEvent, RemoteCommand};
+use anyhow::Context;
+use dinoscore::{collection::StaffIndex, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+	net::{Ipv4Addr, SocketAddr, UdpSocket},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+/// How long to block on the socket before looking if we should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OscConfig {
+	pub enabled: bool,
+	pub port: u16,
+	/// Accept messages from other devices, not only from localhost
+	pub public: bool,
+}
+
+impl Default for OscConfig {
+	fn default() -> Self {
+		OscConfig {
+			enabled: false,
+			port: 9000,
+			public: false,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum OscConfigFile {
+	#[serde(rename = "0")]
+	V0(OscConfig),
+}
+
+impl OscConfig {
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("osc.json") {
+			Some(path) => {
+				let file: OscConfigFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					OscConfigFile::V0(config) => config,
+				}
+			},
+			None => OscConfig::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("osc.json")?;
+		log::info!("Saving OSC configuration ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| serde_json::to_writer_pretty(file, &OscConfigFile::V0(*self)))
+			.context("Could not save OSC configuration (osc.json)")?;
+		Ok(())
+	}
+
+	fn address(&self) -> SocketAddr {
+		let ip = if self.public {
+			Ipv4Addr::UNSPECIFIED
+		} else {
+			Ipv4Addr::LOCALHOST
+		};
+		(ip, self.port).into()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArgument {
+	Int(i32),
+	Float(f32),
+	String(String),
+	/// Types that carry no data we care about (booleans, blobs, 64 bit numbers, …)
+	Other(char),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+	pub address: String,
+	pub arguments: Vec<OscArgument>,
+}
+
+impl OscMessage {
+	/// What the message asks us to do, if anything
+	pub fn command(&self) -> Option<RemoteCommand> {
+		let first = self.arguments.first();
+		let event = match self.address.as_str() {
+			"/dinoscore/song" => {
+				return match first {
+					Some(OscArgument::String(song)) => Some(RemoteCommand::LoadSong(song.clone())),
+					_ => None,
+				}
+			},
+			"/dinoscore/staff" => {
+				let staff = match first {
+					Some(OscArgument::Int(staff)) => *staff as i64,
+					Some(OscArgument::Float(staff)) => *staff as i64,
+					_ => return None,
+				};
+				return usize::try_from(staff)
+					.ok()
+					.map(|staff| RemoteCommand::GoToStaff(StaffIndex(staff)));
+			},
+			"/dinoscore/next" => PageEvent::Next,
+			"/dinoscore/previous" => PageEvent::Previous,
+			"/dinoscore/next-piece" => PageEvent::NextPiece,
+			"/dinoscore/previous-piece" => PageEvent::PreviousPiece,
+			"/dinoscore/start" => PageEvent::JumpToStart,
+			"/dinoscore/zoom-in" => PageEvent::ZoomIn,
+			"/dinoscore/zoom-out" => PageEvent::ZoomOut,
+			"/dinoscore/auto-scroll" => PageEvent::ToggleAutoScroll,
+			_ => return None,
+		};
+		/* Button releases */
+		match first {
+			Some(OscArgument::Int(0)) => None,
+			Some(OscArgument::Float(value)) if *value == 0.0 => None,
+			_ => Some(event.into()),
+		}
+	}
+}
+
+/// Read a zero-terminated string, padded to four bytes
+fn read_string(data: &mut &[u8]) -> anyhow::Result<String> {
+	let length = data
+		.iter()
+		.position(|&byte| byte == 0)
+		.context("Unterminated string")?;
+	let string = std::str::from_utf8(&data[..length])
+		.context("Invalid string")?
+		.to_owned();
+	let padded = (length + 4) & !3;
+	*data = data.get(padded..).unwrap_or_default();
+	Ok(string)
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], length: usize) -> anyhow::Result<&'a [u8]> {
+	anyhow::ensure!(data.len() >= length, "Message too short");
+	let (bytes, rest) = data.split_at(length);
+	*data = rest;
+	Ok(bytes)
+}
+
+fn read_u32(data: &mut &[u8]) -> anyhow::Result<u32> {
+	Ok(u32::from_be_bytes(read_bytes(data, 4)?.try_into().unwrap()))
+}
+
+/// Parse an OSC packet, which is either a single message or a bundle of them
+pub fn parse_packet(mut data: &[u8]) -> anyhow::Result<Vec<OscMessage>> {
+	if data.starts_with(b"#bundle\0") {
+		/* Skip the time tag, we execute everything immediately */
+		read_bytes(&mut data, 16)?;
+		let mut messages = Vec::new();
+		while !data.is_empty() {
+			let length = read_u32(&mut data)? as usize;
+			messages.extend(parse_packet(read_bytes(&mut data, length)?)?);
+		}
+		return Ok(messages);
+	}
+
+	let address = read_string(&mut data)?;
+	anyhow::ensure!(address.starts_with('/'), "Invalid address '{}'", address);
+	/* Very old implementations may omit the type tags */
+	let types = if data.is_empty() {
+		String::new()
+	} else {
+		read_string(&mut data)?
+	};
+	let mut arguments = Vec::new();
+	for tag in types.chars().skip_while(|&tag| tag == ',') {
+		arguments.push(match tag {
+			'i' => OscArgument::Int(read_u32(&mut data)? as i32),
+			'f' => OscArgument::Float(f32::from_bits(read_u32(&mut data)?)),
+			's' | 'S' => OscArgument::String(read_string(&mut data)?),
+			'h' | 't' | 'd' => {
+				read_bytes(&mut data, 8)?;
+				OscArgument::Other(tag)
+			},
+			'c' | 'r' | 'm' => {
+				read_bytes(&mut data, 4)?;
+				OscArgument::Other(tag)
+			},
+			'b' => {
+				let length = read_u32(&mut data)? as usize;
+				read_bytes(&mut data, (length + 3) & !3)?;
+				OscArgument::Other(tag)
+			},
+			'T' | 'F' | 'N' | 'I' => OscArgument::Other(tag),
+			other => anyhow::bail!("Unsupported argument type '{}'", other),
+		});
+	}
+	Ok(vec![OscMessage { address, arguments }])
+}
+
+/// Handle to the background OSC listener
+#[derive(Debug)]
+struct Listener {
+	stop: Arc<AtomicBool>,
+	thread: std::thread::JoinHandle<()>,
+}
+
+/// Manages the OSC listener according to its configuration
+#[derive(Debug)]
+pub struct Osc {
+	config: RefCell<OscConfig>,
+	listener: RefCell<Option<Listener>>,
+	tx: glib::Sender<RemoteCommand>,
+}
+
+impl Osc {
+	/// Load the configuration and start listening if enabled. Commands are sent to `tx`.
+	pub fn new(tx: glib::Sender<RemoteCommand>) -> Self {
+		let config = OscConfig::load()
+			.context("Failed to load OSC configuration")
+			.unwrap_or_else(|err| {
+				log::error!("{:?}", err);
+				OscConfig::default()
+			});
+		let osc = Osc {
+			config: RefCell::new(config),
+			listener: RefCell::new(None),
+			tx,
+		};
+		if let Err(err) = osc.restart() {
+			log::error!("{:?}", err);
+		}
+		osc
+	}
+
+	pub fn config(&self) -> OscConfig {
+		*self.config.borrow()
+	}
+
+	/// Apply a new configuration and persist it. Fails if the port can't be opened.
+	pub fn set_config(&self, config: OscConfig) -> anyhow::Result<()> {
+		if let Err(err) = config.save() {
+			log::error!("{:?}", err);
+		}
+		*self.config.borrow_mut() = config;
+		self.restart()
+	}
+
+	fn restart(&self) -> anyhow::Result<()> {
+		self.shutdown();
+		let config = self.config();
+		if !config.enabled {
+			return Ok(());
+		}
+
+		let address = config.address();
+		let socket = UdpSocket::bind(address)
+			.with_context(|| format!("Failed to listen for OSC on {}", address))?;
+		socket.set_read_timeout(Some(POLL_INTERVAL))?;
+		log::info!("Listening for OSC messages on {}", address);
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let tx = self.tx.clone();
+		let thread = std::thread::Builder::new()
+			.name("OSC".into())
+			.spawn({
+				let stop = stop.clone();
+				move || listen(socket, stop, move |command| tx.send(command).is_ok())
+			})
+			.context("Failed to spawn thread")?;
+		*self.listener.borrow_mut() = Some(Listener { stop, thread });
+		Ok(())
+	}
+
+	/// Stop listening and wait for the background thread to finish
+	pub fn shutdown(&self) {
+		if let Some(listener) = self.listener.borrow_mut().take() {
+			listener.stop.store(true, Ordering::Relaxed);
+			if listener.thread.join().is_err() {
+				log::error!("The OSC thread panicked");
+			}
+		}
+	}
+}
+
+/// Receive packets until stopped or until `on_command` returns `false`
+fn listen(
+	socket: UdpSocket,
+	stop: Arc<AtomicBool>,
+	mut on_command: impl FnMut(RemoteCommand) -> bool,
+) {
+	let mut buffer = [0; 4096];
+	while !stop.load(Ordering::Relaxed) {
+		let (length, sender) = match socket.recv_from(&mut buffer) {
+			Ok(received) => received,
+			Err(err)
+				if err.kind() == std::io::ErrorKind::WouldBlock
+					|| err.kind() == std::io::ErrorKind::TimedOut =>
+			{
+				continue
+			},
+			Err(err) => {
+				log::error!("Failed to receive OSC messages: {:?}", err);
+				return;
+			},
+		};
+		let messages = match parse_packet(&buffer[..length]) {
+			Ok(messages) => messages,
+			Err(err) => {
+				log::warn!("Invalid OSC packet from {}: {:?}", sender, err);
+				continue;
+			},
+		};
+		for message in messages {
+			log::debug!("OSC message from {}: {:?}", sender, message);
+			match message.command() {
+				Some(command) => {
+					if !on_command(command) {
+						/* Nobody is listening anymore */
+						return;
+					}
+				},
+				None => log::debug!("Ignoring OSC message {}", message.address),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn pad(bytes: &mut Vec<u8>) {
+		while bytes.len() % 4 != 0 {
+			bytes.push(0);
+		}
+	}
+
+	fn encode(address: &str, arguments: &[OscArgument]) -> Vec<u8> {
+		let mut bytes = address.as_bytes().to_vec();
+		bytes.push(0);
+		pad(&mut bytes);
+		bytes.push(b',');
+		for argument in arguments {
+			bytes.push(match argument {
+				OscArgument::Int(_) => b'i',
+				OscArgument::Float(_) => b'f',
+				OscArgument::String(_) => b's',
+				OscArgument::Other(tag) => *tag as u8,
+			});
+		}
+		bytes.push(0);
+		pad(&mut bytes);
+		for argument in arguments {
+			match argument {
+				OscArgument::Int(value) => bytes.extend(value.to_be_bytes()),
+				OscArgument::Float(value) => bytes.extend(value.to_be_bytes()),
+				OscArgument::String(value) => {
+					bytes.extend(value.as_bytes());
+					bytes.push(0);
+					pad(&mut bytes);
+				},
+				OscArgument::Other(_) => {},
+			}
+		}
+		bytes
+	}
+
+	#[test]
+	fn test_parse() {
+		let arguments = [
+			OscArgument::Int(-3),
+			OscArgument::String("Hello".into()),
+			OscArgument::Other('T'),
+			OscArgument::Float(0.5),
+		];
+		let packet = encode("/dinoscore/test", &arguments);
+		assert_eq!(packet.len() % 4, 0);
+		assert_eq!(
+			parse_packet(&packet).unwrap(),
+			[OscMessage {
+				address: "/dinoscore/test".into(),
+				arguments: arguments.to_vec(),
+			}]
+		);
+
+		let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+		for message in [encode("/a", &[]), encode("/b", &[OscArgument::Int(1)])] {
+			bundle.extend((message.len() as u32).to_be_bytes());
+			bundle.extend(message);
+		}
+		let messages = parse_packet(&bundle).unwrap();
+		assert_eq!(messages.len(), 2);
+		assert_eq!(messages[1].arguments, [OscArgument::Int(1)]);
+
+		assert!(parse_packet(b"/truncated").is_err());
+		assert!(parse_packet(&encode("/a", &[OscArgument::Int(1)])[..8]).is_err());
+	}
+
+	#[test]
+	fn test_commands() {
+		let command = |address: &str, arguments: &[OscArgument]| {
+			parse_packet(&encode(address, arguments)).unwrap()[0].command()
+		};
+		assert_eq!(
+			command("/dinoscore/next", &[]),
+			Some(RemoteCommand::Page(PageEvent::Next))
+		);
+		assert_eq!(
+			command("/dinoscore/previous", &[OscArgument::Float(1.0)]),
+			Some(RemoteCommand::Page(PageEvent::Previous))
+		);
+		/* Button release */
+		assert_eq!(
+			command("/dinoscore/previous", &[OscArgument::Float(0.0)]),
+			None
+		);
+		assert_eq!(
+			command("/dinoscore/song", &[OscArgument::String("Bolero".into())]),
+			Some(RemoteCommand::LoadSong("Bolero".into()))
+		);
+		assert_eq!(command("/dinoscore/song", &[]), None);
+		assert_eq!(
+			command("/dinoscore/staff", &[OscArgument::Int(12)]),
+			Some(RemoteCommand::GoToStaff(StaffIndex(12)))
+		);
+		assert_eq!(command("/dinoscore/staff", &[OscArgument::Int(-1)]), None);
+		assert_eq!(command("/something/else", &[]), None);
+	}
+
+	#[test]
+	fn test_listen() {
+		let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		socket.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+		let address = socket.local_addr().unwrap();
+		let stop = Arc::new(AtomicBool::new(false));
+		let (tx, rx) = std::sync::mpsc::channel();
+		let thread = std::thread::spawn({
+			let stop = stop.clone();
+			move || listen(socket, stop, move |command| tx.send(command).is_ok())
+		});
+
+		let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		sender.send_to(b"garbage", address).unwrap();
+		sender
+			.send_to(&encode("/dinoscore/next-piece", &[]), address)
+			.unwrap();
+		sender
+			.send_to(&encode("/dinoscore/staff", &[OscArgument::Int(4)]), address)
+			.unwrap();
+		let timeout = Duration::from_secs(5);
+		assert_eq!(
+			rx.recv_timeout(timeout).unwrap(),
+			RemoteCommand::Page(PageEvent::NextPiece)
+		);
+		assert_eq!(
+			rx.recv_timeout(timeout).unwrap(),
+			RemoteCommand::GoToStaff(StaffIndex(4))
+		);
+
+		stop.store(true, Ordering::Relaxed);
+		thread.join().unwrap();
+	}
+}
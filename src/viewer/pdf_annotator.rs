@@ -0,0 +1,102 @@
+//! Annotating with any PDF viewer that can save annotations into the file
+//!
+//! The program works on a copy of the score. For the overlay, we empty the contents of its
+//! pages, which leaves only the annotations. Annotations that the program merged into the
+//! pages themselves are lost that way, it must keep them as PDF annotations.
+
+use super::*;
+use crate::annotation_editor::AnnotationEditor;
+use anyhow::Context;
+use std::{
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+pub struct PdfAnnotator {
+	program: String,
+	args: Vec<String>,
+}
+
+impl PdfAnnotator {
+	/// From a command line like `okular --unique`
+	pub fn new(command: &str) -> Self {
+		let mut words = command.split_whitespace().map(String::from);
+		PdfAnnotator {
+			program: words.next().unwrap_or_default(),
+			args: words.collect(),
+		}
+	}
+}
+
+impl AnnotationEditor for PdfAnnotator {
+	fn name(&self) -> &str {
+		&self.program
+	}
+
+	fn check_available(&self) -> anyhow::Result<()> {
+		anyhow::ensure!(!self.program.is_empty(), "No PDF annotator is configured");
+		let found = std::env::var_os("PATH")
+			.map(|path| {
+				std::env::split_paths(&path)
+					.any(|directory| directory.join(&self.program).is_file())
+			})
+			.unwrap_or(false);
+		anyhow::ensure!(
+			found || Path::new(&self.program).is_file(),
+			"'{}' is not installed",
+			self.program
+		);
+		Ok(())
+	}
+
+	fn document(&self, song: &uuid::Uuid) -> anyhow::Result<PathBuf> {
+		annotations::external_file(song, "annotated.pdf")
+	}
+
+	fn prepare(&self, document: &Path, background_pdf: Vec<u8>) -> anyhow::Result<()> {
+		if !document.exists() {
+			log::debug!("Creating a copy of the score for editing");
+			std::fs::write(document, background_pdf)
+				.with_context(|| format!("Failed to write '{}'", document.display()))?;
+		}
+		Ok(())
+	}
+
+	/// There is no common way to open a PDF at a page, so this starts at the beginning
+	fn launch(&self, document: &Path, _page: usize) -> anyhow::Result<std::process::Child> {
+		Ok(Command::new(&self.program)
+			.args(&self.args)
+			.arg(document)
+			.spawn()?)
+	}
+
+	fn export(&self, document: &Path, overlay: &Path) -> anyhow::Result<()> {
+		let document = std::fs::read(document)
+			.with_context(|| format!("Failed to read '{}'", document.display()))?;
+		let annotations = image_util::strip_page_contents(&document)
+			.context("Failed to remove the score from the annotations")?;
+		std::fs::write(overlay, annotations)
+			.with_context(|| format!("Failed to write '{}'", overlay.display()))
+	}
+
+	/// New pages are copies of the score
+	fn remap(
+		&self,
+		document: &Path,
+		pages: &[Option<usize>],
+		background_pdf: &[u8],
+	) -> anyhow::Result<Vec<u8>> {
+		let pages = pages
+			.iter()
+			.enumerate()
+			.map(|(index, page)| match page {
+				Some(page) => image_util::PdfPage::Of(0, *page),
+				None => image_util::PdfPage::Of(1, index),
+			})
+			.collect::<Vec<_>>();
+		let document = std::fs::read(document)
+			.with_context(|| format!("Failed to read '{}'", document.display()))?;
+		image_util::assemble_pdf(vec![document, background_pdf.to_vec()], &pages)
+			.context("Failed to rearrange the pages")
+	}
+}
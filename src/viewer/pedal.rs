@@ -1,63 +1,1034 @@
 //! Page turning through MIDI pedal
 //!
-//! Uses portmidi to listen on the default MIDI device. By default, maps the
-//! Soft pedal (MIDI controller 67) to "next" and the Sostenuto pedal (MIDI
-//! controller 66) to "previous".
+//! Uses portmidi to listen on all MIDI input devices, including ones connected later on.
+//! Which MIDI messages trigger which action is configured through a [`PedalConfig`], which
+//! is persisted in the user's configuration directory. By default, maps the Soft pedal
+//! (MIDI controller 67) to "next" and the Sostenuto pedal (MIDI controller 66) to "previous".
+//!
+//! Besides plain presses, pedals can be long pressed, double pressed or pressed together with
+//! another one, which allows mapping more actions to the usual two pedals.
 
+use anyhow::Context;
 use gtk::glib::Sender;
 use gtk4 as gtk;
-use std::{any::Any, error::Error};
+use serde::{Deserialize, Serialize};
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
 
+use crate::input::{PageEvent, RemoteCommand};
 use midi_event::*;
 use portmidi as pm;
 
-pub enum PageEvent {
-	Next,
-	Previous,
+/// The kinds of MIDI messages pedals commonly send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MessageType {
+	/// Control change, the value is the controller value
+	Controller,
+	/// Note on/off, the value is the velocity
+	Note,
+	/// Program change, has no value
+	ProgramChange,
+}
+
+/// A MIDI message from a pedal, reduced to what is relevant for mapping it to actions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PedalInput {
+	/// The name of the MIDI device it came from
+	pub device: String,
+	pub channel: u8,
+	pub message: MessageType,
+	/// Controller, note or program number
+	pub number: u8,
+	/// Controller value or velocity. Note offs have zero, program changes always 127
+	pub value: u8,
+}
+
+impl PedalInput {
+	pub fn from_midi(device: &str, event: &MidiEvent) -> Option<Self> {
+		let (message, number, value) = match event.event {
+			MidiEventType::Controller(number, value) => (MessageType::Controller, number, value),
+			MidiEventType::NoteOn(note, velocity) => (MessageType::Note, note, velocity),
+			MidiEventType::NoteOff(note, _) => (MessageType::Note, note, 0),
+			MidiEventType::ProgramChange(program) => (MessageType::ProgramChange, program, 127),
+			_ => return None,
+		};
+		Some(PedalInput {
+			device: device.to_owned(),
+			channel: event.channel,
+			message,
+			number,
+			value,
+		})
+	}
+}
+
+/// A physical pedal, identified by the MIDI messages it sends
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PedalTrigger {
+	/// Only match devices whose name contains this
+	pub device: Option<String>,
+	/// Only match this channel
+	pub channel: Option<u8>,
+	pub message: MessageType,
+	pub number: u8,
+	/// The pedal counts as pressed when the value is at least this
+	pub threshold: u8,
+}
+
+impl PedalTrigger {
+	/// Create a trigger that matches exactly this input, for the "learn" function
+	pub fn learn(input: &PedalInput) -> Self {
+		PedalTrigger {
+			device: Some(input.device.clone()),
+			channel: Some(input.channel),
+			message: input.message,
+			number: input.number,
+			/* Some pedals send 127, some 64, some somewhere in between. Meet them halfway. */
+			threshold: (input.value / 2).max(1),
+		}
+	}
+
+	fn matches(&self, input: &PedalInput) -> bool {
+		self.message == input.message
+			&& self.number == input.number
+			&& self
+				.channel
+				.map_or(true, |channel| channel == input.channel)
+			&& self
+				.device
+				.as_ref()
+				.map_or(true, |device| input.device.contains(device.as_str()))
+	}
+
+	pub fn description(&self) -> String {
+		let message = match self.message {
+			MessageType::Controller => format!("Controller {} ≥ {}", self.number, self.threshold),
+			MessageType::Note => format!("Note {} ≥ {}", self.number, self.threshold),
+			MessageType::ProgramChange => format!("Program {}", self.number),
+		};
+		let channel = match self.channel {
+			Some(channel) => format!("channel {}", channel + 1),
+			None => "any channel".into(),
+		};
+		let device = self.device.as_deref().unwrap_or("any device");
+		format!("{}, {}, {}", message, channel, device)
+	}
+}
+
+/// How a pedal has to be pressed to trigger an action
+///
+/// Program changes have no release, so they only support [`Gesture::Press`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Gesture {
+	/// Press and release
+	Press,
+	/// Hold down for a while
+	LongPress,
+	/// Press twice in quick succession
+	DoublePress,
+	/// Press together with another pedal
+	Chord(PedalTrigger),
+}
+
+impl Default for Gesture {
+	fn default() -> Self {
+		Gesture::Press
+	}
+}
+
+impl Gesture {
+	pub fn description(&self) -> String {
+		match self {
+			Gesture::Press => "Press".into(),
+			Gesture::LongPress => "Long press".into(),
+			Gesture::DoublePress => "Double press".into(),
+			Gesture::Chord(other) => format!("Together with {}", other.description()),
+		}
+	}
+}
+
+/// Trigger an action when a pedal is pressed in a certain way
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PedalMapping {
+	#[serde(flatten)]
+	pub pedal: PedalTrigger,
+	#[serde(default)]
+	pub gesture: Gesture,
+	pub action: PageEvent,
+}
+
+impl PedalMapping {
+	/// Create a mapping that matches exactly this input, for the "learn" function
+	pub fn learn(input: &PedalInput, action: PageEvent) -> Self {
+		PedalMapping {
+			pedal: PedalTrigger::learn(input),
+			gesture: Gesture::Press,
+			action,
+		}
+	}
+
+	pub fn description(&self) -> String {
+		match self.gesture {
+			Gesture::Press => self.pedal.description(),
+			_ => format!(
+				"{} ({})",
+				self.pedal.description(),
+				self.gesture.description().to_lowercase()
+			),
+		}
+	}
+}
+
+/// Timing parameters of the gesture detection, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PedalTiming {
+	/// Holding a pedal at least this long makes a long press
+	pub long_press: u32,
+	/// Maximum pause between the two presses of a double press
+	pub double_press: u32,
+	/// Ignore changes that follow the previous one faster than this, against chattering contacts
+	pub debounce: u32,
+}
+
+impl Default for PedalTiming {
+	fn default() -> Self {
+		PedalTiming {
+			long_press: 600,
+			double_press: 300,
+			debounce: 30,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PedalConfig {
+	pub mappings: Vec<PedalMapping>,
+	pub timing: PedalTiming,
+}
+
+impl Default for PedalConfig {
+	fn default() -> Self {
+		PedalConfig {
+			mappings: vec![
+				PedalMapping {
+					pedal: PedalTrigger {
+						device: None,
+						channel: None,
+						message: MessageType::Controller,
+						number: 67,
+						threshold: 127,
+					},
+					gesture: Gesture::Press,
+					action: PageEvent::Next,
+				},
+				PedalMapping {
+					pedal: PedalTrigger {
+						device: None,
+						channel: None,
+						message: MessageType::Controller,
+						number: 66,
+						threshold: 127,
+					},
+					gesture: Gesture::Press,
+					action: PageEvent::Previous,
+				},
+			],
+			timing: PedalTiming::default(),
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum PedalConfigFile {
+	#[serde(rename = "0")]
+	V0 {
+		mappings: Vec<PedalMapping>,
+		timing: PedalTiming,
+	},
+}
+
+impl PedalConfig {
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("pedals.json") {
+			Some(path) => {
+				let file: PedalConfigFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					PedalConfigFile::V0 { mappings, timing } => PedalConfig { mappings, timing },
+				}
+			},
+			None => PedalConfig::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("pedals.json")?;
+		log::info!("Saving pedal configuration ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| {
+			serde_json::to_writer_pretty(
+				file,
+				&PedalConfigFile::V0 {
+					mappings: self.mappings.clone(),
+					timing: self.timing,
+				},
+			)
+		})
+		.context("Could not save pedal configuration (pedals.json)")?;
+		Ok(())
+	}
+
+	/// All distinct pedals that are used in a mapping
+	pub fn pedals(&self) -> Vec<PedalTrigger> {
+		let mut pedals: Vec<PedalTrigger> = Vec::new();
+		for mapping in &self.mappings {
+			let chord = match &mapping.gesture {
+				Gesture::Chord(other) => Some(other),
+				_ => None,
+			};
+			for pedal in std::iter::once(&mapping.pedal).chain(chord) {
+				if !pedals.contains(pedal) {
+					pedals.push(pedal.clone());
+				}
+			}
+		}
+		pedals
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+struct PedalState {
+	pressed: bool,
+	/// For debouncing
+	last_change: Option<Instant>,
+	pressed_since: Option<Instant>,
+	/// A short press that may become the first half of a double press
+	released_at: Option<Instant>,
+	/// The current press already triggered something and must not trigger anything on release
+	handled: bool,
+	/// The device that sent the last change
+	device: Option<String>,
+}
+
+/**
+ * Turns [`PedalInput`]s into actions according to a configuration
+ *
+ * Detects the gestures from the press and release timing. A pedal that only has plain presses
+ * mapped triggers immediately when pressed down. Otherwise, the decision has to wait for the
+ * release or for a timeout, which is why [`poll`](Self::poll) must be called regularly.
+ */
+#[derive(Debug)]
+pub struct PedalMapper {
+	config: PedalConfig,
+	/// All pedals from the configuration, see [`PedalConfig::pedals`]
+	pedals: Vec<PedalTrigger>,
+	state: Vec<PedalState>,
+}
+
+impl PedalMapper {
+	pub fn new(config: PedalConfig) -> Self {
+		let pedals = config.pedals();
+		PedalMapper {
+			state: vec![PedalState::default(); pedals.len()],
+			pedals,
+			config,
+		}
+	}
+
+	/// The actions mapped to a gesture of a pedal
+	fn actions(&self, pedal: usize, gesture: &Gesture) -> Vec<PageEvent> {
+		self.config
+			.mappings
+			.iter()
+			.filter(|mapping| mapping.pedal == self.pedals[pedal] && mapping.gesture == *gesture)
+			.map(|mapping| mapping.action)
+			.collect()
+	}
+
+	/// The actions mapped to pressing two pedals together, in any order
+	fn chord_actions(&self, a: usize, b: usize) -> Vec<PageEvent> {
+		let (a, b) = (&self.pedals[a], &self.pedals[b]);
+		self.config
+			.mappings
+			.iter()
+			.filter(|mapping| match &mapping.gesture {
+				Gesture::Chord(other) => {
+					(mapping.pedal == *a && other == b) || (mapping.pedal == *b && other == a)
+				},
+				_ => false,
+			})
+			.map(|mapping| mapping.action)
+			.collect()
+	}
+
+	fn has_gesture(&self, pedal: usize, gesture: &Gesture) -> bool {
+		!self.actions(pedal, gesture).is_empty()
+	}
+
+	/// Whether pressing the pedal down could still turn into something else than a plain press
+	fn is_ambiguous(&self, pedal: usize) -> bool {
+		self.has_gesture(pedal, &Gesture::LongPress)
+			|| self.has_gesture(pedal, &Gesture::DoublePress)
+			|| (0..self.pedals.len())
+				.any(|other| other != pedal && !self.chord_actions(pedal, other).is_empty())
+	}
+
+	fn is_long(&self, since: Instant, now: Instant) -> bool {
+		now.saturating_duration_since(since)
+			>= Duration::from_millis(self.config.timing.long_press as u64)
+	}
+
+	fn is_double(&self, released: Instant, now: Instant) -> bool {
+		now.saturating_duration_since(released)
+			<= Duration::from_millis(self.config.timing.double_press as u64)
+	}
+
+	/// Feed an input that arrived at time `now`
+	pub fn process(&mut self, input: &PedalInput, now: Instant) -> Vec<PageEvent> {
+		let mut events = Vec::new();
+		for pedal in 0..self.pedals.len() {
+			if !self.pedals[pedal].matches(input) {
+				continue;
+			}
+			if input.message == MessageType::ProgramChange {
+				/* Those don't have a release */
+				events.extend(self.actions(pedal, &Gesture::Press));
+				continue;
+			}
+
+			let pressed = input.value >= self.pedals[pedal].threshold;
+			let debounce = Duration::from_millis(self.config.timing.debounce as u64);
+			let state = &mut self.state[pedal];
+			if pressed == state.pressed {
+				continue;
+			}
+			if let Some(last_change) = state.last_change {
+				if now.saturating_duration_since(last_change) < debounce {
+					continue;
+				}
+			}
+			state.last_change = Some(now);
+			state.pressed = pressed;
+			state.device = Some(input.device.clone());
+
+			if pressed {
+				self.press(pedal, now, &mut events);
+			} else {
+				self.release(pedal, now, &mut events);
+			}
+		}
+		events
+	}
+
+	fn press(&mut self, pedal: usize, now: Instant, events: &mut Vec<PageEvent>) {
+		/* Another pedal is already held down and waiting for us */
+		let partner = (0..self.pedals.len()).find(|&other| {
+			other != pedal
+				&& self.state[other].pressed
+				&& !self.state[other].handled
+				&& !self.chord_actions(pedal, other).is_empty()
+		});
+		if let Some(other) = partner {
+			events.extend(self.chord_actions(pedal, other));
+			for index in [pedal, other] {
+				self.state[index].handled = true;
+				self.state[index].released_at = None;
+			}
+			return;
+		}
+
+		let previous_release = self.state[pedal].released_at.take();
+		self.state[pedal].pressed_since = Some(now);
+		self.state[pedal].handled = false;
+		if let Some(released) = previous_release {
+			if self.is_double(released, now) {
+				events.extend(self.actions(pedal, &Gesture::DoublePress));
+				self.state[pedal].handled = true;
+				return;
+			}
+			/* Too slow, the first one was a plain press after all */
+			events.extend(self.actions(pedal, &Gesture::Press));
+		}
+		if !self.is_ambiguous(pedal) {
+			events.extend(self.actions(pedal, &Gesture::Press));
+			self.state[pedal].handled = true;
+		}
+	}
+
+	fn release(&mut self, pedal: usize, now: Instant, events: &mut Vec<PageEvent>) {
+		let pressed_since = self.state[pedal].pressed_since.take();
+		if self.state[pedal].handled {
+			return;
+		}
+		self.state[pedal].handled = true;
+		if self.has_gesture(pedal, &Gesture::LongPress)
+			&& pressed_since.map_or(false, |since| self.is_long(since, now))
+		{
+			events.extend(self.actions(pedal, &Gesture::LongPress));
+		} else if self.has_gesture(pedal, &Gesture::DoublePress) {
+			self.state[pedal].released_at = Some(now);
+		} else {
+			events.extend(self.actions(pedal, &Gesture::Press));
+		}
+	}
+
+	/// Resolve gestures that are decided by time passing instead of an input
+	pub fn poll(&mut self, now: Instant) -> Vec<PageEvent> {
+		let mut events = Vec::new();
+		for pedal in 0..self.pedals.len() {
+			let state = self.state[pedal].clone();
+			if let (true, false, Some(since)) = (state.pressed, state.handled, state.pressed_since)
+			{
+				if self.has_gesture(pedal, &Gesture::LongPress) && self.is_long(since, now) {
+					events.extend(self.actions(pedal, &Gesture::LongPress));
+					self.state[pedal].handled = true;
+				}
+			}
+			if let Some(released) = state.released_at {
+				if !self.is_double(released, now) {
+					events.extend(self.actions(pedal, &Gesture::Press));
+					self.state[pedal].released_at = None;
+				}
+			}
+		}
+		events
+	}
+
+	/// Forget the state of the pedals last used on a device that is gone. Otherwise, a pedal
+	/// held down while unplugging would count as pressed until it gets plugged in again.
+	pub fn disconnect(&mut self, device: &str) {
+		for state in &mut self.state {
+			if state.device.as_deref() == Some(device) {
+				*state = PedalState::default();
+			}
+		}
+	}
 }
 
-pub fn run(midi_tx: Sender<PageEvent>) -> Result<Box<dyn Any>, Box<dyn Error>> {
-	/* PortMidi is awful. Not my fault. Please, someone make RtMidi bindings for Rust and save us! */
-	std::thread::spawn(move || {
-		let pm = pm::PortMidi::new().unwrap();
-		let mut suitable_ports = pm
+struct Shared {
+	mapper: PedalMapper,
+	/// When set, the next input is sent there instead of being mapped
+	learn: Option<Sender<PedalInput>>,
+}
+
+/// How often to poll the ports for new events
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// How often to look for newly connected devices
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Handle to the background MIDI listener
+#[derive(Clone)]
+pub struct Pedals {
+	shared: Arc<Mutex<Shared>>,
+	stop: Arc<AtomicBool>,
+	thread: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
+}
+
+impl Pedals {
+	pub fn config(&self) -> PedalConfig {
+		self.shared.lock().unwrap().mapper.config.clone()
+	}
+
+	/// Apply a new configuration and persist it
+	pub fn set_config(&self, config: PedalConfig) {
+		if let Err(err) = config.save() {
+			log::error!("{:?}", err);
+		}
+		self.shared.lock().unwrap().mapper = PedalMapper::new(config);
+	}
+
+	/// Capture the next pedal input
+	pub fn learn(&self, tx: Sender<PedalInput>) {
+		self.shared.lock().unwrap().learn = Some(tx);
+	}
+
+	pub fn stop_learning(&self) {
+		self.shared.lock().unwrap().learn = None;
+	}
+
+	/// Stop listening and wait for the background thread to finish
+	pub fn shutdown(&self) {
+		self.stop.store(true, Ordering::Relaxed);
+		if let Some(thread) = self.thread.lock().unwrap().take() {
+			if thread.join().is_err() {
+				log::error!("The MIDI pedal thread panicked");
+			}
+		}
+	}
+}
+
+/**
+ * Start listening for pedals in the background.
+ *
+ * Page events are sent to `midi_tx`. Whenever the set of connected pedals changes, their names
+ * are sent to `status_tx`. The listener stops when [`Pedals::shutdown`] is called or when
+ * `midi_tx` gets disconnected.
+ */
+pub fn run(
+	midi_tx: Sender<RemoteCommand>,
+	status_tx: Sender<Vec<String>>,
+) -> anyhow::Result<Pedals> {
+	let config = PedalConfig::load()
+		.context("Failed to load pedal configuration")
+		.unwrap_or_else(|err| {
+			log::error!("{:?}", err);
+			PedalConfig::default()
+		});
+	let shared = Arc::new(Mutex::new(Shared {
+		mapper: PedalMapper::new(config),
+		learn: None,
+	}));
+	let stop = Arc::new(AtomicBool::new(false));
+
+	let thread = std::thread::Builder::new()
+		.name("MIDI pedals".into())
+		.spawn({
+			let shared = shared.clone();
+			let stop = stop.clone();
+			move || listen(shared, stop, midi_tx, status_tx)
+		})
+		.context("Failed to spawn thread")?;
+
+	Ok(Pedals {
+		shared,
+		stop,
+		thread: Arc::new(Mutex::new(Some(thread))),
+	})
+}
+
+/* PortMidi is awful. Not my fault. Please, someone make RtMidi bindings for Rust and save us!
+ * It only enumerates devices on initialization, and re-initializing it closes all open ports.
+ * So we keep it and the ports around for as long as the system's MIDI devices stay the same,
+ * and only start over once they changed. It also has no blocking reads, hence the polling.
+ */
+fn listen(
+	shared: Arc<Mutex<Shared>>,
+	stop: Arc<AtomicBool>,
+	midi_tx: Sender<RemoteCommand>,
+	status_tx: Sender<Vec<String>>,
+) {
+	let mut connected: Option<Vec<String>> = None;
+	/* Returns false when nobody is listening anymore */
+	let mut update_connected = |names: Vec<String>| -> bool {
+		if connected.as_ref() == Some(&names) {
+			return true;
+		}
+		let previous = connected.replace(names.clone()).unwrap_or_default();
+		{
+			let mut shared = shared.lock().unwrap();
+			for device in previous.iter().filter(|device| !names.contains(device)) {
+				log::info!("MIDI device {} disconnected", device);
+				shared.mapper.disconnect(device);
+			}
+		}
+		if names.is_empty() {
+			log::info!("No midi ports found to listen on");
+		} else {
+			log::info!("Listening for MIDI pedals on {:?}", names);
+		}
+		status_tx.send(names).is_ok()
+	};
+
+	while !stop.load(Ordering::Relaxed) {
+		let pm = match pm::PortMidi::new() {
+			Ok(pm) => pm,
+			Err(err) => {
+				log::warn!("Failed to initialize PortMidi: {:?}", err);
+				/* Sleep in small steps to not delay shutdown */
+				let failure_time = Instant::now();
+				while failure_time.elapsed() < RESCAN_INTERVAL && !stop.load(Ordering::Relaxed) {
+					std::thread::sleep(POLL_INTERVAL);
+				}
+				continue;
+			},
+		};
+		/* Declared after `pm` so that they get dropped first */
+		let mut ports = pm
 			.devices()
-			.unwrap()
+			.unwrap_or_default()
 			.into_iter()
 			.filter(|d| d.direction() == portmidi::Direction::Input)
 			.filter(|d| !d.name().contains("Through"))
-			.inspect(|d| log::info!("Listening for MIDI pedals on {:?}", d))
-			.map(|d| pm.input_port(d, 12).unwrap())
+			.filter_map(|d| {
+				let name = d.name().to_owned();
+				pm.input_port(d, 12)
+					.map_err(|err| log::warn!("Failed to open MIDI port {}: {:?}", name, err))
+					.ok()
+					.map(|port| (name, port))
+			})
 			.collect::<Vec<_>>();
-		if suitable_ports.is_empty() {
-			log::info!("No midi ports found to listen on");
+
+		if !update_connected(ports.iter().map(|(name, _)| name.clone()).collect()) {
+			return;
 		}
+		/* Taken after the initialization, so that it includes our own client */
+		let devices = system_devices();
 
+		let mut scan_time = Instant::now();
 		loop {
-			std::thread::sleep(std::time::Duration::from_millis(75));
-			for port in &mut suitable_ports {
-				while let Ok(Some(event)) = port.read() {
-					if let Some(event) = MidiEvent::parse(&[
+			std::thread::sleep(POLL_INTERVAL);
+			if stop.load(Ordering::Relaxed) {
+				return;
+			}
+			let mut unplugged = Vec::new();
+			for (index, (device, port)) in ports.iter_mut().enumerate() {
+				loop {
+					let event = match port.read() {
+						Ok(Some(event)) => event,
+						Ok(None) => break,
+						Err(err) => {
+							/* Probably unplugged */
+							log::warn!("Failed to read from {}: {:?}", device, err);
+							unplugged.push(index);
+							break;
+						},
+					};
+					let input = MidiEvent::parse(&[
 						event.message.status,
 						event.message.data1,
 						event.message.data2,
-					]) {
-						dbg!(&event);
-						match event.event {
-							MidiEventType::Controller(67, 127) => {
-								midi_tx.send(PageEvent::Next).unwrap();
-							},
-							MidiEventType::Controller(66, 127) => {
-								midi_tx.send(PageEvent::Previous).unwrap();
-							},
-							_ => {},
+					])
+					.and_then(|event| PedalInput::from_midi(device, &event));
+					if let Some(input) = input {
+						log::debug!("MIDI input: {:?}", input);
+						let mut shared = shared.lock().unwrap();
+						/* Only learn presses, not releases */
+						if input.value > 0 && shared.learn.is_some() {
+							let _ = shared.learn.take().unwrap().send(input);
+							continue;
+						}
+						for event in shared.mapper.process(&input, Instant::now()) {
+							if midi_tx.send(event.into()).is_err() {
+								/* Nobody is listening anymore */
+								return;
+							}
 						}
 					}
 				}
 			}
+			if !unplugged.is_empty() {
+				/* Close only the broken ports, the others stay open */
+				for index in unplugged.into_iter().rev() {
+					ports.remove(index);
+				}
+				if !update_connected(ports.iter().map(|(name, _)| name.clone()).collect()) {
+					return;
+				}
+			}
+			/* Long presses and single presses that waited for a second one */
+			let events = shared.lock().unwrap().mapper.poll(Instant::now());
+			for event in events {
+				if midi_tx.send(event.into()).is_err() {
+					return;
+				}
+			}
+
+			if scan_time.elapsed() >= RESCAN_INTERVAL {
+				scan_time = Instant::now();
+				let changed = match &devices {
+					Some(devices) => system_devices().as_ref() != Some(devices),
+					/* We can't tell, so only look for new devices while there are none */
+					None => ports.is_empty(),
+				};
+				if changed {
+					break;
+				}
+			}
+		}
+	}
+}
+
+/**
+ * The names of the MIDI clients known to ALSA, to notice when devices get plugged in or out
+ * without having to re-initialize PortMidi.
+ *
+ * Only the client lines are taken, the rest of the file has statistics that change all the time.
+ * Returns `None` when not available, like on other systems than Linux.
+ */
+fn system_devices() -> Option<Vec<String>> {
+	std::fs::read_to_string("/proc/asound/seq/clients")
+		.ok()
+		.map(|clients| {
+			clients
+				.lines()
+				.filter(|line| line.starts_with("Client "))
+				.map(str::to_owned)
+				.collect()
+		})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn controller(number: u8, value: u8) -> PedalInput {
+		PedalInput {
+			device: "USB MIDI Pedal".into(),
+			channel: 0,
+			message: MessageType::Controller,
+			number,
+			value,
+		}
+	}
+
+	fn mapping(number: u8, gesture: Gesture, action: PageEvent) -> PedalMapping {
+		PedalMapping {
+			gesture,
+			..PedalMapping::learn(&controller(number, 127), action)
+		}
+	}
+
+	/// Feed raw MIDI messages at the given times (in milliseconds), polling in between
+	fn play(mapper: &mut PedalMapper, timeline: &[(u64, [u8; 3])]) -> Vec<(u64, PageEvent)> {
+		let start = Instant::now();
+		let at = |ms: u64| start + Duration::from_millis(ms);
+		let end = timeline.last().map_or(0, |(time, _)| *time) + 2000;
+		let mut timeline = timeline.iter().peekable();
+		let mut events = Vec::new();
+		for ms in (0..=end).step_by(10) {
+			while let Some((_, message)) = timeline.next_if(|(time, _)| *time <= ms) {
+				let input = MidiEvent::parse(message)
+					.and_then(|event| PedalInput::from_midi("USB MIDI Pedal", &event))
+					.unwrap();
+				events.extend(mapper.process(&input, at(ms)).into_iter().map(|e| (ms, e)));
+			}
+			events.extend(mapper.poll(at(ms)).into_iter().map(|e| (ms, e)));
 		}
-	});
+		events
+	}
+
+	const DOWN: [u8; 3] = [0xB0, 64, 127];
+	const UP: [u8; 3] = [0xB0, 64, 0];
+	const OTHER_DOWN: [u8; 3] = [0xB0, 65, 127];
+	const OTHER_UP: [u8; 3] = [0xB0, 65, 0];
+
+	#[test]
+	fn test_default_mapping() {
+		let start = Instant::now();
+		let at = |ms: u64| start + Duration::from_millis(ms);
+		let mut mapper = PedalMapper::new(PedalConfig::default());
+		assert_eq!(
+			mapper.process(&controller(67, 127), at(0)),
+			[PageEvent::Next]
+		);
+		assert_eq!(mapper.process(&controller(67, 0), at(100)), []);
+		assert_eq!(
+			mapper.process(&controller(66, 127), at(200)),
+			[PageEvent::Previous]
+		);
+		assert_eq!(mapper.process(&controller(64, 127), at(300)), []);
+	}
+
+	#[test]
+	fn test_threshold_and_release() {
+		let start = Instant::now();
+		let at = |ms: u64| start + Duration::from_millis(ms);
+		let input = controller(64, 64);
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![PedalMapping::learn(&input, PageEvent::JumpToStart)],
+			timing: PedalTiming::default(),
+		});
+		/* A continuous pedal only triggers once while held down */
+		assert_eq!(
+			mapper.process(&controller(64, 40), at(0)),
+			[PageEvent::JumpToStart]
+		);
+		assert_eq!(mapper.process(&controller(64, 64), at(100)), []);
+		assert_eq!(mapper.process(&controller(64, 10), at(200)), []);
+		assert_eq!(mapper.process(&controller(64, 0), at(300)), []);
+		assert_eq!(
+			mapper.process(&controller(64, 64), at(400)),
+			[PageEvent::JumpToStart]
+		);
+		/* Wrong channel, wrong device */
+		assert_eq!(
+			mapper.process(
+				&PedalInput {
+					channel: 3,
+					..controller(64, 127)
+				},
+				at(500)
+			),
+			[]
+		);
+		assert_eq!(
+			mapper.process(
+				&PedalInput {
+					device: "Keyboard".into(),
+					..controller(64, 127)
+				},
+				at(600)
+			),
+			[]
+		);
+	}
+
+	#[test]
+	fn test_debounce() {
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![mapping(64, Gesture::Press, PageEvent::Next)],
+			timing: PedalTiming::default(),
+		});
+		/* A chattering contact on press and on release */
+		let timeline = [
+			(0, DOWN),
+			(10, UP),
+			(20, DOWN),
+			(200, UP),
+			(210, DOWN),
+			(220, UP),
+			(500, DOWN),
+		];
+		assert_eq!(
+			play(&mut mapper, &timeline),
+			[(0, PageEvent::Next), (500, PageEvent::Next)]
+		);
+	}
+
+	#[test]
+	fn test_long_press() {
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![
+				mapping(64, Gesture::Press, PageEvent::Next),
+				mapping(64, Gesture::LongPress, PageEvent::NextPiece),
+			],
+			timing: PedalTiming::default(),
+		});
+		/* A short press triggers on release, a long one while still held */
+		let timeline = [(0, DOWN), (200, UP), (1000, DOWN), (3000, UP)];
+		assert_eq!(
+			play(&mut mapper, &timeline),
+			[(200, PageEvent::Next), (1600, PageEvent::NextPiece)]
+		);
+	}
+
+	#[test]
+	fn test_disconnect() {
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![
+				mapping(64, Gesture::Press, PageEvent::Next),
+				mapping(64, Gesture::LongPress, PageEvent::NextPiece),
+			],
+			timing: PedalTiming::default(),
+		});
+		let start = Instant::now();
+		let at = |ms: u64| start + Duration::from_millis(ms);
+
+		/* Unplugged while held down */
+		assert_eq!(mapper.process(&controller(64, 127), at(0)), []);
+		mapper.disconnect("Some other device");
+		assert!(mapper.state[0].pressed);
+		mapper.disconnect("USB MIDI Pedal");
+		assert_eq!(mapper.poll(at(1000)), []);
+
+		/* Plugged in again, the first press is a fresh one */
+		assert_eq!(mapper.process(&controller(64, 127), at(2000)), []);
+		assert_eq!(
+			mapper.process(&controller(64, 0), at(2100)),
+			[PageEvent::Next]
+		);
+	}
+
+	#[test]
+	fn test_double_press() {
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![
+				mapping(64, Gesture::Press, PageEvent::Next),
+				mapping(64, Gesture::DoublePress, PageEvent::ToggleAutoScroll),
+			],
+			timing: PedalTiming::default(),
+		});
+		let timeline = [
+			/* Double press, triggers on the second press */
+			(0, DOWN),
+			(100, UP),
+			(250, DOWN),
+			(350, UP),
+			/* Single press, triggers once the second one did not come */
+			(1000, DOWN),
+			(1100, UP),
+			/* Two presses too far apart */
+			(2000, DOWN),
+			(2100, UP),
+			(2600, DOWN),
+			(2700, UP),
+		];
+		assert_eq!(
+			play(&mut mapper, &timeline),
+			[
+				(250, PageEvent::ToggleAutoScroll),
+				(1410, PageEvent::Next),
+				(2410, PageEvent::Next),
+				(3010, PageEvent::Next),
+			]
+		);
+	}
+
+	#[test]
+	fn test_chord() {
+		let other = PedalTrigger::learn(&controller(65, 127));
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![
+				mapping(64, Gesture::Press, PageEvent::Next),
+				mapping(65, Gesture::Press, PageEvent::Previous),
+				mapping(64, Gesture::Chord(other), PageEvent::JumpToStart),
+			],
+			timing: PedalTiming::default(),
+		});
+		let timeline = [
+			/* Both together, in any order, trigger only the chord */
+			(0, OTHER_DOWN),
+			(50, DOWN),
+			(200, UP),
+			(220, OTHER_UP),
+			/* Each one alone */
+			(1000, DOWN),
+			(1100, UP),
+			(2000, OTHER_DOWN),
+			(2100, OTHER_UP),
+		];
+		assert_eq!(
+			play(&mut mapper, &timeline),
+			[
+				(50, PageEvent::JumpToStart),
+				(1100, PageEvent::Next),
+				(2100, PageEvent::Previous),
+			]
+		);
+	}
 
-	/* In other implementations, this can be used to keep an object alive for the time the program runs */
-	Ok(Box::new(()))
+	#[test]
+	fn test_program_change() {
+		let mut mapper = PedalMapper::new(PedalConfig {
+			mappings: vec![PedalMapping::learn(
+				&PedalInput {
+					message: MessageType::ProgramChange,
+					number: 3,
+					..controller(0, 127)
+				},
+				PageEvent::Previous,
+			)],
+			timing: PedalTiming::default(),
+		});
+		/* No releases, so every message counts */
+		let timeline = [(0, [0xC0, 3, 0]), (100, [0xC0, 3, 0]), (200, [0xC0, 4, 0])];
+		assert_eq!(
+			play(&mut mapper, &timeline),
+			[(0, PageEvent::Previous), (100, PageEvent::Previous)]
+		);
+	}
 }
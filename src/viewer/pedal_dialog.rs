@@ -0,0 +1,296 @@
+//! Configure the MIDI pedal mappings
+//!
+//! New mappings are added by "learning" them: the next pedal input is captured
+//! and mapped to an action, which can then be changed. Learning a pedal again adds
+//! another mapping for it, so that different gestures can do different things.
+
+use crate::{
+	input::PageEvent,
+	pedal::{Gesture, MessageType, PedalConfig, PedalMapping, PedalTiming, Pedals},
+};
+use dinoscore::prelude::*;
+
+glib::wrapper! {
+	pub struct PedalDialog(ObjectSubclass<imp::PedalDialog>)
+		@extends gtk::Window, gtk::Widget,
+		@implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Native,
+					gtk::Root, gtk::ShortcutManager;
+}
+
+impl PedalDialog {
+	pub fn new(pedals: Pedals, parent: &impl IsA<gtk::Window>) -> Self {
+		let obj: Self = Object::new(&[]);
+		obj.set_transient_for(Some(parent));
+		obj.imp().pedals.set(pedals).unwrap();
+		obj.imp().load_timing();
+		obj.imp().reload_mappings();
+		obj
+	}
+}
+
+mod imp {
+	use super::*;
+
+	#[derive(CompositeTemplate, Default)]
+	#[template(resource = "/de/piegames/dinoscore/viewer/pedal_dialog.ui")]
+	pub struct PedalDialog {
+		#[template_child]
+		mappings: TemplateChild<gtk::ListBox>,
+		#[template_child]
+		learn_button: TemplateChild<gtk::ToggleButton>,
+		#[template_child]
+		learn_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		long_press_time: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		double_press_time: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		debounce_time: TemplateChild<gtk::SpinButton>,
+
+		pub pedals: OnceCell<Pedals>,
+		/// Don't save while filling in the timing values
+		loading_timing: Cell<bool>,
+	}
+
+	#[glib::object_subclass]
+	impl ObjectSubclass for PedalDialog {
+		const NAME: &'static str = "ViewerPedalDialog";
+		type Type = super::PedalDialog;
+		type ParentType = gtk::Window;
+
+		fn class_init(klass: &mut Self::Class) {
+			klass.bind_template();
+			klass.bind_template_callbacks();
+		}
+
+		fn instance_init(obj: &InitializingObject<Self>) {
+			obj.init_template();
+		}
+	}
+
+	impl ObjectImpl for PedalDialog {}
+
+	impl WidgetImpl for PedalDialog {}
+
+	impl WindowImpl for PedalDialog {
+		fn close_request(&self) -> gtk::Inhibit {
+			if let Some(pedals) = self.pedals.get() {
+				pedals.stop_learning();
+			}
+			self.parent_close_request()
+		}
+	}
+
+	#[gtk::template_callbacks]
+	impl PedalDialog {
+		/// Modify the configuration, save and apply it
+		fn update_config(&self, update: impl FnOnce(&mut PedalConfig)) -> PedalConfig {
+			let pedals = self.pedals.get().unwrap();
+			let mut config = pedals.config();
+			update(&mut config);
+			pedals.set_config(config.clone());
+			config
+		}
+
+		/// Recreate all rows from the current configuration
+		pub fn reload_mappings(&self) {
+			while let Some(row) = self.mappings.first_child() {
+				self.mappings.remove(&row);
+			}
+
+			let config = self.pedals.get().unwrap().config();
+			let pedals = config.pedals();
+			for (index, mapping) in config.mappings.iter().enumerate() {
+				let row = adw::ActionRow::builder()
+					.title(&mapping.description())
+					.build();
+
+				/* Program changes only have plain presses. Chords are possible with every other pedal */
+				let mut gestures = vec![Gesture::Press];
+				if mapping.pedal.message != MessageType::ProgramChange {
+					gestures.push(Gesture::LongPress);
+					gestures.push(Gesture::DoublePress);
+					gestures.extend(
+						pedals
+							.iter()
+							.filter(|pedal| **pedal != mapping.pedal)
+							.filter(|pedal| pedal.message != MessageType::ProgramChange)
+							.map(|pedal| Gesture::Chord(pedal.clone())),
+					);
+				}
+				let gesture = gtk::ComboBoxText::builder()
+					.valign(gtk::Align::Center)
+					.tooltip_text("How the pedal has to be pressed")
+					.build();
+				for (i, g) in gestures.iter().enumerate() {
+					gesture.append(Some(&i.to_string()), &g.description());
+				}
+				if let Some(active) = gestures.iter().position(|g| *g == mapping.gesture) {
+					gesture.set_active_id(Some(&active.to_string()));
+				}
+				gesture.connect_changed(clone_!(self, move |obj, gesture| {
+					let selected = gesture
+						.active_id()
+						.and_then(|id| id.parse::<usize>().ok())
+						.map(|i| gestures[i].clone());
+					if let Some(selected) = selected {
+						obj.imp().update_config(|config| {
+							config.mappings[index].gesture = selected;
+						});
+						obj.imp().reload_mappings();
+					}
+				}));
+				row.add_suffix(&gesture);
+
+				let action = gtk::ComboBoxText::builder()
+					.valign(gtk::Align::Center)
+					.tooltip_text("What to do when the pedal is pressed")
+					.build();
+				for (i, event) in PageEvent::ALL.iter().enumerate() {
+					action.append(Some(&i.to_string()), event.description());
+				}
+				let active = PageEvent::ALL
+					.iter()
+					.position(|event| *event == mapping.action)
+					.unwrap();
+				action.set_active_id(Some(&active.to_string()));
+				action.connect_changed(clone_!(self, move |obj, action| {
+					let event = action
+						.active_id()
+						.and_then(|id| id.parse::<usize>().ok())
+						.map(|i| PageEvent::ALL[i]);
+					if let Some(event) = event {
+						obj.imp().update_config(|config| {
+							config.mappings[index].action = event;
+						});
+					}
+				}));
+				row.add_suffix(&action);
+
+				let threshold = gtk::SpinButton::with_range(1.0, 127.0, 1.0);
+				threshold.set_valign(gtk::Align::Center);
+				threshold.set_tooltip_text(Some("The pedal counts as pressed from this value on"));
+				threshold.set_value(mapping.pedal.threshold as f64);
+				threshold.set_sensitive(mapping.pedal.message != MessageType::ProgramChange);
+				let obj = self.instance();
+				threshold.connect_value_changed(clone!(@weak obj, @weak row => move |threshold| {
+					let value = threshold.value_as_int() as u8;
+					let config = obj.imp().update_config(|config| {
+						config.mappings[index].pedal.threshold = value;
+					});
+					/* The title contains the threshold as well */
+					row.set_title(&config.mappings[index].description());
+				}));
+				row.add_suffix(&threshold);
+
+				let delete = gtk::Button::builder()
+					.icon_name("user-trash-symbolic")
+					.tooltip_text("Remove this mapping")
+					.valign(gtk::Align::Center)
+					.build();
+				delete.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_config(|config| {
+						config.mappings.remove(index);
+					});
+					obj.imp().reload_mappings();
+				}));
+				row.add_suffix(&delete);
+
+				self.mappings.append(&row);
+			}
+		}
+
+		#[template_callback]
+		fn on_learn_toggled(&self) {
+			let pedals = self.pedals.get().unwrap();
+			if !self.learn_button.is_active() {
+				pedals.stop_learning();
+				self.learn_status
+					.set_label("Press “Learn” and then a pedal to add it");
+				return;
+			}
+
+			self.learn_status.set_label("Press the pedal now …");
+			let (learn_tx, learn_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+			pedals.learn(learn_tx);
+			learn_rx.attach(
+				None,
+				clone_!(self, move |obj, input| {
+					let imp = obj.imp();
+					/* Do this first, because it resets the status label */
+					imp.learn_button.set_active(false);
+					/* A known pedal gets the next gesture that is still free */
+					let config = imp.pedals.get().unwrap().config();
+					let known = config.mappings.iter().find(|mapping| {
+						mapping.pedal.message == input.message
+							&& mapping.pedal.number == input.number
+							&& mapping.pedal.device.as_ref() == Some(&input.device)
+					});
+					let mapping = match known {
+						None => Some(PedalMapping::learn(&input, PageEvent::Next)),
+						Some(known) if known.pedal.message != MessageType::ProgramChange => {
+							[Gesture::Press, Gesture::LongPress, Gesture::DoublePress]
+								.into_iter()
+								.find(|gesture| {
+									!config.mappings.iter().any(|mapping| {
+										mapping.pedal == known.pedal && mapping.gesture == *gesture
+									})
+								})
+								.map(|gesture| PedalMapping {
+									pedal: known.pedal.clone(),
+									gesture,
+									action: PageEvent::Next,
+								})
+						},
+						Some(_) => None,
+					};
+					match mapping {
+						Some(mapping) => {
+							imp.update_config(|config| config.mappings.push(mapping));
+							imp.reload_mappings();
+							imp.learn_status
+								.set_label("Pedal added. Choose what it should do.");
+						},
+						None => {
+							imp.learn_status.set_label(&format!(
+								"“{}” is already mapped",
+								PedalMapping::learn(&input, PageEvent::Next).description()
+							));
+						},
+					}
+					Continue(false)
+				}),
+			);
+		}
+
+		pub fn load_timing(&self) {
+			let timing = self.pedals.get().unwrap().config().timing;
+			self.loading_timing.set(true);
+			self.long_press_time.set_value(timing.long_press as f64);
+			self.double_press_time.set_value(timing.double_press as f64);
+			self.debounce_time.set_value(timing.debounce as f64);
+			self.loading_timing.set(false);
+		}
+
+		#[template_callback]
+		fn on_timing_changed(&self) {
+			if self.loading_timing.get() || self.pedals.get().is_none() {
+				return;
+			}
+			let timing = PedalTiming {
+				long_press: self.long_press_time.value_as_int() as u32,
+				double_press: self.double_press_time.value_as_int() as u32,
+				debounce: self.debounce_time.value_as_int() as u32,
+			};
+			self.update_config(|config| config.timing = timing);
+		}
+
+		/// Throw away all mappings and go back to the default ones
+		#[template_callback]
+		fn on_reset(&self) {
+			self.update_config(|config| *config = PedalConfig::default());
+			self.load_timing();
+			self.reload_mappings();
+		}
+	}
+}
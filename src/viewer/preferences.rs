@@ -0,0 +1,377 @@
+//! Application preferences
+//!
+//! The key bindings for keyboard-emulating pedals can be loaded from a preset and then
+//! modified. New keys are added by pressing them. Remote control through OSC can be enabled,
+//! and the viewer can lead or follow the page turns of other viewers, or turn them by itself.
+//! Annotations can be made in an external editor of choice.
+
+use crate::{
+	annotation_editor::{EditorConfig, EditorKind},
+	input::{KeyBinding, KeyBindings, PageEvent, PRESETS},
+	osc::OscConfig,
+	song_widget::AutoScrollConfig,
+	sync::{SyncConfig, SyncRole},
+};
+use dinoscore::prelude::*;
+
+glib::wrapper! {
+	pub struct PreferencesWindow(ObjectSubclass<imp::PreferencesWindow>)
+		@extends adw::PreferencesWindow, adw::Window, gtk::Window, gtk::Widget,
+		@implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::Native,
+					gtk::Root, gtk::ShortcutManager;
+}
+
+impl PreferencesWindow {
+	pub fn new(song: &crate::song_widget::SongWidget, parent: &impl IsA<gtk::Window>) -> Self {
+		let obj: Self = Object::new(&[]);
+		obj.set_transient_for(Some(parent));
+		obj.imp().song.set(song.clone()).unwrap();
+		obj.imp().reload_bindings();
+		obj.imp().load_osc();
+		obj.imp().load_sync();
+		obj.imp().load_auto_scroll();
+		obj.imp().load_annotation_editor();
+		obj
+	}
+}
+
+mod imp {
+	use super::*;
+
+	#[derive(CompositeTemplate, Default)]
+	#[template(resource = "/de/piegames/dinoscore/viewer/preferences.ui")]
+	pub struct PreferencesWindow {
+		#[template_child]
+		preset: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		bindings: TemplateChild<gtk::ListBox>,
+		#[template_child]
+		add_key_button: TemplateChild<gtk::ToggleButton>,
+		#[template_child]
+		add_key_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		osc_enabled: TemplateChild<gtk::Switch>,
+		#[template_child]
+		osc_port: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		osc_public: TemplateChild<gtk::Switch>,
+		#[template_child]
+		osc_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		sync_role: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		sync_port: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		sync_loopback: TemplateChild<gtk::Switch>,
+		#[template_child]
+		sync_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		auto_scroll_interval: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		annotation_editor: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		pdf_annotator_row: TemplateChild<adw::ActionRow>,
+		#[template_child]
+		pdf_annotator: TemplateChild<gtk::Entry>,
+
+		pub song: OnceCell<crate::song_widget::SongWidget>,
+		/// Don't apply while filling in the OSC values
+		loading_osc: Cell<bool>,
+		/// Don't apply while filling in the sync values
+		loading_sync: Cell<bool>,
+		/// Don't apply while filling in the auto-scroll values
+		loading_auto_scroll: Cell<bool>,
+		/// Don't apply while filling in the annotation editor values
+		loading_annotation_editor: Cell<bool>,
+	}
+
+	#[glib::object_subclass]
+	impl ObjectSubclass for PreferencesWindow {
+		const NAME: &'static str = "ViewerPreferences";
+		type Type = super::PreferencesWindow;
+		type ParentType = adw::PreferencesWindow;
+
+		fn class_init(klass: &mut Self::Class) {
+			klass.bind_template();
+			klass.bind_template_callbacks();
+		}
+
+		fn instance_init(obj: &InitializingObject<Self>) {
+			obj.init_template();
+		}
+	}
+
+	impl ObjectImpl for PreferencesWindow {
+		fn constructed(&self) {
+			self.parent_constructed();
+			for (i, preset) in PRESETS.iter().enumerate() {
+				self.preset.append(Some(&i.to_string()), preset.name);
+			}
+			self.preset.set_active(Some(0));
+		}
+	}
+
+	impl WidgetImpl for PreferencesWindow {}
+
+	impl WindowImpl for PreferencesWindow {}
+
+	impl AdwWindowImpl for PreferencesWindow {}
+
+	impl PreferencesWindowImpl for PreferencesWindow {}
+
+	#[gtk::template_callbacks]
+	impl PreferencesWindow {
+		/// Modify the key bindings, save and apply them
+		fn update_bindings(&self, update: impl FnOnce(&mut KeyBindings)) {
+			let song = self.song.get().unwrap();
+			let mut key_bindings = song.key_bindings();
+			update(&mut key_bindings);
+			song.set_key_bindings(key_bindings);
+		}
+
+		/// Recreate all rows from the current key bindings
+		pub fn reload_bindings(&self) {
+			while let Some(row) = self.bindings.first_child() {
+				self.bindings.remove(&row);
+			}
+
+			let key_bindings = self.song.get().unwrap().key_bindings();
+			for (index, binding) in key_bindings.bindings.iter().enumerate() {
+				let row = adw::ActionRow::builder().title(&binding.key).build();
+
+				let action = gtk::ComboBoxText::builder()
+					.valign(gtk::Align::Center)
+					.tooltip_text("What to do when the key is pressed")
+					.build();
+				for (i, event) in PageEvent::ALL.iter().enumerate() {
+					action.append(Some(&i.to_string()), event.description());
+				}
+				let active = PageEvent::ALL
+					.iter()
+					.position(|event| *event == binding.action)
+					.unwrap();
+				action.set_active_id(Some(&active.to_string()));
+				action.connect_changed(clone_!(self, move |obj, action| {
+					let event = action
+						.active_id()
+						.and_then(|id| id.parse::<usize>().ok())
+						.map(|i| PageEvent::ALL[i]);
+					if let Some(event) = event {
+						obj.imp().update_bindings(|key_bindings| {
+							key_bindings.bindings[index].action = event;
+						});
+					}
+				}));
+				row.add_suffix(&action);
+
+				let delete = gtk::Button::builder()
+					.icon_name("user-trash-symbolic")
+					.tooltip_text("Remove this key")
+					.valign(gtk::Align::Center)
+					.build();
+				delete.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_bindings(|key_bindings| {
+						key_bindings.bindings.remove(index);
+					});
+					obj.imp().reload_bindings();
+				}));
+				row.add_suffix(&delete);
+
+				self.bindings.append(&row);
+			}
+		}
+
+		/// Replace all key bindings with the selected preset
+		#[template_callback]
+		fn on_apply_preset(&self) {
+			let preset = self
+				.preset
+				.active_id()
+				.and_then(|id| id.parse::<usize>().ok())
+				.map(|i| &PRESETS[i]);
+			if let Some(preset) = preset {
+				self.update_bindings(|key_bindings| {
+					*key_bindings = KeyBindings::from_preset(preset)
+				});
+				self.reload_bindings();
+			}
+		}
+
+		#[template_callback]
+		fn on_add_key_toggled(&self) {
+			if self.add_key_button.is_active() {
+				self.add_key_status.set_label("Press the pedal now …");
+			} else {
+				self.add_key_status
+					.set_label("Press “Add” and then a pedal to add the key it sends");
+			}
+		}
+
+		/// While adding a key, capture the next key press
+		#[template_callback]
+		fn on_key_pressed(&self, keyval: gdk::Key) -> gtk::Inhibit {
+			if !self.add_key_button.is_active() {
+				return gtk::Inhibit(false);
+			}
+			let name = match keyval.to_lower().name() {
+				Some(name) => name.to_string(),
+				None => return gtk::Inhibit(false),
+			};
+			/* Do this first, because it resets the status label */
+			self.add_key_button.set_active(false);
+			if self
+				.song
+				.get()
+				.unwrap()
+				.key_bindings()
+				.action_for(&name)
+				.is_some()
+			{
+				self.add_key_status
+					.set_label(&format!("“{}” is already bound", name));
+			} else {
+				self.update_bindings(|key_bindings| {
+					key_bindings.bindings.push(KeyBinding {
+						key: name,
+						action: PageEvent::Next,
+					});
+				});
+				self.reload_bindings();
+				self.add_key_status
+					.set_label("Key added. Choose what it should do.");
+			}
+			gtk::Inhibit(true)
+		}
+
+		pub fn load_osc(&self) {
+			let config = self.song.get().unwrap().osc().config();
+			self.loading_osc.set(true);
+			self.osc_enabled.set_active(config.enabled);
+			self.osc_port.set_value(config.port as f64);
+			self.osc_public.set_active(config.public);
+			self.loading_osc.set(false);
+			self.update_osc_status(Ok(()));
+		}
+
+		fn update_osc_status(&self, result: anyhow::Result<()>) {
+			let config = self.song.get().unwrap().osc().config();
+			self.osc_status.set_label(&match result {
+				Err(err) => format!("{:#}", err),
+				Ok(()) if config.enabled => format!("Listening on port {}", config.port),
+				Ok(()) => "Not listening".into(),
+			});
+		}
+
+		#[template_callback]
+		fn on_osc_changed(&self) {
+			if self.loading_osc.get() || self.song.get().is_none() {
+				return;
+			}
+			let config = OscConfig {
+				enabled: self.osc_enabled.is_active(),
+				port: self.osc_port.value_as_int() as u16,
+				public: self.osc_public.is_active(),
+			};
+			let result = self.song.get().unwrap().osc().set_config(config);
+			self.update_osc_status(result);
+		}
+
+		pub fn load_sync(&self) {
+			let config = self.song.get().unwrap().page_sync().config();
+			self.loading_sync.set(true);
+			self.sync_role.set_active_id(Some(match config.role {
+				SyncRole::Off => "off",
+				SyncRole::Leader => "leader",
+				SyncRole::Follower => "follower",
+			}));
+			self.sync_port.set_value(config.port as f64);
+			self.sync_loopback.set_active(config.loopback);
+			self.loading_sync.set(false);
+			self.update_sync_status(Ok(()));
+		}
+
+		fn update_sync_status(&self, result: anyhow::Result<()>) {
+			let config = self.song.get().unwrap().page_sync().config();
+			self.sync_status.set_label(&match result {
+				Err(err) => format!("{:#}", err),
+				Ok(()) => match config.role {
+					SyncRole::Off => "Not synchronizing".into(),
+					SyncRole::Leader => format!("Sending page turns on port {}", config.port),
+					SyncRole::Follower => format!("Following page turns on port {}", config.port),
+				},
+			});
+		}
+
+		#[template_callback]
+		fn on_sync_changed(&self) {
+			if self.loading_sync.get() || self.song.get().is_none() {
+				return;
+			}
+			let role = match self.sync_role.active_id().as_deref() {
+				Some("leader") => SyncRole::Leader,
+				Some("follower") => SyncRole::Follower,
+				_ => SyncRole::Off,
+			};
+			let config = SyncConfig {
+				role,
+				port: self.sync_port.value_as_int() as u16,
+				loopback: self.sync_loopback.is_active(),
+			};
+			let result = self.song.get().unwrap().page_sync().set_config(config);
+			self.update_sync_status(result);
+		}
+
+		pub fn load_auto_scroll(&self) {
+			let config = self.song.get().unwrap().auto_scroll_config();
+			self.loading_auto_scroll.set(true);
+			self.auto_scroll_interval.set_value(config.interval as f64);
+			self.loading_auto_scroll.set(false);
+		}
+
+		#[template_callback]
+		fn on_auto_scroll_changed(&self) {
+			if self.loading_auto_scroll.get() || self.song.get().is_none() {
+				return;
+			}
+			let config = AutoScrollConfig {
+				interval: self.auto_scroll_interval.value_as_int() as u32,
+			};
+			self.song.get().unwrap().set_auto_scroll_config(config);
+		}
+
+		pub fn load_annotation_editor(&self) {
+			let config = self.song.get().unwrap().annotation_editor_config();
+			self.loading_annotation_editor.set(true);
+			self.annotation_editor
+				.set_active_id(Some(match config.kind {
+					EditorKind::Xournal => "xournal",
+					EditorKind::PdfAnnotator => "pdf-annotator",
+				}));
+			self.pdf_annotator.set_text(&config.pdf_annotator);
+			self.pdf_annotator_row
+				.set_sensitive(config.kind == EditorKind::PdfAnnotator);
+			self.loading_annotation_editor.set(false);
+		}
+
+		#[template_callback]
+		fn on_annotation_editor_changed(&self) {
+			if self.loading_annotation_editor.get() || self.song.get().is_none() {
+				return;
+			}
+			let kind = match self.annotation_editor.active_id().as_deref() {
+				Some("pdf-annotator") => EditorKind::PdfAnnotator,
+				_ => EditorKind::Xournal,
+			};
+			self.pdf_annotator_row
+				.set_sensitive(kind == EditorKind::PdfAnnotator);
+			let config = EditorConfig {
+				kind,
+				pdf_annotator: self.pdf_annotator.text().to_string(),
+			};
+			self.song
+				.get()
+				.unwrap()
+				.set_annotation_editor_config(config);
+		}
+	}
+}
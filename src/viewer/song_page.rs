@@ -26,20 +26,62 @@ impl SongPage {
 		pages: Rc<
 			TiVec<collection::PageIndex, RefCell<(Option<gdk::Texture>, Option<poppler::Page>)>>,
 		>,
+		annotations: Rc<RefCell<annotations::Annotations>>,
+		notes: Rc<RefCell<Vec<library::StaffNote>>>,
 	) -> Self {
 		let obj: Self = Object::new(&[]);
 		obj.imp().song.set(song).unwrap();
 		obj.imp().pages.set(pages).unwrap();
+		obj.imp().annotations.set(annotations).unwrap();
+		obj.imp().notes.set(notes).unwrap();
 		obj.update_layout(layout);
 		obj
 	}
 
+	/// A small preview of the page. The layout is scaled down to fit into the widget.
+	pub fn new_thumbnail(
+		song: Arc<collection::SongMeta>,
+		layout: PageLayout,
+		pages: Rc<
+			TiVec<collection::PageIndex, RefCell<(Option<gdk::Texture>, Option<poppler::Page>)>>,
+		>,
+		annotations: Rc<RefCell<annotations::Annotations>>,
+		notes: Rc<RefCell<Vec<library::StaffNote>>>,
+		width: i32,
+	) -> Self {
+		let height = width * layout.height / layout.width.max(1);
+		let obj = Self::new(song, layout, pages, annotations, notes);
+		obj.imp().thumbnail.set(true);
+		obj.set_hexpand(false);
+		obj.set_vexpand(false);
+		obj.set_size_request(width, height);
+		obj
+	}
+
 	pub fn update_layout(&self, layout: PageLayout) {
 		*self.imp().layout.borrow_mut() = Some(layout);
 		self.queue_draw();
 	}
+
+	/// Tint each staff by how much time was spent on it (see [`library::LibrarySong::heatmap`])
+	pub fn set_heatmap(&self, heatmap: Option<Rc<Vec<f64>>>) {
+		*self.imp().heatmap.borrow_mut() = heatmap;
+		self.queue_draw();
+	}
+
+	/// Where the marker for the notes of a staff is drawn: its top right corner
+	pub fn note_marker(staff_layout: &layout::StaffLayout) -> graphene::Rect {
+		graphene::Rect::new(
+			(staff_layout.x + staff_layout.width - NOTE_MARKER_SIZE - 4.0) as f32,
+			(staff_layout.y + 4.0) as f32,
+			NOTE_MARKER_SIZE as f32,
+			NOTE_MARKER_SIZE as f32,
+		)
+	}
 }
 
+const NOTE_MARKER_SIZE: f64 = 24.0;
+
 mod imp {
 	use super::*;
 
@@ -55,6 +97,14 @@ mod imp {
 				>,
 			>,
 		>,
+		/// Our own ink annotations
+		pub annotations: OnceCell<Rc<RefCell<annotations::Annotations>>>,
+		/// Typed notes, shown as markers on their staves
+		pub notes: OnceCell<Rc<RefCell<Vec<library::StaffNote>>>>,
+		/// Scale the layout to our size instead of drawing it as is
+		pub thumbnail: Cell<bool>,
+		/// The relative dwell time of each staff, if it should be shown
+		pub heatmap: RefCell<Option<Rc<Vec<f64>>>>,
 	}
 
 	#[glib::object_subclass]
@@ -90,13 +140,21 @@ mod imp {
 			if obj.width() < 1 || obj.height() < 1 {
 				return;
 			}
-			let bounds = graphene::Rect::new(0.0, 0.0, obj.width() as f32, obj.height() as f32);
+			let mut bounds = graphene::Rect::new(0.0, 0.0, obj.width() as f32, obj.height() as f32);
 			/* Make sure we don't render outside of out widget */
 			snapshot.push_clip(&bounds);
 
 			let layout = self.layout.borrow();
 			let layout = layout.as_ref().unwrap();
 
+			snapshot.save();
+			if self.thumbnail.get() && layout.width > 0 && layout.height > 0 {
+				let scale = (obj.width() as f32 / layout.width as f32)
+					.min(obj.height() as f32 / layout.height as f32);
+				snapshot.scale(scale, scale);
+				bounds = graphene::Rect::new(0.0, 0.0, layout.width as f32, layout.height as f32);
+			}
+
 			/* The actual rendering code. Might be called twice for dark mode */
 			let render = || {
 				snapshot.append_color(&gdk::RGBA::WHITE, &bounds);
@@ -186,6 +244,62 @@ mod imp {
 							page.render(&context);
 						}
 
+						/* Render our own annotations */
+						let ink = self.annotations.get().unwrap().borrow();
+						if ink.strokes_on(staff.page).next().is_some()
+							|| ink.stamps_on(staff.page).next().is_some()
+						{
+							let context = snapshot.append_cairo(&bounds);
+
+							context.translate(staff_layout.x, staff_layout.y);
+							let scale = staff_layout.width / staff.width();
+							context.scale(scale, scale);
+							context.translate(-staff.start.0, -staff.start.1);
+							context.rectangle(
+								staff.start.0,
+								staff.start.1,
+								staff.width(),
+								staff.height(),
+							);
+							context.clip();
+
+							draw_annotations(&context, &ink, staff.page)?;
+						}
+
+						/* Heat map */
+						let heat = self
+							.heatmap
+							.borrow()
+							.as_ref()
+							.and_then(|heatmap| heatmap.get(*staff_layout.index).copied())
+							.unwrap_or(0.0);
+						if heat > 0.0 {
+							snapshot.append_color(
+								&gdk::RGBA::new(1.0, 0.3, 0.0, 0.5 * heat as f32),
+								&graphene::Rect::new(
+									staff_layout.x as f32,
+									staff_layout.y as f32,
+									staff_layout.width as f32,
+									staff_layout.width as f32 * staff.aspect_ratio() as f32,
+								),
+							);
+						}
+
+						/* Note marker, with the number of notes */
+						let notes = self
+							.notes
+							.get()
+							.unwrap()
+							.borrow()
+							.iter()
+							.filter(|note| note.staff == staff_layout.index)
+							.count();
+						if notes > 0 {
+							let marker = super::SongPage::note_marker(staff_layout);
+							let context = snapshot.append_cairo(&bounds);
+							draw_note_marker(&context, &marker, notes)?;
+						}
+
 						cairo::Result::Ok(())
 					})
 					.expect("Failed to draw");
@@ -207,9 +321,97 @@ mod imp {
 				render();
 			}
 
+			snapshot.restore();
 			snapshot.pop();
 		}
 	}
 
 	impl SongPage {}
+
+	fn draw_note_marker(
+		context: &cairo::Context,
+		marker: &graphene::Rect,
+		notes: usize,
+	) -> cairo::Result<()> {
+		let (x, y) = (marker.x() as f64, marker.y() as f64);
+		let (width, height) = (marker.width() as f64, marker.height() as f64);
+		context.set_source_rgba(0.98, 0.82, 0.2, 0.9);
+		context.rectangle(x, y, width, height);
+		context.fill()?;
+		context.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+		context.set_font_size(height * 0.7);
+		let text = notes.to_string();
+		let extents = context.text_extents(&text)?;
+		context.move_to(
+			x + (width - extents.width()) / 2.0 - extents.x_bearing(),
+			y + (height - extents.height()) / 2.0 - extents.y_bearing(),
+		);
+		context.show_text(&text)?;
+		Ok(())
+	}
+
+	/// Draw the annotations of a page, in page coordinates
+	fn draw_annotations(
+		context: &cairo::Context,
+		annotations: &annotations::Annotations,
+		page: collection::PageIndex,
+	) -> cairo::Result<()> {
+		context.set_line_cap(cairo::LineCap::Round);
+		context.set_line_join(cairo::LineJoin::Round);
+		for stroke in annotations.strokes_on(page) {
+			let (r, g, b, a) = stroke.tool.color();
+			context.set_source_rgba(r, g, b, a);
+			match stroke.points.as_slice() {
+				[] => {},
+				[point] => {
+					/* A dot */
+					context.arc(
+						point.x,
+						point.y,
+						stroke.width_at(point, point) / 2.0,
+						0.0,
+						2.0 * std::f64::consts::PI,
+					);
+					context.fill()?;
+				},
+				points => {
+					/* Varying pressure means varying width, so each segment needs its own line.
+					 * Translucent strokes would get darker where the segments overlap, thus those
+					 * are drawn as a whole with the average width.
+					 */
+					if a < 1.0 {
+						let average = points
+							.windows(2)
+							.map(|segment| stroke.width_at(&segment[0], &segment[1]))
+							.sum::<f64>() / (points.len() - 1) as f64;
+						context.set_line_width(average);
+						context.move_to(points[0].x, points[0].y);
+						for point in &points[1..] {
+							context.line_to(point.x, point.y);
+						}
+						context.stroke()?;
+					} else {
+						for segment in points.windows(2) {
+							context.set_line_width(stroke.width_at(&segment[0], &segment[1]));
+							context.move_to(segment[0].x, segment[0].y);
+							context.line_to(segment[1].x, segment[1].y);
+							context.stroke()?;
+						}
+					}
+				},
+			}
+		}
+
+		context.set_source_rgba(0.1, 0.1, 0.6, 1.0);
+		context.set_font_size(annotations::Stamp::SIZE);
+		for stamp in annotations.stamps_on(page) {
+			let extents = context.text_extents(&stamp.text)?;
+			context.move_to(
+				stamp.x - extents.width() / 2.0 - extents.x_bearing(),
+				stamp.y - extents.height() / 2.0 - extents.y_bearing(),
+			);
+			context.show_text(&stamp.text)?;
+		}
+		Ok(())
+	}
 }
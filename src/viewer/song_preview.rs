@@ -46,10 +46,32 @@ mod imp {
 		stats_time_played: TemplateChild<gtk::Label>,
 		#[template_child]
 		stats_last_played: TemplateChild<gtk::Label>,
+		#[template_child]
+		setlist_name: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		continue_button: TemplateChild<gtk::Button>,
+		#[template_child]
+		goal_song: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		goal_piece: TemplateChild<gtk::SpinButton>,
+		#[template_child]
+		goal_piece_label: TemplateChild<gtk::Label>,
+		#[template_child]
+		goal_piece_unit: TemplateChild<gtk::Label>,
+		/// Don't save the goals while showing them
+		loading_goals: Cell<bool>,
+		#[template_child]
+		notes_box: TemplateChild<gtk::Box>,
+		#[template_child]
+		notes_list: TemplateChild<gtk::ListBox>,
+		/// Where each row of the notes list jumps to
+		notes_targets: RefCell<Vec<collection::StaffIndex>>,
 
 		pub library: OnceCell<Rc<RefCell<library::Library>>>,
 		pub library_widget: OnceCell<crate::library_widget::LibraryWidget>,
 		song_uuid: Cell<uuid::Uuid>,
+		/// Kept alive while it is shown
+		export_dialog: RefCell<Option<gtk::FileChooserNative>>,
 		inhibit_autoscroll: Cell<bool>,
 	}
 
@@ -91,6 +113,9 @@ mod imp {
 	#[gtk::template_callbacks]
 	impl SongPreview {
 		pub fn on_item_selected(&self, song: uuid::Uuid) {
+			/* The last position and notes may have changed since then, even if the song didn't */
+			self.update_continue_button(song);
+			self.update_notes(song);
 			if song == self.song_uuid.get() {
 				return;
 			}
@@ -182,6 +207,115 @@ mod imp {
 				.load_song(self.song_uuid.get(), 0.into());
 		}
 
+		/* Where should "continue" start, if it makes sense at all? */
+		fn continue_position(&self, song: uuid::Uuid) -> Option<collection::StaffIndex> {
+			let library = self.library.get().unwrap().borrow();
+			let n_staves = library.songs.get(&song)?.index.staves.len();
+			library
+				.stats
+				.get(&song)?
+				.last_position
+				/* The song might have been edited in the meantime */
+				.filter(|&staff| *staff > 0 && *staff < n_staves)
+		}
+
+		fn update_continue_button(&self, song: uuid::Uuid) {
+			self.continue_button
+				.set_visible(self.continue_position(song).is_some());
+		}
+
+		/// List the notes of the song, each with its staff
+		fn update_notes(&self, song: uuid::Uuid) {
+			while let Some(row) = self.notes_list.first_child() {
+				self.notes_list.remove(&row);
+			}
+			let mut targets = self.notes_targets.borrow_mut();
+			targets.clear();
+			let library = self.library.get().unwrap().borrow();
+			let meta = &library.songs[&song].index;
+			let notes = library.stats[&song]
+				.notes
+				.iter()
+				/* The song might have been edited in the meantime */
+				.filter(|note| *note.staff < meta.staves.len());
+			for note in notes {
+				let (page, staff) = meta.page_of_piece(note.staff);
+				let label = gtk::Label::builder()
+					.label(&format!("{}-{}  {}", *page + 1, *staff, note.text))
+					.wrap(true)
+					.xalign(0.0)
+					.build();
+				let row = gtk::ListBoxRow::builder().child(&label).build();
+				self.notes_list.append(&row);
+				targets.push(note.staff);
+			}
+			self.notes_box
+				.set_visible(self.notes_list.first_child().is_some());
+		}
+
+		#[template_callback]
+		fn on_note_activated(&self, row: &gtk::ListBoxRow) {
+			let staff = self
+				.notes_targets
+				.borrow()
+				.get(row.index() as usize)
+				.copied();
+			if let Some(staff) = staff {
+				self.library_widget
+					.get()
+					.unwrap()
+					.load_song(self.song_uuid.get(), staff);
+			}
+		}
+
+		#[template_callback]
+		fn on_export_notes(&self) {
+			let library = self.library.get().unwrap().borrow();
+			let song = &library.songs[&self.song_uuid.get()];
+			let markdown = library.stats[&self.song_uuid.get()].notes_to_markdown(&song.index);
+
+			let filter = gtk::FileFilter::new();
+			filter.add_mime_type("text/markdown");
+			let choose = gtk::FileChooserNative::builder()
+				.title("Export notes")
+				.action(gtk::FileChooserAction::Save)
+				.modal(true)
+				.filter(&filter)
+				.build();
+			if let Some(root) = self.instance().root() {
+				choose.set_transient_for(root.downcast_ref::<gtk::Window>());
+			}
+			choose.set_current_name(&format!("{}.md", song.title().unwrap_or("Notes")));
+			choose.connect_response(clone_!(self, move |obj, dialog, response| {
+				if response == gtk::ResponseType::Accept {
+					if let Some(path) = dialog.file().and_then(|file| file.path()) {
+						if let Err(err) = std::fs::write(&path, &markdown) {
+							log::error!(
+								"Failed to export the notes to '{}': {:?}",
+								path.display(),
+								err
+							);
+						}
+					}
+				}
+				obj.imp().export_dialog.take();
+			}));
+			choose.show();
+			*self.export_dialog.borrow_mut() = Some(choose);
+		}
+
+		/* The "continue" button below the "play" button */
+		#[template_callback]
+		fn on_continue_button_pressed(&self) {
+			let start_at = self
+				.continue_position(self.song_uuid.get())
+				.unwrap_or_else(|| 0.into());
+			self.library_widget
+				.get()
+				.unwrap()
+				.load_song(self.song_uuid.get(), start_at);
+		}
+
 		/* That's the small "▶" button next to the part_name */
 		#[template_callback]
 		fn on_quick_play_button_pressed(&self) {
@@ -202,6 +336,112 @@ mod imp {
 				.load_song(self.song_uuid.get(), start_at);
 		}
 
+		#[template_callback]
+		fn on_goal_popover_shown(&self) {
+			let library = self.library.get().unwrap().borrow();
+			let song = &library.songs[&self.song_uuid.get()].index;
+			let stats = &library.stats[&self.song_uuid.get()];
+			let piece = self.shown_piece(song);
+			let multiple_pieces = song.piece_starts.len() > 1;
+
+			self.loading_goals.set(true);
+			self.goal_song
+				.set_value(stats.practice_target(None).unwrap_or(0) as f64);
+			self.goal_piece
+				.set_value(stats.practice_target(Some(piece)).unwrap_or(0) as f64);
+			self.loading_goals.set(false);
+			for widget in [
+				self.goal_piece.upcast_ref::<gtk::Widget>(),
+				self.goal_piece_label.upcast_ref(),
+				self.goal_piece_unit.upcast_ref(),
+			] {
+				widget.set_visible(multiple_pieces);
+			}
+		}
+
+		#[template_callback]
+		fn on_goal_changed(&self) {
+			if self.loading_goals.get() {
+				return;
+			}
+			let mut library = self.library.get().unwrap().borrow_mut();
+			let piece = self.shown_piece(&library.songs[&self.song_uuid.get()].index);
+			let every_days =
+				|spin: &gtk::SpinButton| Some(spin.value_as_int() as u32).filter(|&days| days > 0);
+			let stats = library.stats.get_mut(&self.song_uuid.get()).unwrap();
+			stats.set_practice_target(None, every_days(&self.goal_song));
+			if self.goal_piece.is_visible() {
+				stats.set_practice_target(Some(piece), every_days(&self.goal_piece));
+			}
+			library.save_in_background();
+		}
+
+		/// The first staff of the piece shown in the preview
+		fn shown_piece(&self, song: &collection::SongMeta) -> collection::StaffIndex {
+			*song
+				.piece_starts
+				.keys()
+				.nth(self.part_preview.position() as usize)
+				.unwrap_or(&collection::StaffIndex(0))
+		}
+
+		#[template_callback]
+		fn on_setlist_popover_shown(&self) {
+			let library = self.library.get().unwrap().borrow();
+			self.setlist_name.remove_all();
+			for setlist in &library.setlists {
+				self.setlist_name.append_text(&setlist.name);
+			}
+		}
+
+		/* Append the currently previewed part to the chosen setlist, creating it if necessary */
+		#[template_callback]
+		fn on_add_to_setlist(&self) {
+			let name = self
+				.setlist_name
+				.child()
+				.and_then(|entry| entry.downcast::<gtk::Entry>().ok())
+				.map(|entry| entry.text().trim().to_owned())
+				.unwrap_or_default();
+			if name.is_empty() {
+				return;
+			}
+
+			let mut library = self.library.get().unwrap().borrow_mut();
+			let piece = *library
+				.songs
+				.get(&self.song_uuid.get())
+				.unwrap()
+				.index
+				.piece_starts
+				.keys()
+				.nth(self.part_preview.position() as usize)
+				.unwrap();
+			let index = match library
+				.setlists
+				.iter()
+				.position(|setlist| setlist.name == name)
+			{
+				Some(index) => index,
+				None => {
+					library.setlists.push(library::Setlist {
+						name,
+						entries: Vec::new(),
+					});
+					library.setlists.len() - 1
+				},
+			};
+			library.setlists[index].entries.push(library::SetlistEntry {
+				song: self.song_uuid.get(),
+				piece,
+				scale_mode: None,
+			});
+			library.save_in_background();
+			std::mem::drop(library);
+
+			self.library_widget.get().unwrap().update_setlists();
+		}
+
 		/** Called every 20 seconds
 		 * Flip the page of the preview carousel, slide show style.
 		 * Don't do that when the user has the mouse near it to not
@@ -1,6 +1,13 @@
+use anyhow::Context;
 use dinoscore::{library::ScaleMode, prelude::*, *};
+use serde::{Deserialize, Serialize};
 
-use std::sync::mpsc::*;
+use std::{collections::BTreeSet, sync::mpsc::*};
+
+/// The width of the page previews in the overview
+const THUMBNAIL_WIDTH: i32 = 240;
+/// In pixels
+const ERASER_RADIUS: f64 = 12.0;
 
 glib::wrapper! {
 	pub struct SongWidget(ObjectSubclass<imp::SongWidget>)
@@ -25,6 +32,62 @@ impl SongWidget {
 			.load_song(song, Arc::new(pages), scale_mode, start_at);
 	}
 
+	/// Play only that piece as part of a setlist. At its end, "next" emits `end-of-entry` instead
+	pub fn set_setlist_piece(&self, piece: Option<collection::StaffIndex>) {
+		if let Some(song) = self.imp().song.borrow_mut().as_mut() {
+			song.setlist_piece = piece;
+		}
+	}
+
+	#[cfg(unix)]
+	pub fn pedals(&self) -> Option<crate::pedal::Pedals> {
+		self.imp().pedals.get().cloned()
+	}
+
+	pub fn osc(&self) -> &crate::osc::Osc {
+		self.imp().osc.get().unwrap()
+	}
+
+	pub fn page_sync(&self) -> &crate::sync::PageSync {
+		self.imp().page_sync.get().unwrap()
+	}
+
+	pub fn key_bindings(&self) -> crate::input::KeyBindings {
+		self.imp().key_bindings.borrow().clone()
+	}
+
+	/// Apply new key bindings and persist them
+	pub fn set_key_bindings(&self, key_bindings: crate::input::KeyBindings) {
+		if let Err(err) = key_bindings.save() {
+			log::error!("{:?}", err);
+		}
+		*self.imp().key_bindings.borrow_mut() = key_bindings;
+	}
+
+	pub fn annotation_editor_config(&self) -> crate::annotation_editor::EditorConfig {
+		self.imp().annotation_editor_config.borrow().clone()
+	}
+
+	/// Choose another program for annotating and persist the choice
+	pub fn set_annotation_editor_config(&self, config: crate::annotation_editor::EditorConfig) {
+		if let Err(err) = config.save() {
+			log::error!("{:?}", err);
+		}
+		*self.imp().annotation_editor_config.borrow_mut() = config;
+	}
+
+	pub fn auto_scroll_config(&self) -> AutoScrollConfig {
+		*self.imp().auto_scroll_config.borrow()
+	}
+
+	/// Persist the new settings. They apply the next time auto-scroll is started
+	pub fn set_auto_scroll_config(&self, config: AutoScrollConfig) {
+		if let Err(err) = config.save() {
+			log::error!("{:?}", err);
+		}
+		*self.imp().auto_scroll_config.borrow_mut() = config;
+	}
+
 	#[cfg(test)]
 	pub fn part_selection(&self) -> gtk::ComboBoxText {
 		self.imp().part_selection.get()
@@ -41,6 +104,57 @@ impl SongWidget {
 	}
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutoScrollConfig {
+	/// How long to stay on each page, in seconds
+	pub interval: u32,
+}
+
+impl Default for AutoScrollConfig {
+	fn default() -> Self {
+		AutoScrollConfig { interval: 30 }
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum AutoScrollConfigFile {
+	#[serde(rename = "0")]
+	V0(AutoScrollConfig),
+}
+
+impl AutoScrollConfig {
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("auto-scroll.json") {
+			Some(path) => {
+				let file: AutoScrollConfigFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					AutoScrollConfigFile::V0(config) => config,
+				}
+			},
+			None => AutoScrollConfig::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("auto-scroll.json")?;
+		log::info!("Saving auto-scroll configuration ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| serde_json::to_writer_pretty(file, &AutoScrollConfigFile::V0(*self)))
+			.context("Could not save auto-scroll configuration (auto-scroll.json)")?;
+		Ok(())
+	}
+}
+
 mod imp {
 	use super::*;
 
@@ -60,8 +174,65 @@ mod imp {
 		pub part_selection: TemplateChild<gtk::ComboBoxText>,
 		/* Needed to inhibit that signal sometimes. */
 		part_selection_changed_signal: OnceCell<glib::SignalHandlerId>,
+		/* Part profiles: show only some staves */
+		#[template_child]
+		part_profile: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		part_profile_name: TemplateChild<gtk::Entry>,
+		#[template_child]
+		part_profile_kind: TemplateChild<gtk::ComboBoxText>,
+		#[template_child]
+		part_profile_staves: TemplateChild<gtk::Entry>,
+		#[template_child]
+		part_profile_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		delete_part_profile_button: TemplateChild<gtk::Button>,
 		#[template_child]
 		pub zoom_button: TemplateChild<gtk::MenuButton>,
+		#[template_child]
+		performance_pass: TemplateChild<gtk::Label>,
+		#[template_child]
+		pedal_status: TemplateChild<gtk::Image>,
+		/* Annotations */
+		#[template_child]
+		annotation_toolbar: TemplateChild<gtk::Revealer>,
+		#[template_child]
+		annotation_gesture: TemplateChild<gtk::GestureDrag>,
+		#[template_child]
+		stamp_selection: TemplateChild<gtk::ComboBoxText>,
+		/// Stateful, draw on the pages instead of turning them
+		annotating: gio::SimpleAction,
+		/// Stateful, one of "pen", "highlighter", "eraser" or "stamp"
+		annotation_tool: gio::SimpleAction,
+		/// Where the current stroke began. All of its points are relative to that staff
+		drawing: RefCell<Option<layout::StaffLayout>>,
+		/// The song that is open in the external annotation editor, if any
+		annotation_editor: Cell<Option<uuid::Uuid>>,
+		annotation_editor_config: RefCell<crate::annotation_editor::EditorConfig>,
+		#[template_child]
+		notes_popover: TemplateChild<gtk::Popover>,
+		#[template_child]
+		notes_title: TemplateChild<gtk::Label>,
+		#[template_child]
+		notes_list: TemplateChild<gtk::ListBox>,
+		#[template_child]
+		notes_entry: TemplateChild<gtk::Entry>,
+		/// The staff whose notes are shown in the popover
+		notes_staff: Cell<Option<collection::StaffIndex>>,
+		#[template_child]
+		go_to_popover: TemplateChild<gtk::Popover>,
+		#[template_child]
+		go_to_entry: TemplateChild<gtk::SearchEntry>,
+		#[template_child]
+		go_to_status: TemplateChild<gtk::Label>,
+		#[template_child]
+		view_stack: TemplateChild<gtk::Stack>,
+		#[template_child]
+		overview_grid: TemplateChild<gtk::FlowBox>,
+		/// The page previews in the overview, to redraw them as pages get rendered
+		overview_pages: RefCell<Vec<crate::song_page::SongPage>>,
+		/// Kept alive while it is shown
+		export_dialog: RefCell<Option<gtk::FileChooserNative>>,
 
 		pub library: OnceCell<Rc<RefCell<library::Library>>>,
 		song: RefCell<Option<SongState>>,
@@ -72,6 +243,14 @@ mod imp {
 		previous: gio::SimpleAction,
 		next_piece: gio::SimpleAction,
 		previous_piece: gio::SimpleAction,
+		/// Stateful, turn pages automatically
+		auto_scroll: gio::SimpleAction,
+		auto_scroll_timer: RefCell<Option<glib::source::SourceId>>,
+		auto_scroll_config: RefCell<AutoScrollConfig>,
+		/// Stateful, show all pages in a grid
+		overview: gio::SimpleAction,
+		/// Stateful, show where the most time is spent in the overview
+		heatmap: gio::SimpleAction,
 		/* Zoom */
 		#[template_child]
 		zoom_gesture: TemplateChild<gtk::GestureZoom>,
@@ -84,6 +263,14 @@ mod imp {
 		song_load_time: Cell<Option<std::time::Instant>>,
 
 		hide_cursor: RefCell<Option<glib::source::SourceId>>,
+		/// For keyboard-emulating pedals
+		key_bindings: RefCell<crate::input::KeyBindings>,
+		/// The MIDI pedal listener
+		#[cfg(unix)]
+		pub pedals: OnceCell<crate::pedal::Pedals>,
+		pub osc: OnceCell<crate::osc::Osc>,
+		/// Page turning along with other viewers
+		pub page_sync: OnceCell<crate::sync::PageSync>,
 	}
 
 	#[glib::object_subclass]
@@ -103,6 +290,22 @@ mod imp {
 			actions.add_action(&previous);
 			actions.add_action(&previous_piece);
 			actions.add_action(&next_piece);
+			let auto_scroll =
+				gio::SimpleAction::new_stateful("auto-scroll", None, &false.to_variant());
+			actions.add_action(&auto_scroll);
+			let overview = gio::SimpleAction::new_stateful("overview", None, &false.to_variant());
+			actions.add_action(&overview);
+			let heatmap = gio::SimpleAction::new_stateful("heatmap", None, &false.to_variant());
+			actions.add_action(&heatmap);
+			let annotating =
+				gio::SimpleAction::new_stateful("annotating", None, &false.to_variant());
+			actions.add_action(&annotating);
+			let annotation_tool = gio::SimpleAction::new_stateful(
+				"annotation-tool",
+				Some(&String::static_variant_type()),
+				&"pen".to_variant(),
+			);
+			actions.add_action(&annotation_tool);
 
 			let sizing_mode_action = gio::SimpleAction::new_stateful(
 				"sizing-mode",
@@ -118,7 +321,35 @@ mod imp {
 				song_progress: Default::default(),
 				part_selection: Default::default(),
 				part_selection_changed_signal: Default::default(),
+				part_profile: Default::default(),
+				part_profile_name: Default::default(),
+				part_profile_kind: Default::default(),
+				part_profile_staves: Default::default(),
+				part_profile_status: Default::default(),
+				delete_part_profile_button: Default::default(),
 				zoom_button: Default::default(),
+				performance_pass: Default::default(),
+				pedal_status: Default::default(),
+				annotation_toolbar: Default::default(),
+				annotation_gesture: Default::default(),
+				stamp_selection: Default::default(),
+				annotating,
+				annotation_tool,
+				drawing: Default::default(),
+				annotation_editor: Default::default(),
+				annotation_editor_config: Default::default(),
+				notes_popover: Default::default(),
+				notes_title: Default::default(),
+				notes_list: Default::default(),
+				notes_entry: Default::default(),
+				notes_staff: Default::default(),
+				go_to_popover: Default::default(),
+				go_to_entry: Default::default(),
+				go_to_status: Default::default(),
+				view_stack: Default::default(),
+				overview_grid: Default::default(),
+				overview_pages: Default::default(),
+				export_dialog: Default::default(),
 				library: Default::default(),
 				song: Default::default(),
 
@@ -127,6 +358,11 @@ mod imp {
 				previous,
 				next_piece,
 				previous_piece,
+				auto_scroll,
+				auto_scroll_timer: Default::default(),
+				auto_scroll_config: Default::default(),
+				overview,
+				heatmap,
 				zoom_gesture: Default::default(),
 				scroll_gesture: Default::default(),
 
@@ -135,6 +371,11 @@ mod imp {
 				song_load_time: Default::default(),
 
 				hide_cursor: Default::default(),
+				key_bindings: Default::default(),
+				#[cfg(unix)]
+				pedals: Default::default(),
+				osc: Default::default(),
+				page_sync: Default::default(),
 			}
 		}
 
@@ -175,6 +416,37 @@ mod imp {
 					None,                       /* default */
 					glib::ParamFlags::READABLE, /* read-only */
 				),
+				glib::ParamSpecString::new(
+					"piece", /* name */
+					"piece", /* nickname */
+					"name of the current piece",
+					None,                       /* default */
+					glib::ParamFlags::READABLE, /* read-only */
+				),
+				glib::ParamSpecInt::new(
+					"page", /* name */
+					"page", /* nickname */
+					"current page, -1 if no song is loaded",
+					-1,                         /* minimum */
+					i32::MAX,                   /* maximum */
+					-1,                         /* default */
+					glib::ParamFlags::READABLE, /* read-only */
+				),
+			]))
+		}
+
+		fn signals() -> &'static [glib::subclass::Signal] {
+			Box::leak(Box::new([
+				/* Going forward at the end of the current setlist entry */
+				glib::subclass::Signal::builder("end-of-entry").build(),
+				/* A remote control asks for a song, by UUID or title, starting at a staff */
+				glib::subclass::Signal::builder("load-song")
+					.param_types([String::static_type(), u64::static_type()])
+					.build(),
+				/* Something the user should know about, but that doesn't need an answer */
+				glib::subclass::Signal::builder("show-message")
+					.param_types([String::static_type()])
+					.build(),
 			]))
 		}
 
@@ -192,6 +464,23 @@ mod imp {
 					.as_ref()
 					.map(|song| song.song.song_uuid.to_string())
 					.to_value(),
+				"piece" => self
+					.song
+					.borrow()
+					.as_ref()
+					.and_then(|song| {
+						song.song
+							.piece_starts
+							.get(&song.part_start(song.page))
+							.cloned()
+					})
+					.to_value(),
+				"page" => self
+					.song
+					.borrow()
+					.as_ref()
+					.map_or(-1, |song| *song.page as i32)
+					.to_value(),
 				_ => unimplemented!(),
 			}
 		}
@@ -201,6 +490,8 @@ mod imp {
 			let obj = self.obj();
 
 			obj.insert_action_group("song", Some(&self.actions));
+			/* It points at the staves, so it needs to live on the carousel */
+			self.notes_popover.set_parent(&*self.carousel);
 
 			self.part_selection_changed_signal
 				.set(
@@ -227,6 +518,35 @@ mod imp {
 				.connect_activate(clone_!(self, move |obj, _a, _p| {
 					obj.imp().previous_piece();
 				}));
+			self.auto_scroll
+				.connect_change_state(clone_!(self, move |obj, action, state| {
+					let enabled = state.and_then(|state| state.get::<bool>()).unwrap_or(false);
+					action.set_state(&enabled.to_variant());
+					obj.imp().set_auto_scroll(enabled);
+				}));
+			self.overview
+				.connect_change_state(clone_!(self, move |obj, action, state| {
+					let enabled = state.and_then(|state| state.get::<bool>()).unwrap_or(false);
+					action.set_state(&enabled.to_variant());
+					obj.imp().set_overview(enabled);
+				}));
+			self.heatmap
+				.connect_change_state(clone_!(self, move |obj, action, state| {
+					let enabled = state.and_then(|state| state.get::<bool>()).unwrap_or(false);
+					action.set_state(&enabled.to_variant());
+					obj.imp().update_heatmap();
+				}));
+			self.annotating
+				.connect_change_state(clone_!(self, move |obj, action, state| {
+					let enabled = state.and_then(|state| state.get::<bool>()).unwrap_or(false);
+					action.set_state(&enabled.to_variant());
+					obj.imp().set_annotating(enabled);
+				}));
+			for (text, description) in annotations::STAMPS {
+				self.stamp_selection
+					.append(Some(text), &format!("{}  {}", text, description));
+			}
+			self.stamp_selection.set_active(Some(0));
 			self.sizing_mode_action
 				.connect_activate(clone_!(self, move |obj, _a, p| {
 					obj.imp().scale_mode_changed(p.unwrap());
@@ -244,28 +564,70 @@ mod imp {
 			}));
 			self.carousel.add_controller(&hide_mouse_controller);
 
-			/* MIDI handling */
+			match crate::input::KeyBindings::load() {
+				Ok(key_bindings) => *self.key_bindings.borrow_mut() = key_bindings,
+				Err(err) => log::error!("Failed to load key bindings: {:?}", err),
+			}
+			match AutoScrollConfig::load() {
+				Ok(config) => *self.auto_scroll_config.borrow_mut() = config,
+				Err(err) => log::error!("Failed to load the auto-scroll configuration: {:?}", err),
+			}
+			match crate::annotation_editor::EditorConfig::load() {
+				Ok(config) => *self.annotation_editor_config.borrow_mut() = config,
+				Err(err) => log::error!(
+					"Failed to load the annotation editor configuration: {:?}",
+					err
+				),
+			}
+
+			/* Remote control: MIDI pedals, OSC and other viewers */
+			let (remote_tx, remote_rx) = glib::MainContext::channel(glib::Priority::default());
 			#[cfg(unix)]
 			{
-				let (midi_tx, midi_rx) = glib::MainContext::channel(glib::Priority::default());
-				let handler = crate::pedal::run(midi_tx).unwrap();
-				midi_rx.attach(
+				let (status_tx, status_rx) = glib::MainContext::channel(glib::Priority::default());
+				match crate::pedal::run(remote_tx.clone(), status_tx) {
+					Ok(pedals) => self.pedals.set(pedals).unwrap(),
+					Err(err) => log::error!("Failed to start listening for MIDI pedals: {:?}", err),
+				}
+				status_rx.attach(
 					None,
-					clone!(@weak obj => @default-return Continue(false), move |event| {
-						/* Reference the MIDI handler which holds the Sender so that it doesn't get dropped. */
-						let _handler = &handler;
-						match event {
-							crate::pedal::PageEvent::Next => {
-								obj.imp().next.activate(None);
-							},
-							crate::pedal::PageEvent::Previous => {
-								obj.imp().previous.activate(None);
-							},
-						}
+					clone!(@weak obj => @default-return Continue(false), move |devices: Vec<String>| {
+						let pedal_status = &obj.imp().pedal_status;
+						pedal_status.set_visible(!devices.is_empty());
+						pedal_status.set_tooltip_text(Some(&format!(
+							"MIDI pedals connected: {}",
+							devices.join(", ")
+						)));
 						Continue(true)
 					}),
 				);
 			}
+			self.page_sync
+				.set(crate::sync::PageSync::new(remote_tx.clone()))
+				.unwrap();
+			self.osc.set(crate::osc::Osc::new(remote_tx)).unwrap();
+			remote_rx.attach(
+				None,
+				clone!(@weak obj => @default-return Continue(false), move |command| {
+					obj.imp().remote_command(command);
+					Continue(true)
+				}),
+			);
+		}
+
+		fn dispose(&self) {
+			self.notes_popover.unparent();
+			if let Some(osc) = self.osc.get() {
+				osc.shutdown();
+			}
+			if let Some(page_sync) = self.page_sync.get() {
+				page_sync.shutdown();
+			}
+			/* Don't leave the MIDI thread running when we're gone */
+			#[cfg(unix)]
+			if let Some(pedals) = self.pedals.get() {
+				pedals.shutdown();
+			}
 		}
 	}
 
@@ -305,7 +667,8 @@ mod imp {
 
 			self.carousel.grab_focus();
 
-			let song = SongState::new(
+			let page_identity = annotations::PageIdentity::new(&song, &pages);
+			let mut song = SongState::new(
 				renderer,
 				Rc::new(
 					std::iter::repeat(Default::default())
@@ -331,11 +694,46 @@ mod imp {
 
 			self.sizing_mode_action
 				.set_state(&scale_mode.action_string().to_variant());
+			let library = self.library.get().unwrap().borrow();
+			/* Songs from outside the collection are read-only */
+			let read_only = library.is_read_only(&song.song.song_uuid);
+			/* Without own annotations, start with those shared along with the song */
+			let annotations = annotations::Annotations::load(&song.song.song_uuid).map(|own| {
+				match &library.songs[&song.song.song_uuid].annotations {
+					Some(shared) if own.is_empty() => shared.clone(),
+					_ => own,
+				}
+			});
+			std::mem::drop(library);
+			/* The pages may have changed since the annotations were made */
+			let annotations = annotations.and_then(|mut annotations| {
+				let song = &song.song;
+				if read_only {
+					annotations.migrate(&page_identity);
+				} else {
+					self.migrate_annotations(song, &mut annotations, &page_identity)?;
+				}
+				Ok(annotations)
+			});
+			/* Don't overwrite annotations we failed to read */
+			self.annotating
+				.set_enabled(!read_only && annotations.is_ok());
+			*song.annotations.borrow_mut() = annotations.unwrap_or_else(|err| {
+				log::error!("{:?}", err);
+				Default::default()
+			});
+			song.page_identity = page_identity;
+			song.session_staff = start_at;
+			*song.notes.borrow_mut() = self.library.get().unwrap().borrow().stats
+				[&song.song.song_uuid]
+				.notes
+				.clone();
 
 			*self.song.borrow_mut() = Some(song);
 			self.instance().notify("song-name");
 			self.instance().notify("song-id");
 
+			self.populate_part_profiles();
 			self.load_annotations();
 			self.update_content();
 			self.song_progress.get().set_fraction(0.0);
@@ -369,7 +767,20 @@ mod imp {
 		/// Unload the song
 		#[template_callback]
 		fn unload_song(&self) {
+			self.auto_scroll.change_state(&false.to_variant());
+			self.overview.change_state(&false.to_variant());
+			self.annotating.change_state(&false.to_variant());
+			self.record_position();
+			/* Account the time since the last activity before the session ends */
+			self.on_activity();
+			self.record_dwell();
 			let song = self.song.take().unwrap();
+			let mut library = self.library.get().unwrap().borrow_mut();
+			if song.session.active_seconds > 0 {
+				library.record_session(&song.session);
+			}
+			library.save_in_background();
+			std::mem::drop(library);
 			std::mem::drop(song);
 			let carousel = &self.carousel;
 			for page in (0..carousel.n_pages()).rev() {
@@ -379,10 +790,14 @@ mod imp {
 			self.part_selection.set_active(None);
 			self.part_selection.set_sensitive(false);
 			self.part_selection.remove_all();
+			self.part_profile.remove_all();
 			self.instance().notify("song-name");
 			self.instance().notify("song-id");
+			self.instance().notify("page");
+			self.instance().notify("piece");
 			self.on_activity();
 			self.song_load_time.take();
+			self.page_sync.get().unwrap().publish(None);
 		}
 
 		/// The size has changed, maybe update the layout?
@@ -446,6 +861,8 @@ mod imp {
 								height,
 							},
 							song.rendered_pages.clone(),
+							song.annotations.clone(),
+							song.notes.clone(),
 						);
 
 						carousel.append(&area);
@@ -509,11 +926,15 @@ mod imp {
 				carousel.nth_page(i).queue_draw();
 			}
 			carousel.queue_draw();
+			for thumbnail in self.overview_pages.borrow().iter() {
+				thumbnail.queue_draw();
+			}
 		}
 
 		/// When the current carousel page has changed
 		#[template_callback]
 		fn page_changed(&self, page: u32) {
+			self.record_dwell();
 			/* Do nothing if no song loaded */
 			let mut song_ = self.song.borrow_mut();
 			let song = match song_.as_mut() {
@@ -522,6 +943,14 @@ mod imp {
 			};
 			song.change_page(layout::PageIndex(page as usize));
 			let active_id = song.part_start(song.page).to_string();
+			let performance_pass =
+				song.performance
+					.get(song.performance_position)
+					.and_then(|step| match step.after_jump {
+						Some(collection::Jump::DaCapo) => Some("D.C.".to_owned()),
+						Some(collection::Jump::DalSegno) => Some("D.S.".to_owned()),
+						None => (step.pass > 1).then(|| format!("Pass {}", step.pass)),
+					});
 
 			self.previous_piece.set_enabled(page > 0);
 			self.next_piece.set_enabled(
@@ -532,51 +961,365 @@ mod imp {
 					.is_some(),
 			);
 
+			let position = crate::sync::SyncPosition {
+				song: song.song.song_uuid,
+				version: song.song.version_uuid,
+				staff: song.current_staves[0],
+			};
+
 			std::mem::drop(song_);
+			self.page_sync.get().unwrap().publish(Some(position));
 			self.part_selection
 				.block_signal(self.part_selection_changed_signal.get().unwrap());
 			self.part_selection.set_active_id(Some(&active_id));
 			self.part_selection
 				.unblock_signal(self.part_selection_changed_signal.get().unwrap());
+			self.performance_pass
+				.set_visible(performance_pass.is_some());
+			self.performance_pass
+				.set_label(performance_pass.as_deref().unwrap_or_default());
+			self.record_position();
 			self.on_activity();
+			self.instance().notify("page");
+			self.instance().notify("piece");
 		}
 
-		/// Go to the next page
+		/// Go to the next page, following repetitions and jumps
 		fn next_page(&self) {
 			let carousel = &self.carousel;
-			if self.song.borrow().is_some() {
-				let new_page = u32::min(
-					carousel.position().round() as u32 + 1,
-					carousel.n_pages() as u32 - 1,
-				);
-				carousel.scroll_to(&carousel.nth_page(new_page), true);
+			let new_page = match self.song.borrow_mut().as_mut() {
+				/* Let the library load the next entry */
+				Some(song) if song.is_end_of_setlist_entry() => None,
+				Some(song) => Some(song.go_forward().map(|page| *page as u32).unwrap_or_else(
+					|| {
+						u32::min(
+							carousel.position().round() as u32 + 1,
+							carousel.n_pages() as u32 - 1,
+						)
+					},
+				)),
+				None => return,
+			};
+			match new_page {
+				Some(new_page) => carousel.scroll_to(&carousel.nth_page(new_page), true),
+				None => self.instance().emit_by_name::<()>("end-of-entry", &[]),
 			}
 		}
 
-		/// Go to the previous page
+		/// Go to the previous page, following repetitions and jumps backwards
 		fn previous_page(&self) {
 			let carousel = &self.carousel;
-			if let Some(song) = self.song.borrow().as_ref() {
-				let new_page = song
-					.go_back(layout::PageIndex(carousel.position().round() as usize))
-					.unwrap_or_else(|| {
-						layout::PageIndex(usize::max(carousel.position() as usize, 1) - 1)
-					});
-				carousel.scroll_to(&carousel.nth_page(*new_page as u32), true);
-			}
+			let new_page = match self.song.borrow_mut().as_mut() {
+				Some(song) => song
+					.go_back()
+					.map(|page| *page as u32)
+					.unwrap_or_else(|| u32::max(carousel.position().round() as u32, 1) - 1),
+				None => return,
+			};
+			carousel.scroll_to(&carousel.nth_page(new_page), true);
 		}
 
 		/// Key press on the drawingarea
 		#[template_callback]
 		fn carousel_key(&self, keyval: gdk::Key) -> gtk::Inhibit {
-			if keyval == gdk::Key::Left || keyval == gdk::Key::KP_Left {
-				self.previous.activate(None);
-				gtk::Inhibit(true)
-			} else if keyval == gdk::Key::Right || keyval == gdk::Key::KP_Right {
-				self.next.activate(None);
-				gtk::Inhibit(true)
+			let action = keyval
+				.to_lower()
+				.name()
+				.and_then(|name| self.key_bindings.borrow().action_for(&name));
+			match action {
+				Some(action) => {
+					self.page_event(action);
+					gtk::Inhibit(true)
+				},
+				None => gtk::Inhibit(false),
+			}
+		}
+
+		fn remote_command(&self, command: crate::input::RemoteCommand) {
+			use crate::input::RemoteCommand;
+			match command {
+				RemoteCommand::Page(event) => self.page_event(event),
+				RemoteCommand::LoadSong(song) => {
+					self.instance()
+						.emit_by_name::<()>("load-song", &[&song, &0u64]);
+				},
+				RemoteCommand::GoToStaff(staff) => self.go_to_staff(staff),
+				RemoteCommand::Follow(position) => self.follow(position),
+			}
+		}
+
+		/// An action from a pedal or other remote control
+		fn page_event(&self, event: crate::input::PageEvent) {
+			use crate::input::PageEvent;
+			match event {
+				PageEvent::Next => {
+					self.next.activate(None);
+				},
+				PageEvent::Previous => {
+					self.previous.activate(None);
+				},
+				PageEvent::NextPiece => {
+					self.next_piece.activate(None);
+				},
+				PageEvent::PreviousPiece => {
+					self.previous_piece.activate(None);
+				},
+				PageEvent::ZoomIn => {
+					self.zoom_in();
+				},
+				PageEvent::ZoomOut => {
+					self.zoom_out();
+				},
+				PageEvent::JumpToStart => {
+					self.go_to_start();
+				},
+				PageEvent::ToggleAutoScroll => {
+					self.auto_scroll.activate(None);
+				},
+			}
+		}
+
+		/// Go to the first page
+		fn go_to_start(&self) {
+			let carousel = &self.carousel;
+			if let Some(song) = self.song.borrow_mut().as_mut() {
+				song.performance_position = 0;
 			} else {
-				gtk::Inhibit(false)
+				return;
+			}
+			if carousel.n_pages() > 0 {
+				carousel.scroll_to(&carousel.nth_page(0), true);
+			}
+		}
+
+		/// Go to the page with that staff
+		fn go_to_staff(&self, staff: collection::StaffIndex) {
+			let carousel = &self.carousel;
+			let page = match self.song.borrow().as_ref() {
+				Some(song) if *staff < song.song.staves.len() => {
+					*song.layout.get_page_of_staff(staff)
+				},
+				Some(_) => {
+					log::warn!("Cannot go to staff {}, the song is not that long", staff);
+					return;
+				},
+				None => return,
+			};
+			carousel.scroll_to(&carousel.nth_page(page as u32), true);
+		}
+
+		#[template_callback]
+		fn go_to_shown(&self) {
+			self.go_to_status.set_visible(false);
+			self.go_to_entry.grab_focus();
+		}
+
+		/// Jump to a bar number or rehearsal mark entered in the popover
+		#[template_callback]
+		fn go_to_position(&self) {
+			let text = self.go_to_entry.text();
+			let query = match collection::PositionQuery::parse(&text) {
+				Some(query) => query,
+				None => return,
+			};
+			let found = match self.song.borrow().as_ref() {
+				Some(song) => song.song.find_position(&query, song.current_staves[0]),
+				None => return,
+			};
+			match found {
+				Some(staff) => {
+					self.go_to_popover.popdown();
+					self.go_to_entry.set_text("");
+					self.go_to_staff(staff);
+				},
+				None => {
+					self.go_to_status.set_label(&match query {
+						collection::PositionQuery::Measure(measure) => {
+							format!(
+								"Bar {} not found. Bar numbers are set in the editor",
+								measure
+							)
+						},
+						collection::PositionQuery::RehearsalMark(mark) => {
+							format!("There is no rehearsal mark “{}”", mark)
+						},
+					});
+					self.go_to_status.set_visible(true);
+				},
+			}
+		}
+
+		/// Fill the part profile menu with the profiles of the current song, and select the active one
+		fn populate_part_profiles(&self) {
+			let (names, active) = match self.song.borrow().as_ref() {
+				Some(song) => {
+					let library = self.library.get().unwrap().borrow();
+					let stats = &library.stats[&song.song.song_uuid];
+					let names: Vec<String> = stats
+						.part_profiles
+						.iter()
+						.map(|profile| profile.name.clone())
+						.collect();
+					(names, stats.part_profile.clone())
+				},
+				None => return,
+			};
+			self.part_profile.remove_all();
+			self.part_profile.append(Some(""), "Full score");
+			for name in &names {
+				self.part_profile.append(Some(name), name);
+			}
+			/* Profiles that don't exist anymore fall back to the full score */
+			if !active.map_or(false, |name| self.part_profile.set_active_id(Some(&name))) {
+				self.part_profile.set_active_id(Some(""));
+			}
+		}
+
+		/// A part profile got selected. Remember it and show its definition for editing
+		#[template_callback]
+		fn select_part_profile(&self) {
+			let name = match self.part_profile.active_id() {
+				Some(id) if id.is_empty() => None,
+				Some(id) => Some(id.to_string()),
+				None => return,
+			};
+			let uuid = match self.song.borrow().as_ref() {
+				Some(song) => song.song.song_uuid,
+				None => return,
+			};
+			let profile = {
+				let mut library = self.library.get().unwrap().borrow_mut();
+				let stats = library.stats.get_mut(&uuid).unwrap();
+				let profile = name.as_ref().and_then(|name| {
+					stats
+						.part_profiles
+						.iter()
+						.find(|profile| &profile.name == name)
+						.cloned()
+				});
+				if stats.part_profile != name {
+					stats.part_profile = name;
+					library.save_in_background();
+				}
+				profile
+			};
+
+			self.part_profile_status.set_visible(false);
+			self.delete_part_profile_button
+				.set_sensitive(profile.is_some());
+			match &profile {
+				Some(profile) => {
+					self.part_profile_name.set_text(&profile.name);
+					self.part_profile_kind
+						.set_active_id(Some(match profile.selection {
+							library::PartSelection::Position(_) => "position",
+							library::PartSelection::Staves(_) => "staves",
+						}));
+					self.part_profile_staves
+						.set_text(&profile.selection.to_list());
+				},
+				None => {
+					self.part_profile_name.set_text("");
+					self.part_profile_staves.set_text("");
+				},
+			}
+			self.apply_part_profile(profile.as_ref());
+		}
+
+		/// Relayout with only the staves of that profile, or all of them
+		fn apply_part_profile(&self, profile: Option<&library::PartProfile>) {
+			let mut song_ = self.song.borrow_mut();
+			let song = match song_.as_mut() {
+				Some(song) => song,
+				None => return,
+			};
+			let part = profile.map(|profile| profile.visible_staves(&song.song));
+			if part != song.part {
+				song.part = part;
+				std::mem::drop(song_);
+				self.update_content();
+			}
+		}
+
+		/// Add a part profile from the form, or replace the one with the same name
+		#[template_callback]
+		fn save_part_profile(&self) {
+			let uuid = match self.song.borrow().as_ref() {
+				Some(song) => song.song.song_uuid,
+				None => return,
+			};
+			let name = self.part_profile_name.text().trim().to_string();
+			let selection = library::PartSelection::parse(
+				self.part_profile_kind.active_id().as_deref() == Some("position"),
+				&self.part_profile_staves.text(),
+			);
+			let selection = match selection {
+				Ok(_) if name.is_empty() => Err(anyhow::format_err!("The profile needs a name")),
+				selection => selection,
+			};
+			let selection = match selection {
+				Ok(selection) => selection,
+				Err(err) => {
+					self.part_profile_status.set_label(&err.to_string());
+					self.part_profile_status.set_visible(true);
+					return;
+				},
+			};
+
+			{
+				let mut library = self.library.get().unwrap().borrow_mut();
+				let stats = library.stats.get_mut(&uuid).unwrap();
+				let profile = library::PartProfile {
+					name: name.clone(),
+					selection,
+				};
+				match stats.part_profiles.iter_mut().find(|p| p.name == name) {
+					Some(existing) => *existing = profile,
+					None => stats.part_profiles.push(profile),
+				}
+				stats.part_profile = Some(name);
+				library.save_in_background();
+			}
+			self.populate_part_profiles();
+		}
+
+		#[template_callback]
+		fn delete_part_profile(&self) {
+			let uuid = match self.song.borrow().as_ref() {
+				Some(song) => song.song.song_uuid,
+				None => return,
+			};
+			let name = match self.part_profile.active_id() {
+				Some(id) if !id.is_empty() => id.to_string(),
+				_ => return,
+			};
+			{
+				let mut library = self.library.get().unwrap().borrow_mut();
+				let stats = library.stats.get_mut(&uuid).unwrap();
+				stats.part_profiles.retain(|profile| profile.name != name);
+				stats.part_profile = None;
+				library.save_in_background();
+			}
+			self.populate_part_profiles();
+		}
+
+		/// Go to where the leading viewer is. Its layout may differ from ours, so we only match staves
+		fn follow(&self, position: crate::sync::SyncPosition) {
+			let version = self
+				.song
+				.borrow()
+				.as_ref()
+				.filter(|song| song.song.song_uuid == position.song)
+				.map(|song| song.song.version_uuid);
+			match version {
+				Some(version) if version == position.version => self.go_to_staff(position.staff),
+				Some(_) => log::warn!(
+					"Not following: the leader has a different version of this song, staves may not match"
+				),
+				None => self.instance().emit_by_name::<()>(
+					"load-song",
+					&[&position.song.to_string(), &(*position.staff as u64)],
+				),
 			}
 		}
 
@@ -696,6 +1439,8 @@ mod imp {
 				song.scale_mode = match mode.get::<String>().unwrap().as_str() {
 					"fit-staves" => ScaleMode::FitStaves(3),
 					"fit-columns" => ScaleMode::FitPages(2),
+					"fixed-width" => ScaleMode::FixedWidth(1),
+					"fixed-height" => ScaleMode::FixedHeight(3),
 					"manual" => return,
 					invalid => unreachable!("Invalid value: '{}'", invalid),
 				};
@@ -724,6 +1469,246 @@ mod imp {
 			self.on_activity();
 		}
 
+		/// Start or stop turning to the next page at the configured interval.
+		/// Stops by itself at the end of the song or of the setlist entry.
+		fn set_auto_scroll(&self, enabled: bool) {
+			if let Some(timer) = self.auto_scroll_timer.borrow_mut().take() {
+				timer.remove();
+			}
+			if enabled {
+				let interval = self.auto_scroll_config.borrow().interval;
+				let obj = self.instance();
+				*self.auto_scroll_timer.borrow_mut() = Some(glib::source::timeout_add_local(
+					std::time::Duration::from_secs(interval as u64),
+					clone!(@weak obj => @default-return Continue(false), move || {
+						let imp = obj.imp();
+						if imp.is_at_end() {
+							/* The source gets removed by returning, not by the state change */
+							imp.auto_scroll_timer.borrow_mut().take();
+							imp.auto_scroll.change_state(&false.to_variant());
+							return Continue(false);
+						}
+						imp.next.activate(None);
+						Continue(true)
+					}),
+				));
+			}
+		}
+
+		/// Whether going to the next page is not possible anymore, or would end the setlist entry
+		fn is_at_end(&self) -> bool {
+			let carousel = &self.carousel;
+			match self.song.borrow().as_ref() {
+				Some(song) if song.is_end_of_setlist_entry() => true,
+				Some(song) if !song.performance.is_empty() => song.is_end_of_song(),
+				Some(_) => carousel.position().round() as u32 + 1 >= carousel.n_pages(),
+				None => true,
+			}
+		}
+
+		fn set_overview(&self, enabled: bool) {
+			if enabled {
+				/* Build it while the carousel still has its size */
+				self.populate_overview();
+				self.view_stack.set_visible_child_name("overview");
+			} else {
+				self.view_stack.set_visible_child_name("song");
+				self.clear_overview();
+				self.carousel.grab_focus();
+			}
+		}
+
+		fn clear_overview(&self) {
+			while let Some(child) = self.overview_grid.first_child() {
+				self.overview_grid.remove(&child);
+			}
+			self.overview_pages.borrow_mut().clear();
+		}
+
+		/// Fill the overview with a preview of every page of the current layout
+		fn populate_overview(&self) {
+			self.clear_overview();
+			let mut thumbnails = self.overview_pages.borrow_mut();
+
+			let song_ = self.song.borrow();
+			let song = match song_.as_ref() {
+				Some(song) => song,
+				None => return,
+			};
+			let width = self.carousel.width();
+			let height = self.carousel.height();
+			if width <= 1 || height <= 1 {
+				return;
+			}
+
+			for (index, staves) in song.layout.pages.iter_enumerated() {
+				let thumbnail = crate::song_page::SongPage::new_thumbnail(
+					song.song.clone(),
+					crate::song_page::PageLayout {
+						page: index,
+						staves: staves.clone(),
+						width,
+						height,
+					},
+					song.rendered_pages.clone(),
+					song.annotations.clone(),
+					song.notes.clone(),
+					THUMBNAIL_WIDTH,
+				);
+				let (title, sections) = song.page_label(index);
+				let title = gtk::Label::builder()
+					.label(&title)
+					.ellipsize(gtk::pango::EllipsizeMode::End)
+					.max_width_chars(1)
+					.hexpand(true)
+					.build();
+				let sections = gtk::Label::builder()
+					.label(&sections)
+					.visible(!sections.is_empty())
+					.ellipsize(gtk::pango::EllipsizeMode::End)
+					.max_width_chars(1)
+					.hexpand(true)
+					.build();
+				sections.add_css_class("dim-label");
+				sections.add_css_class("caption");
+
+				let entry = gtk::Box::new(gtk::Orientation::Vertical, 6);
+				entry.append(&thumbnail);
+				entry.append(&title);
+				entry.append(&sections);
+				self.overview_grid.insert(&entry, -1);
+				thumbnails.push(thumbnail);
+			}
+
+			/* Highlight where we are */
+			if let Some(current) = self.overview_grid.child_at_index(*song.page as i32) {
+				self.overview_grid.select_child(&current);
+			}
+			std::mem::drop(thumbnails);
+			std::mem::drop(song_);
+			self.update_heatmap();
+		}
+
+		/// Show or hide the dwell times on the pages of the overview
+		fn update_heatmap(&self) {
+			let enabled = self
+				.heatmap
+				.state()
+				.and_then(|state| state.get::<bool>())
+				.unwrap_or(false);
+			let heatmap = match self.song.borrow().as_ref() {
+				Some(song) if enabled => Some(Rc::new(
+					self.library.get().unwrap().borrow().stats[&song.song.song_uuid]
+						.heatmap(&song.song),
+				)),
+				_ => None,
+			};
+			for thumbnail in self.overview_pages.borrow().iter() {
+				thumbnail.set_heatmap(heatmap.clone());
+			}
+		}
+
+		/// Export the dwell time of each staff as CSV
+		#[template_callback]
+		fn export_dwell(&self) {
+			let (csv, title) = match self.song.borrow().as_ref() {
+				Some(song) => (
+					self.library.get().unwrap().borrow().stats[&song.song.song_uuid]
+						.dwell_to_csv(&song.song),
+					song.song.title.clone(),
+				),
+				None => return,
+			};
+
+			let filter = gtk::FileFilter::new();
+			filter.add_mime_type("text/csv");
+			let choose = gtk::FileChooserNative::builder()
+				.title("Export practice time")
+				.action(gtk::FileChooserAction::Save)
+				.modal(true)
+				.filter(&filter)
+				.build();
+			if let Some(root) = self.instance().root() {
+				choose.set_transient_for(root.downcast_ref::<gtk::Window>());
+			}
+			choose.set_current_name(&format!(
+				"{}.csv",
+				title.as_deref().unwrap_or("Practice time")
+			));
+			choose.connect_response(clone_!(self, move |obj, dialog, response| {
+				if response == gtk::ResponseType::Accept {
+					if let Some(path) = dialog.file().and_then(|file| file.path()) {
+						if let Err(err) = std::fs::write(&path, &csv) {
+							log::error!(
+								"Failed to export the practice time to '{}': {:?}",
+								path.display(),
+								err
+							);
+							obj.imp().show_message("Failed to export the practice time");
+						}
+					}
+				}
+				obj.imp().export_dialog.take();
+			}));
+			choose.show();
+			*self.export_dialog.borrow_mut() = Some(choose);
+		}
+
+		/// Account the time the current page was on screen to its staves
+		fn record_dwell(&self) {
+			let now = std::time::Instant::now();
+			let mut song_ = self.song.borrow_mut();
+			let song = match song_.as_mut() {
+				Some(song) => song,
+				None => return,
+			};
+			/* Like in on_activity, count at most 3 minutes since the last interaction */
+			let idle_since = self.last_interaction.get().max(song.page_shown_since);
+			let seconds = (idle_since.duration_since(song.page_shown_since)
+				+ now
+					.duration_since(idle_since)
+					.min(std::time::Duration::from_secs(180)))
+			.as_secs_f64();
+			song.page_shown_since = now;
+			self.library
+				.get()
+				.unwrap()
+				.borrow_mut()
+				.stats
+				.get_mut(&song.song.song_uuid)
+				.unwrap()
+				.record_dwell(&song.current_staves, seconds);
+		}
+
+		/// A page in the overview was clicked, go there
+		#[template_callback]
+		fn overview_activated(&self, child: &gtk::FlowBoxChild) {
+			let page = child.index();
+			self.overview.change_state(&false.to_variant());
+			if page >= 0 && (page as u32) < self.carousel.n_pages() {
+				self.carousel
+					.scroll_to(&self.carousel.nth_page(page as u32), false);
+			}
+		}
+
+		/// Remember where we are in the song, to continue there later on
+		fn record_position(&self) {
+			let song = self.song.borrow();
+			let song = match song.as_ref() {
+				Some(song) => song,
+				None => return,
+			};
+			let is_last_page = *song.page + 1 >= song.layout.pages.len();
+			self.library
+				.get()
+				.unwrap()
+				.borrow_mut()
+				.stats
+				.get_mut(&song.song.song_uuid)
+				.unwrap()
+				.last_position = (!is_last_page).then(|| song.current_staves[0]);
+		}
+
 		/// Should be called on every user action. Update the time played statistic
 		fn on_activity(&self) {
 			let last_interaction = std::time::Instant::now();
@@ -736,12 +1721,22 @@ mod imp {
 			if diff < 5 {
 				return;
 			}
+			/* While the previous interaction is still known */
+			self.record_dwell();
 			let mut song_ = self.song.borrow_mut();
 			let song = match song_.as_mut() {
 				Some(song) => song,
 				None => return,
 			};
 
+			song.session.record(
+				&song.song,
+				song.session_staff,
+				diff,
+				std::time::SystemTime::now(),
+			);
+			song.session_staff = song.current_staves[0];
+
 			let library = &mut self.library.get().unwrap().borrow_mut();
 			let stats = library.stats.get_mut(&song.song.song_uuid).unwrap();
 			stats.on_update(diff);
@@ -770,7 +1765,28 @@ mod imp {
 		}
 
 		#[template_callback]
-		fn carousel_button_release(&self, _n_press: i32, x: f64, _y: f64) {
+		fn carousel_button_release(&self, _n_press: i32, x: f64, y: f64) {
+			if self.is_annotating() {
+				return;
+			}
+			/* Tapping a note marker opens the notes */
+			let marked = self.song.borrow().as_ref().and_then(|song| {
+				let notes = song.notes.borrow();
+				song.layout.pages[song.page]
+					.iter()
+					.filter(|staff_layout| {
+						notes.iter().any(|note| note.staff == staff_layout.index)
+					})
+					.find(|staff_layout| {
+						crate::song_page::SongPage::note_marker(staff_layout)
+							.contains_point(&graphene::Point::new(x as f32, y as f32))
+					})
+					.cloned()
+			});
+			if let Some(staff_layout) = marked {
+				self.show_notes(&staff_layout);
+				return;
+			}
 			let x = x / self.carousel.width() as f64;
 			if (0.0..0.3).contains(&x) {
 				self.previous.activate(None);
@@ -801,30 +1817,177 @@ mod imp {
 			}
 		}
 
+		/// Long press on a staff to add notes to it
+		#[template_callback]
+		fn notes_long_press(&self, x: f64, y: f64) {
+			if self.is_annotating() {
+				return;
+			}
+			let staff_layout = self.song.borrow().as_ref().and_then(|song| {
+				song.layout
+					.get_staff_at(&song.song, song.page, x, y)
+					.cloned()
+			});
+			if let Some(staff_layout) = staff_layout {
+				self.show_notes(&staff_layout);
+			}
+		}
+
+		fn show_notes(&self, staff_layout: &layout::StaffLayout) {
+			let marker = crate::song_page::SongPage::note_marker(staff_layout);
+			self.notes_staff.set(Some(staff_layout.index));
+			self.reload_notes();
+			self.notes_popover
+				.set_pointing_to(Some(&gdk::Rectangle::new(
+					marker.x() as i32,
+					marker.y() as i32,
+					marker.width() as i32,
+					marker.height() as i32,
+				)));
+			self.notes_entry.set_text("");
+			self.notes_popover.popup();
+			self.notes_entry.grab_focus();
+		}
+
+		/// Fill the popover with the notes of the staff
+		fn reload_notes(&self) {
+			while let Some(row) = self.notes_list.first_child() {
+				self.notes_list.remove(&row);
+			}
+			let (song, staff) = match (self.song.borrow().as_ref(), self.notes_staff.get()) {
+				(Some(song), Some(staff)) => (song.song.clone(), staff),
+				_ => return,
+			};
+			let (page, page_staff) = song.page_of_piece(staff);
+			self.notes_title
+				.set_label(&format!("Notes on {}-{}", *page + 1, *page_staff));
+
+			let notes = self.song.borrow().as_ref().unwrap().notes.clone();
+			let notes = notes.borrow();
+			for (index, note) in notes.iter().enumerate() {
+				if note.staff != staff {
+					continue;
+				}
+				let row = gtk::Box::builder()
+					.orientation(gtk::Orientation::Horizontal)
+					.spacing(5)
+					.build();
+				row.append(
+					&gtk::Label::builder()
+						.label(&note.text)
+						.wrap(true)
+						.max_width_chars(30)
+						.xalign(0.0)
+						.hexpand(true)
+						.build(),
+				);
+				let delete = gtk::Button::builder()
+					.icon_name("user-trash-symbolic")
+					.tooltip_text("Remove this note")
+					.valign(gtk::Align::Center)
+					.build();
+				delete.add_css_class("flat");
+				delete.connect_clicked(clone_!(self, move |obj, _button| {
+					obj.imp().update_notes(|stats| {
+						stats.notes.remove(index);
+					});
+				}));
+				row.append(&delete);
+				self.notes_list.append(&row);
+			}
+			self.notes_list
+				.set_visible(self.notes_list.first_child().is_some());
+		}
+
+		/// Modify the notes of the song, save them and redraw
+		fn update_notes(&self, update: impl FnOnce(&mut library::LibrarySong)) {
+			if let Some(song) = self.song.borrow().as_ref() {
+				let mut library = self.library.get().unwrap().borrow_mut();
+				let stats = library.stats.get_mut(&song.song.song_uuid).unwrap();
+				update(stats);
+				*song.notes.borrow_mut() = stats.notes.clone();
+				library.save_in_background();
+			}
+			self.reload_notes();
+			let carousel = &self.carousel;
+			for i in 0..carousel.n_pages() {
+				carousel.nth_page(i).queue_draw();
+			}
+		}
+
+		#[template_callback]
+		fn add_note(&self) {
+			let text = self.notes_entry.text().trim().to_string();
+			let staff = match self.notes_staff.get() {
+				Some(staff) if !text.is_empty() => staff,
+				_ => return,
+			};
+			self.update_notes(|stats| stats.add_note(staff, text));
+			self.notes_entry.set_text("");
+		}
+
+		fn show_message(&self, message: &str) {
+			self.instance()
+				.emit_by_name::<()>("show-message", &[&message.to_string()]);
+		}
+
+		/// Move the annotations to the current pages, putting those that don't fit anymore into quarantine
+		fn migrate_annotations(
+			&self,
+			song: &collection::SongMeta,
+			annotations: &mut annotations::Annotations,
+			pages: &annotations::PageIdentity,
+		) -> anyhow::Result<()> {
+			let was_unknown = annotations.made_for.is_unknown();
+			let orphaned = match annotations.migrate(pages) {
+				/* Nothing moved, but remember the pages from now on */
+				None if was_unknown && !annotations.is_empty() => None,
+				None => return Ok(()),
+				Some(orphaned) => Some(orphaned),
+			};
+			log::info!("The pages of the song changed, moving its annotations");
+			if let Some(orphaned) = orphaned {
+				let path = orphaned.quarantine(&song.song_uuid)?;
+				self.show_message(&format!(
+					"Some annotations of '{}' don't fit its pages anymore. They were moved to '{}'.",
+					song.title.as_deref().unwrap_or("unnamed"),
+					path.display()
+				));
+			}
+			annotations.save(&song.song_uuid)
+		}
+
 		fn load_annotations(&self) {
 			if let Some(song) = &self.song.borrow_mut().as_mut() {
 				log::debug!("Reloading annotations");
 				let uuid = song.song.song_uuid;
-				// TODO don't hardcode here
-				let xdg = xdg::BaseDirectories::with_prefix("dinoscore").unwrap();
-				let annotations_export = xdg
-					.place_data_file(format!("annotations/{}.pdf", uuid))
-					.unwrap();
-
-				let document = annotations_export.exists().then(|| {
-					poppler::Document::from_bytes(
-						&glib::Bytes::from_owned(std::fs::read(annotations_export).unwrap()),
-						None,
-					)
-					.unwrap()
-				});
+				let config = self.annotation_editor_config.borrow();
+				let library = self.library.get().unwrap().borrow();
+				let checked = crate::annotation_editor::check_pages(
+					&config,
+					&library.songs[&uuid],
+					&song.page_identity,
+				);
+				std::mem::drop(library);
+				match checked {
+					Ok(None) => {},
+					Ok(Some(path)) => self.show_message(&format!(
+						"Some external annotations of '{}' don't fit its pages anymore. They were moved to '{}'.",
+						song.song.title.as_deref().unwrap_or("unnamed"),
+						path.display()
+					)),
+					Err(err) => log::error!("Failed to check the external annotations: {:?}", err),
+				}
+
+				let document =
+					crate::annotation_editor::load_overlay(&uuid).unwrap_or_else(|err| {
+						log::error!("{:?}", err);
+						None
+					});
 				for i in 0..song.rendered_pages.len() {
-					(*song.rendered_pages[collection::PageIndex(i)].borrow_mut()).1 =
-						document.as_ref().map(|document| {
-							document.page(i as i32).expect(
-								"Annotation document must have as many pages as original PDF",
-							)
-						});
+					(*song.rendered_pages[collection::PageIndex(i)].borrow_mut()).1 = document
+						.as_ref()
+						.and_then(|document| document.page(i as i32));
 				}
 				let carousel = &self.carousel;
 				for i in 0..carousel.n_pages() {
@@ -834,23 +1997,184 @@ mod imp {
 			}
 		}
 
-		/// Launch Xournal++ for annotating
+		fn is_annotating(&self) -> bool {
+			self.annotating
+				.state()
+				.and_then(|state| state.get::<bool>())
+				.unwrap_or(false)
+		}
+
+		fn set_annotating(&self, enabled: bool) {
+			/* Swiping would get in the way of drawing */
+			self.carousel.set_interactive(!enabled);
+			self.annotation_toolbar.set_reveal_child(enabled);
+			self.drawing.take();
+		}
+
+		/// The pen pressure of the current event. Devices without pressure information draw at full pressure
+		fn stylus_pressure(&self) -> f64 {
+			let gesture = &self.annotation_gesture;
+			gesture
+				.last_event(gesture.current_sequence().as_ref())
+				.and_then(|event| event.axis(gdk::AxisUse::Pressure))
+				.filter(|pressure| *pressure > 0.0)
+				.unwrap_or(1.0)
+		}
+
+		/// Apply the current tool at a point of the page. `start` begins a new stroke.
+		fn annotate_at(&self, x: f64, y: f64, start: bool) {
+			let song = self.song.borrow();
+			let song = match song.as_ref() {
+				Some(song) => song,
+				None => return,
+			};
+			let staff_layout = match &*self.drawing.borrow() {
+				Some(staff_layout) => staff_layout.clone(),
+				None => return,
+			};
+			let staff = &song.song.staves[staff_layout.index];
+			let (page_x, page_y) = staff_layout.to_page_coordinates(staff, x, y);
+			let pixel = staff.width() / staff_layout.width;
+			let tool = self
+				.annotation_tool
+				.state()
+				.and_then(|state| state.get::<String>())
+				.unwrap_or_default();
+
+			let mut annotations = song.annotations.borrow_mut();
+			let point = annotations::StrokePoint {
+				x: page_x,
+				y: page_y,
+				pressure: self.stylus_pressure(),
+			};
+			match tool.as_str() {
+				"eraser" => {
+					annotations.erase(staff.page, page_x, page_y, ERASER_RADIUS * pixel);
+				},
+				"stamp" if start => {
+					if let Some(text) = self.stamp_selection.active_id() {
+						annotations.stamps.push(annotations::Stamp {
+							page: staff.page,
+							x: page_x,
+							y: page_y,
+							text: text.to_string(),
+						});
+					}
+				},
+				"stamp" => {},
+				tool if start => annotations.strokes.push(annotations::Stroke {
+					page: staff.page,
+					tool: if tool == "highlighter" {
+						annotations::Tool::Highlighter
+					} else {
+						annotations::Tool::Pen
+					},
+					points: vec![point],
+				}),
+				_ => {
+					if let Some(stroke) = annotations.strokes.last_mut() {
+						/* Skip points that are closer than a pixel, they only take up space */
+						let last = stroke.points.last().unwrap();
+						if (last.x - point.x).hypot(last.y - point.y) >= pixel {
+							stroke.points.push(point);
+						}
+					}
+				},
+			}
+			self.carousel.nth_page(*song.page as u32).queue_draw();
+		}
+
+		#[template_callback]
+		fn annotation_begin(&self, x: f64, y: f64) {
+			let staff_layout = self.song.borrow().as_ref().and_then(|song| {
+				song.layout
+					.get_staff_at(&song.song, song.page, x, y)
+					.cloned()
+			});
+			match staff_layout {
+				Some(staff_layout) if self.is_annotating() => {
+					self.annotation_gesture
+						.set_state(gtk::EventSequenceState::Claimed);
+					*self.drawing.borrow_mut() = Some(staff_layout);
+					self.annotate_at(x, y, true);
+				},
+				_ => {
+					self.annotation_gesture
+						.set_state(gtk::EventSequenceState::Denied);
+				},
+			}
+		}
+
+		#[template_callback]
+		fn annotation_update(&self, offset_x: f64, offset_y: f64) {
+			if let Some((x, y)) = self.annotation_gesture.start_point() {
+				self.annotate_at(x + offset_x, y + offset_y, false);
+			}
+		}
+
+		#[template_callback]
+		fn annotation_end(&self, _offset_x: f64, _offset_y: f64) {
+			if self.drawing.take().is_none() {
+				return;
+			}
+			if let Some(song) = self.song.borrow().as_ref() {
+				if let Err(err) = song.annotations.borrow().save(&song.song.song_uuid) {
+					log::error!("{:?}", err);
+				}
+			}
+		}
+
+		/// Launch the external editor for annotating. It runs in the background, and we reload
+		/// the annotations whenever it saves.
 		#[template_callback]
 		fn annotate(&self) {
 			log::debug!("annotate!");
-			if let Some(song) = &self.song.borrow_mut().as_mut() {
-				let library = &mut self.library.get().unwrap().borrow_mut();
-				let page = song.song.staves[song.current_staves[0]].page;
-				let song = library.songs.get_mut(&song.song.song_uuid).unwrap();
-
-				// TODO make async
-				// TODO error handling
-				use anyhow::Context;
-				crate::xournal::run_editor(song, *page + 1)
-					.context("Failed to launch editor")
-					.unwrap();
+			let editor = self.annotation_editor_config.borrow().editor();
+			if self.annotation_editor.get().is_some() {
+				self.show_message(&format!("{} is already open", editor.name()));
+				return;
 			}
-			self.load_annotations();
+			let events = match self.song.borrow().as_ref() {
+				Some(song) => {
+					let library = self.library.get().unwrap().borrow();
+					let page = song.song.staves[song.current_staves[0]].page;
+					self.annotation_editor.set(Some(song.song.song_uuid));
+					crate::annotation_editor::spawn_editor(
+						editor,
+						&library.songs[&song.song.song_uuid],
+						*page + 1,
+						song.page_identity.clone(),
+					)
+				},
+				None => return,
+			};
+			events.attach(
+				None,
+				clone_!(self, move |obj, event| {
+					let this = obj.imp();
+					let editing = this.annotation_editor.get();
+					let is_current =
+						this.song.borrow().as_ref().map(|song| song.song.song_uuid) == editing;
+					match event {
+						crate::annotation_editor::SessionEvent::Saved => {
+							if is_current {
+								this.load_annotations();
+							}
+						},
+						crate::annotation_editor::SessionEvent::Failed(err) => {
+							log::error!("{:?}", err);
+							this.show_message(&format!("{:#}", err));
+						},
+						crate::annotation_editor::SessionEvent::Closed => {
+							this.annotation_editor.set(None);
+							if is_current {
+								this.load_annotations();
+							}
+						},
+					}
+					Continue(true)
+				}),
+			);
 		}
 	}
 }
@@ -870,6 +2194,26 @@ struct SongState {
 	 * synchronize the view on layout changes
 	 */
 	current_staves: Vec<collection::StaffIndex>,
+	/// The staves in the order they are played, with repetitions and jumps unrolled
+	performance: Vec<collection::PerformedStaff>,
+	/// Where in [`performance`](Self::performance) we currently are
+	performance_position: usize,
+	/// When playing a setlist, the piece of the current entry
+	setlist_piece: Option<collection::StaffIndex>,
+	/// With a part profile selected, only these staves are shown
+	part: Option<BTreeSet<collection::StaffIndex>>,
+	/// Shared with the pages, which draw them
+	annotations: Rc<RefCell<annotations::Annotations>>,
+	/// The pages of the song, to tell whether annotations still fit them
+	page_identity: annotations::PageIdentity,
+	/// A copy of the notes in the library, shared with the pages
+	notes: Rc<RefCell<Vec<library::StaffNote>>>,
+	/// Goes into the practice journal when the song is closed
+	session: journal::PracticeSession,
+	/// Where the user was at the last activity. The time since then is accounted to it
+	session_staff: collection::StaffIndex,
+	/// Since when the current page is on screen
+	page_shown_since: std::time::Instant,
 }
 
 impl SongState {
@@ -883,13 +2227,21 @@ impl SongState {
 		height: f64,
 		scale_mode: ScaleMode,
 	) -> Self {
-		// let layout = Arc::new(layout::layout_fixed_width(&song, width, height, 1.0, 10.0));
-		// let layout = Arc::new(layout::layout_fixed_height(&song, width, height));
 		let layout = Arc::new(layout::layout_fixed_scale(&song, width, height, 1.0));
 		Self {
+			session: journal::PracticeSession::new(song.song_uuid, std::time::SystemTime::now()),
+			session_staff: 0.into(),
+			page_shown_since: std::time::Instant::now(),
+			performance: song.performance_order(),
 			song,
 			page: 0.into(),
 			current_staves: layout.get_staves_of_page(0.into()).collect(),
+			performance_position: 0,
+			setlist_piece: None,
+			part: None,
+			annotations: Default::default(),
+			page_identity: Default::default(),
+			notes: Default::default(),
 			layout,
 			renderer,
 			rendered_pages,
@@ -900,21 +2252,46 @@ impl SongState {
 	}
 
 	fn change_size(&mut self, width: f64, height: f64) {
-		// self.layout = Arc::new(layout::layout_fixed_width(&self.song, width, height, zoom, 10.0));
-		// self.layout = Arc::new(layout::layout_fixed_height(&self.song, width, height));
-		match self.scale_mode {
-			ScaleMode::Zoom(_) => {},
+		let scale_mode = self.scale_mode;
+		let mut zoom = self.zoom;
+		/* Fitting the scale only considers the staves that are shown */
+		let mut layout = |song: &collection::SongMeta| match scale_mode {
+			ScaleMode::Zoom(_) => layout::layout_fixed_scale(song, width, height, zoom),
 			ScaleMode::FitStaves(num) => {
-				self.zoom = layout::find_scale_for_fixed_staves(&self.song, width, height, num)
+				zoom = layout::find_scale_for_fixed_staves(song, width, height, num);
+				layout::layout_fixed_scale(song, width, height, zoom)
 			},
 			ScaleMode::FitPages(num) => {
-				self.zoom = layout::find_scale_for_fixed_columns(&self.song, width, height, num)
+				zoom = layout::find_scale_for_fixed_columns(song, width, height, num);
+				layout::layout_fixed_scale(song, width, height, zoom)
+			},
+			ScaleMode::FixedWidth(num) => {
+				layout::layout_fixed_width(song, width, height, 1.0, num as usize, 10.0)
 			},
+			ScaleMode::FixedHeight(num) => {
+				layout::layout_fixed_height(song, width, height, num as usize)
+			},
+		};
+		self.layout = Arc::new(match &self.part {
+			Some(visible) => layout::layout_part(&self.song, visible, layout),
+			None => layout(&self.song),
+		});
+		self.zoom = zoom;
+		if let ScaleMode::FixedWidth(_) | ScaleMode::FixedHeight(_) = self.scale_mode {
+			/* These layouts have no single scale. Use the average one, so that the zoom
+			 * label is meaningful and switching to manual zoom doesn't jump around.
+			 */
+			let (sum, count) = self
+				.layout
+				.pages
+				.iter()
+				.flatten()
+				.map(|layout_staff| {
+					layout_staff.width / self.song.staves[layout_staff.index].width() / height
+				})
+				.fold((0.0, 0), |(sum, count), zoom| (sum + zoom, count + 1));
+			self.zoom = sum / count as f64;
 		}
-
-		self.layout = Arc::new(layout::layout_fixed_scale(
-			&self.song, width, height, self.zoom,
-		));
 		/* Calculate the new page, which has the most staves in common with the previous layout/page */
 		self.page = {
 			use itertools::Itertools;
@@ -935,6 +2312,7 @@ impl SongState {
 				.unwrap()
 		};
 		self.current_staves = self.layout.get_staves_of_page(self.page).collect();
+		self.sync_performance();
 
 		/* Calculate the maximum effective page width for this layout */
 		use noisy_float::prelude::*;
@@ -963,6 +2341,7 @@ impl SongState {
 	fn change_page(&mut self, page: layout::PageIndex) {
 		self.page = page;
 		self.current_staves = self.layout.get_staves_of_page(page).collect();
+		self.sync_performance();
 
 		/* Notify background renderer about potential changes */
 		self.renderer
@@ -974,6 +2353,53 @@ impl SongState {
 			.unwrap();
 	}
 
+	/// Describe a page for the overview: its number with the pieces, and the sections starting on it
+	fn page_label(&self, page: layout::PageIndex) -> (String, String) {
+		let staves: Vec<_> = self.layout.get_staves_of_page(page).collect();
+		let (first, last) = match (staves.first(), staves.last()) {
+			(Some(first), Some(last)) => (*first, *last),
+			_ => return (format!("{}", *page + 1), String::new()),
+		};
+		let piece_name = |staff: &collection::StaffIndex, name: &String| {
+			if name.is_empty() {
+				format!("({})", staff)
+			} else {
+				name.clone()
+			}
+		};
+
+		let pieces: Vec<String> = self
+			.song
+			.piece_starts
+			.range(first..=last)
+			.map(|(staff, name)| piece_name(staff, name))
+			.collect();
+		let pieces = if pieces.is_empty() {
+			/* Continuation of an earlier piece */
+			let (staff, name) = self.song.piece_starts.range(..=first).next_back().unwrap();
+			format!("{} (cont.)", piece_name(staff, name))
+		} else {
+			pieces.join(", ")
+		};
+
+		let sections: Vec<String> = self
+			.song
+			.section_starts
+			.iter()
+			.enumerate()
+			.filter(|(_, (staff, _))| (first..=last).contains(staff))
+			.map(|(index, (_, section))| {
+				if section.is_repetition {
+					format!("Section {} (repeat)", index + 1)
+				} else {
+					format!("Section {}", index + 1)
+				}
+			})
+			.collect();
+
+		(format!("{}. {}", *page + 1, pieces), sections.join(", "))
+	}
+
 	fn get_parts(&self) -> Vec<(collection::StaffIndex, String)> {
 		self.song
 			.piece_starts
@@ -989,22 +2415,90 @@ impl SongState {
 			.collect()
 	}
 
-	/* When we're at a given page and want to go back, should we jump to the start of the repetition? */
-	fn go_back(&self, work_page: layout::PageIndex) -> Option<layout::PageIndex> {
-		/* Find all sections that are repetitions and are visible on the current page.
-		 * Go back to the beginning of the first of them.
-		 */
-		self.song
-			.sections()
+	fn performance_page(&self, position: usize) -> layout::PageIndex {
+		self.layout
+			.get_page_of_staff(self.performance[position].staff)
+	}
+
+	/* If the page was changed by other means than `go_forward`/`go_back` (swiping, jumping to
+	 * a piece, relayout), find the closest step in the performance that is on the current page.
+	 * Prefer going forward, so that swiping to the next page doesn't loop back into a repetition.
+	 */
+	fn sync_performance(&mut self) {
+		if self.performance.is_empty()
+			|| self.performance_page(self.performance_position) == self.page
+		{
+			return;
+		}
+		let forward = (self.performance_position..self.performance.len())
+			.find(|&position| self.performance_page(position) == self.page);
+		let backward = || {
+			(0..self.performance_position)
+				.rev()
+				.find(|&position| self.performance_page(position) == self.page)
+				.map(|position| self.visit_start(position))
+		};
+		if let Some(position) = forward.or_else(backward) {
+			self.performance_position = position;
+		}
+	}
+
+	/* Go back to where we entered the page we are on at the given step */
+	fn visit_start(&self, mut position: usize) -> usize {
+		let page = self.performance_page(position);
+		while position > 0 && self.performance_page(position - 1) == page {
+			position -= 1;
+		}
+		position
+	}
+
+	/* Advance to the next page in performance order. Returns `None` at the end */
+	fn go_forward(&mut self) -> Option<layout::PageIndex> {
+		let position = (self.performance_position..self.performance.len())
+			.find(|&position| self.performance_page(position) != self.page)?;
+		self.performance_position = position;
+		Some(self.performance_page(position))
+	}
+
+	/* Go back to the page we came from in performance order. Returns `None` at the start */
+	fn go_back(&mut self) -> Option<layout::PageIndex> {
+		if self.performance.is_empty() {
+			return None;
+		}
+		let start = self.visit_start(self.performance_position);
+		if start == 0 {
+			return None;
+		}
+		self.performance_position = self.visit_start(start - 1);
+		Some(self.performance_page(self.performance_position))
+	}
+
+	/* Are there no more pages to come in performance order? */
+	fn is_end_of_song(&self) -> bool {
+		!(self.performance_position..self.performance.len())
+			.any(|position| self.performance_page(position) != self.page)
+	}
+
+	/* When playing a setlist, are there no more pages of the current piece to come? */
+	fn is_end_of_setlist_entry(&self) -> bool {
+		let piece = match self.setlist_piece {
+			Some(piece) => piece,
+			None => return false,
+		};
+		let next_piece = self
+			.song
+			.piece_starts
+			.range(piece..)
+			.nth(1)
+			.map(|(&staff, _)| staff);
+		let in_piece = |staff: collection::StaffIndex| {
+			staff >= piece && next_piece.map_or(true, |next| staff < next)
+		};
+		!self.performance[self.performance_position..]
 			.iter()
-			.filter(|(_, repetition)| *repetition)
-			.map(|(range, _)| range)
-			/* Find a section that ends on the current page but starts somewhere before */
-			.find(|range| {
-				self.layout.get_page_of_staff(*range.end()) == work_page
-					&& self.layout.get_page_of_staff(*range.start()) < work_page
-			})
-			.map(|range| self.layout.get_page_of_staff(*range.start()))
+			.skip_while(|step| !in_piece(step.staff))
+			.take_while(|step| in_piece(step.staff))
+			.any(|step| self.layout.get_page_of_staff(step.staff) != self.page)
 	}
 
 	/* When we're at a given position, where did the part we are in start? */
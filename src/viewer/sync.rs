@@ -0,0 +1,421 @@
+//! Synchronized page turning between multiple viewers on the local network
+//!
+//! One viewer is the leader: whenever its page changes, it broadcasts the song and the first
+//! staff on screen over UDP. Followers load that song if necessary and go to the page
+//! with that staff in their own layout, since each screen may have a different size and zoom.
+//! The leader repeats its position every few seconds so that late-comers catch up, but followers
+//! only act on changes. This way, they can still look at other pages between page turns.
+//!
+//! Staff numbers are only comparable between identical versions of a song, thus followers
+//! ignore positions from a different `version_uuid`.
+//!
+//! With "loopback" enabled, messages don't leave the machine, which is useful for testing
+//! with two instances. Note that only one follower per machine can listen on the port.
+
+use crate::input::RemoteCommand;
+use anyhow::Context;
+use dinoscore::{collection::StaffIndex, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+	net::{Ipv4Addr, SocketAddr, UdpSocket},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc, Arc,
+	},
+	time::Duration,
+};
+
+/// How long to block on the socket before looking if we should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the leader repeats its position
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncRole {
+	Off,
+	/// Broadcast our page turns
+	Leader,
+	/// Turn pages along with the leader
+	Follower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncConfig {
+	pub role: SyncRole,
+	pub port: u16,
+	/// Stay on this machine instead of broadcasting to the network
+	pub loopback: bool,
+}
+
+impl Default for SyncConfig {
+	fn default() -> Self {
+		SyncConfig {
+			role: SyncRole::Off,
+			port: 9001,
+			loopback: false,
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum SyncConfigFile {
+	#[serde(rename = "0")]
+	V0(SyncConfig),
+}
+
+impl SyncConfig {
+	pub fn load() -> anyhow::Result<Self> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		Ok(match xdg.find_config_file("sync.json") {
+			Some(path) => {
+				let file: SyncConfigFile = pipeline::pipe!(
+					path
+					=> std::fs::File::open(_)?
+					=> std::io::BufReader::new
+					=> serde_json::from_reader(_)?
+				);
+				match file {
+					SyncConfigFile::V0(config) => config,
+				}
+			},
+			None => SyncConfig::default(),
+		})
+	}
+
+	pub fn save(&self) -> anyhow::Result<()> {
+		// TODO don't hardcode here
+		let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
+		let path = xdg.place_config_file("sync.json")?;
+		log::info!("Saving sync configuration ({})", path.display());
+		let file = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+		file.write(|file| serde_json::to_writer_pretty(file, &SyncConfigFile::V0(*self)))
+			.context("Could not save sync configuration (sync.json)")?;
+		Ok(())
+	}
+
+	/// Where the leader sends to
+	fn target(&self) -> SocketAddr {
+		let ip = if self.loopback {
+			Ipv4Addr::LOCALHOST
+		} else {
+			Ipv4Addr::BROADCAST
+		};
+		(ip, self.port).into()
+	}
+
+	/// Where the follower listens on
+	fn address(&self) -> SocketAddr {
+		let ip = if self.loopback {
+			Ipv4Addr::LOCALHOST
+		} else {
+			Ipv4Addr::UNSPECIFIED
+		};
+		(ip, self.port).into()
+	}
+}
+
+/// Where the leader currently is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncPosition {
+	pub song: uuid::Uuid,
+	pub version: uuid::Uuid,
+	/// The first staff on the leader's page
+	pub staff: StaffIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "version")]
+enum SyncMessage {
+	#[serde(rename = "0")]
+	V0 {
+		/// Random on each start, to tell leaders apart
+		leader: uuid::Uuid,
+		/// Incremented on each change of position, but not on repetitions
+		sequence: u64,
+		position: SyncPosition,
+	},
+}
+
+impl SyncMessage {
+	fn encode(&self) -> Vec<u8> {
+		serde_json::to_vec(self).unwrap()
+	}
+
+	fn decode(data: &[u8]) -> anyhow::Result<Self> {
+		serde_json::from_slice(data).context("Invalid sync message")
+	}
+}
+
+/// Handle to a background thread
+#[derive(Debug)]
+enum Worker {
+	Leader {
+		/// Dropping this stops the thread
+		positions: mpsc::Sender<Option<SyncPosition>>,
+		thread: std::thread::JoinHandle<()>,
+	},
+	Follower {
+		stop: Arc<AtomicBool>,
+		thread: std::thread::JoinHandle<()>,
+	},
+}
+
+/// Manages leading or following according to the configuration
+#[derive(Debug)]
+pub struct PageSync {
+	config: RefCell<SyncConfig>,
+	worker: RefCell<Option<Worker>>,
+	/// Positions received as a follower are sent here
+	tx: glib::Sender<RemoteCommand>,
+}
+
+impl PageSync {
+	/// Load the configuration and start leading or following if enabled
+	pub fn new(tx: glib::Sender<RemoteCommand>) -> Self {
+		let config = SyncConfig::load()
+			.context("Failed to load sync configuration")
+			.unwrap_or_else(|err| {
+				log::error!("{:?}", err);
+				SyncConfig::default()
+			});
+		let sync = PageSync {
+			config: RefCell::new(config),
+			worker: RefCell::new(None),
+			tx,
+		};
+		if let Err(err) = sync.restart() {
+			log::error!("{:?}", err);
+		}
+		sync
+	}
+
+	pub fn config(&self) -> SyncConfig {
+		*self.config.borrow()
+	}
+
+	/// Apply a new configuration and persist it. Fails if the socket can't be opened.
+	pub fn set_config(&self, config: SyncConfig) -> anyhow::Result<()> {
+		if let Err(err) = config.save() {
+			log::error!("{:?}", err);
+		}
+		*self.config.borrow_mut() = config;
+		self.restart()
+	}
+
+	fn restart(&self) -> anyhow::Result<()> {
+		self.shutdown();
+		let config = self.config();
+		let worker = match config.role {
+			SyncRole::Off => return Ok(()),
+			SyncRole::Leader => {
+				let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+					.context("Failed to open a socket for sending")?;
+				socket.set_broadcast(true)?;
+				let target = config.target();
+				log::info!("Leading page turns, sending to {}", target);
+
+				let (positions, rx) = mpsc::channel();
+				let thread = std::thread::Builder::new()
+					.name("Sync leader".into())
+					.spawn(move || lead(socket, target, rx, HEARTBEAT_INTERVAL))
+					.context("Failed to spawn thread")?;
+				Worker::Leader { positions, thread }
+			},
+			SyncRole::Follower => {
+				let address = config.address();
+				let socket = UdpSocket::bind(address)
+					.with_context(|| format!("Failed to listen for page turns on {}", address))?;
+				socket.set_read_timeout(Some(POLL_INTERVAL))?;
+				log::info!("Following page turns on {}", address);
+
+				let stop = Arc::new(AtomicBool::new(false));
+				let tx = self.tx.clone();
+				let thread = std::thread::Builder::new()
+					.name("Sync follower".into())
+					.spawn({
+						let stop = stop.clone();
+						move || {
+							follow(socket, stop, move |position| {
+								tx.send(RemoteCommand::Follow(position)).is_ok()
+							})
+						}
+					})
+					.context("Failed to spawn thread")?;
+				Worker::Follower { stop, thread }
+			},
+		};
+		*self.worker.borrow_mut() = Some(worker);
+		Ok(())
+	}
+
+	/// Tell the followers where we are, if we are leading. `None` when no song is loaded.
+	pub fn publish(&self, position: Option<SyncPosition>) {
+		if let Some(Worker::Leader { positions, .. }) = &*self.worker.borrow() {
+			/* If the thread died, it already logged why */
+			let _ = positions.send(position);
+		}
+	}
+
+	/// Stop leading or following and wait for the background thread to finish
+	pub fn shutdown(&self) {
+		let thread = match self.worker.borrow_mut().take() {
+			Some(Worker::Leader { positions, thread }) => {
+				std::mem::drop(positions);
+				thread
+			},
+			Some(Worker::Follower { stop, thread }) => {
+				stop.store(true, Ordering::Relaxed);
+				thread
+			},
+			None => return,
+		};
+		if thread.join().is_err() {
+			log::error!("The sync thread panicked");
+		}
+	}
+}
+
+/// Send each new position, and repeat the last one periodically. Stops when the channel is closed.
+fn lead(
+	socket: UdpSocket,
+	target: SocketAddr,
+	positions: mpsc::Receiver<Option<SyncPosition>>,
+	heartbeat: Duration,
+) {
+	let leader = uuid::Uuid::new_v4();
+	let mut sequence = 0;
+	let mut current = None;
+	loop {
+		match positions.recv_timeout(heartbeat) {
+			Ok(position) => {
+				current = position;
+				sequence += 1;
+			},
+			Err(mpsc::RecvTimeoutError::Timeout) => {},
+			Err(mpsc::RecvTimeoutError::Disconnected) => return,
+		}
+		if let Some(position) = current {
+			let message = SyncMessage::V0 {
+				leader,
+				sequence,
+				position,
+			};
+			/* Don't give up, the network may come back */
+			if let Err(err) = socket.send_to(&message.encode(), target) {
+				log::warn!("Failed to send page position to {}: {}", target, err);
+			}
+		}
+	}
+}
+
+/// Receive positions until stopped or until `on_position` returns `false`.
+/// Repetitions of the same position are filtered out.
+fn follow(
+	socket: UdpSocket,
+	stop: Arc<AtomicBool>,
+	mut on_position: impl FnMut(SyncPosition) -> bool,
+) {
+	let mut buffer = [0; 1024];
+	let mut last_seen = None;
+	while !stop.load(Ordering::Relaxed) {
+		let (length, sender) = match socket.recv_from(&mut buffer) {
+			Ok(received) => received,
+			Err(err)
+				if err.kind() == std::io::ErrorKind::WouldBlock
+					|| err.kind() == std::io::ErrorKind::TimedOut =>
+			{
+				continue
+			},
+			Err(err) => {
+				log::error!("Failed to receive page positions: {:?}", err);
+				return;
+			},
+		};
+		let SyncMessage::V0 {
+			leader,
+			sequence,
+			position,
+		} = match SyncMessage::decode(&buffer[..length]) {
+			Ok(message) => message,
+			Err(err) => {
+				log::warn!("Ignoring packet from {}: {:?}", sender, err);
+				continue;
+			},
+		};
+		if last_seen == Some((leader, sequence)) {
+			continue;
+		}
+		last_seen = Some((leader, sequence));
+		log::debug!("Page position from {}: {:?}", sender, position);
+		if !on_position(position) {
+			/* Nobody is listening anymore */
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_message() {
+		let message = SyncMessage::V0 {
+			leader: uuid::Uuid::new_v4(),
+			sequence: 3,
+			position: SyncPosition {
+				song: uuid::Uuid::new_v4(),
+				version: uuid::Uuid::new_v4(),
+				staff: StaffIndex(7),
+			},
+		};
+		assert_eq!(SyncMessage::decode(&message.encode()).unwrap(), message);
+		assert!(SyncMessage::decode(b"{\"version\": \"99\"}").is_err());
+	}
+
+	#[test]
+	fn test_lead_and_follow() {
+		let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		socket.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+		let target = socket.local_addr().unwrap();
+		let stop = Arc::new(AtomicBool::new(false));
+		let (tx, rx) = mpsc::channel();
+		let follower = std::thread::spawn({
+			let stop = stop.clone();
+			move || follow(socket, stop, move |position| tx.send(position).is_ok())
+		});
+
+		let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		sender.send_to(b"garbage", target).unwrap();
+		let (positions, positions_rx) = mpsc::channel();
+		let leader = std::thread::spawn(move || {
+			lead(sender, target, positions_rx, Duration::from_millis(20))
+		});
+
+		let song = uuid::Uuid::new_v4();
+		let version = uuid::Uuid::new_v4();
+		let position = |staff| SyncPosition {
+			song,
+			version,
+			staff: StaffIndex(staff),
+		};
+		let timeout = Duration::from_secs(5);
+
+		positions.send(Some(position(4))).unwrap();
+		assert_eq!(rx.recv_timeout(timeout).unwrap(), position(4));
+		/* Heartbeats don't count as page turns */
+		std::thread::sleep(Duration::from_millis(200));
+		assert!(rx.try_recv().is_err());
+
+		positions.send(None).unwrap();
+		positions.send(Some(position(9))).unwrap();
+		assert_eq!(rx.recv_timeout(timeout).unwrap(), position(9));
+
+		std::mem::drop(positions);
+		leader.join().unwrap();
+		stop.store(true, Ordering::Relaxed);
+		follower.join().unwrap();
+	}
+}
@@ -3,6 +3,7 @@
 //! Hosts the two main sub panes, library and song.
 //! Also does fullscreen handling.
 
+use anyhow::Context;
 use dinoscore::{prelude::*, *};
 
 glib::wrapper! {
@@ -21,6 +22,25 @@ impl Window {
 		self.imp().show_no_gl_toast();
 	}
 
+	/// Apply the command line arguments
+	pub fn open(&self, options: &crate::args::LaunchOptions) {
+		if options.fullscreen {
+			self.fullscreen();
+		}
+		if let Some(song) = &options.song {
+			if let Err(err) = self
+				.imp()
+				.library
+				.open_song(song, &options.start, options.scale)
+			{
+				log::error!("{:?}", err);
+				self.imp()
+					.toasts
+					.add_toast(&adw::Toast::new(&format!("{:#}", err)));
+			}
+		}
+	}
+
 	#[cfg(test)]
 	pub fn library(&self) -> crate::library_widget::LibraryWidget {
 		self.imp().library.get()
@@ -32,6 +52,42 @@ impl Window {
 	}
 }
 
+/// What the D-Bus interface sees of the window
+struct WindowViewer(glib::WeakRef<Window>);
+
+impl crate::dbus::Viewer for WindowViewer {
+	fn state(&self) -> crate::dbus::ViewerState {
+		let window = match self.0.upgrade() {
+			Some(window) => window,
+			None => return Default::default(),
+		};
+		let song = &window.imp().song;
+		crate::dbus::ViewerState {
+			song: song
+				.property::<Option<String>>("song-id")
+				.unwrap_or_default(),
+			title: song
+				.property::<Option<String>>("song-name")
+				.unwrap_or_default(),
+			piece: song.property::<Option<String>>("piece").unwrap_or_default(),
+			page: song.property::<i32>("page"),
+		}
+	}
+
+	fn activate_action(&self, action: &str) {
+		if let Some(window) = self.0.upgrade() {
+			if let Err(err) = window.imp().song.activate_action(action, None) {
+				log::warn!("Failed to activate {}: {}", action, err);
+			}
+		}
+	}
+
+	fn open_song(&self, song: uuid::Uuid, staff: collection::StaffIndex) -> anyhow::Result<()> {
+		let window = self.0.upgrade().context("The window is gone")?;
+		window.imp().library.load_song_checked(song, staff)
+	}
+}
+
 mod imp {
 	use super::*;
 
@@ -48,6 +104,7 @@ mod imp {
 		pub song: TemplateChild<crate::song_widget::SongWidget>,
 		/// When a song is loaded, prevent the screen from going blank
 		inhibit_cookie: Cell<Option<u32>>,
+		dbus: OnceCell<crate::dbus::Exported>,
 	}
 
 	#[glib::object_subclass]
@@ -95,6 +152,54 @@ mod imp {
 			let library = Rc::new(RefCell::new(library));
 			self.song.init(library.clone());
 			self.library.init(library, self.song.get());
+			self.song.connect_local(
+				"show-message",
+				false,
+				clone!(@weak obj => @default-return None, move |args| {
+					let message = args[1].get::<String>().unwrap();
+					obj.imp().toasts.add_toast(&adw::Toast::new(&message));
+					None
+				}),
+			);
+
+			/* MIDI pedal configuration */
+			#[cfg(unix)]
+			{
+				let pedal_settings = gio::SimpleAction::new("pedal-settings", None);
+				obj.add_action(&pedal_settings);
+				pedal_settings.connect_activate(
+					clone!(@weak obj => @default-panic, move |_a, _p| {
+						match obj.imp().song.pedals() {
+							Some(pedals) => crate::pedal_dialog::PedalDialog::new(pedals, &obj).present(),
+							None => log::warn!("MIDI pedals are not available"),
+						}
+					}),
+				);
+			}
+
+			/* Remote control over D-Bus */
+			let exported = catch!({
+				let connection = gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE)?;
+				crate::dbus::Exported::export(&connection, Rc::new(WindowViewer(obj.downgrade())))
+			});
+			match exported {
+				Ok(exported) => {
+					self.dbus.set(exported).ok().unwrap();
+					self.song.connect_notify_local(
+						None,
+						clone!(@weak obj => move |_song, _property| {
+							obj.imp().dbus.get().unwrap().update();
+						}),
+					);
+				},
+				Err(err) => log::error!("Failed to export the D-Bus interface: {:?}", err),
+			}
+
+			let preferences = gio::SimpleAction::new("preferences", None);
+			obj.add_action(&preferences);
+			preferences.connect_activate(clone!(@weak obj => @default-panic, move |_a, _p| {
+				crate::preferences::PreferencesWindow::new(&obj.imp().song, &obj).present();
+			}));
 
 			/* Fullscreen handling */
 
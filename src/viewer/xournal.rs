@@ -1,67 +1,105 @@
 //! Integration with Xournal++ for annotations
 //!
-//! Automatically launches Xournal++ to annotate the score. On save, the changes
-//! are incorporated back into our format.
+//! We create a Xournal++ document with the score as PDF background. Exporting it without the
+//! background gives exactly the annotations.
 
 use super::*;
+use crate::annotation_editor::AnnotationEditor;
 use anyhow::Context;
 use gtk::glib;
 use lenient_version::Version;
-use std::{io::Write, process::Command};
-
-pub fn run_editor(song: &mut collection::SongFile, page: usize) -> anyhow::Result<()> {
-	// TODO don't hardcode here
-	let xdg = xdg::BaseDirectories::with_prefix("dinoscore")?;
-
-	catch!({
-		log::debug!("Checking Xournal++ availability and version");
-		let version = Command::new("xournalpp")
-			.arg("--version")
-			.output()?;
-		anyhow::ensure!(version.status.success());
-		let version = String::from_utf8(version.stdout)?;
-		let version: String = version
-			.lines()
-			.next()
-			.and_then(|line| line.strip_prefix("Xournal++ ").map(String::from))
-			.ok_or_else(|| anyhow::format_err!("`xournalpp --version` somehow gave weird input, expecting at least one line of text."))?;
-		let version = lenient_semver_parser::parse::<Version>(&version)
-			.map_err(|err| err.owned())?;
-
-		#[allow(non_snake_case)]
-		let MINIMUM_VERSION = lenient_semver_parser::parse::<Version>("1.1.0").unwrap();
-		anyhow::ensure!(version >= MINIMUM_VERSION, "A Xournal++ version >= 1.1.0 is required");
+use std::{
+	io::{Read, Write},
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+pub struct Xournal;
+
+impl Xournal {
+	/// Xournal++ looks for attached backgrounds next to the document
+	fn background_file(document: &Path) -> PathBuf {
+		document.parent().unwrap().join({
+			let mut name = document.file_name().unwrap().to_owned();
+			name.push(".background.pdf");
+			name
+		})
+	}
+
+	/// The background of a page is the page of the score with the same index
+	fn background(index: usize) -> String {
+		if index == 0 {
+			/* The first page tells where the PDF is */
+			format!(
+				r#"<background type="pdf" pageno="{}ll" domain="attach" filename="background.pdf" />"#,
+				index + 1
+			)
+		} else {
+			format!(r#"<background type="pdf" pageno="{}ll" />"#, index + 1)
+		}
+	}
+
+	/// An empty page for the page of the score with that index
+	fn write_page(
+		xopp: &mut impl Write,
+		pdf: &poppler::Document,
+		index: usize,
+	) -> anyhow::Result<()> {
+		let page = pdf
+			.page(index as i32)
+			.ok_or_else(|| anyhow::format_err!("The score has no page {}", index + 1))?;
+		let (width, height) = page.size();
+		writeln!(xopp, r#"<page width="{}" height="{}">"#, width, height)?;
+		writeln!(xopp, "{}", Self::background(index))?;
+		writeln!(xopp, "<layer />")?;
+		writeln!(xopp, "</page>")?;
 		Ok(())
-	}).context("Failed to check Xournal++ version")?;
-
-	let annotations_file = xdg.place_data_file(format!("annotations/{}.xopp", song.uuid()))?;
-	let annotations_background_file = annotations_file.parent().unwrap().join({
-		let mut name = annotations_file.file_name().unwrap().to_owned();
-		name.push(".background.pdf");
-		name
-	});
-	let annotations_export = xdg.place_data_file(format!("annotations/{}.pdf", song.uuid()))?;
-
-	let background_pdf: Vec<u8> = catch!({
-		log::debug!("Creating the PDF background for the file");
-		let background_pdf = pipeline::pipe!(
-			song.load_pages(|_index, file, data| Ok((data, file.ends_with(".pdf"))))()
-				.context("Failed to load pages")?
-			=> Into::into
-			=> image_util::concat_files
-		)
-		.context("Internal error")?;
-		std::fs::write(&annotations_background_file, &background_pdf)
-			.context("Failed to write file")?;
-		anyhow::Result::<_>::Ok(background_pdf)
-	})
-	.context("Failed to create the background PDF for the Xournal document")?;
-
-	if !annotations_file.exists() {
-		log::debug!("Creating an empty file for editing");
+	}
+}
+
+impl AnnotationEditor for Xournal {
+	fn name(&self) -> &str {
+		"Xournal++"
+	}
+
+	fn check_available(&self) -> anyhow::Result<()> {
+		catch!({
+			log::debug!("Checking Xournal++ availability and version");
+			let version = Command::new("xournalpp")
+				.arg("--version")
+				.output()
+				.context("Xournal++ is not installed")?;
+			anyhow::ensure!(version.status.success());
+			let version = String::from_utf8(version.stdout)?;
+			let version: String = version
+				.lines()
+				.next()
+				.and_then(|line| line.strip_prefix("Xournal++ ").map(String::from))
+				.ok_or_else(|| anyhow::format_err!("`xournalpp --version` somehow gave weird input, expecting at least one line of text."))?;
+			let version = lenient_semver_parser::parse::<Version>(&version)
+				.map_err(|err| err.owned())?;
+
+			#[allow(non_snake_case)]
+			let MINIMUM_VERSION = lenient_semver_parser::parse::<Version>("1.1.0").unwrap();
+			anyhow::ensure!(version >= MINIMUM_VERSION, "A Xournal++ version >= 1.1.0 is required");
+			Ok(())
+		}).context("Failed to check Xournal++ version")
+	}
+
+	fn document(&self, song: &uuid::Uuid) -> anyhow::Result<PathBuf> {
+		annotations::external_file(song, "xopp")
+	}
 
+	fn prepare(&self, document: &Path, background_pdf: Vec<u8>) -> anyhow::Result<()> {
+		std::fs::write(Self::background_file(document), &background_pdf)
+			.context("Failed to write the background PDF for the Xournal document")?;
+		if document.exists() {
+			return Ok(());
+		}
+
+		log::debug!("Creating an empty file for editing");
 		catch!({
-			let xopp = std::fs::File::create(&annotations_file)?;
+			let xopp = std::fs::File::create(document)?;
 			let mut xopp = flate2::write::GzEncoder::new(xopp, Default::default());
 			let pdf =
 				poppler::Document::from_bytes(&glib::Bytes::from_owned(background_pdf), None)?;
@@ -75,66 +113,96 @@ pub fn run_editor(song: &mut collection::SongFile, page: usize) -> anyhow::Resul
 			)?;
 
 			for index in 0..pdf.n_pages() {
-				let page = pdf.page(index).unwrap();
-				let (width, height) = page.size();
-				writeln!(xopp, r#"<page width="{}" height="{}">"#, width, height)?;
-				if index == 0 {
-					writeln!(
-						xopp,
-						r#"<background type="pdf" pageno="{}ll" domain="attach" filename="background.pdf" />"#,
-						(index + 1)
-					)?;
-				} else {
-					writeln!(
-						xopp,
-						r#"<background type="pdf" pageno="{}ll" />"#,
-						(index + 1)
-					)?;
-				}
-				writeln!(xopp, "<layer />")?;
-				writeln!(xopp, "</page>")?;
+				Self::write_page(&mut xopp, &pdf, index as usize)?;
 			}
 
 			writeln!(xopp, "</xournal>")?;
 			anyhow::Result::<_>::Ok(())
 		})
-		.context("Failed to create Xournal file")?;
-	} else {
-		std::mem::drop(background_pdf);
+		.context("Failed to create Xournal file")
+	}
+
+	fn launch(&self, document: &Path, page: usize) -> anyhow::Result<std::process::Child> {
+		Ok(Command::new("xournalpp")
+			.args(&[
+				"--page".as_ref(),
+				page.to_string().as_ref(),
+				document.as_os_str(),
+			])
+			.spawn()?)
+	}
+
+	fn export(&self, document: &Path, overlay: &Path) -> anyhow::Result<()> {
+		let run = Command::new("xournalpp")
+			.args(&[
+				"--export-no-background".as_ref(),
+				"--create-pdf".as_ref(),
+				overlay.as_os_str(),
+				document.as_os_str(),
+			])
+			.output()
+			.context("Failed to launch Xournal")?;
+		anyhow::ensure!(
+			run.status.success(),
+			"Failed to export the annotations: {}",
+			String::from_utf8_lossy(&run.stderr).trim()
+		);
+		Ok(())
+	}
+
+	/// Moves the `<page>` elements around, and points their backgrounds to the new page numbers
+	fn remap(
+		&self,
+		document: &Path,
+		pages: &[Option<usize>],
+		background_pdf: &[u8],
+	) -> anyhow::Result<Vec<u8>> {
+		catch!({
+			let mut content = String::new();
+			flate2::read::GzDecoder::new(std::fs::File::open(document)?)
+				.read_to_string(&mut content)?;
+			let start = content
+				.find("<page ")
+				.ok_or_else(|| anyhow::format_err!("The document has no pages"))?;
+			let end = content.rfind("</page>").unwrap() + "</page>".len();
+			let old_pages: Vec<&str> = content[start..end]
+				.split_inclusive("</page>")
+				.map(str::trim)
+				.collect();
+			let pdf = poppler::Document::from_bytes(&glib::Bytes::from(background_pdf), None)?;
+
+			let mut xopp = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+			write!(xopp, "{}", &content[..start])?;
+			for (index, page) in pages.iter().enumerate() {
+				match page.and_then(|page| old_pages.get(page)) {
+					Some(page) => {
+						/* Only touch the backgrounds that refer to the score */
+						let background = page.find("<background").and_then(|from| {
+							let to = from + page[from..].find('>')? + 1;
+							page[from..to].contains(r#"type="pdf""#).then(|| (from, to))
+						});
+						match background {
+							Some((from, to)) => writeln!(
+								xopp,
+								"{}{}{}",
+								&page[..from],
+								Self::background(index),
+								&page[to..]
+							)?,
+							None => writeln!(xopp, "{}", page)?,
+						}
+					},
+					None => Self::write_page(&mut xopp, &pdf, index)?,
+				}
+			}
+			write!(xopp, "{}", &content[end..])?;
+			anyhow::Result::<_>::Ok(xopp.finish()?)
+		})
+		.with_context(|| format!("Failed to rearrange the pages of '{}'", document.display()))
+	}
+
+	fn finish(&self, document: &Path) -> anyhow::Result<()> {
+		std::fs::remove_file(Self::background_file(document))?;
+		Ok(())
 	}
-	anyhow::ensure!(
-		annotations_file.is_file(),
-		"'{}' must be a regular file. Please delete whatever is there",
-		annotations_file.display()
-	);
-
-	log::debug!("Launching Xournal++ editor (page {})", page);
-	let run = Command::new("xournalpp")
-		.args(&[
-			"--page".as_ref(),
-			page.to_string().as_ref(),
-			annotations_file.as_os_str(),
-		])
-		.status()?;
-	anyhow::ensure!(run.success());
-
-	log::debug!("Integrating back the annotations into DiNoScore");
-	let run = Command::new("xournalpp")
-		.args(&[
-			"--export-no-background".as_ref(),
-			"--create-pdf".as_ref(),
-			annotations_export.as_os_str(),
-			annotations_file.as_os_str(),
-		])
-		.status()
-		.context("Failed to launch Xournal")?;
-	anyhow::ensure!(run.success());
-
-	catch!({
-		std::fs::remove_file(annotations_background_file)?;
-		anyhow::Result::<_>::Ok(())
-	})
-	.context("Post-editor cleanup failed")?;
-
-	Ok(())
 }